@@ -0,0 +1,639 @@
+// The nondeterministic evaluator of SICP 4.3. It executes the same analyzed
+// nodes as `eval`, in success/failure continuation-passing style: a success
+// continuation receives a value and the failure continuation to call if that
+// value leads to a dead end, and `amb` installs a failure continuation that
+// tries its next choice.
+//
+// Continuations never call each other directly. They return the next `Step`
+// to a driver loop, so long searches run in constant Rust stack.
+//
+// An error is a `Step` as well, which the driver loop hands to the innermost
+// guard or exception handler in force.
+
+use super::analyze::*;
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::gc;
+use super::sexp::*;
+use super::value::*;
+
+use std::cell::RefCell;
+use std::fs;
+use std::ops::Deref;
+use std::rc::Rc;
+
+type Succeed = Rc<Continuation<dyn Fn(Rc<Value>, Fail) -> Step>>;
+type Fail = Rc<Continuation<dyn Fn() -> Step>>;
+
+// Each pending choice's failure continuation holds on to the previous one, so
+// continuations nest as deeply as a search is long, and dropping them
+// recursively would overflow the stack. A continuation dropped while another
+// one is being dropped is queued instead, and the outermost drop empties the
+// queue.
+pub struct Continuation<F: ?Sized + 'static>(Option<Box<F>>);
+
+type Graveyard = Option<Vec<Box<dyn FnOnce()>>>;
+
+// The guards and exception handlers in force, innermost first. Each
+// continuation reinstates the ones in force where it was made, since a
+// failure can take the search back into a guard's body after the guard has
+// returned.
+type Handlers = Option<Rc<Handler>>;
+
+struct Handler {
+    catch: Catch,
+    outer: Handlers,
+}
+
+enum Catch {
+    // A guard, with the environment and success continuation it was
+    // evaluated with.
+    Guard {
+        node: Rc<Node>,
+        env: Rc<Environment>,
+        succeed: Succeed,
+    },
+    // A with-exception-handler's handler.
+    Procedure(Rc<Value>),
+}
+
+thread_local! {
+    static GRAVEYARD: RefCell<Graveyard> = const { RefCell::new(None) };
+    static HANDLERS: RefCell<Handlers> = const { RefCell::new(None) };
+}
+
+fn handlers() -> Handlers {
+    HANDLERS.with(|handlers| handlers.borrow().clone())
+}
+
+fn set_handlers(new: Handlers) {
+    HANDLERS.with(|handlers| *handlers.borrow_mut() = new);
+}
+
+fn push_handler(catch: Catch) {
+    let outer = handlers();
+    set_handlers(Some(Rc::new(Handler { catch, outer })));
+}
+
+impl<F: ?Sized> Deref for Continuation<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<F: ?Sized> Drop for Continuation<F> {
+    fn drop(&mut self) {
+        let f = match self.0.take() {
+            Some(f) => f,
+            None => return,
+        };
+        let outermost = GRAVEYARD.with(|graveyard| match &mut *graveyard.borrow_mut() {
+            Some(queue) => {
+                queue.push(Box::new(move || drop(f)));
+                None
+            }
+            graveyard => {
+                *graveyard = Some(vec![]);
+                Some(f)
+            }
+        });
+        if let Some(f) = outermost {
+            drop(f);
+            while let Some(next) =
+                GRAVEYARD.with(|graveyard| graveyard.borrow_mut().as_mut().and_then(Vec::pop))
+            {
+                next();
+            }
+            GRAVEYARD.with(|graveyard| *graveyard.borrow_mut() = None);
+        }
+    }
+}
+
+fn on_success(f: impl Fn(Rc<Value>, Fail) -> Step + 'static) -> Succeed {
+    let handlers = handlers();
+    Rc::new(Continuation(Some(Box::new(move |value, fail| {
+        set_handlers(handlers.clone());
+        f(value, fail)
+    }))))
+}
+
+fn on_failure(f: impl Fn() -> Step + 'static) -> Fail {
+    let handlers = handlers();
+    Rc::new(Continuation(Some(Box::new(move || {
+        set_handlers(handlers.clone());
+        f()
+    }))))
+}
+
+enum Step {
+    Next(Box<dyn FnOnce() -> Step>),
+    Succeeded(Rc<Value>, Fail),
+    Failed,
+    // An error, and the failure continuation where it was raised.
+    Error(LispError, Fail),
+}
+
+pub enum Outcome {
+    Value(Rc<Value>),
+    NoMoreValues(Sexp),
+    NoCurrentProblem,
+}
+
+// The driver loop's state: the current problem and the failure continuation
+// that `try-again` calls to get its next value.
+#[derive(Default)]
+pub struct Driver {
+    problem: Option<(Sexp, Fail)>,
+}
+
+// Turns on the amb special forms and defines the procedures the evaluator
+// itself needs, such as require.
+pub(crate) fn install(env: &Rc<Environment>) -> Result<(), LispError> {
+    env.set_amb(true);
+    let require = parser::expression("(define (require p) (if (not p) (amb) 'ok))")
+        .map_err(|e| LispError::Parse(e.to_string()))?;
+    eval(&require, env)?;
+    Ok(())
+}
+
+impl Driver {
+    pub fn eval(&mut self, exp: &Sexp, env: &Rc<Environment>) -> Result<Outcome, LispError> {
+        if let Sexp::Symbol(s) = exp {
+            if s == "try-again" {
+                return match self.problem.take() {
+                    Some((problem, fail)) => self.run(problem, fail()),
+                    None => Ok(Outcome::NoCurrentProblem),
+                };
+            }
+        }
+        self.problem = None;
+        set_handlers(None);
+        let node = Rc::new(analyze(exp, &env.scope(), env)?);
+        let succeed = on_success(Step::Succeeded);
+        let fail = on_failure(|| Step::Failed);
+        let step = execute(&node, env, succeed, fail);
+        self.run(exp.clone(), step)
+    }
+
+    fn run(&mut self, problem: Sexp, mut step: Step) -> Result<Outcome, LispError> {
+        loop {
+            step = match step {
+                Step::Next(next) => next(),
+                Step::Succeeded(value, fail) => {
+                    self.problem = Some((problem, fail));
+                    return Ok(Outcome::Value(value));
+                }
+                Step::Failed => return Ok(Outcome::NoMoreValues(problem)),
+                Step::Error(e, fail) => match handlers() {
+                    Some(handler) if e.is_catchable() => raise(&handler, e, fail),
+                    _ => {
+                        set_handlers(None);
+                        return Err(e);
+                    }
+                },
+            }
+        }
+    }
+}
+
+// Hands an error to a guard or handler, with the ones outside it in force.
+// A handler may not return, as for raise in the ordinary evaluator. A later
+// failure still backtracks to the choices made before the error.
+fn raise(handler: &Handler, e: LispError, fail: Fail) -> Step {
+    set_handlers(handler.outer.clone());
+    match &handler.catch {
+        Catch::Guard { node, env, succeed } => {
+            let env = match &**node {
+                Node::Guard { scope, .. } => Environment::extend(scope, vec![e.payload()], env),
+                _ => unreachable!("not a guard"),
+            };
+            execute_clauses(node.clone(), 0, &env, e, succeed.clone(), fail)
+        }
+        Catch::Procedure(procedure) => {
+            let payload = e.payload();
+            let returned = on_success(move |_, fail| {
+                let e = LispError::User {
+                    message: Rc::new(Value::String(
+                        "Exception handler returned from raise".to_string(),
+                    )),
+                    irritants: vec![payload.clone()],
+                };
+                Step::Error(e, fail)
+            });
+            execute_application(procedure.clone(), vec![e.payload()], returned, fail)
+        }
+    }
+}
+
+// Tries a guard's clauses from `i` on, with the guard's variable bound in
+// `env`. The error is raised again when none of them applies.
+fn execute_clauses(
+    node: Rc<Node>,
+    i: usize,
+    env: &Rc<Environment>,
+    e: LispError,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    let clause = match &*node {
+        Node::Guard { clauses, .. } => clauses.get(i),
+        _ => unreachable!("not a guard"),
+    };
+    let (test, expressions) = match clause {
+        None => return Step::Error(e, fail),
+        Some(GuardClause::Else(expressions)) => return execute(expressions, env, succeed, fail),
+        Some(GuardClause::Test(test, expressions)) => (test.clone(), expressions.clone()),
+    };
+    let env2 = env.clone();
+    let node2 = node.clone();
+    let next = on_success(move |value, fail| {
+        if !value.is_true() {
+            let (node, e) = (node2.clone(), e.clone());
+            return execute_clauses(node, i + 1, &env2, e, succeed.clone(), fail);
+        }
+        match &expressions {
+            None => succeed(value, fail),
+            Some(expressions) => execute(expressions, &env2, succeed.clone(), fail),
+        }
+    });
+    execute(&test, env, next, fail)
+}
+
+fn succeed_with(succeed: Succeed, value: Rc<Value>, fail: Fail) -> Step {
+    Step::Next(Box::new(move || succeed(value, fail)))
+}
+
+fn result(value: Result<Rc<Value>, LispError>, succeed: Succeed, fail: Fail) -> Step {
+    match value {
+        Ok(value) => succeed_with(succeed, value, fail),
+        Err(e) => Step::Error(e, fail),
+    }
+}
+
+fn execute(node: &Rc<Node>, env: &Rc<Environment>, succeed: Succeed, fail: Fail) -> Step {
+    match &**node {
+        Node::Constant(value) => succeed_with(succeed, value.clone(), fail),
+        Node::LocalVariable {
+            depth, index, name, ..
+        } => result(env.lookup_address(*depth, *index, name), succeed, fail),
+        Node::GlobalVariable { name, .. } => result(env.lookup_global(name), succeed, fail),
+        Node::LocalAssignment { .. } | Node::GlobalAssignment { .. } => {
+            execute_assignment_node(node, env, succeed, fail, true)
+        }
+        Node::PermanentAssignment(assignment) => {
+            execute_assignment_node(assignment, env, succeed, fail, false)
+        }
+        Node::LocalDefinition { index, value } => {
+            let index = *index;
+            let env2 = env.clone();
+            let define =
+                on_success(move |value, fail| succeed(env2.define_address(index, value), fail));
+            execute(value, env, define, fail)
+        }
+        Node::Default { index, definition } => {
+            if env.is_bound_address(*index) {
+                succeed_with(succeed, Value::ok(), fail)
+            } else {
+                execute(definition, env, succeed, fail)
+            }
+        }
+        Node::GlobalDefinition { name, value } => {
+            let name = name.clone();
+            let env2 = env.clone();
+            let define =
+                on_success(move |value, fail| succeed(env2.define_global(&name, value), fail));
+            execute(value, env, define, fail)
+        }
+        Node::If(p, c, a) => {
+            let (c, a) = (c.clone(), a.clone());
+            let env2 = env.clone();
+            let branch = on_success(move |p, fail| {
+                let node = if p.is_true() { &c } else { &a };
+                execute(node, &env2, succeed.clone(), fail)
+            });
+            execute(p, env, branch, fail)
+        }
+        Node::Sequence(nodes) => execute_sequence(nodes.clone(), 0, env, succeed, fail),
+        Node::Lambda(lambda) => succeed_with(succeed, lambda.make_procedure(env), fail),
+        Node::Cons(car, cdr) => {
+            let cdr = cdr.clone();
+            let env2 = env.clone();
+            let car_succeed = on_success(move |car, fail| {
+                let succeed = succeed.clone();
+                let cdr_succeed =
+                    on_success(move |cdr, fail| succeed(Value::cons(car.clone(), cdr), fail));
+                execute(&cdr, &env2, cdr_succeed, fail)
+            });
+            execute(car, env, car_succeed, fail)
+        }
+        Node::Load(file) => {
+            let env2 = env.clone();
+            let load = on_success(move |file, fail| {
+                let exps = match &*file {
+                    Value::String(path) => read(path),
+                    _ => Err(LispError::invalid_arguments("load", &[file])),
+                };
+                match exps {
+                    Ok(exps) => execute_file(exps.into(), 0, &env2, succeed.clone(), fail),
+                    Err(e) => Step::Error(e, fail),
+                }
+            });
+            execute(file, env, load, fail)
+        }
+        Node::Guard { body, .. } => {
+            push_handler(Catch::Guard {
+                node: node.clone(),
+                env: env.clone(),
+                succeed: succeed.clone(),
+            });
+            execute(body, env, succeed, fail)
+        }
+        Node::WithExceptionHandler(handler, thunk) => {
+            let thunk = thunk.clone();
+            let env2 = env.clone();
+            let install = on_success(move |handler, fail| {
+                let succeed = succeed.clone();
+                let env = env2.clone();
+                let call = on_success(move |thunk, fail| {
+                    push_handler(Catch::Procedure(handler.clone()));
+                    execute_application(thunk, vec![], succeed.clone(), fail)
+                });
+                execute(&thunk, &env, call, fail)
+            });
+            execute(handler, env, install, fail)
+        }
+        Node::Amb(choices) => try_next(choices.clone(), 0, env, succeed, fail),
+        Node::IfFail(e, alternative) => {
+            let alternative = alternative.clone();
+            let env2 = env.clone();
+            let succeed2 = succeed.clone();
+            let otherwise =
+                on_failure(move || execute(&alternative, &env2, succeed2.clone(), fail.clone()));
+            execute(e, env, succeed, otherwise)
+        }
+        Node::Application {
+            operator,
+            operands,
+            form,
+            scope,
+        } => {
+            let (operands, form, scope) = (operands.clone(), form.clone(), scope.clone());
+            let env2 = env.clone();
+            let call = on_success(move |procedure, fail| {
+                let expansion = match &*procedure {
+                    Value::Macro(transformer) => match &*form {
+                        Sexp::Pair(_, operands, _) => Some(expand(transformer, operands)),
+                        _ => None,
+                    },
+                    Value::Syntax(rules) => Some(rules.expand(&form, &scope)),
+                    _ => None,
+                };
+                match expansion.map(|e| e.and_then(|e| analyze(&e, &scope, &env2))) {
+                    Some(Ok(node)) => execute(&Rc::new(node), &env2, succeed.clone(), fail),
+                    Some(Err(e)) => Step::Error(e, fail),
+                    None => {
+                        let args = vec![];
+                        let succeed = succeed.clone();
+                        get_args(operands.clone(), args, &env2, procedure, succeed, fail)
+                    }
+                }
+            });
+            execute(operator, env, call, fail)
+        }
+        // The remaining forms cannot make choices and run with the ordinary
+        // evaluator.
+        Node::Macro(_)
+        | Node::Append(..)
+        | Node::Vector(_)
+        | Node::MacroExpand { .. }
+        | Node::Delay(_)
+        | Node::Debug(_)
+        | Node::ShowEnvironment { .. } => result(super::eval::execute(node, env), succeed, fail),
+    }
+}
+
+fn execute_assignment_node(
+    node: &Node,
+    env: &Rc<Environment>,
+    succeed: Succeed,
+    fail: Fail,
+    undo: bool,
+) -> Step {
+    match node {
+        Node::LocalAssignment {
+            depth,
+            index,
+            name,
+            value,
+        } => {
+            let variable = Variable::Local(*depth, *index, name.clone());
+            execute_assignment(variable, value, env, succeed, fail, undo)
+        }
+        Node::GlobalAssignment { name, value } => {
+            let variable = Variable::Global(name.clone());
+            execute_assignment(variable, value, env, succeed, fail, undo)
+        }
+        _ => unreachable!("not an assignment"),
+    }
+}
+
+#[derive(Clone)]
+enum Variable {
+    Local(usize, usize, String),
+    Global(String),
+}
+
+impl Variable {
+    fn lookup(&self, env: &Environment) -> Result<Rc<Value>, LispError> {
+        match self {
+            Variable::Local(depth, index, name) => env.lookup_address(*depth, *index, name),
+            Variable::Global(name) => env.lookup_global(name),
+        }
+    }
+
+    fn set(&self, env: &Environment, value: Rc<Value>) -> Result<Rc<Value>, LispError> {
+        match self {
+            Variable::Local(depth, index, name) => env.set_address(*depth, *index, name, value),
+            Variable::Global(name) => env.set_global(name, value),
+        }
+    }
+}
+
+// An ordinary assignment is undone when the computation after it fails.
+fn execute_assignment(
+    variable: Variable,
+    value: &Rc<Node>,
+    env: &Rc<Environment>,
+    succeed: Succeed,
+    fail: Fail,
+    undo: bool,
+) -> Step {
+    let env2 = env.clone();
+    let assign = on_success(move |value, fail| {
+        let old = match variable.lookup(&env2) {
+            Ok(old) => old,
+            Err(e) => return Step::Error(e, fail),
+        };
+        if let Err(e) = variable.set(&env2, value) {
+            return Step::Error(e, fail);
+        }
+        let fail = if undo {
+            let (variable, env) = (variable.clone(), env2.clone());
+            on_failure(move || {
+                let _ = variable.set(&env, old.clone());
+                let fail = fail.clone();
+                Step::Next(Box::new(move || fail()))
+            })
+        } else {
+            fail
+        };
+        succeed_with(succeed.clone(), Value::ok(), fail)
+    });
+    execute(value, env, assign, fail)
+}
+
+fn execute_sequence(
+    nodes: Rc<[Rc<Node>]>,
+    i: usize,
+    env: &Rc<Environment>,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    if i + 1 >= nodes.len() {
+        return execute(&nodes[i], env, succeed, fail);
+    }
+    let env2 = env.clone();
+    let rest = nodes.clone();
+    let next = on_success(move |_, fail| {
+        execute_sequence(rest.clone(), i + 1, &env2, succeed.clone(), fail)
+    });
+    execute(&nodes[i], env, next, fail)
+}
+
+fn try_next(
+    choices: Rc<[Rc<Node>]>,
+    i: usize,
+    env: &Rc<Environment>,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    if i >= choices.len() {
+        return Step::Next(Box::new(move || fail()));
+    }
+    let env2 = env.clone();
+    let rest = choices.clone();
+    let succeed2 = succeed.clone();
+    let next =
+        on_failure(move || try_next(rest.clone(), i + 1, &env2, succeed2.clone(), fail.clone()));
+    execute(&choices[i], env, succeed, next)
+}
+
+// Evaluates the operands left to right, then applies the procedure.
+fn get_args(
+    operands: Rc<[Rc<Node>]>,
+    args: Vec<Rc<Value>>,
+    env: &Rc<Environment>,
+    procedure: Rc<Value>,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    let operand = match operands.get(args.len()) {
+        Some(operand) => operand.clone(),
+        None => return execute_application(procedure, args, succeed, fail),
+    };
+    let env2 = env.clone();
+    let next = on_success(move |arg, fail| {
+        let mut args = args.clone();
+        args.push(arg);
+        let (operands, procedure) = (operands.clone(), procedure.clone());
+        get_args(operands, args, &env2, procedure, succeed.clone(), fail)
+    });
+    execute(&operand, env, next, fail)
+}
+
+fn execute_application(
+    procedure: Rc<Value>,
+    args: Vec<Rc<Value>>,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    match &*procedure {
+        Value::CompoundProcedure {
+            lambda,
+            environment,
+        } => match lambda.bind(args, environment) {
+            Ok(env) => {
+                gc::collect_if_needed();
+                execute(&lambda.body, &env, succeed, fail)
+            }
+            Err(e) => Step::Error(e, fail),
+        },
+        // (apply f a b '(c d)) is (f a b c d).
+        Value::Control(Control::Apply) => {
+            let spread = match &args[..] {
+                [procedure, rest @ .., list] => list.list_to_vec().map(|list| {
+                    let mut spread = rest.to_vec();
+                    spread.extend(list);
+                    (procedure.clone(), spread)
+                }),
+                _ => None,
+            };
+            match spread {
+                Some((procedure, args)) => execute_application(procedure, args, succeed, fail),
+                None => Step::Error(LispError::invalid_arguments("apply", &args), fail),
+            }
+        }
+        // The handler's value is the value of raise-continuable.
+        Value::Control(Control::RaiseContinuable) => match (&args[..], handlers()) {
+            ([payload], Some(handler)) if matches!(handler.catch, Catch::Procedure(_)) => {
+                let procedure = match &handler.catch {
+                    Catch::Procedure(procedure) => procedure.clone(),
+                    _ => unreachable!("not a handler"),
+                };
+                set_handlers(handler.outer.clone());
+                execute_application(procedure, vec![payload.clone()], succeed, fail)
+            }
+            ([payload], _) => Step::Error(LispError::Raise(payload.clone()), fail),
+            _ => {
+                let e = LispError::invalid_arguments("raise-continuable", &args);
+                Step::Error(e, fail)
+            }
+        },
+        _ => result(apply(procedure.clone(), args), succeed, fail),
+    }
+}
+
+fn read(path: &str) -> Result<Vec<Sexp>, LispError> {
+    let source = fs::read_to_string(path).map_err(|e| LispError::FileNotReadable {
+        path: path.to_string(),
+        message: e.to_string(),
+    })?;
+    parser::expressions(&source).map_err(|e| LispError::Parse(e.to_string()))
+}
+
+// Each expression of a loaded file is analyzed only after the previous ones
+// have run, so that it sees their macro definitions.
+fn execute_file(
+    exps: Rc<[Sexp]>,
+    i: usize,
+    env: &Rc<Environment>,
+    succeed: Succeed,
+    fail: Fail,
+) -> Step {
+    let exp = match exps.get(i) {
+        Some(exp) => exp,
+        None => return succeed_with(succeed, Value::ok(), fail),
+    };
+    let node = match analyze(exp, &env.scope(), env) {
+        Ok(node) => Rc::new(node),
+        Err(e) => return Step::Error(e, fail),
+    };
+    let env2 = env.clone();
+    let rest = exps.clone();
+    let next =
+        on_success(move |_, fail| execute_file(rest.clone(), i + 1, &env2, succeed.clone(), fail));
+    execute(&node, env, next, fail)
+}
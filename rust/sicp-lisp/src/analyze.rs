@@ -0,0 +1,731 @@
+// Syntactic analysis (SICP 4.1.7). An expression is analyzed once into a tree
+// of `Node`s, with special forms recognized, derived forms and macro calls
+// expanded, and variables resolved to lexical addresses, so that executing a
+// procedure body does no pattern matching on the `Sexp` any more.
+
+use super::derived::*;
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::gc;
+use super::sexp::*;
+use super::syntax_rules::*;
+use super::value::*;
+
+use super::sexp::Sexp::{Nil, Pair, Symbol};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SPECIAL_FORMS: &[&str] = &[
+    "quote",
+    "quasiquote",
+    "set!",
+    "define",
+    "if",
+    "begin",
+    "lambda",
+    "with-exception-handler",
+    "guard",
+    "define-macro",
+    "defmacro",
+    "define-syntax",
+    "macroexpand",
+    "macroexpand-1",
+    "load",
+    "delay",
+    "cons-stream",
+    "debug",
+    "show-environment",
+    "environment-diagram",
+];
+
+pub enum Node {
+    Constant(Rc<Value>),
+    // A variable in an enclosing procedure's frame, at (frame number, displacement).
+    // Variables are located at the innermost form read from source that they
+    // are in, with the scope of that place, for errors such as an unbound
+    // variable.
+    LocalVariable {
+        depth: usize,
+        index: usize,
+        name: String,
+        span: Option<Rc<Span>>,
+        scope: Option<Rc<Scope>>,
+    },
+    GlobalVariable {
+        name: String,
+        span: Option<Rc<Span>>,
+        scope: Option<Rc<Scope>>,
+    },
+    LocalAssignment {
+        depth: usize,
+        index: usize,
+        name: String,
+        value: Rc<Node>,
+    },
+    GlobalAssignment {
+        name: String,
+        value: Rc<Node>,
+    },
+    LocalDefinition {
+        index: usize,
+        value: Rc<Node>,
+    },
+    GlobalDefinition {
+        name: String,
+        value: Rc<Node>,
+    },
+    If(Rc<Node>, Rc<Node>, Rc<Node>),
+    Sequence(Rc<[Rc<Node>]>),
+    Lambda(Rc<Lambda>),
+    // Turns the value of a procedure-valued node into a macro.
+    Macro(Rc<Node>),
+    // The pair-building steps of a quasiquote template.
+    Cons(Rc<Node>, Rc<Node>),
+    Append(Rc<Node>, Rc<Node>),
+    // A vector template, built as a list.
+    Vector(Rc<Node>),
+    WithExceptionHandler(Rc<Node>, Rc<Node>),
+    Guard {
+        body: Rc<Node>,
+        scope: Rc<Scope>,
+        clauses: Vec<GuardClause>,
+    },
+    MacroExpand {
+        form: Rc<Node>,
+        once: bool,
+    },
+    Load(Rc<Node>),
+    Delay(Rc<Node>),
+    // Evaluates an expression under the stepper.
+    Debug(Rc<Node>),
+    // The environment diagram of the current environment, printed or as a
+    // string.
+    ShowEnvironment {
+        print: bool,
+    },
+    // Runs the local definition of an optional parameter when no argument
+    // was supplied for the slot at `index`.
+    Default {
+        index: usize,
+        definition: Rc<Node>,
+    },
+    // The nondeterministic forms of the amb evaluator (SICP 4.3). A permanent
+    // assignment wraps the assignment node it keeps from being undone.
+    Amb(Rc<[Rc<Node>]>),
+    PermanentAssignment(Rc<Node>),
+    IfFail(Rc<Node>, Rc<Node>),
+    // The original form and scope are kept for operators that turn out to be
+    // macros only at run time, such as ones defined by an internal
+    // define-syntax. Operands are shared with the thunks of lazy mode.
+    Application {
+        operator: Rc<Node>,
+        operands: Rc<[Rc<Node>]>,
+        form: Rc<Sexp>,
+        scope: Option<Rc<Scope>>,
+    },
+}
+
+pub struct Lambda {
+    // The name the procedure was defined with, for error messages.
+    pub name: Option<String>,
+    // The required parameters, then the optional ones, then the rest
+    // parameter if there is one.
+    pub parameters: Vec<String>,
+    pub required: usize,
+    pub rest: bool,
+    pub scope: Rc<Scope>,
+    pub body: Rc<Node>,
+    // The parameters and body as written, for environment diagrams.
+    pub source: (Sexp, Sexp),
+}
+
+pub enum GuardClause {
+    // (test expression ...); with no expressions the test's value is returned.
+    Test(Rc<Node>, Option<Rc<Node>>),
+    Else(Rc<Node>),
+}
+
+fn ill_formed(exp: &Sexp) -> LispError {
+    LispError::IllFormedSpecialForm(exp.clone())
+}
+
+thread_local! {
+    // The span of the innermost form being analyzed that was read from
+    // source. Forms made by derived expressions and macros have none, so
+    // their variables are located at the form they came from.
+    static SPAN: RefCell<Option<Rc<Span>>> = const { RefCell::new(None) };
+}
+
+// Analyzes `exp` in `scope`. `env` is any environment whose outermost frame is
+// the global one; it is consulted for global macros.
+pub fn analyze(
+    exp: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    let span = match exp.span() {
+        Some(span) => span.clone(),
+        None => return analyze_form(exp, scope, env),
+    };
+    let outer = SPAN.with(|outer| outer.replace(Some(span)));
+    let node = analyze_form(exp, scope, env);
+    SPAN.with(|span| *span.borrow_mut() = outer);
+    node
+}
+
+fn analyze_form(
+    exp: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    let (mut items, tail) = match exp {
+        Symbol(s) => return Ok(analyze_variable(s, scope)),
+        Pair(..) => exp.elements(),
+        _ => return Ok(Node::Constant(Value::from_sexp(exp))),
+    };
+    // An operator bound in an enclosing procedure is never a keyword, and an
+    // alias a macro expansion made for a keyword stands for the keyword.
+    let keyword;
+    if let Some(Symbol(name)) = items.first() {
+        match Scope::resolve(scope, name) {
+            (_, Some(_)) => return analyze_application(exp, &items, scope, env),
+            (original, None) if original != *name && is_keyword(&original, env) => {
+                keyword = Symbol(original);
+                items[0] = &keyword;
+            }
+            _ => {}
+        }
+    }
+    let node = match (&items[..], tail) {
+        ([Symbol(tag), datum], Nil) if tag == "quote" => Node::Constant(Value::from_sexp(datum)),
+        ([Symbol(tag), template], Nil) if tag == "quasiquote" => {
+            analyze_quasiquote(template, 1, scope, env)?
+        }
+        ([Symbol(tag), Symbol(var), val], Nil) if tag == "set!" => {
+            let value = Rc::new(analyze(val, scope, env)?);
+            match Scope::resolve(scope, var) {
+                (name, Some((depth, index))) => Node::LocalAssignment {
+                    depth,
+                    index,
+                    name,
+                    value,
+                },
+                (name, None) => Node::GlobalAssignment { name, value },
+            }
+        }
+        ([Symbol(tag), Symbol(var), val], Nil) if tag == "define" => {
+            let value = match lambda_parts(val) {
+                Some((p, b)) => Node::Lambda(analyze_lambda(Some(var), p, b, scope, env)?),
+                None => analyze(val, scope, env)?,
+            };
+            definition(var, value, scope)
+        }
+        ([Symbol(tag), p, c, a], Nil) if tag == "if" => Node::If(
+            Rc::new(analyze(p, scope, env)?),
+            Rc::new(analyze(c, scope, env)?),
+            Rc::new(analyze(a, scope, env)?),
+        ),
+        // A one-armed if is #f when the predicate is false, like when.
+        ([Symbol(tag), p, c], Nil) if tag == "if" => Node::If(
+            Rc::new(analyze(p, scope, env)?),
+            Rc::new(analyze(c, scope, env)?),
+            Rc::new(Node::Constant(Value::f())),
+        ),
+        ([Symbol(tag), ..], _) if tag == "begin" => analyze_sequence(exp.tail(1), scope, env)?,
+        ([Symbol(tag), p, ..], _) if tag == "lambda" => {
+            Node::Lambda(analyze_lambda(None, p, exp.tail(2), scope, env)?)
+        }
+        ([Symbol(tag), handler, thunk], Nil) if tag == "with-exception-handler" => {
+            Node::WithExceptionHandler(
+                Rc::new(analyze(handler, scope, env)?),
+                Rc::new(analyze(thunk, scope, env)?),
+            )
+        }
+        ([Symbol(tag), Pair(var, clauses, _), ..], _) if tag == "guard" => match &**var {
+            Symbol(var) => analyze_guard(var, clauses, exp.tail(2), scope, env)?,
+            _ => return Err(ill_formed(exp)),
+        },
+        ([Symbol(tag), Pair(name, p, _), ..], _) if tag == "define-macro" => match &**name {
+            Symbol(name) => {
+                let lambda = analyze_lambda(Some(name), p, exp.tail(2), scope, env)?;
+                definition(name, Node::Macro(Rc::new(Node::Lambda(lambda))), scope)
+            }
+            _ => return Err(ill_formed(exp)),
+        },
+        ([Symbol(tag), Symbol(name), transformer], Nil) if tag == "define-macro" => {
+            let transformer = analyze(transformer, scope, env)?;
+            definition(name, Node::Macro(Rc::new(transformer)), scope)
+        }
+        ([Symbol(tag), Symbol(name), p, ..], _) if tag == "defmacro" => {
+            let lambda = analyze_lambda(Some(name), p, exp.tail(3), scope, env)?;
+            definition(name, Node::Macro(Rc::new(Node::Lambda(lambda))), scope)
+        }
+        ([Symbol(tag), Symbol(name), spec], Nil) if tag == "define-syntax" => {
+            let rules = Rc::new(Value::Syntax(SyntaxRules::new(spec, scope)?));
+            definition(name, Node::Constant(rules), scope)
+        }
+        ([Symbol(tag), form], Nil) if tag == "macroexpand" || tag == "macroexpand-1" => {
+            Node::MacroExpand {
+                form: Rc::new(analyze(form, scope, env)?),
+                once: tag == "macroexpand-1",
+            }
+        }
+        ([Symbol(tag), file], Nil) if tag == "load" => {
+            Node::Load(Rc::new(analyze(file, scope, env)?))
+        }
+        ([Symbol(tag), exp], Nil) if tag == "delay" => {
+            Node::Delay(Rc::new(analyze(exp, scope, env)?))
+        }
+        // (cons-stream a b) is (cons a (delay b)) (SICP 3.5.1).
+        ([Symbol(tag), a, b], Nil) if tag == "cons-stream" => {
+            let delay = Node::Delay(Rc::new(analyze(b, scope, env)?));
+            Node::Cons(Rc::new(analyze(a, scope, env)?), Rc::new(delay))
+        }
+        ([Symbol(tag), exp], Nil) if tag == "debug" => {
+            Node::Debug(Rc::new(analyze(exp, scope, env)?))
+        }
+        ([Symbol(tag)], Nil) if tag == "show-environment" || tag == "environment-diagram" => {
+            Node::ShowEnvironment {
+                print: tag == "show-environment",
+            }
+        }
+        ([Symbol(tag), choices @ ..], _) if tag == "amb" && env.is_amb() => {
+            let mut nodes = vec![];
+            for choice in choices {
+                nodes.push(Rc::new(analyze(choice, scope, env)?));
+            }
+            Node::Amb(nodes.into())
+        }
+        ([Symbol(tag), Symbol(var), val], Nil) if tag == "permanent-set!" && env.is_amb() => {
+            let set = Sexp::list(vec![
+                Sexp::symbol("set!"),
+                Symbol(var.clone()),
+                (*val).clone(),
+            ]);
+            Node::PermanentAssignment(Rc::new(analyze(&set, scope, env)?))
+        }
+        ([Symbol(tag), e, alternative], Nil) if tag == "if-fail" && env.is_amb() => Node::IfFail(
+            Rc::new(analyze(e, scope, env)?),
+            Rc::new(analyze(alternative, scope, env)?),
+        ),
+        ([Symbol(tag), Pair(..), ..], _) if tag == "define" => {
+            analyze(&procedure_definition(exp)?, scope, env)?
+        }
+        ([Symbol(tag), ..], _) if tag == "cond" => analyze(&cond_to_if(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "let" => analyze(&let_to_combination(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "let*" => {
+            analyze(&let_star_to_nested_lets(exp)?, scope, env)?
+        }
+        ([Symbol(tag), ..], _) if tag == "letrec" => analyze(&letrec_to_let(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "and" => analyze(&and_to_if(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "or" => analyze(&or_to_if(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "when" => analyze(&when_to_if(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if tag == "unless" => analyze(&unless_to_if(exp)?, scope, env)?,
+        ([Symbol(tag), ..], _) if SPECIAL_FORMS.contains(&tag.as_str()) => {
+            return Err(ill_formed(exp));
+        }
+        _ => return analyze_application(exp, &items, scope, env),
+    };
+    Ok(node)
+}
+
+// The names `analyze` takes as keywords besides the special forms.
+const DERIVED_FORMS: &[&str] = &[
+    "cond", "let", "let*", "letrec", "and", "or", "when", "unless",
+];
+
+const AMB_FORMS: &[&str] = &["amb", "permanent-set!", "if-fail"];
+
+fn is_keyword(name: &str, env: &Environment) -> bool {
+    SPECIAL_FORMS.contains(&name)
+        || DERIVED_FORMS.contains(&name)
+        || (env.is_amb() && AMB_FORMS.contains(&name))
+}
+
+fn analyze_application(
+    exp: &Sexp,
+    items: &[&Sexp],
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    if let Some(expansion) = expand_global_macro(exp, scope, env)? {
+        return analyze(&expansion, scope, env);
+    }
+    let mut nodes = vec![];
+    for operand in &items[1..] {
+        nodes.push(Rc::new(analyze(operand, scope, env)?));
+    }
+    Ok(Node::Application {
+        operator: Rc::new(analyze(items[0], scope, env)?),
+        operands: nodes.into(),
+        form: Rc::new(exp.clone()),
+        scope: scope.clone(),
+    })
+}
+
+// The parameters and body of a (lambda parameters body ...) form.
+fn lambda_parts(exp: &Sexp) -> Option<(&Sexp, &Sexp)> {
+    match &exp.elements().0[..] {
+        [Symbol(tag), p, ..] if tag == "lambda" => Some((p, exp.tail(2))),
+        _ => None,
+    }
+}
+
+fn analyze_variable(name: &str, scope: &Option<Rc<Scope>>) -> Node {
+    let span = SPAN.with(|span| span.borrow().clone());
+    let scope = scope.clone();
+    match Scope::resolve(&scope, name) {
+        (name, Some((depth, index))) => Node::LocalVariable {
+            depth,
+            index,
+            name,
+            span,
+            scope,
+        },
+        (name, None) => Node::GlobalVariable { name, span, scope },
+    }
+}
+
+// A definition inside a procedure body gets a slot in the procedure's frame.
+fn definition(name: &str, value: Node, scope: &Option<Rc<Scope>>) -> Node {
+    let value = Rc::new(value);
+    match scope {
+        Some(scope) => Node::LocalDefinition {
+            index: scope.add(name),
+            value,
+        },
+        None => Node::GlobalDefinition {
+            name: name.to_string(),
+            value,
+        },
+    }
+}
+
+// Expands a call whose operator names a global macro. Lexically bound names
+// shadow global macros, and macros that are not known yet are expanded when
+// the call is executed instead.
+fn expand_global_macro(
+    exp: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Option<Sexp>, LispError> {
+    if let Pair(operator, operands, _) = exp {
+        let name = match &**operator {
+            Symbol(name) => name,
+            _ => return Ok(None),
+        };
+        let name = match Scope::resolve(scope, name) {
+            (_, Some(_)) => return Ok(None),
+            (name, None) => name,
+        };
+        if let Ok(value) = env.lookup_global(&name) {
+            match &*value {
+                Value::Macro(transformer) => return Ok(Some(expand(transformer, operands)?)),
+                Value::Syntax(rules) => return Ok(Some(rules.expand(exp, scope)?)),
+                _ => {}
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn analyze_sequence(
+    exps: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    let mut nodes = vec![];
+    let mut p = exps;
+    while let Pair(car, cdr, _) = p {
+        nodes.push(Rc::new(analyze(car, scope, env)?));
+        p = cdr;
+    }
+    if nodes.is_empty() {
+        return Ok(Node::Constant(Rc::new(Value::Nil)));
+    }
+    Ok(Node::Sequence(nodes.into()))
+}
+
+// The names defined at the top level of a body (SICP 4.1.6), which get their
+// frame slots before the body is analyzed so that internal definitions can
+// refer to each other.
+fn scan_out_defines(body: &Sexp, names: &mut Vec<String>) {
+    let mut p = body;
+    while let Pair(exp, cdr, _) = p {
+        match &exp.elements().0[..] {
+            [Symbol(tag), Symbol(name), ..]
+                if tag == "define"
+                    || tag == "define-macro"
+                    || tag == "defmacro"
+                    || tag == "define-syntax" =>
+            {
+                names.push(name.clone());
+            }
+            [Symbol(tag), Pair(name, _, _), ..] if tag == "define" || tag == "define-macro" => {
+                if let Symbol(name) = &**name {
+                    names.push(name.clone());
+                }
+            }
+            [Symbol(tag), ..] if tag == "begin" => scan_out_defines(exp.tail(1), names),
+            _ => {}
+        }
+        p = cdr;
+    }
+}
+
+// The parameters are a list of required names, optionally followed by
+// #!optional and names or (name default) pairs, and ended by a dotted or
+// #!rest name that receives the remaining arguments as a list. A bare symbol
+// receives all of them. An optional parameter without a default is #f. A
+// name may be a parameter only once.
+fn analyze_lambda(
+    name: Option<&str>,
+    p: &Sexp,
+    b: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Rc<Lambda>, LispError> {
+    let mut parameters = vec![];
+    let mut defaults = vec![];
+    let mut optional = false;
+    let mut rest = None;
+    let mut next = p;
+    loop {
+        let (car, cdr) = match next {
+            Nil => break,
+            Symbol(s) => {
+                rest = Some(s.clone());
+                break;
+            }
+            Pair(car, cdr, _) => (&**car, &**cdr),
+            _ => return Err(ill_formed(p)),
+        };
+        let (items, tail) = cdr.elements();
+        match (car, &items[..], tail) {
+            (Symbol(s), [Symbol(r)], Nil) if s == "#!rest" => {
+                rest = Some(r.clone());
+                break;
+            }
+            (Symbol(s), ..) if s == "#!optional" && !optional => optional = true,
+            (Symbol(s), ..) if s.starts_with("#!") => return Err(ill_formed(p)),
+            (Symbol(s), ..) => {
+                parameters.push(s.clone());
+                if optional {
+                    defaults.push(Sexp::Boolean(false));
+                }
+            }
+            (Pair(..), ..) if optional => {
+                let (items, tail) = car.elements();
+                match (&items[..], tail) {
+                    ([Symbol(s), default], Nil) => {
+                        parameters.push(s.clone());
+                        defaults.push((*default).clone());
+                    }
+                    _ => return Err(ill_formed(p)),
+                }
+            }
+            _ => return Err(ill_formed(p)),
+        }
+        next = cdr;
+    }
+    let required = parameters.len() - defaults.len();
+    parameters.extend(rest.iter().cloned());
+    if has_duplicates(&parameters) {
+        return Err(ill_formed(p));
+    }
+
+    let mut names = parameters.clone();
+    scan_out_defines(b, &mut names);
+    let inner = Scope::named(name, vec![], scope.clone());
+    for name in &names {
+        inner.add(name);
+    }
+    let inner_scope = Some(inner.clone());
+    let mut body = vec![];
+    for (i, default) in defaults.iter().enumerate() {
+        let index = required + i;
+        let value = Rc::new(analyze(default, &inner_scope, env)?);
+        let definition = Rc::new(Node::LocalDefinition { index, value });
+        body.push(Rc::new(Node::Default { index, definition }));
+    }
+    let sequence = analyze_sequence(b, &inner_scope, env)?;
+    let body = if body.is_empty() {
+        Rc::new(sequence)
+    } else {
+        body.push(Rc::new(sequence));
+        Rc::new(Node::Sequence(body.into()))
+    };
+    Ok(Rc::new(Lambda {
+        name: name.map(str::to_string),
+        parameters,
+        required,
+        rest: rest.is_some(),
+        scope: inner,
+        body,
+        source: (p.clone(), b.clone()),
+    }))
+}
+
+pub(crate) fn has_duplicates(names: &[String]) -> bool {
+    names
+        .iter()
+        .enumerate()
+        .any(|(i, name)| names[..i].contains(name))
+}
+
+// Builds the nodes for a quasiquote template. `depth` counts the enclosing
+// quasiquotes, and only unquotes at depth 1 are evaluated.
+fn analyze_quasiquote(
+    template: &Sexp,
+    depth: usize,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    let (car, cdr) = match template {
+        Pair(car, cdr, _) => (&**car, &**cdr),
+        Sexp::Vector(elems) => {
+            let list = Sexp::list(elems.clone());
+            let node = analyze_quasiquote(&list, depth, scope, env)?;
+            return Ok(Node::Vector(Rc::new(node)));
+        }
+        _ => return Ok(Node::Constant(Value::from_sexp(template))),
+    };
+    let (items, tail) = cdr.elements();
+    match (car, &items[..], tail) {
+        (Symbol(tag), [e], Nil) if tag == "unquote" => {
+            if depth == 1 {
+                analyze(e, scope, env)
+            } else {
+                let inner = analyze_quasiquote(e, depth - 1, scope, env)?;
+                Ok(quasiquote_form(tag, inner))
+            }
+        }
+        (Symbol(tag), [e], Nil) if tag == "quasiquote" => {
+            let inner = analyze_quasiquote(e, depth + 1, scope, env)?;
+            Ok(quasiquote_form(tag, inner))
+        }
+        (Symbol(tag), ..) if tag == "unquote-splicing" && depth == 1 => Err(ill_formed(template)),
+        (Pair(..), ..) => match (&car.elements().0[..], car.tail(2)) {
+            ([Symbol(tag), e], Nil) if tag == "unquote-splicing" && depth == 1 => Ok(Node::Append(
+                Rc::new(analyze(e, scope, env)?),
+                Rc::new(analyze_quasiquote(cdr, depth, scope, env)?),
+            )),
+            _ => Ok(Node::Cons(
+                Rc::new(analyze_quasiquote(car, depth, scope, env)?),
+                Rc::new(analyze_quasiquote(cdr, depth, scope, env)?),
+            )),
+        },
+        _ => Ok(Node::Cons(
+            Rc::new(analyze_quasiquote(car, depth, scope, env)?),
+            Rc::new(analyze_quasiquote(cdr, depth, scope, env)?),
+        )),
+    }
+}
+
+fn quasiquote_form(tag: &str, node: Node) -> Node {
+    let symbol = Node::Constant(Rc::new(Value::Symbol(tag.to_string())));
+    let nil = Node::Constant(Rc::new(Value::Nil));
+    Node::Cons(
+        Rc::new(symbol),
+        Rc::new(Node::Cons(Rc::new(node), Rc::new(nil))),
+    )
+}
+
+// (guard (var clause ...) body ...): each clause is (test expression ...) or
+// (else expression ...), evaluated with `var` bound to the raised object.
+fn analyze_guard(
+    var: &str,
+    clauses: &Sexp,
+    body: &Sexp,
+    scope: &Option<Rc<Scope>>,
+    env: &Environment,
+) -> Result<Node, LispError> {
+    let body = Rc::new(analyze_sequence(body, scope, env)?);
+    let inner = Scope::new(vec![var.to_string()], scope.clone());
+    let clause_scope = Some(inner.clone());
+
+    let mut analyzed = vec![];
+    let mut p = clauses;
+    while let Pair(clause, cdr, _) = p {
+        let (test, expressions) = match &**clause {
+            Pair(test, expressions, _) => (&**test, &**expressions),
+            _ => break,
+        };
+        analyzed.push(match test {
+            Symbol(s) if s == "else" => {
+                GuardClause::Else(Rc::new(analyze_sequence(expressions, &clause_scope, env)?))
+            }
+            _ => GuardClause::Test(
+                Rc::new(analyze(test, &clause_scope, env)?),
+                match expressions {
+                    Nil => None,
+                    _ => Some(Rc::new(analyze_sequence(expressions, &clause_scope, env)?)),
+                },
+            ),
+        });
+        p = cdr;
+    }
+    Ok(Node::Guard {
+        body,
+        scope: inner,
+        clauses: analyzed,
+    })
+}
+
+impl Lambda {
+    pub fn make_procedure(self: &Rc<Self>, env: &Rc<Environment>) -> Rc<Value> {
+        gc::track(Value::CompoundProcedure {
+            lambda: self.clone(),
+            environment: env.clone(),
+        })
+    }
+
+    // The frame for a call of a procedure made from this lambda. Slots of
+    // optional parameters without an argument are left for their defaults.
+    pub fn bind(
+        &self,
+        mut arguments: Vec<Rc<Value>>,
+        env: &Rc<Environment>,
+    ) -> Result<Rc<Environment>, LispError> {
+        let positional = self.parameters.len() - self.rest as usize;
+        let given = arguments.len();
+        if given < self.required || (given > positional && !self.rest) {
+            return Err(LispError::WrongNumberOfArguments {
+                procedure: match &self.name {
+                    Some(name) => name.clone(),
+                    None => "anonymous procedure".to_string(),
+                },
+                expected: self.arity(),
+                given,
+            });
+        }
+        if !self.rest {
+            return Ok(Environment::extend(&self.scope, arguments, env));
+        }
+        let rest = Value::list(arguments.split_off(given.min(positional)));
+        let frame = Environment::extend(&self.scope, arguments, env);
+        frame.define_address(positional, rest);
+        Ok(frame)
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => "#<procedure>".to_string(),
+        }
+    }
+
+    // The number of arguments accepted, as arity errors state it.
+    pub fn arity(&self) -> String {
+        let positional = self.parameters.len() - self.rest as usize;
+        if self.rest {
+            format!("at least {}", self.required)
+        } else if positional > self.required {
+            format!("between {} and {}", self.required, positional)
+        } else {
+            self.required.to_string()
+        }
+    }
+}
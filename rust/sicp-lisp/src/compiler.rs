@@ -0,0 +1,809 @@
+// The compiler of SICP 5.5, from expressions to register-machine instructions,
+// and the run-time support for executing its output on the simulator.
+//
+// Compiled procedures are values of their own. `apply` runs them on a fresh
+// machine, and compiled code calls any other procedure through `apply`, so
+// compiled and interpreted code can call each other (SICP 5.5.7). Special
+// forms the compiler does not know, such as guard or quasiquote, are handed
+// to the interpreter in the environment the compiled code runs in.
+
+use super::analyze::{has_duplicates, SPECIAL_FORMS};
+use super::derived::*;
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::gc;
+use super::machine::*;
+use super::sexp::*;
+use super::value::*;
+
+use super::sexp::Sexp::{Nil, Pair, Symbol};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const REGISTERS: [&str; 5] = ["env", "proc", "val", "argl", "continue"];
+
+// Register sets, as bit masks over REGISTERS.
+type Registers = u8;
+const ENV: Registers = 1;
+const PROC: Registers = 2;
+const VAL: Registers = 4;
+const ARGL: Registers = 8;
+const CONTINUE: Registers = 16;
+const ALL: Registers = ENV | PROC | VAL | ARGL | CONTINUE;
+
+#[derive(Clone)]
+enum Linkage {
+    Next,
+    Return,
+    Label(String),
+}
+
+struct InstructionSequence {
+    needs: Registers,
+    modifies: Registers,
+    statements: Vec<Sexp>,
+}
+
+impl InstructionSequence {
+    fn new(needs: Registers, modifies: Registers, statements: Vec<Sexp>) -> Self {
+        InstructionSequence {
+            needs,
+            modifies,
+            statements,
+        }
+    }
+
+    fn empty() -> Self {
+        Self::new(0, 0, vec![])
+    }
+
+    fn label(name: &str) -> Self {
+        Self::new(0, 0, vec![Sexp::symbol(name)])
+    }
+
+    fn append(mut self, seq: Self) -> Self {
+        self.needs |= seq.needs & !self.modifies;
+        self.modifies |= seq.modifies;
+        self.statements.extend(seq.statements);
+        self
+    }
+
+    // Saves and restores around `self` each register in `regs` that it
+    // modifies and `seq` needs.
+    fn preserving(mut self, regs: Registers, seq: Self) -> Self {
+        for (i, name) in REGISTERS.iter().enumerate() {
+            let reg = 1 << i;
+            if regs & reg != 0 && seq.needs & reg != 0 && self.modifies & reg != 0 {
+                let mut statements = vec![list(&["save", name])];
+                statements.extend(self.statements);
+                statements.push(list(&["restore", name]));
+                self = Self::new(self.needs | reg, self.modifies & !reg, statements);
+            }
+        }
+        self.append(seq)
+    }
+
+    // Appends a procedure body that is jumped to, never fallen into.
+    fn tack_on(mut self, body: Self) -> Self {
+        self.statements.extend(body.statements);
+        self
+    }
+
+    // Two alternative branches, only one of which runs.
+    fn parallel(mut self, seq: Self) -> Self {
+        self.needs |= seq.needs;
+        self.modifies |= seq.modifies;
+        self.statements.extend(seq.statements);
+        self
+    }
+}
+
+fn list(symbols: &[&str]) -> Sexp {
+    Sexp::list(symbols.iter().map(|s| Sexp::symbol(s)).collect())
+}
+
+fn instruction(tag: &str, operands: Vec<Sexp>) -> Sexp {
+    Sexp::cons(Sexp::symbol(tag), Sexp::list(operands))
+}
+
+fn reg(name: &str) -> Sexp {
+    list(&["reg", name])
+}
+
+fn constant(exp: &Sexp) -> Sexp {
+    Sexp::list(vec![Sexp::symbol("const"), exp.clone()])
+}
+
+fn label(name: &str) -> Sexp {
+    list(&["label", name])
+}
+
+fn op(name: &str) -> Sexp {
+    list(&["op", name])
+}
+
+fn register_name(reg: Registers) -> &'static str {
+    REGISTERS[reg.trailing_zeros() as usize]
+}
+
+fn assign(target: Registers, source: Vec<Sexp>) -> Sexp {
+    let mut operands = vec![Sexp::symbol(register_name(target))];
+    operands.extend(source);
+    instruction("assign", operands)
+}
+
+struct Compiler<'a> {
+    env: &'a Environment,
+    labels: usize,
+    // Parameters of the enclosing lambdas, which shadow global macros.
+    locals: Vec<String>,
+}
+
+// Compiles `exp` so that its value ends up in `val` and control falls off the
+// end, and returns the instructions with their labels.
+pub fn compile(exp: &Sexp, env: &Environment) -> Result<Vec<Sexp>, LispError> {
+    let mut compiler = Compiler {
+        env,
+        labels: 0,
+        locals: vec![],
+    };
+    Ok(compiler.compile(exp, VAL, Linkage::Next)?.statements)
+}
+
+impl Compiler<'_> {
+    fn make_label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!("{}{}", name, self.labels)
+    }
+
+    fn compile(
+        &mut self,
+        exp: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        let (items, tail) = match exp {
+            Symbol(var) => return Ok(self.compile_variable(var, target, linkage)),
+            Pair(..) => exp.elements(),
+            _ => return Ok(self.compile_constant(exp, target, linkage)),
+        };
+        let seq = match (&items[..], tail) {
+            ([Symbol(tag), datum], Nil) if tag == "quote" => {
+                self.compile_constant(datum, target, linkage)
+            }
+            // Lambdas with #!optional or #!rest are left to the interpreter,
+            // along with a definition that names one.
+            ([Symbol(tag), Symbol(_), val], Nil)
+                if tag == "define" && lambda_parameters(val).is_some_and(has_keywords) =>
+            {
+                self.compile_interpreted(exp, target, linkage)
+            }
+            ([Symbol(tag), parameters, ..], _) if tag == "lambda" && has_keywords(parameters) => {
+                self.compile_interpreted(exp, target, linkage)
+            }
+            ([Symbol(tag), Symbol(var), val], Nil) if tag == "set!" || tag == "define" => {
+                let operation = if tag == "set!" {
+                    "set-variable-value!"
+                } else {
+                    "define-variable!"
+                };
+                self.compile_assignment(operation, var, val, target, linkage)?
+            }
+            ([Symbol(tag), Pair(..), ..], _) if tag == "define" => {
+                self.compile(&procedure_definition(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), p, c, a], Nil) if tag == "if" => {
+                self.compile_if(p, c, a, target, linkage)?
+            }
+            ([Symbol(tag), p, c], Nil) if tag == "if" => {
+                self.compile_if(p, c, &Sexp::Boolean(false), target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "begin" => {
+                self.compile_sequence(exp.tail(1), target, linkage)?
+            }
+            ([Symbol(tag), parameters, ..], _) if tag == "lambda" => {
+                self.compile_lambda(None, parameters, exp.tail(2), target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "cond" => {
+                self.compile(&cond_to_if(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "let" => {
+                self.compile(&let_to_combination(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "let*" => {
+                self.compile(&let_star_to_nested_lets(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "letrec" => {
+                self.compile(&letrec_to_let(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "and" => {
+                self.compile(&and_to_if(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "or" => {
+                self.compile(&or_to_if(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "when" => {
+                self.compile(&when_to_if(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if tag == "unless" => {
+                self.compile(&unless_to_if(exp)?, target, linkage)?
+            }
+            ([Symbol(tag), ..], _) if SPECIAL_FORMS.contains(&tag.as_str()) => {
+                self.compile_interpreted(exp, target, linkage)
+            }
+            _ => {
+                if let Some(expansion) = self.expand_global_macro(exp)? {
+                    return self.compile(&expansion, target, linkage);
+                }
+                self.compile_application(items[0], exp.tail(1), target, linkage)?
+            }
+        };
+        Ok(seq)
+    }
+
+    fn expand_global_macro(&self, exp: &Sexp) -> Result<Option<Sexp>, LispError> {
+        if let Pair(operator, operands, _) = exp {
+            let name = match &**operator {
+                Symbol(name) => name,
+                _ => return Ok(None),
+            };
+            if self.locals.contains(name) {
+                return Ok(None);
+            }
+            if let Ok(value) = self.env.lookup_global(name) {
+                match &*value {
+                    Value::Macro(transformer) => return Ok(Some(expand(transformer, operands)?)),
+                    Value::Syntax(rules) => return Ok(Some(rules.expand(exp, &None)?)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn end_with_linkage(
+        &mut self,
+        linkage: Linkage,
+        seq: InstructionSequence,
+    ) -> InstructionSequence {
+        let linkage = match linkage {
+            Linkage::Return => InstructionSequence::new(
+                CONTINUE,
+                0,
+                vec![instruction("goto", vec![reg("continue")])],
+            ),
+            Linkage::Next => InstructionSequence::empty(),
+            Linkage::Label(name) => {
+                InstructionSequence::new(0, 0, vec![instruction("goto", vec![label(&name)])])
+            }
+        };
+        seq.preserving(CONTINUE, linkage)
+    }
+
+    fn compile_constant(
+        &mut self,
+        exp: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> InstructionSequence {
+        let seq = InstructionSequence::new(0, target, vec![assign(target, vec![constant(exp)])]);
+        self.end_with_linkage(linkage, seq)
+    }
+
+    fn compile_variable(
+        &mut self,
+        var: &str,
+        target: Registers,
+        linkage: Linkage,
+    ) -> InstructionSequence {
+        let source = vec![
+            op("lookup-variable-value"),
+            constant(&Sexp::symbol(var)),
+            reg("env"),
+        ];
+        let seq = InstructionSequence::new(ENV, target, vec![assign(target, source)]);
+        self.end_with_linkage(linkage, seq)
+    }
+
+    fn compile_assignment(
+        &mut self,
+        operation: &str,
+        var: &str,
+        value: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        // A procedure is named after the variable it is defined as.
+        let get_value_code = match lambda_parameters(value) {
+            Some(parameters) if operation == "define-variable!" => {
+                self.compile_lambda(Some(var), parameters, value.tail(2), VAL, Linkage::Next)?
+            }
+            _ => self.compile(value, VAL, Linkage::Next)?,
+        };
+        let perform = instruction(
+            "perform",
+            vec![
+                op(operation),
+                constant(&Sexp::symbol(var)),
+                reg("val"),
+                reg("env"),
+            ],
+        );
+        let ok = assign(target, vec![constant(&Sexp::symbol("ok"))]);
+        let seq = InstructionSequence::new(ENV | VAL, target, vec![perform, ok]);
+        Ok(self.end_with_linkage(linkage, get_value_code.preserving(ENV, seq)))
+    }
+
+    fn compile_if(
+        &mut self,
+        predicate: &Sexp,
+        consequent: &Sexp,
+        alternative: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        let t_branch = self.make_label("true-branch");
+        let f_branch = self.make_label("false-branch");
+        let after_if = self.make_label("after-if");
+        let consequent_linkage = match &linkage {
+            Linkage::Next => Linkage::Label(after_if.clone()),
+            linkage => linkage.clone(),
+        };
+        let p_code = self.compile(predicate, VAL, Linkage::Next)?;
+        let c_code = self.compile(consequent, target, consequent_linkage)?;
+        let a_code = self.compile(alternative, target, linkage)?;
+        let test = InstructionSequence::new(
+            VAL,
+            0,
+            vec![
+                instruction("test", vec![op("false?"), reg("val")]),
+                instruction("branch", vec![label(&f_branch)]),
+            ],
+        );
+        let branches = InstructionSequence::label(&t_branch)
+            .append(c_code)
+            .parallel(InstructionSequence::label(&f_branch).append(a_code));
+        let seq = test
+            .append(branches)
+            .append(InstructionSequence::label(&after_if));
+        Ok(p_code.preserving(ENV | CONTINUE, seq))
+    }
+
+    fn compile_sequence(
+        &mut self,
+        exps: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        match exps {
+            Pair(first, rest, _) if **rest == Nil => self.compile(first, target, linkage),
+            Pair(first, rest, _) => {
+                let first = self.compile(first, target, Linkage::Next)?;
+                let rest = self.compile_sequence(rest, target, linkage)?;
+                Ok(first.preserving(ENV | CONTINUE, rest))
+            }
+            _ => Ok(self.compile_constant(&Nil, target, linkage)),
+        }
+    }
+
+    fn compile_lambda(
+        &mut self,
+        name: Option<&str>,
+        parameters: &Sexp,
+        body: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        let proc_entry = self.make_label("entry");
+        let after_lambda = self.make_label("after-lambda");
+        let lambda_linkage = match linkage {
+            Linkage::Next => Linkage::Label(after_lambda.clone()),
+            linkage => linkage,
+        };
+        let make_procedure = InstructionSequence::new(
+            ENV,
+            target,
+            vec![assign(
+                target,
+                vec![
+                    op("make-compiled-procedure"),
+                    label(&proc_entry),
+                    reg("env"),
+                ],
+            )],
+        );
+        let body = self.compile_lambda_body(name, parameters, body, &proc_entry)?;
+        Ok(self
+            .end_with_linkage(lambda_linkage, make_procedure)
+            .tack_on(body)
+            .append(InstructionSequence::label(&after_lambda)))
+    }
+
+    fn compile_lambda_body(
+        &mut self,
+        name: Option<&str>,
+        parameters: &Sexp,
+        body: &Sexp,
+        proc_entry: &str,
+    ) -> Result<InstructionSequence, LispError> {
+        // The parameters may end in a rest parameter, as in (a . rest) or args.
+        let mut names = vec![];
+        let mut p = parameters;
+        loop {
+            match p {
+                Nil => break,
+                Symbol(name) => {
+                    names.push(name.clone());
+                    break;
+                }
+                Pair(car, cdr, _) => match &**car {
+                    Symbol(name) => {
+                        names.push(name.clone());
+                        p = cdr;
+                    }
+                    _ => return Err(LispError::IllFormedSpecialForm(parameters.clone())),
+                },
+                _ => return Err(LispError::IllFormedSpecialForm(parameters.clone())),
+            }
+        }
+        if has_duplicates(&names) {
+            return Err(LispError::IllFormedSpecialForm(parameters.clone()));
+        }
+        let name = match name {
+            Some(name) => Sexp::symbol(name),
+            None => Sexp::Boolean(false),
+        };
+        let entry = InstructionSequence::new(
+            ENV | PROC | ARGL,
+            ENV,
+            vec![
+                Sexp::symbol(proc_entry),
+                assign(ENV, vec![op("compiled-procedure-env"), reg("proc")]),
+                assign(
+                    ENV,
+                    vec![
+                        op("extend-environment"),
+                        constant(parameters),
+                        reg("argl"),
+                        reg("env"),
+                        constant(&name),
+                    ],
+                ),
+            ],
+        );
+        let depth = self.locals.len();
+        self.locals.extend(names);
+        let body = self.compile_sequence(body, VAL, Linkage::Return);
+        self.locals.truncate(depth);
+        Ok(entry.append(body?))
+    }
+
+    fn compile_interpreted(
+        &mut self,
+        exp: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> InstructionSequence {
+        let source = vec![op("evaluate"), constant(exp), reg("env")];
+        let seq = InstructionSequence::new(ENV, target, vec![assign(target, source)]);
+        self.end_with_linkage(linkage, seq)
+    }
+
+    fn compile_application(
+        &mut self,
+        operator: &Sexp,
+        operands: &Sexp,
+        target: Registers,
+        linkage: Linkage,
+    ) -> Result<InstructionSequence, LispError> {
+        let proc_code = self.compile(operator, PROC, Linkage::Next)?;
+        let mut operand_codes = vec![];
+        let mut p = operands;
+        while let Pair(car, cdr, _) = p {
+            operand_codes.push(self.compile(car, VAL, Linkage::Next)?);
+            p = cdr;
+        }
+        let arglist = construct_arglist(operand_codes);
+        let call = self.compile_procedure_call(target, linkage);
+        Ok(proc_code.preserving(ENV | CONTINUE, arglist.preserving(PROC | CONTINUE, call)))
+    }
+
+    // Compiled procedures are entered directly; everything else goes through
+    // `apply`.
+    fn compile_procedure_call(
+        &mut self,
+        target: Registers,
+        linkage: Linkage,
+    ) -> InstructionSequence {
+        let compiled_branch = self.make_label("compiled-branch");
+        let after_call = self.make_label("after-call");
+        let compiled_linkage = match linkage {
+            Linkage::Next => Linkage::Label(after_call.clone()),
+            linkage => linkage,
+        };
+        let test = InstructionSequence::new(
+            PROC,
+            0,
+            vec![
+                instruction("test", vec![op("compiled-procedure?"), reg("proc")]),
+                instruction("branch", vec![label(&compiled_branch)]),
+            ],
+        );
+        let apply_code = InstructionSequence::new(
+            PROC | ARGL,
+            target,
+            vec![assign(
+                target,
+                vec![op("apply-procedure"), reg("proc"), reg("argl")],
+            )],
+        );
+        let apply_branch = self.end_with_linkage(compiled_linkage.clone(), apply_code);
+        let compiled_code = InstructionSequence::label(&compiled_branch)
+            .append(self.compile_proc_appl(target, compiled_linkage));
+        test.append(apply_branch.parallel(compiled_code))
+            .append(InstructionSequence::label(&after_call))
+    }
+
+    fn compile_proc_appl(&mut self, target: Registers, linkage: Linkage) -> InstructionSequence {
+        let enter = vec![
+            assign(VAL, vec![op("compiled-procedure-entry"), reg("proc")]),
+            instruction("goto", vec![reg("val")]),
+        ];
+        match linkage {
+            Linkage::Return => {
+                // Tail call: the callee returns straight to our caller. The
+                // compiler only ever asks for a return linkage with target val.
+                debug_assert_eq!(target, VAL);
+                InstructionSequence::new(PROC | CONTINUE, ALL, enter)
+            }
+            Linkage::Label(name) if target == VAL => {
+                let mut statements = vec![assign(CONTINUE, vec![label(&name)])];
+                statements.extend(enter);
+                InstructionSequence::new(PROC, ALL, statements)
+            }
+            linkage => {
+                let proc_return = self.make_label("proc-return");
+                let mut statements = vec![assign(CONTINUE, vec![label(&proc_return)])];
+                statements.extend(enter);
+                statements.push(Sexp::symbol(&proc_return));
+                statements.push(assign(target, vec![reg("val")]));
+                let seq = InstructionSequence::new(PROC, ALL, statements);
+                self.end_with_linkage(linkage, seq)
+            }
+        }
+    }
+}
+
+fn construct_arglist(mut operand_codes: Vec<InstructionSequence>) -> InstructionSequence {
+    operand_codes.reverse();
+    let mut codes = operand_codes.into_iter();
+    let last = match codes.next() {
+        Some(last) => last,
+        None => {
+            let empty = assign(ARGL, vec![constant(&Nil)]);
+            return InstructionSequence::new(0, ARGL, vec![empty]);
+        }
+    };
+    let list = assign(ARGL, vec![op("list"), reg("val")]);
+    let mut seq = last.append(InstructionSequence::new(VAL, ARGL, vec![list]));
+    for code in codes {
+        let cons = assign(ARGL, vec![op("cons"), reg("val"), reg("argl")]);
+        let next = code.preserving(ARGL, InstructionSequence::new(VAL | ARGL, ARGL, vec![cons]));
+        seq = seq.preserving(ENV, next);
+    }
+    seq
+}
+
+thread_local! {
+    static OPERATIONS: HashMap<String, Rc<Value>> = operations();
+}
+
+fn register_index(reg: Registers) -> usize {
+    reg.trailing_zeros() as usize
+}
+
+fn registers() -> Vec<Rc<Value>> {
+    let mut registers = REGISTERS
+        .iter()
+        .map(|_| Rc::new(Value::Nil))
+        .collect::<Vec<_>>();
+    registers[register_index(CONTINUE)] = Rc::new(Value::Label(Label::halt()));
+    registers
+}
+
+// Compiles `exp` and runs it in `env` (SICP's compile-and-go).
+pub fn compile_and_go(exp: &Sexp, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+    let statements = compile(exp, env)?;
+    let statements: Vec<&Sexp> = statements.iter().collect();
+    let names = REGISTERS.iter().map(|r| r.to_string()).collect();
+    let code = OPERATIONS.with(|operations| Code::assemble(names, &statements, operations))?;
+
+    let mut registers = registers();
+    registers[register_index(ENV)] = Rc::new(Value::Environment(env.clone()));
+    run(Rc::new(code).start(), &mut registers, &mut Stack::default())?;
+    Ok(registers[register_index(VAL)].clone())
+}
+
+// Calls a compiled procedure from Rust, as `apply` does for the interpreter.
+pub fn apply_compiled(
+    procedure: Rc<Value>,
+    entry: &Label,
+    arguments: Vec<Rc<Value>>,
+) -> Result<Rc<Value>, LispError> {
+    let mut registers = registers();
+    registers[register_index(PROC)] = procedure;
+    registers[register_index(ARGL)] = Value::list(arguments);
+    run(entry.clone(), &mut registers, &mut Stack::default())?;
+    Ok(registers[register_index(VAL)].clone())
+}
+
+// A machine operation; None stands for invalid arguments.
+type Operation = fn(&[Rc<Value>]) -> Option<Result<Rc<Value>, LispError>>;
+
+// The machine operations that compiled code uses.
+fn operations() -> HashMap<String, Rc<Value>> {
+    let mut operations = HashMap::new();
+    let mut define = |name: &str, f: Operation| {
+        let name = name.to_string();
+        let primitive = {
+            let name = name.clone();
+            move |args: &[Rc<Value>]| {
+                f(args).unwrap_or_else(|| Err(LispError::invalid_arguments(&name, args)))
+            }
+        };
+        operations.insert(
+            name,
+            Rc::new(Value::PrimitiveProcedure(Box::new(primitive))),
+        );
+    };
+
+    define("lookup-variable-value", |args| match args {
+        [var, env] => Some(environment(env)?.lookup(symbol(var)?)),
+        _ => None,
+    });
+    define("set-variable-value!", |args| match args {
+        [var, value, env] => Some(environment(env)?.set(symbol(var)?, value.clone())),
+        _ => None,
+    });
+    define("define-variable!", |args| match args {
+        [var, value, env] => Some(Ok(environment(env)?.define(symbol(var)?, value.clone()))),
+        _ => None,
+    });
+    define("make-compiled-procedure", |args| match args {
+        [entry, env] => match &**entry {
+            Value::Label(entry) => Some(Ok(gc::track(Value::CompiledProcedure {
+                entry: entry.clone(),
+                environment: environment(env)?.clone(),
+            }))),
+            _ => None,
+        },
+        _ => None,
+    });
+    define("compiled-procedure?", |args| match args {
+        [p] => Some(Ok(Value::boolean(matches!(
+            &**p,
+            Value::CompiledProcedure { .. }
+        )))),
+        _ => None,
+    });
+    define("compiled-procedure-entry", |args| match args {
+        [p] => match &**p {
+            Value::CompiledProcedure { entry, .. } => {
+                Some(Ok(Rc::new(Value::Label(entry.clone()))))
+            }
+            _ => None,
+        },
+        _ => None,
+    });
+    define("compiled-procedure-env", |args| match args {
+        [p] => match &**p {
+            Value::CompiledProcedure { environment, .. } => {
+                Some(Ok(Rc::new(Value::Environment(environment.clone()))))
+            }
+            _ => None,
+        },
+        _ => None,
+    });
+    // (extend-environment parameters arguments env name) binds the arguments
+    // of a call of a compiled procedure, which is named `name` or #f.
+    define("extend-environment", |args| match args {
+        [parameters, arguments, env, name] => {
+            let env = environment(env)?;
+            let mut names = vec![];
+            let mut rest = None;
+            let mut p = parameters.clone();
+            loop {
+                match &*p {
+                    Value::Nil => break,
+                    Value::Symbol(name) => {
+                        rest = Some(name.clone());
+                        break;
+                    }
+                    _ => {
+                        names.push(symbol(&*p.car()?)?.to_string());
+                        p = p.cdr()?;
+                    }
+                }
+            }
+            let mut values = arguments.list_to_vec()?;
+            let (required, given) = (names.len(), values.len());
+            if given < required || (given > required && rest.is_none()) {
+                return Some(Err(LispError::WrongNumberOfArguments {
+                    procedure: symbol(name)
+                        .unwrap_or("anonymous compiled procedure")
+                        .to_string(),
+                    expected: match rest {
+                        Some(_) => format!("at least {}", required),
+                        None => required.to_string(),
+                    },
+                    given,
+                }));
+            }
+            if let Some(rest) = rest {
+                let tail = Value::list(values.split_off(required));
+                values.push(tail);
+                names.push(rest);
+            }
+            let scope = Scope::new(names, env.scope());
+            Some(Ok(Rc::new(Value::Environment(Environment::extend(
+                &scope, values, env,
+            )))))
+        }
+        _ => None,
+    });
+    define("apply-procedure", |args| match args {
+        [procedure, arguments] => Some(apply(procedure.clone(), arguments.list_to_vec()?)),
+        _ => None,
+    });
+    define("evaluate", |args| match args {
+        [exp, env] => Some(eval(&exp.to_sexp()?, environment(env)?)),
+        _ => None,
+    });
+    define("false?", |args| match args {
+        [value] => Some(Ok(Value::boolean(value.is_false()))),
+        _ => None,
+    });
+    define("list", |args| Some(Ok(Value::list(args.to_vec()))));
+    define("cons", |args| match args {
+        [car, cdr] => Some(Ok(Value::cons(car.clone(), cdr.clone()))),
+        _ => None,
+    });
+    operations
+}
+
+// The parameters of a lambda expression.
+fn lambda_parameters(exp: &Sexp) -> Option<&Sexp> {
+    match &exp.elements().0[..] {
+        [Symbol(tag), parameters, ..] if tag == "lambda" => Some(parameters),
+        _ => None,
+    }
+}
+
+// Whether parameters use #!optional or #!rest, which the compiler leaves to
+// the interpreter.
+fn has_keywords(parameters: &Sexp) -> bool {
+    let mut p = parameters;
+    while let Pair(car, cdr, _) = p {
+        if matches!(&**car, Symbol(s) if s.starts_with("#!")) {
+            return true;
+        }
+        p = cdr;
+    }
+    false
+}
+
+fn environment(value: &Value) -> Option<&Rc<Environment>> {
+    match value {
+        Value::Environment(env) => Some(env),
+        _ => None,
+    }
+}
+
+fn symbol(value: &Value) -> Option<&str> {
+    match value {
+        Value::Symbol(name) => Some(name),
+        _ => None,
+    }
+}
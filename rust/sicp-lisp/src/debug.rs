@@ -0,0 +1,179 @@
+// Debugging aids for following the evaluator: tracing calls of chosen
+// procedures, and a stepper that stops at each application.
+
+use super::analyze::*;
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::sexp::*;
+use super::value::*;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+// The procedures an interpreter traces and how deeply their calls are
+// nested, kept in its global environment so that interpreters trace
+// independently.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    traced: RefCell<Vec<Rc<Value>>>,
+    depth: Cell<usize>,
+}
+
+impl Tracer {
+    // (trace f ...) prints each call of the procedures and what it returns,
+    // indented by how deeply the traced calls are nested.
+    pub fn trace(&self, args: &[Rc<Value>]) -> Result<Rc<Value>, LispError> {
+        if !args
+            .iter()
+            .all(|arg| matches!(&**arg, Value::CompoundProcedure { .. }))
+        {
+            return Err(LispError::invalid_arguments("trace", args));
+        }
+        let mut traced = self.traced.borrow_mut();
+        for arg in args {
+            if !traced.iter().any(|p| Rc::ptr_eq(p, arg)) {
+                traced.push(arg.clone());
+            }
+        }
+        Ok(Value::ok())
+    }
+
+    // (untrace f ...) stops tracing the procedures, and (untrace) all of them.
+    pub fn untrace(&self, args: &[Rc<Value>]) -> Result<Rc<Value>, LispError> {
+        let mut traced = self.traced.borrow_mut();
+        if args.is_empty() {
+            traced.clear();
+        } else {
+            traced.retain(|p| !args.iter().any(|arg| Rc::ptr_eq(p, arg)));
+        }
+        Ok(Value::ok())
+    }
+
+    pub(crate) fn is_traced(&self, procedure: &Rc<Value>) -> bool {
+        let traced = self.traced.borrow();
+        !traced.is_empty() && traced.iter().any(|p| Rc::ptr_eq(p, procedure))
+    }
+
+    // The traced procedures, for the collector.
+    pub(crate) fn procedures(&self) -> Vec<Rc<Value>> {
+        self.traced.borrow().clone()
+    }
+
+    // Prints a traced call and returns the depth its value is to be printed at.
+    pub(crate) fn enter(&self, lambda: &Lambda, arguments: &[Rc<Value>]) -> usize {
+        let depth = self.depth.replace(self.depth.get() + 1);
+        let mut call = format!("({}", lambda.describe());
+        for argument in arguments {
+            call.push_str(&format!(" {:?}", argument));
+        }
+        println!("{:width$}{})", "", call, width = depth * 2);
+        depth
+    }
+
+    // Prints what a traced call returned; `value` is None when it was left by
+    // an error.
+    pub(crate) fn leave(&self, depth: usize, value: Option<&Rc<Value>>) {
+        self.depth.set(depth);
+        if let Some(value) = value {
+            println!("{:width$}=> {:?}", "", value, width = depth * 2);
+        }
+    }
+}
+
+// The stepper of (debug expr). It stops before each application, shows it
+// with the variables of each frame of its environment, and reads what to do
+// next: step in, step over, step out or continue.
+pub struct Stepper {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    // Stop at applications nested at most this deep; None to run freely.
+    until: Option<usize>,
+}
+
+const HELP: &str = "s: step in, n: step over, o: step out, c: continue";
+
+impl Stepper {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Stepper {
+            input,
+            output,
+            until: Some(usize::MAX),
+        }
+    }
+
+    pub(crate) fn stops_at(&self, level: usize) -> bool {
+        self.until.is_some_and(|until| level <= until)
+    }
+
+    // Shows an application about to be evaluated `level` applications deep
+    // and waits for a command.
+    pub(crate) fn stop(&mut self, form: &Sexp, env: &Environment, level: usize) {
+        // The stepper only talks to the user; a closed output just leaves it
+        // silent.
+        let _ = self.show(form, env, level);
+        loop {
+            let _ = write!(self.output, "step> ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    self.until = None;
+                    return;
+                }
+                Ok(_) => {}
+            }
+            self.until = match line.trim() {
+                "" | "s" => Some(usize::MAX),
+                "n" => Some(level),
+                "o" => level.checked_sub(1),
+                "c" => None,
+                _ => {
+                    let _ = writeln!(self.output, "{}", HELP);
+                    continue;
+                }
+            };
+            return;
+        }
+    }
+
+    // Shows the value of an application the stepper stopped at.
+    pub(crate) fn returned(&mut self, form: &Sexp, value: &Rc<Value>, level: usize) {
+        let _ = writeln!(self.output, "[{}] {} => {:?}", level, form, value);
+    }
+
+    fn show(&mut self, form: &Sexp, env: &Environment, level: usize) -> io::Result<()> {
+        writeln!(self.output, "[{}] {}", level, form)?;
+        let mut frame = Some(env);
+        while let Some(env) = frame {
+            if env.scope().is_none() {
+                writeln!(self.output, "    <global environment>")?;
+                break;
+            }
+            let variables: Vec<String> = env
+                .variables()
+                .into_iter()
+                .map(|(name, value)| match value {
+                    Some(value) => format!("{} = {:?}", name, value),
+                    None => format!("{} = *unassigned*", name),
+                })
+                .collect();
+            writeln!(self.output, "    {}", variables.join(", "))?;
+            frame = env.base().map(|base| &**base);
+        }
+        Ok(())
+    }
+}
+
+// Evaluates `exp` under the stepper, reading commands from `input` and
+// writing to `output`.
+pub fn debug(
+    exp: &Sexp,
+    env: &Rc<Environment>,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+) -> Result<Rc<Value>, LispError> {
+    let node = analyze(exp, &env.scope(), env)?;
+    execute_stepping(&Rc::new(node), env, Stepper::new(input, output))
+}
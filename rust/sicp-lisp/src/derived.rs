@@ -0,0 +1,283 @@
+// Derived expressions (SICP 4.1.2): each special form here is rewritten into
+// the core forms `eval` knows about, the same way SICP turns `cond` into `if`.
+
+use super::error::*;
+use super::sexp::*;
+
+use super::sexp::Sexp::{Nil, Pair, Symbol};
+
+fn ill_formed(exp: &Sexp) -> LispError {
+    LispError::IllFormedSpecialForm(exp.clone())
+}
+
+// The operands of a special form, which must be a proper list.
+fn operands(exp: &Sexp) -> Result<Vec<&Sexp>, LispError> {
+    match exp {
+        Pair(_, cdr, _) => cdr.to_vec().ok_or_else(|| ill_formed(exp)),
+        _ => Err(ill_formed(exp)),
+    }
+}
+
+fn make_if(predicate: Sexp, consequent: Sexp, alternative: Sexp) -> Sexp {
+    Sexp::list(vec![Sexp::symbol("if"), predicate, consequent, alternative])
+}
+
+fn make_lambda(parameters: Sexp, body: Vec<Sexp>) -> Sexp {
+    Sexp::list_with_tail(vec![Sexp::symbol("lambda"), parameters], Sexp::list(body))
+}
+
+fn sequence_to_exp(actions: &[&Sexp]) -> Sexp {
+    match actions {
+        [] => Sexp::Boolean(false),
+        [action] => (*action).clone(),
+        _ => Sexp::list_with_tail(
+            vec![Sexp::symbol("begin")],
+            Sexp::list(actions.iter().map(|a| (*a).clone()).collect()),
+        ),
+    }
+}
+
+// (define (name . parameters) body ...) => (define name (lambda parameters body ...))
+pub fn procedure_definition(exp: &Sexp) -> Result<Sexp, LispError> {
+    let (signature, body) = match exp.tail(1) {
+        Pair(signature, body, _) => (&**signature, &**body),
+        _ => return Err(ill_formed(exp)),
+    };
+    let (name, parameters) = match signature {
+        Pair(name, parameters, _) if matches!(**name, Symbol(_)) => (name, parameters),
+        _ => return Err(ill_formed(exp)),
+    };
+    let body = body.to_vec().ok_or_else(|| ill_formed(exp))?;
+    if body.is_empty() {
+        return Err(ill_formed(exp));
+    }
+    let lambda = make_lambda((**parameters).clone(), body.into_iter().cloned().collect());
+    Ok(Sexp::list(vec![
+        Sexp::symbol("define"),
+        (**name).clone(),
+        lambda,
+    ]))
+}
+
+// (cond (p1 e1 ...) (p2 => f) (p3) (else e ...))
+pub fn cond_to_if(exp: &Sexp) -> Result<Sexp, LispError> {
+    expand_clauses(exp, &operands(exp)?)
+}
+
+fn expand_clauses(exp: &Sexp, clauses: &[&Sexp]) -> Result<Sexp, LispError> {
+    let (first, rest) = match clauses.split_first() {
+        Some(split) => split,
+        None => return Ok(Sexp::Boolean(false)),
+    };
+    let clause = first.to_vec().ok_or_else(|| ill_formed(exp))?;
+    match clause.as_slice() {
+        [] => Err(ill_formed(exp)),
+        [Symbol(s), actions @ ..] if s == "else" => {
+            if !rest.is_empty() || actions.is_empty() {
+                return Err(ill_formed(exp));
+            }
+            Ok(sequence_to_exp(actions))
+        }
+        [predicate, Symbol(s), recipient] if s == "=>" => {
+            // ((lambda (value recipient rest) (if value ((recipient) value) (rest)))
+            //  predicate (lambda () recipient) (lambda () (cond rest ...)))
+            let rest = Sexp::list_with_tail(
+                vec![Sexp::symbol("cond")],
+                Sexp::list(rest.iter().map(|c| (*c).clone()).collect()),
+            );
+            let body = make_if(
+                Sexp::symbol("value"),
+                Sexp::list(vec![
+                    Sexp::list(vec![Sexp::symbol("recipient")]),
+                    Sexp::symbol("value"),
+                ]),
+                Sexp::list(vec![Sexp::symbol("rest")]),
+            );
+            let lambda = make_lambda(
+                Sexp::list(vec![
+                    Sexp::symbol("value"),
+                    Sexp::symbol("recipient"),
+                    Sexp::symbol("rest"),
+                ]),
+                vec![body],
+            );
+            Ok(Sexp::list(vec![
+                lambda,
+                (*predicate).clone(),
+                make_lambda(Nil, vec![(*recipient).clone()]),
+                make_lambda(Nil, vec![rest]),
+            ]))
+        }
+        [Symbol(s), ..] if s == "=>" => Err(ill_formed(exp)),
+        [predicate] => Ok(Sexp::list(vec![
+            Sexp::symbol("or"),
+            (*predicate).clone(),
+            expand_clauses(exp, rest)?,
+        ])),
+        [predicate, actions @ ..] => Ok(make_if(
+            (*predicate).clone(),
+            sequence_to_exp(actions),
+            expand_clauses(exp, rest)?,
+        )),
+    }
+}
+
+// A list of (var init) bindings, split into variables and initial values.
+fn bindings(exp: &Sexp, bindings: &Sexp) -> Result<(Vec<Sexp>, Vec<Sexp>), LispError> {
+    let mut vars = vec![];
+    let mut inits = vec![];
+    for binding in bindings.to_vec().ok_or_else(|| ill_formed(exp))? {
+        match binding.to_vec().as_deref() {
+            Some([var @ Symbol(_), init]) => {
+                vars.push((*var).clone());
+                inits.push((*init).clone());
+            }
+            _ => return Err(ill_formed(exp)),
+        }
+    }
+    Ok((vars, inits))
+}
+
+// (let ((var init) ...) body ...) => ((lambda (var ...) body ...) init ...)
+// (let name ((var init) ...) body ...) => ((letrec ((name (lambda (var ...) body ...))) name) init ...)
+pub fn let_to_combination(exp: &Sexp) -> Result<Sexp, LispError> {
+    let operands = operands(exp)?;
+    let (name, operands) = match operands.as_slice() {
+        [name @ Symbol(_), rest @ ..] => (Some(*name), rest),
+        rest => (None, rest),
+    };
+    let (vars, inits) = match operands {
+        [bs, _, ..] => bindings(exp, bs)?,
+        _ => return Err(ill_formed(exp)),
+    };
+    let body = operands[1..].iter().map(|b| (*b).clone()).collect();
+    let lambda = make_lambda(Sexp::list(vars), body);
+
+    let operator = match name {
+        None => lambda,
+        Some(name) => Sexp::list(vec![
+            Sexp::symbol("letrec"),
+            Sexp::list(vec![Sexp::list(vec![name.clone(), lambda])]),
+            name.clone(),
+        ]),
+    };
+    Ok(Sexp::list_with_tail(vec![operator], Sexp::list(inits)))
+}
+
+// (let* ((v1 e1) (v2 e2) ...) body ...) => (let ((v1 e1)) (let* ((v2 e2) ...) body ...))
+pub fn let_star_to_nested_lets(exp: &Sexp) -> Result<Sexp, LispError> {
+    let operands = operands(exp)?;
+    let (vars, inits) = match operands.as_slice() {
+        [bs, _, ..] => bindings(exp, bs)?,
+        _ => return Err(ill_formed(exp)),
+    };
+    let body: Vec<Sexp> = operands[1..].iter().map(|b| (*b).clone()).collect();
+
+    let inner = vars
+        .into_iter()
+        .zip(inits)
+        .rev()
+        .fold(None, |inner, (var, init)| {
+            let body = match inner {
+                None => body.clone(),
+                Some(inner) => vec![inner],
+            };
+            let binding = Sexp::list(vec![Sexp::list(vec![var, init])]);
+            Some(Sexp::list_with_tail(
+                vec![Sexp::symbol("let"), binding],
+                Sexp::list(body),
+            ))
+        });
+    Ok(inner
+        .unwrap_or_else(|| Sexp::list_with_tail(vec![Sexp::symbol("let"), Nil], Sexp::list(body))))
+}
+
+// (letrec ((var init) ...) body ...) => (let () (define var init) ... body ...)
+pub fn letrec_to_let(exp: &Sexp) -> Result<Sexp, LispError> {
+    let operands = operands(exp)?;
+    let (vars, inits) = match operands.as_slice() {
+        [bs, _, ..] => bindings(exp, bs)?,
+        _ => return Err(ill_formed(exp)),
+    };
+    let mut body: Vec<Sexp> = vars
+        .into_iter()
+        .zip(inits)
+        .map(|(var, init)| Sexp::list(vec![Sexp::symbol("define"), var, init]))
+        .collect();
+    body.extend(operands[1..].iter().map(|b| (*b).clone()));
+    Ok(Sexp::list_with_tail(
+        vec![Sexp::symbol("let"), Nil],
+        Sexp::list(body),
+    ))
+}
+
+// (and) => #t
+// (and e) => e
+// (and e1 e2 ...) => (if e1 (and e2 ...) #f)
+pub fn and_to_if(exp: &Sexp) -> Result<Sexp, LispError> {
+    match operands(exp)?.as_slice() {
+        [] => Ok(Sexp::Boolean(true)),
+        [e] => Ok((*e).clone()),
+        [e, rest @ ..] => Ok(make_if(
+            (*e).clone(),
+            Sexp::list_with_tail(
+                vec![Sexp::symbol("and")],
+                Sexp::list(rest.iter().map(|e| (*e).clone()).collect()),
+            ),
+            Sexp::Boolean(false),
+        )),
+    }
+}
+
+// (or) => #f
+// (or e) => e
+// (or e1 e2 ...) => ((lambda (value rest) (if value value (rest))) e1 (lambda () (or e2 ...)))
+pub fn or_to_if(exp: &Sexp) -> Result<Sexp, LispError> {
+    match operands(exp)?.as_slice() {
+        [] => Ok(Sexp::Boolean(false)),
+        [e] => Ok((*e).clone()),
+        [e, rest @ ..] => {
+            let rest = Sexp::list_with_tail(
+                vec![Sexp::symbol("or")],
+                Sexp::list(rest.iter().map(|e| (*e).clone()).collect()),
+            );
+            let lambda = make_lambda(
+                Sexp::list(vec![Sexp::symbol("value"), Sexp::symbol("rest")]),
+                vec![make_if(
+                    Sexp::symbol("value"),
+                    Sexp::symbol("value"),
+                    Sexp::list(vec![Sexp::symbol("rest")]),
+                )],
+            );
+            Ok(Sexp::list(vec![
+                lambda,
+                (*e).clone(),
+                make_lambda(Nil, vec![rest]),
+            ]))
+        }
+    }
+}
+
+// (when test e ...) => (if test (begin e ...) #f)
+pub fn when_to_if(exp: &Sexp) -> Result<Sexp, LispError> {
+    match operands(exp)?.as_slice() {
+        [test, actions @ ..] if !actions.is_empty() => Ok(make_if(
+            (*test).clone(),
+            sequence_to_exp(actions),
+            Sexp::Boolean(false),
+        )),
+        _ => Err(ill_formed(exp)),
+    }
+}
+
+// (unless test e ...) => (if test #f (begin e ...))
+pub fn unless_to_if(exp: &Sexp) -> Result<Sexp, LispError> {
+    match operands(exp)?.as_slice() {
+        [test, actions @ ..] if !actions.is_empty() => Ok(make_if(
+            (*test).clone(),
+            Sexp::Boolean(false),
+            sequence_to_exp(actions),
+        )),
+        _ => Err(ill_formed(exp)),
+    }
+}
@@ -0,0 +1,210 @@
+// Environment diagrams (SICP 3.2) in Graphviz's DOT language: frames are
+// boxes listing their bindings, each pointing to its enclosing environment,
+// and compound procedures are double circles pointing to their parameters
+// and body and to the environment they were created in.
+
+use super::environment::*;
+use super::gc;
+use super::sexp::*;
+use super::value::*;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// Values longer than this are cut short in frames.
+const MAX_VALUE_LENGTH: usize = 40;
+
+// Draws the global environment and what is reachable from it. The bindings
+// the global environment had when the diagram was set up, such as the
+// primitives and the prelude, are left out unless they have been redefined.
+#[derive(Debug, Default)]
+pub struct Diagram {
+    builtins: HashMap<String, usize>,
+}
+
+enum Object {
+    Frame(Rc<Environment>),
+    Procedure(Rc<Value>),
+}
+
+impl Diagram {
+    pub fn new(env: &Environment) -> Self {
+        let builtins = env
+            .variables()
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, gc::address(&value?))))
+            .collect();
+        Diagram { builtins }
+    }
+
+    pub fn render(&self, env: &Rc<Environment>) -> String {
+        let mut ids: HashMap<usize, String> = HashMap::new();
+        let mut frames = 0;
+        let mut procedures = 0;
+        let mut id = |object: &Object, pending: &mut Vec<Object>| -> String {
+            let (address, prefix, count) = match object {
+                Object::Frame(env) => (gc::address(env), "e", &mut frames),
+                Object::Procedure(p) => (gc::address(p), "p", &mut procedures),
+            };
+            if let Some(id) = ids.get(&address) {
+                return id.clone();
+            }
+            let id = format!("{}{}", prefix, *count);
+            *count += 1;
+            ids.insert(address, id.clone());
+            pending.push(match object {
+                Object::Frame(env) => Object::Frame(env.clone()),
+                Object::Procedure(p) => Object::Procedure(p.clone()),
+            });
+            id
+        };
+
+        let mut out = String::new();
+        out.push_str("digraph environment {\n");
+        out.push_str("  node [fontname=\"monospace\"];\n");
+        // The global environment is always e0, so that the frames of
+        // procedure calls are numbered from E1.
+        let mut global = env;
+        while let Some(base) = global.base() {
+            global = base;
+        }
+        let mut pending = vec![];
+        id(&Object::Frame(global.clone()), &mut pending);
+        id(&Object::Frame(env.clone()), &mut pending);
+        while let Some(object) = pending.pop() {
+            let this = id(&object, &mut vec![]);
+            match object {
+                Object::Frame(env) => {
+                    let is_global = env.scope().is_none();
+                    let title = if is_global {
+                        "global env".to_string()
+                    } else {
+                        this.to_uppercase()
+                    };
+                    let mut fields = vec![escape_field(&title)];
+                    let mut edges = vec![];
+                    for (name, value) in env.variables() {
+                        let value = match value {
+                            Some(value) => value,
+                            None => {
+                                fields.push(escape_field(&format!("{}: *unassigned*", name)));
+                                continue;
+                            }
+                        };
+                        if is_global && self.builtins.get(&name) == Some(&gc::address(&value)) {
+                            continue;
+                        }
+                        if let Value::CompoundProcedure { .. } = &*value {
+                            let target = id(&Object::Procedure(value.clone()), &mut pending);
+                            let port = fields.len();
+                            fields.push(format!("<f{}> {}:", port, escape_field(&name)));
+                            edges.push(format!("  {}:f{} -> {};\n", this, port, target));
+                        } else {
+                            let text = shorten(&format!("{:?}", value));
+                            fields.push(escape_field(&format!("{}: {}", name, text)));
+                        }
+                    }
+                    let _ = writeln!(
+                        out,
+                        "  {} [shape=record, label=\"{{{}}}\"];",
+                        this,
+                        fields.join("|")
+                    );
+                    edges.iter().for_each(|edge| out.push_str(edge));
+                    if let Some(base) = env.base() {
+                        let base = id(&Object::Frame(base.clone()), &mut pending);
+                        let _ = writeln!(out, "  {} -> {};", this, base);
+                    }
+                }
+                Object::Procedure(procedure) => {
+                    let (lambda, environment) = match &*procedure {
+                        Value::CompoundProcedure {
+                            lambda,
+                            environment,
+                        } => (lambda, environment),
+                        _ => unreachable!("not a compound procedure"),
+                    };
+                    let (parameters, body) = &lambda.source;
+                    let body: Vec<String> = match body.to_vec() {
+                        Some(body) => body.iter().map(|exp| exp.to_string()).collect(),
+                        None => vec![body.to_string()],
+                    };
+                    let code = format!(
+                        "parameters: {}\\lbody: {}\\l",
+                        escape(&parameters_text(parameters)),
+                        escape(&body.join(" "))
+                    );
+                    let _ = writeln!(
+                        out,
+                        "  {} [shape=doublecircle, label=\"\", width=0.2];",
+                        this
+                    );
+                    let _ = writeln!(out, "  {}_code [shape=note, label=\"{}\"];", this, code);
+                    let _ = writeln!(out, "  {} -> {}_code;", this, this);
+                    let env = id(&Object::Frame(environment.clone()), &mut pending);
+                    let _ = writeln!(out, "  {} -> {};", this, env);
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// The diagram of `env` that show-environment draws, leaving out what the
+// global environment was set up with.
+pub(crate) fn render(env: &Rc<Environment>) -> String {
+    match env.diagram() {
+        Some(diagram) => diagram.render(env),
+        None => Diagram::default().render(env),
+    }
+}
+
+// The parameter list as the book writes it, without the parentheses.
+fn parameters_text(parameters: &Sexp) -> String {
+    match parameters {
+        Sexp::Nil => String::new(),
+        Sexp::Pair(..) => {
+            let text = parameters.to_string();
+            text[1..text.len() - 1].to_string()
+        }
+        _ => parameters.to_string(),
+    }
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_VALUE_LENGTH) {
+        Some((i, _)) => format!("{}...", &text[..i]),
+        None => text.to_string(),
+    }
+}
+
+// Escapes text for a quoted label.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Escapes text for a field of a record label, where braces, bars and angle
+// brackets are special too.
+fn escape_field(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in escape(text).chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
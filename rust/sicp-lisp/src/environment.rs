@@ -1,225 +1,1515 @@
+use super::compiler::*;
+use super::debug::Tracer;
+use super::diagram::Diagram;
+use super::error::*;
+use super::eval::*;
+use super::gc;
+use super::machine::*;
+use super::number::*;
+use super::sexp::*;
 use super::value::*;
 
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::hash_map::HashMap;
-use std::rc::Rc;
+use std::convert::TryFrom;
+use std::rc::{Rc, Weak};
+
+// Procedures written in Lisp itself.
+const PRELUDE: &str = include_str!("prelude.scm");
+
+// The compile-time shape of a procedure's frame: the names of its parameters
+// followed by its internal definitions. Variables are resolved against the
+// chain of scopes once, when an expression is analyzed, into lexical
+// addresses (SICP 5.5.6); the global environment has no scope and is looked up
+// by name. The scope of a procedure defined with a name keeps it for
+// backtraces. A scope also keeps the aliases that syntax-rules expansions in
+// it gave to free template identifiers, each with the original name and the
+// number of frames out to where the macro was defined.
+#[derive(Debug)]
+pub struct Scope {
+    names: RefCell<Vec<String>>,
+    name: Option<String>,
+    base: Option<Rc<Scope>>,
+    aliases: RefCell<Vec<(String, String, usize)>>,
+}
+
+impl Scope {
+    pub fn new(names: Vec<String>, base: Option<Rc<Scope>>) -> Rc<Self> {
+        Scope::named(None, names, base)
+    }
+
+    pub fn named(name: Option<&str>, names: Vec<String>, base: Option<Rc<Scope>>) -> Rc<Self> {
+        Rc::new(Scope {
+            names: RefCell::new(names),
+            name: name.map(str::to_string),
+            base,
+            aliases: RefCell::new(vec![]),
+        })
+    }
+
+    // The name of the innermost named procedure `scope` is in.
+    pub fn procedure_name(scope: &Option<Rc<Scope>>) -> Option<&str> {
+        let mut scope = scope;
+        while let Some(s) = scope {
+            if let Some(name) = &s.name {
+                return Some(name);
+            }
+            scope = &s.base;
+        }
+        None
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.names.borrow().len()
+    }
+
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.names.borrow().iter().position(|n| n == name)
+    }
+
+    // The slot of `name` in this frame, adding one for a new definition.
+    pub fn add(&self, name: &str) -> usize {
+        if let Some(index) = self.position(name) {
+            return index;
+        }
+        let mut names = self.names.borrow_mut();
+        names.push(name.to_string());
+        names.len() - 1
+    }
+
+    // The name `name` stands for and its (frame number, displacement), or
+    // None for a global. Aliases are followed out to the frames they were
+    // made for.
+    pub fn resolve(scope: &Option<Rc<Scope>>, name: &str) -> (String, Option<(usize, usize)>) {
+        let mut name = name.to_string();
+        let mut depth = 0;
+        let mut scope = scope;
+        while let Some(s) = scope {
+            if let Some(index) = s.position(&name) {
+                return (name, Some((depth, index)));
+            }
+            let alias = s
+                .aliases
+                .borrow()
+                .iter()
+                .find(|(a, ..)| *a == name)
+                .cloned();
+            let frames = match alias {
+                Some((_, original, frames)) => {
+                    name = original;
+                    frames
+                }
+                None => 1,
+            };
+            for _ in 0..frames {
+                if let Some(s) = scope {
+                    depth += 1;
+                    scope = &s.base;
+                }
+            }
+        }
+        (name, None)
+    }
+
+    // An alias for the free template identifier `original` of a macro
+    // defined in `definition` and used in `scope`, if a frame in between
+    // binds the name and would capture it. `fresh` makes the alias's name.
+    pub(crate) fn alias(
+        scope: &Option<Rc<Scope>>,
+        definition: &Option<Rc<Scope>>,
+        original: &str,
+        fresh: impl FnOnce() -> String,
+    ) -> Option<String> {
+        let use_scope = scope.as_ref()?;
+        let mut frames = 0;
+        let mut captured = false;
+        let mut s = scope;
+        loop {
+            match (s, definition) {
+                (Some(a), Some(b)) if Rc::ptr_eq(a, b) => break,
+                (None, None) => break,
+                (None, Some(_)) => return None,
+                (Some(a), _) => {
+                    captured |= a.position(original).is_some();
+                    frames += 1;
+                    s = &a.base;
+                }
+            }
+        }
+        if !captured {
+            return None;
+        }
+        let mut aliases = use_scope.aliases.borrow_mut();
+        if let Some((alias, ..)) = aliases
+            .iter()
+            .find(|(_, o, f)| o == original && *f == frames)
+        {
+            return Some(alias.clone());
+        }
+        let alias = fresh();
+        aliases.push((alias.clone(), original.to_string(), frames));
+        Some(alias)
+    }
+}
+
+#[derive(Debug)]
+enum Frame {
+    // The global frame also keeps the diagram that show-environment draws,
+    // which leaves out the bindings it was set up with, the procedures being
+    // traced, and the evaluator's modes: normal-order evaluation (SICP 4.2)
+    // and the amb special forms (SICP 4.3).
+    Global {
+        variables: HashMap<String, Rc<Value>>,
+        diagram: Option<Rc<Diagram>>,
+        tracer: Rc<Tracer>,
+        lazy: bool,
+        amb: bool,
+    },
+    // A slot is None until its internal definition has been evaluated.
+    Local {
+        scope: Rc<Scope>,
+        values: Vec<Option<Rc<Value>>>,
+    },
+}
 
 #[derive(Debug)]
 pub struct Environment {
-    variables: RefCell<HashMap<String, Rc<Value>>>,
+    frame: RefCell<Frame>,
     base: Option<Rc<Environment>>,
 }
 
 impl Environment {
     pub fn empty() -> Rc<Self> {
-        Rc::new(Environment {
-            variables: RefCell::new(HashMap::new()),
+        gc::track_environment(Environment {
+            frame: RefCell::new(Frame::Global {
+                variables: HashMap::new(),
+                diagram: None,
+                tracer: Rc::default(),
+                lazy: false,
+                amb: false,
+            }),
             base: None,
         })
     }
 
+    pub fn extend(
+        scope: &Rc<Scope>,
+        mut values: Vec<Rc<Value>>,
+        base: &Rc<Environment>,
+    ) -> Rc<Self> {
+        let mut slots: Vec<Option<Rc<Value>>> = Vec::with_capacity(scope.len());
+        slots.extend(values.drain(..).map(Some));
+        slots.resize(scope.len(), None);
+        gc::track_environment(Self {
+            frame: RefCell::new(Frame::Local {
+                scope: scope.clone(),
+                values: slots,
+            }),
+            base: Some(base.clone()),
+        })
+    }
+
+    // The scope describing this environment's frame; None for the global one.
+    pub fn scope(&self) -> Option<Rc<Scope>> {
+        match &*self.frame.borrow() {
+            Frame::Global { .. } => None,
+            Frame::Local { scope, .. } => Some(scope.clone()),
+        }
+    }
+
+    // The variables of this frame with their values, in the order they were
+    // bound, or by name for the global frame. A local variable whose
+    // internal definition hasn't been evaluated yet has no value.
+    pub fn variables(&self) -> Vec<(String, Option<Rc<Value>>)> {
+        match &*self.frame.borrow() {
+            Frame::Global { variables, .. } => {
+                let mut variables: Vec<_> = variables
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Some(value.clone())))
+                    .collect();
+                variables.sort_by(|(a, _), (b, _)| a.cmp(b));
+                variables
+            }
+            Frame::Local { scope, values } => {
+                let names = scope.names.borrow();
+                let mut values = values.iter().cloned();
+                names
+                    .iter()
+                    .map(|name| (name.clone(), values.next().flatten()))
+                    .collect()
+            }
+        }
+    }
+
+    // The enclosing environment; None for the global one.
+    pub fn base(&self) -> Option<&Rc<Environment>> {
+        self.base.as_ref()
+    }
+
+    fn ancestor(&self, depth: usize) -> &Self {
+        let mut env = self;
+        for _ in 0..depth {
+            env = env.base.as_ref().expect("lexical address out of range");
+        }
+        env
+    }
+
     pub fn define(&self, var: &str, value: Rc<Value>) -> Rc<Value> {
-        let mut variables = self.variables.borrow_mut();
-        variables.insert(var.to_string(), value);
+        match &mut *self.frame.borrow_mut() {
+            Frame::Global { variables, .. } => {
+                variables.insert(var.to_string(), value);
+            }
+            Frame::Local { scope, values } => {
+                let index = scope.add(var);
+                define_slot(values, index, value);
+            }
+        }
         Value::ok()
     }
 
-    pub fn set(&self, var: &str, value: Rc<Value>) -> Rc<Value> {
-        let mut variables = self.variables.borrow_mut();
-        if variables.contains_key(var) {
-            variables.insert(var.to_string(), value);
-        } else if let Some(base) = &self.base {
-            base.set(var, value);
-        } else {
-            panic!("Unbound variable: {}", var);
+    pub fn set(&self, var: &str, value: Rc<Value>) -> Result<Rc<Value>, LispError> {
+        let mut env = self;
+        loop {
+            match &mut *env.frame.borrow_mut() {
+                Frame::Global { variables, .. } => {
+                    if let Some(slot) = variables.get_mut(var) {
+                        *slot = value;
+                        return Ok(Value::ok());
+                    }
+                }
+                Frame::Local { scope, values } => {
+                    if let Some(index) = scope.position(var) {
+                        return match values.get_mut(index) {
+                            Some(Some(slot)) => {
+                                *slot = value;
+                                Ok(Value::ok())
+                            }
+                            _ => Err(LispError::UnboundVariable(var.to_string())),
+                        };
+                    }
+                }
+            }
+            env = match &env.base {
+                Some(base) => base,
+                None => return Err(LispError::UnboundVariable(var.to_string())),
+            };
+        }
+    }
+
+    pub fn lookup(&self, var: &str) -> Result<Rc<Value>, LispError> {
+        let mut env = self;
+        loop {
+            match &*env.frame.borrow() {
+                Frame::Global { variables, .. } => {
+                    if let Some(value) = variables.get(var) {
+                        return Ok(value.clone());
+                    }
+                }
+                Frame::Local { scope, values } => {
+                    if let Some(index) = scope.position(var) {
+                        return match values.get(index) {
+                            Some(Some(value)) => Ok(value.clone()),
+                            _ => Err(LispError::UnboundVariable(var.to_string())),
+                        };
+                    }
+                }
+            }
+            env = match &env.base {
+                Some(base) => base,
+                None => return Err(LispError::UnboundVariable(var.to_string())),
+            };
+        }
+    }
+
+    // Accessors for lexical addresses; `var` is only used for error messages.
+    pub fn lookup_address(
+        &self,
+        depth: usize,
+        index: usize,
+        var: &str,
+    ) -> Result<Rc<Value>, LispError> {
+        if let Frame::Local { values, .. } = &*self.ancestor(depth).frame.borrow() {
+            if let Some(Some(value)) = values.get(index) {
+                return Ok(value.clone());
+            }
+        }
+        Err(LispError::UnboundVariable(var.to_string()))
+    }
+
+    pub fn set_address(
+        &self,
+        depth: usize,
+        index: usize,
+        var: &str,
+        value: Rc<Value>,
+    ) -> Result<Rc<Value>, LispError> {
+        if let Frame::Local { values, .. } = &mut *self.ancestor(depth).frame.borrow_mut() {
+            if let Some(Some(slot)) = values.get_mut(index) {
+                *slot = value;
+                return Ok(Value::ok());
+            }
+        }
+        Err(LispError::UnboundVariable(var.to_string()))
+    }
+
+    // Whether the slot at `index` in this frame holds a value.
+    pub fn is_bound_address(&self, index: usize) -> bool {
+        match &*self.frame.borrow() {
+            Frame::Local { values, .. } => matches!(values.get(index), Some(Some(_))),
+            Frame::Global { .. } => false,
+        }
+    }
+
+    pub fn define_address(&self, index: usize, value: Rc<Value>) -> Rc<Value> {
+        if let Frame::Local { values, .. } = &mut *self.frame.borrow_mut() {
+            define_slot(values, index, value);
         }
         Value::ok()
     }
 
-    pub fn extend(vars: &[String], vals: &[Rc<Value>], base: &Rc<Environment>) -> Rc<Self> {
-        if vars.len() < vals.len() {
-            panic!("Too many arguments supplied");
+    // Global variables are found by name in the outermost environment.
+    pub fn lookup_global(&self, var: &str) -> Result<Rc<Value>, LispError> {
+        self.outermost().lookup(var)
+    }
+
+    pub fn set_global(&self, var: &str, value: Rc<Value>) -> Result<Rc<Value>, LispError> {
+        self.outermost().set(var, value)
+    }
+
+    pub fn define_global(&self, var: &str, value: Rc<Value>) -> Rc<Value> {
+        self.outermost().define(var, value)
+    }
+
+    fn outermost(&self) -> &Self {
+        let mut env = self;
+        while let Some(base) = &env.base {
+            env = base;
         }
-        if vars.len() > vals.len() {
-            panic!("Too few arguments supplied");
+        env
+    }
+
+    // The diagram that show-environment draws, if the global environment has
+    // one.
+    pub(crate) fn diagram(&self) -> Option<Rc<Diagram>> {
+        match &*self.outermost().frame.borrow() {
+            Frame::Global { diagram, .. } => diagram.clone(),
+            Frame::Local { .. } => None,
         }
+    }
 
-        let mut variables = HashMap::new();
-        for (var, val) in vars.iter().zip(vals.iter()) {
-            variables.insert(var.clone(), val.clone());
+    // The tracer of the interpreter this environment belongs to, if it
+    // traces `procedure`.
+    pub(crate) fn tracing(&self, procedure: &Rc<Value>) -> Option<Rc<Tracer>> {
+        match &*self.outermost().frame.borrow() {
+            Frame::Global { tracer, .. } if tracer.is_traced(procedure) => Some(tracer.clone()),
+            _ => None,
         }
+    }
 
-        Rc::new(Self {
-            variables: RefCell::new(variables),
-            base: Some(base.clone()),
-        })
+    fn tracer(&self) -> Rc<Tracer> {
+        match &*self.outermost().frame.borrow() {
+            Frame::Global { tracer, .. } => tracer.clone(),
+            Frame::Local { .. } => unreachable!("the outermost frame is global"),
+        }
+    }
+
+    // Whether compound procedures receive their arguments as thunks, which
+    // are forced by primitives, conditionals and the REPL's printer.
+    pub(crate) fn is_lazy(&self) -> bool {
+        matches!(
+            &*self.outermost().frame.borrow(),
+            Frame::Global { lazy: true, .. }
+        )
     }
 
-    pub fn lookup(&self, var: &str) -> Rc<Value> {
-        let variables = self.variables.borrow();
-        if let Some(value) = variables.get(var) {
-            return value.clone();
+    pub(crate) fn set_lazy(&self, on: bool) {
+        if let Frame::Global { lazy, .. } = &mut *self.outermost().frame.borrow_mut() {
+            *lazy = on;
+        }
+    }
+
+    // Whether amb, permanent-set! and if-fail are special forms rather than
+    // ordinary names.
+    pub(crate) fn is_amb(&self) -> bool {
+        matches!(
+            &*self.outermost().frame.borrow(),
+            Frame::Global { amb: true, .. }
+        )
+    }
+
+    pub(crate) fn set_amb(&self, on: bool) {
+        if let Frame::Global { amb, .. } = &mut *self.outermost().frame.borrow_mut() {
+            *amb = on;
+        }
+    }
+
+    // Reports the addresses this environment refers to, or returns false if
+    // its variables are borrowed and cannot be inspected.
+    pub fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let frame = match self.frame.try_borrow() {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        match &*frame {
+            Frame::Global {
+                variables, tracer, ..
+            } => {
+                variables.values().for_each(|v| visit(gc::address(v)));
+                tracer
+                    .procedures()
+                    .iter()
+                    .for_each(|p| visit(gc::address(p)));
+            }
+            Frame::Local { values, .. } => {
+                values.iter().flatten().for_each(|v| visit(gc::address(v)))
+            }
         }
         if let Some(base) = &self.base {
-            return base.lookup(var);
+            visit(gc::address(base));
+        }
+        true
+    }
+
+    // Drops every binding; used by the collector to break garbage cycles.
+    pub fn clear(&self) {
+        if let Ok(mut frame) = self.frame.try_borrow_mut() {
+            match &mut *frame {
+                Frame::Global {
+                    variables, tracer, ..
+                } => {
+                    variables.clear();
+                    let _ = tracer.untrace(&[]);
+                }
+                Frame::Local { values, .. } => values.clear(),
+            }
         }
-        panic!("Unbound variable: {}", var);
     }
 
     pub fn new() -> Rc<Self> {
         let env = Self::empty();
 
-        env.define_primitive_procedure("+", box |args| {
-            let mut result = 0;
-            for arg in args {
-                if let Value::Integer(n) = &**arg {
-                    result += *n;
-                } else {
-                    panic!("Invalid arguments for +: {:?}", arg);
+        env.define("true", Value::t());
+        env.define("false", Value::f());
+
+        env.define_primitive_procedure("+", |args| {
+            let mut result = Number::Integer(0);
+            for n in numbers("+", args)? {
+                result = result.add(n);
+            }
+            Ok(Rc::new(Value::Number(result)))
+        });
+
+        env.define_primitive_procedure("-", |args| {
+            let ns = numbers("-", args)?;
+            let result = match ns.split_first() {
+                Some((n, [])) => n.neg(),
+                Some((n, rest)) => rest.iter().fold((*n).clone(), |acc, m| acc.sub(m)),
+                None => return Err(LispError::invalid_arguments("-", args)),
+            };
+            Ok(Rc::new(Value::Number(result)))
+        });
+
+        env.define_primitive_procedure("*", |args| {
+            let mut result = Number::Integer(1);
+            for n in numbers("*", args)? {
+                result = result.mul(n);
+            }
+            Ok(Rc::new(Value::Number(result)))
+        });
+
+        env.define_primitive_procedure("/", |args| {
+            let ns = numbers("/", args)?;
+            let (mut result, rest) = match ns.split_first() {
+                Some((n, [])) => (Number::Integer(1), vec![*n]),
+                Some((n, rest)) => ((*n).clone(), rest.to_vec()),
+                None => return Err(LispError::invalid_arguments("/", args)),
+            };
+            for n in rest {
+                result = result.div(n).ok_or(LispError::DivisionByZero)?;
+            }
+            Ok(Rc::new(Value::Number(result)))
+        });
+
+        env.define_primitive_procedure("cons", |args| {
+            if args.len() != 2 {
+                return Err(LispError::invalid_arguments("cons", args));
+            }
+            Ok(Value::cons(args[0].clone(), args[1].clone()))
+        });
+
+        env.define_primitive_procedure("car", |args| {
+            if let [arg] = args {
+                if let Some(car) = arg.car() {
+                    return Ok(car);
                 }
             }
-            Rc::new(Value::Integer(result))
+            Err(LispError::invalid_arguments("car", args))
         });
 
-        env.define_primitive_procedure("-", box |args| {
-            let mut result = match &*args[0] {
-                Value::Integer(n) => *n,
-                _ => panic!("Invalid arguments for -: {:?}", args),
+        env.define_primitive_procedure("cdr", |args| {
+            if let [arg] = args {
+                if let Some(cdr) = arg.cdr() {
+                    return Ok(cdr);
+                }
+            }
+            Err(LispError::invalid_arguments("cdr", args))
+        });
+
+        env.define_primitive_procedure("set-car!", |args| {
+            if let [pair, value] = args {
+                if let Value::Pair(car, _) = &**pair {
+                    *car.borrow_mut() = value.clone();
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("set-car!", args))
+        });
+
+        env.define_primitive_procedure("set-cdr!", |args| {
+            if let [pair, value] = args {
+                if let Value::Pair(_, cdr) = &**pair {
+                    *cdr.borrow_mut() = value.clone();
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("set-cdr!", args))
+        });
+
+        env.define_primitive_procedure("pair?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("pair?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::Pair(..))))
+        });
+
+        env.define_primitive_procedure("vector?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("vector?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::Vector(_))))
+        });
+
+        env.define_primitive_procedure("make-vector", |args| {
+            let (k, fill) = match args {
+                [k] => (index(k), Value::f()),
+                [k, fill] => (index(k), fill.clone()),
+                _ => return Err(LispError::invalid_arguments("make-vector", args)),
             };
-            for arg in &args[1..] {
-                if let Value::Integer(n) = &**arg {
-                    result -= *n;
-                } else {
-                    panic!("Invalid arguments for -: {:?}", arg);
+            match k {
+                Some(k) => Ok(gc::track(Value::Vector(RefCell::new(vec![fill; k])))),
+                None => Err(LispError::invalid_arguments("make-vector", args)),
+            }
+        });
+
+        env.define_primitive_procedure("vector", |args| {
+            Ok(gc::track(Value::Vector(RefCell::new(args.to_vec()))))
+        });
+
+        env.define_primitive_procedure("vector-length", |args| {
+            if let [vector] = args {
+                if let Value::Vector(elems) = &**vector {
+                    let len = elems.borrow().len() as i64;
+                    return Ok(Rc::new(Value::Number(Number::Integer(len))));
+                }
+            }
+            Err(LispError::invalid_arguments("vector-length", args))
+        });
+
+        env.define_primitive_procedure("vector-ref", |args| {
+            if let [vector, k] = args {
+                if let (Value::Vector(elems), Some(k)) = (&**vector, index(k)) {
+                    if let Some(elem) = elems.borrow().get(k) {
+                        return Ok(elem.clone());
+                    }
+                }
+            }
+            Err(LispError::invalid_arguments("vector-ref", args))
+        });
+
+        env.define_primitive_procedure("vector-set!", |args| {
+            if let [vector, k, value] = args {
+                if let (Value::Vector(elems), Some(k)) = (&**vector, index(k)) {
+                    if let Some(elem) = elems.borrow_mut().get_mut(k) {
+                        *elem = value.clone();
+                        return Ok(Value::ok());
+                    }
+                }
+            }
+            Err(LispError::invalid_arguments("vector-set!", args))
+        });
+
+        env.define_primitive_procedure("vector-fill!", |args| {
+            if let [vector, value] = args {
+                if let Value::Vector(elems) = &**vector {
+                    for elem in elems.borrow_mut().iter_mut() {
+                        *elem = value.clone();
+                    }
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("vector-fill!", args))
+        });
+
+        env.define_primitive_procedure("vector->list", |args| {
+            if let [vector] = args {
+                if let Value::Vector(elems) = &**vector {
+                    return Ok(Value::list(elems.borrow().clone()));
+                }
+            }
+            Err(LispError::invalid_arguments("vector->list", args))
+        });
+
+        env.define_primitive_procedure("list->vector", |args| {
+            if let [list] = args {
+                if let Some(elems) = list.list_to_vec() {
+                    return Ok(gc::track(Value::Vector(RefCell::new(elems))));
+                }
+            }
+            Err(LispError::invalid_arguments("list->vector", args))
+        });
+
+        env.define_primitive_procedure("make-hash-table", |args| {
+            if !args.is_empty() {
+                return Err(LispError::invalid_arguments("make-hash-table", args));
+            }
+            Ok(gc::track(Value::HashTable(RefCell::new(HashMap::new()))))
+        });
+
+        env.define_primitive_procedure("hash-table?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("hash-table?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::HashTable(_))))
+        });
+
+        env.define_primitive_procedure("hash-table-set!", |args| {
+            if let [table, key, value] = args {
+                if let Value::HashTable(entries) = &**table {
+                    entries
+                        .borrow_mut()
+                        .insert(HashKey::new(key), (key.clone(), value.clone()));
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-set!", args))
+        });
+
+        // (hash-table-ref table key) fails for a missing key unless a default
+        // is given as a third argument.
+        env.define_primitive_procedure("hash-table-ref", |args| {
+            if let [table, key, default @ ..] = args {
+                if let Value::HashTable(entries) = &**table {
+                    match (entries.borrow().get(&HashKey::new(key)), default) {
+                        (Some((_, value)), [] | [_]) => return Ok(value.clone()),
+                        (None, [default]) => return Ok(default.clone()),
+                        _ => {}
+                    }
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-ref", args))
+        });
+
+        env.define_primitive_procedure("hash-table-contains?", |args| {
+            if let [table, key] = args {
+                if let Value::HashTable(entries) = &**table {
+                    let found = entries.borrow().contains_key(&HashKey::new(key));
+                    return Ok(Value::boolean(found));
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-contains?", args))
+        });
+
+        env.define_primitive_procedure("hash-table-delete!", |args| {
+            if let [table, key] = args {
+                if let Value::HashTable(entries) = &**table {
+                    entries.borrow_mut().remove(&HashKey::new(key));
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-delete!", args))
+        });
+
+        env.define_primitive_procedure("hash-table-count", |args| {
+            if let [table] = args {
+                if let Value::HashTable(entries) = &**table {
+                    let count = entries.borrow().len() as i64;
+                    return Ok(Rc::new(Value::Number(Number::Integer(count))));
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-count", args))
+        });
+
+        env.define_primitive_procedure("hash-table-keys", |args| {
+            if let [table] = args {
+                if let Value::HashTable(entries) = &**table {
+                    let keys = entries.borrow().values().map(|(k, _)| k.clone()).collect();
+                    return Ok(Value::list(keys));
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table-keys", args))
+        });
+
+        env.define_primitive_procedure("hash-table->alist", |args| {
+            if let [table] = args {
+                if let Value::HashTable(entries) = &**table {
+                    let alist = entries
+                        .borrow()
+                        .values()
+                        .map(|(k, v)| Value::cons(k.clone(), v.clone()))
+                        .collect();
+                    return Ok(Value::list(alist));
+                }
+            }
+            Err(LispError::invalid_arguments("hash-table->alist", args))
+        });
+
+        env.define_primitive_procedure("display", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("display", args));
+            }
+            print!("{}", args[0]);
+            Ok(Value::ok())
+        });
+
+        env.define_primitive_procedure("write", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("write", args));
+            }
+            print!("{:?}", args[0]);
+            Ok(Value::ok())
+        });
+
+        env.define_primitive_procedure("newline", |args| {
+            if !args.is_empty() {
+                return Err(LispError::invalid_arguments("newline", args));
+            }
+            println!();
+            Ok(Value::ok())
+        });
+
+        env.define_primitive_procedure("<", |args| compare("<", args, Ordering::is_lt));
+        env.define_primitive_procedure(">", |args| compare(">", args, Ordering::is_gt));
+        env.define_primitive_procedure("=", |args| compare("=", args, Ordering::is_eq));
+        env.define_primitive_procedure("<=", |args| compare("<=", args, Ordering::is_le));
+        env.define_primitive_procedure(">=", |args| compare(">=", args, Ordering::is_ge));
+
+        env.define_primitive_procedure("number?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("number?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::Number(_))))
+        });
+
+        env.define_primitive_procedure("integer?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("integer?", args));
+            }
+            Ok(Value::boolean(
+                matches!(&*args[0], Value::Number(n) if n.is_integer()),
+            ))
+        });
+
+        env.define_primitive_procedure("exact?", |args| {
+            let n = number("exact?", args)?;
+            Ok(Value::boolean(n.is_exact()))
+        });
+
+        env.define_primitive_procedure("inexact?", |args| {
+            let n = number("inexact?", args)?;
+            Ok(Value::boolean(!n.is_exact()))
+        });
+
+        env.define_primitive_procedure("exact->inexact", |args| {
+            let n = number("exact->inexact", args)?;
+            Ok(Rc::new(Value::Number(n.to_inexact())))
+        });
+
+        env.define_primitive_procedure("inexact->exact", |args| {
+            let n = number("inexact->exact", args)?;
+            match n.to_exact() {
+                Some(n) => Ok(Rc::new(Value::Number(n))),
+                None => Err(LispError::invalid_arguments("inexact->exact", args)),
+            }
+        });
+
+        env.define_primitive_procedure("quotient", |args| {
+            integer_division("quotient", args, Number::quotient)
+        });
+
+        env.define_primitive_procedure("remainder", |args| {
+            integer_division("remainder", args, Number::remainder)
+        });
+
+        env.define_primitive_procedure("modulo", |args| {
+            integer_division("modulo", args, Number::modulo)
+        });
+
+        env.define_primitive_procedure("expt", |args| {
+            if let [base, exponent] = numbers("expt", args)?[..] {
+                if base.is_exact()
+                    && base.is_zero()
+                    && exponent.is_exact()
+                    && exponent.is_negative()
+                {
+                    return Err(LispError::DivisionByZero);
                 }
+                if let Some(n) = base.expt(exponent) {
+                    return Ok(Rc::new(Value::Number(n)));
+                }
+            }
+            Err(LispError::invalid_arguments("expt", args))
+        });
+
+        env.define_primitive_procedure("sqrt", |args| match number("sqrt", args)?.sqrt() {
+            Some(n) => Ok(Rc::new(Value::Number(n))),
+            None => Err(LispError::invalid_arguments("sqrt", args)),
+        });
+
+        env.define_primitive_procedure("number->string", |args| {
+            let (n, radix) = match numbers("number->string", args)?[..] {
+                [n] => (n, 10),
+                [n, Number::Integer(radix)] if 2 <= *radix && *radix <= 36 => (n, *radix as u32),
+                _ => return Err(LispError::invalid_arguments("number->string", args)),
+            };
+            match n.to_string_radix(radix) {
+                Some(s) => Ok(Rc::new(Value::String(s))),
+                None => Err(LispError::invalid_arguments("number->string", args)),
             }
-            Rc::new(Value::Integer(result))
         });
 
-        env.define_primitive_procedure("*", box |args| {
-            let mut result = match &*args[0] {
-                Value::Integer(n) => *n,
-                _ => panic!("Invalid arguments for *: {:?}", args),
+        env.define_primitive_procedure("string->number", |args| {
+            let radix = match args {
+                [_] => 10,
+                [_, radix] => match &**radix {
+                    Value::Number(Number::Integer(r)) if 2 <= *r && *r <= 36 => *r as u32,
+                    _ => return Err(LispError::invalid_arguments("string->number", args)),
+                },
+                _ => return Err(LispError::invalid_arguments("string->number", args)),
             };
-            for arg in &args[1..] {
-                if let Value::Integer(n) = &**arg {
-                    result *= *n;
-                } else {
-                    panic!("Invalid arguments for *: {:?}", arg);
+            let s = string("string->number", &args[..1])?;
+            match Number::parse_radix(s, radix) {
+                Some(n) => Ok(Rc::new(Value::Number(n))),
+                None => Ok(Value::f()),
+            }
+        });
+
+        env.define_primitive_procedure("string?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("string?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::String(_))))
+        });
+
+        env.define_primitive_procedure("char?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("char?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::Char(_))))
+        });
+
+        env.define_primitive_procedure("symbol?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("symbol?", args));
+            }
+            Ok(Value::boolean(matches!(&*args[0], Value::Symbol(_))))
+        });
+
+        env.define_primitive_procedure("string-length", |args| {
+            let s = string("string-length", args)?;
+            Ok(Rc::new(Value::Number(Number::Integer(
+                s.chars().count() as i64
+            ))))
+        });
+
+        env.define_primitive_procedure("string-ref", |args| {
+            if let [s, k] = args {
+                if let (Value::String(s), Some(k)) = (&**s, index(k)) {
+                    if let Some(c) = s.chars().nth(k) {
+                        return Ok(Rc::new(Value::Char(c)));
+                    }
+                }
+            }
+            Err(LispError::invalid_arguments("string-ref", args))
+        });
+
+        env.define_primitive_procedure("substring", |args| {
+            if let [s, start, rest @ ..] = args {
+                if let (Value::String(s), Some(start)) = (&**s, index(start)) {
+                    let len = s.chars().count();
+                    let end = match rest {
+                        [] => Some(len),
+                        [end] => index(end),
+                        _ => None,
+                    };
+                    if let Some(end) = end.filter(|end| start <= *end && *end <= len) {
+                        let sub = s.chars().skip(start).take(end - start).collect();
+                        return Ok(Rc::new(Value::String(sub)));
+                    }
                 }
             }
-            Rc::new(Value::Integer(result))
+            Err(LispError::invalid_arguments("substring", args))
+        });
+
+        env.define_primitive_procedure("string-append", |args| {
+            let mut result = String::new();
+            for arg in args {
+                match &**arg {
+                    Value::String(s) => result.push_str(s),
+                    _ => return Err(LispError::invalid_arguments("string-append", args)),
+                }
+            }
+            Ok(Rc::new(Value::String(result)))
+        });
+
+        env.define_primitive_procedure("string", |args| {
+            let s = chars("string", args)?.into_iter().collect();
+            Ok(Rc::new(Value::String(s)))
         });
 
-        env.define_primitive_procedure("/", box |args| {
-            let mut result = match &*args[0] {
-                Value::Integer(n) => *n,
-                _ => panic!("Invalid arguments for /: {:?}", args),
+        env.define_primitive_procedure("make-string", |args| {
+            let (k, c) = match args {
+                [k] => (index(k), ' '),
+                [k, c] => match &**c {
+                    Value::Char(c) => (index(k), *c),
+                    _ => (None, ' '),
+                },
+                _ => (None, ' '),
             };
-            for arg in &args[1..] {
-                if let Value::Integer(n) = &**arg {
-                    result /= *n;
-                } else {
-                    panic!("Invalid arguments for /: {:?}", arg);
+            match k {
+                Some(k) => Ok(Rc::new(Value::String(std::iter::repeat_n(c, k).collect()))),
+                None => Err(LispError::invalid_arguments("make-string", args)),
+            }
+        });
+
+        env.define_primitive_procedure("string->list", |args| {
+            let s = string("string->list", args)?;
+            let elems = s.chars().map(|c| Rc::new(Value::Char(c))).collect();
+            Ok(Value::list(elems))
+        });
+
+        env.define_primitive_procedure("list->string", |args| {
+            if let [list] = args {
+                if let Some(elems) = list.list_to_vec() {
+                    let s = chars("list->string", &elems)?.into_iter().collect();
+                    return Ok(Rc::new(Value::String(s)));
                 }
             }
-            Rc::new(Value::Integer(result))
+            Err(LispError::invalid_arguments("list->string", args))
         });
 
-        env.define_primitive_procedure("cons", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for cons: {:?}", args);
+        env.define_primitive_procedure("string->symbol", |args| {
+            let s = string("string->symbol", args)?;
+            Ok(Rc::new(Value::Symbol(s.to_string())))
+        });
+
+        env.define_primitive_procedure("symbol->string", |args| {
+            if let [arg] = args {
+                if let Value::Symbol(s) = &**arg {
+                    return Ok(Rc::new(Value::String(s.clone())));
+                }
             }
-            Rc::new(Value::Pair(args[0].clone(), args[1].clone()))
+            Err(LispError::invalid_arguments("symbol->string", args))
+        });
+
+        env.define_primitive_procedure("string-upcase", |args| {
+            let s = string("string-upcase", args)?;
+            Ok(Rc::new(Value::String(s.to_uppercase())))
+        });
+
+        env.define_primitive_procedure("string-downcase", |args| {
+            let s = string("string-downcase", args)?;
+            Ok(Rc::new(Value::String(s.to_lowercase())))
         });
 
-        env.define_primitive_procedure("car", box |args| {
-            if let Value::Pair(car, _) = &*args[0] {
-                return car.clone();
+        env.define_primitive_procedure("string=?", |args| {
+            compare_strings("string=?", args, Ordering::is_eq)
+        });
+        env.define_primitive_procedure("string<?", |args| {
+            compare_strings("string<?", args, Ordering::is_lt)
+        });
+        env.define_primitive_procedure("string>?", |args| {
+            compare_strings("string>?", args, Ordering::is_gt)
+        });
+        env.define_primitive_procedure("string<=?", |args| {
+            compare_strings("string<=?", args, Ordering::is_le)
+        });
+        env.define_primitive_procedure("string>=?", |args| {
+            compare_strings("string>=?", args, Ordering::is_ge)
+        });
+
+        env.define_primitive_procedure("char=?", |args| {
+            compare_chars("char=?", args, Ordering::is_eq)
+        });
+        env.define_primitive_procedure("char<?", |args| {
+            compare_chars("char<?", args, Ordering::is_lt)
+        });
+        env.define_primitive_procedure("char>?", |args| {
+            compare_chars("char>?", args, Ordering::is_gt)
+        });
+        env.define_primitive_procedure("char<=?", |args| {
+            compare_chars("char<=?", args, Ordering::is_le)
+        });
+        env.define_primitive_procedure("char>=?", |args| {
+            compare_chars("char>=?", args, Ordering::is_ge)
+        });
+
+        env.define_primitive_procedure("char->integer", |args| {
+            let c = char("char->integer", args)?;
+            Ok(Rc::new(Value::Number(Number::Integer(c as i64))))
+        });
+
+        env.define_primitive_procedure("integer->char", |args| {
+            if let [Number::Integer(n)] = numbers("integer->char", args)?[..] {
+                if let Some(c) = u32::try_from(*n).ok().and_then(std::char::from_u32) {
+                    return Ok(Rc::new(Value::Char(c)));
+                }
             }
-            panic!("Invalid arguments for car: {:?}", args);
+            Err(LispError::invalid_arguments("integer->char", args))
+        });
+
+        env.define_primitive_procedure("char-upcase", |args| {
+            let c = char("char-upcase", args)?;
+            Ok(Rc::new(Value::Char(c.to_uppercase().next().unwrap_or(c))))
+        });
+
+        env.define_primitive_procedure("char-downcase", |args| {
+            let c = char("char-downcase", args)?;
+            Ok(Rc::new(Value::Char(c.to_lowercase().next().unwrap_or(c))))
+        });
+
+        env.define_primitive_procedure("char-alphabetic?", |args| {
+            Ok(Value::boolean(
+                char("char-alphabetic?", args)?.is_alphabetic(),
+            ))
         });
 
-        env.define_primitive_procedure("cdr", box |args| {
-            if let Value::Pair(_, cdr) = &*args[0] {
-                return cdr.clone();
+        env.define_primitive_procedure("char-numeric?", |args| {
+            Ok(Value::boolean(char("char-numeric?", args)?.is_numeric()))
+        });
+
+        env.define_primitive_procedure("char-whitespace?", |args| {
+            Ok(Value::boolean(
+                char("char-whitespace?", args)?.is_whitespace(),
+            ))
+        });
+
+        env.define_primitive_procedure("null?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("null?", args));
             }
-            panic!("Invalid arguments for cdr: {:?}", args);
+            Ok(Value::boolean(matches!(&*args[0], Value::Nil)))
         });
 
-        env.define_primitive_procedure("display", box |args| {
+        env.define_primitive_procedure("boolean?", |args| {
             if args.len() != 1 {
-                panic!("Invalid arguments for display: {:?}", args);
+                return Err(LispError::invalid_arguments("boolean?", args));
             }
-            println!("{:?}", args[0]);
-            Rc::new(Value::Nil)
+            Ok(Value::boolean(matches!(&*args[0], Value::Boolean(_))))
         });
 
-        env.define_primitive_procedure("<", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for <: {:?}", args);
+        env.define_primitive_procedure("not", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("not", args));
             }
-            if let (Value::Integer(lhs), Value::Integer(rhs)) = (&*args[0], &*args[1]) {
-                return if lhs < rhs { Value::t() } else { Value::f() };
+            Ok(Value::boolean(args[0].is_false()))
+        });
+
+        env.define_primitive_procedure("eq?", |args| {
+            if let [lhs, rhs] = args {
+                return Ok(Value::boolean(Value::is_eq(lhs, rhs)));
             }
-            panic!("Invalid arguments for <: {:?}", args);
+            Err(LispError::invalid_arguments("eq?", args))
         });
 
-        env.define_primitive_procedure(">", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for >: {:?}", args);
+        env.define_primitive_procedure("equal?", |args| {
+            if let [lhs, rhs] = args {
+                return Ok(Value::boolean(Value::is_equal(lhs, rhs)));
             }
-            if let (Value::Integer(lhs), Value::Integer(rhs)) = (&*args[0], &*args[1]) {
-                return if lhs > rhs { Value::t() } else { Value::f() };
+            Err(LispError::invalid_arguments("equal?", args))
+        });
+
+        // The list procedures that walk whole lists are primitives; the
+        // rest are in the prelude.
+        env.define_primitive_procedure("list", |args| Ok(Value::list(args.to_vec())));
+
+        env.define_primitive_procedure("length", |args| {
+            if let [list] = args {
+                if let Some(elems) = list.list_to_vec() {
+                    return Ok(Rc::new(Value::Number(Number::Integer(elems.len() as i64))));
+                }
             }
-            panic!("Invalid arguments for >: {:?}", args);
+            Err(LispError::invalid_arguments("length", args))
         });
 
-        env.define_primitive_procedure("=", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for =: {:?}", args);
+        env.define_primitive_procedure("append", |args| {
+            let (last, init) = match args.split_last() {
+                Some(split) => split,
+                None => return Ok(Rc::new(Value::Nil)),
+            };
+            let mut result = last.clone();
+            for list in init.iter().rev() {
+                let elems = list
+                    .list_to_vec()
+                    .ok_or_else(|| LispError::invalid_arguments("append", args))?;
+                result = elems
+                    .into_iter()
+                    .rev()
+                    .fold(result, |cdr, car| Value::cons(car, cdr));
             }
-            if let (Value::Integer(lhs), Value::Integer(rhs)) = (&*args[0], &*args[1]) {
-                return if lhs == rhs { Value::t() } else { Value::f() };
+            Ok(result)
+        });
+
+        env.define_primitive_procedure("reverse", |args| {
+            if let [list] = args {
+                if let Some(elems) = list.list_to_vec() {
+                    let nil = Rc::new(Value::Nil);
+                    return Ok(elems
+                        .into_iter()
+                        .fold(nil, |cdr, car| Value::cons(car, cdr)));
+                }
             }
-            panic!("Invalid arguments for =: {:?}", args);
+            Err(LispError::invalid_arguments("reverse", args))
         });
 
-        env.define_primitive_procedure("<=", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for <=: {:?}", args);
+        env.define_primitive_procedure("gc", |args| {
+            if !args.is_empty() {
+                return Err(LispError::invalid_arguments("gc", args));
             }
-            if let (Value::Integer(lhs), Value::Integer(rhs)) = (&*args[0], &*args[1]) {
-                return if lhs <= rhs { Value::t() } else { Value::f() };
+            let reclaimed = gc::collect() as i64;
+            Ok(Rc::new(Value::Number(Number::Integer(reclaimed))))
+        });
+
+        env.define_primitive_procedure("memory-stats", |args| {
+            if !args.is_empty() {
+                return Err(LispError::invalid_arguments("memory-stats", args));
             }
-            panic!("Invalid arguments for <=: {:?}", args);
+            let stats = gc::stats();
+            let entry = |name: &str, n: usize| {
+                Value::cons(
+                    Rc::new(Value::Symbol(name.to_string())),
+                    Rc::new(Value::Number(Number::Integer(n as i64))),
+                )
+            };
+            Ok(Value::list(vec![
+                entry("environments", stats.environments),
+                entry("objects", stats.objects),
+                entry("collections", stats.collections),
+                entry("reclaimed", stats.reclaimed),
+            ]))
         });
 
-        env.define_primitive_procedure(">=", box |args| {
-            if args.len() != 2 {
-                panic!("Invalid arguments for >=: {:?}", args);
+        env.define_primitive_procedure("make-machine", |args| {
+            if let [registers, operations, controller] = args {
+                if let Some(machine) = Machine::new(registers, operations, controller) {
+                    return Ok(Rc::new(Value::Machine(RefCell::new(machine?))));
+                }
+            }
+            Err(LispError::invalid_arguments("make-machine", args))
+        });
+
+        env.define_primitive_procedure("set-register-contents!", |args| {
+            if let [machine, register, value] = args {
+                if let (Some(mut machine), Value::Symbol(register)) =
+                    (machine_mut(machine), &**register)
+                {
+                    if machine
+                        .set_register_contents(register, value.clone())
+                        .is_some()
+                    {
+                        return Ok(Value::ok());
+                    }
+                }
             }
-            if let (Value::Integer(lhs), Value::Integer(rhs)) = (&*args[0], &*args[1]) {
-                return if lhs >= rhs { Value::t() } else { Value::f() };
+            Err(LispError::invalid_arguments("set-register-contents!", args))
+        });
+
+        env.define_primitive_procedure("get-register-contents", |args| {
+            if let [machine, register] = args {
+                if let (Some(mut machine), Value::Symbol(register)) =
+                    (machine_mut(machine), &**register)
+                {
+                    if let Some(value) = machine.get_register_contents(register) {
+                        return Ok(value);
+                    }
+                }
             }
-            panic!("Invalid arguments for >=: {:?}", args);
+            Err(LispError::invalid_arguments("get-register-contents", args))
         });
 
-        env.define_primitive_procedure("null?", box |args| {
+        env.define_primitive_procedure("start", |args| {
+            if let [machine] = args {
+                if let Some(mut machine) = machine_mut(machine) {
+                    machine.start()?;
+                    return Ok(Value::ok());
+                }
+            }
+            Err(LispError::invalid_arguments("start", args))
+        });
+
+        env.define_primitive_procedure("machine-statistics", |args| {
+            if let [machine] = args {
+                if let Some(machine) = machine_mut(machine) {
+                    return Ok(machine.statistics());
+                }
+            }
+            Err(LispError::invalid_arguments("machine-statistics", args))
+        });
+
+        // The compiler needs the global environment for macros and as the
+        // environment compiled code starts in.
+        let global = Rc::downgrade(&env);
+        env.define_primitive_procedure("compile", move |args| {
+            if let ([exp], Some(env)) = (args, Weak::upgrade(&global)) {
+                if let Some(exp) = exp.to_sexp() {
+                    let statements = compile(&exp, &env)?;
+                    return Ok(Value::list(
+                        statements.iter().map(Value::from_sexp).collect(),
+                    ));
+                }
+            }
+            Err(LispError::invalid_arguments("compile", args))
+        });
+
+        let global = Rc::downgrade(&env);
+        env.define_primitive_procedure("compile-and-go", move |args| {
+            if let ([exp], Some(env)) = (args, Weak::upgrade(&global)) {
+                if let Some(exp) = exp.to_sexp() {
+                    return compile_and_go(&exp, &env);
+                }
+            }
+            Err(LispError::invalid_arguments("compile-and-go", args))
+        });
+
+        env.define_primitive_procedure("error", |args| {
+            if args.is_empty() {
+                return Err(LispError::invalid_arguments("error", args));
+            }
+            Err(LispError::User {
+                message: args[0].clone(),
+                irritants: args[1..].to_vec(),
+            })
+        });
+
+        env.define_primitive_procedure("raise", |args| {
             if args.len() != 1 {
-                panic!("Invalid arguments for display: {:?}", args);
+                return Err(LispError::invalid_arguments("raise", args));
             }
-            if let Value::Nil = &*args[0] {
-                Value::t()
-            } else {
-                Value::f()
+            Err(LispError::Raise(args[0].clone()))
+        });
+
+        env.define_primitive_procedure("error-object?", |args| {
+            if args.len() != 1 {
+                return Err(LispError::invalid_arguments("error-object?", args));
             }
+            Ok(Value::boolean(matches!(&*args[0], Value::Condition(_))))
         });
 
+        env.define_primitive_procedure("error-object-message", |args| {
+            if let [arg] = args {
+                if let Value::Condition(LispError::User { message, .. }) = &**arg {
+                    return Ok(message.clone());
+                }
+            }
+            Err(LispError::invalid_arguments("error-object-message", args))
+        });
+
+        env.define_primitive_procedure("error-object-irritants", |args| {
+            if let [arg] = args {
+                if let Value::Condition(LispError::User { irritants, .. }) = &**arg {
+                    return Ok(Value::list(irritants.clone()));
+                }
+            }
+            Err(LispError::invalid_arguments("error-object-irritants", args))
+        });
+
+        let tracer = env.tracer();
+        env.define_primitive_procedure("trace", move |args| tracer.trace(args));
+        let tracer = env.tracer();
+        env.define_primitive_procedure("untrace", move |args| tracer.untrace(args));
+
+        let call_cc = Rc::new(Value::Control(Control::CallWithCurrentContinuation));
+        env.define("call-with-current-continuation", call_cc.clone());
+        env.define("call/cc", call_cc);
+        env.define(
+            "dynamic-wind",
+            Rc::new(Value::Control(Control::DynamicWind)),
+        );
+        env.define(
+            "call-with-values",
+            Rc::new(Value::Control(Control::CallWithValues)),
+        );
+
+        env.define("force", Rc::new(Value::Control(Control::Force)));
+        env.define("apply", Rc::new(Value::Control(Control::Apply)));
+        env.define(
+            "raise-continuable",
+            Rc::new(Value::Control(Control::RaiseContinuable)),
+        );
+
+        env.define_primitive_procedure("make-promise", |args| match args {
+            [value] if matches!(&**value, Value::Promise(_)) => Ok(value.clone()),
+            [value] => {
+                let promise = Thunk::Forced(value.clone());
+                Ok(gc::track(Value::Promise(RefCell::new(promise))))
+            }
+            _ => Err(LispError::invalid_arguments("make-promise", args)),
+        });
+
+        env.define_primitive_procedure("promise?", |args| match args {
+            [value] => Ok(Value::boolean(matches!(&**value, Value::Promise(_)))),
+            _ => Err(LispError::invalid_arguments("promise?", args)),
+        });
+
+        env.define_primitive_procedure("values", |args| match args {
+            [value] => Ok(value.clone()),
+            _ => Ok(Rc::new(Value::Values(args.to_vec()))),
+        });
+
+        for exp in parser::expressions(PRELUDE).expect("the prelude parses") {
+            eval(&exp, &env).expect("the prelude evaluates");
+        }
+
+        let diagram = Rc::new(Diagram::new(&env));
+        if let Frame::Global { diagram: d, .. } = &mut *env.frame.borrow_mut() {
+            *d = Some(diagram);
+        }
+
         env
     }
 
-    fn define_primitive_procedure(&self, var: &str, p: Box<dyn Fn(&[Rc<Value>]) -> Rc<Value>>) {
-        self.define(var, Rc::new(Value::PrimitiveProcedure(p)));
+    fn define_primitive_procedure<F>(&self, var: &str, p: F)
+    where
+        F: Fn(&[Rc<Value>]) -> Result<Rc<Value>, LispError> + 'static,
+    {
+        self.define(var, Rc::new(Value::PrimitiveProcedure(Box::new(p))));
+    }
+}
+
+fn define_slot(values: &mut Vec<Option<Rc<Value>>>, index: usize, value: Rc<Value>) {
+    if values.len() <= index {
+        values.resize(index + 1, None);
+    }
+    values[index] = Some(value);
+}
+
+// A machine that is not currently running.
+fn machine_mut(value: &Value) -> Option<std::cell::RefMut<'_, Machine>> {
+    match value {
+        Value::Machine(machine) => machine.try_borrow_mut().ok(),
+        _ => None,
+    }
+}
+
+fn numbers<'a>(name: &str, args: &'a [Rc<Value>]) -> Result<Vec<&'a Number>, LispError> {
+    args.iter()
+        .map(|arg| match &**arg {
+            Value::Number(n) => Ok(n),
+            _ => Err(LispError::invalid_arguments(name, args)),
+        })
+        .collect()
+}
+
+fn number<'a>(name: &str, args: &'a [Rc<Value>]) -> Result<&'a Number, LispError> {
+    match numbers(name, args)?[..] {
+        [n] => Ok(n),
+        _ => Err(LispError::invalid_arguments(name, args)),
+    }
+}
+
+// Numeric comparisons hold when every adjacent pair of arguments is ordered by
+// `holds`; NaN is unordered and compares false.
+fn compare(
+    name: &str,
+    args: &[Rc<Value>],
+    holds: fn(Ordering) -> bool,
+) -> Result<Rc<Value>, LispError> {
+    let ns = numbers(name, args)?;
+    if ns.len() < 2 {
+        return Err(LispError::invalid_arguments(name, args));
+    }
+    let result = ns.windows(2).all(|w| w[0].compare(w[1]).is_some_and(holds));
+    Ok(Value::boolean(result))
+}
+
+fn string<'a>(name: &str, args: &'a [Rc<Value>]) -> Result<&'a str, LispError> {
+    match args {
+        [arg] => match &**arg {
+            Value::String(s) => Ok(s),
+            _ => Err(LispError::invalid_arguments(name, args)),
+        },
+        _ => Err(LispError::invalid_arguments(name, args)),
+    }
+}
+
+fn chars(name: &str, args: &[Rc<Value>]) -> Result<Vec<char>, LispError> {
+    args.iter()
+        .map(|arg| match &**arg {
+            Value::Char(c) => Ok(*c),
+            _ => Err(LispError::invalid_arguments(name, args)),
+        })
+        .collect()
+}
+
+fn char(name: &str, args: &[Rc<Value>]) -> Result<char, LispError> {
+    match chars(name, args)?[..] {
+        [c] => Ok(c),
+        _ => Err(LispError::invalid_arguments(name, args)),
+    }
+}
+
+// A non-negative exact integer used as a string index or length.
+fn index(value: &Value) -> Option<usize> {
+    match value {
+        Value::Number(Number::Integer(n)) => usize::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+fn compare_strings(
+    name: &str,
+    args: &[Rc<Value>],
+    holds: fn(Ordering) -> bool,
+) -> Result<Rc<Value>, LispError> {
+    let ss = args
+        .iter()
+        .map(|arg| match &**arg {
+            Value::String(s) => Ok(s),
+            _ => Err(LispError::invalid_arguments(name, args)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if ss.len() < 2 {
+        return Err(LispError::invalid_arguments(name, args));
+    }
+    Ok(Value::boolean(ss.windows(2).all(|w| holds(w[0].cmp(w[1])))))
+}
+
+fn compare_chars(
+    name: &str,
+    args: &[Rc<Value>],
+    holds: fn(Ordering) -> bool,
+) -> Result<Rc<Value>, LispError> {
+    let cs = chars(name, args)?;
+    if cs.len() < 2 {
+        return Err(LispError::invalid_arguments(name, args));
+    }
+    Ok(Value::boolean(
+        cs.windows(2).all(|w| holds(w[0].cmp(&w[1]))),
+    ))
+}
+
+fn integer_division(
+    name: &str,
+    args: &[Rc<Value>],
+    op: fn(&Number, &Number) -> Option<Number>,
+) -> Result<Rc<Value>, LispError> {
+    if let [a, b] = numbers(name, args)?[..] {
+        if b.is_zero() {
+            return Err(LispError::DivisionByZero);
+        }
+        if let Some(n) = op(a, b) {
+            return Ok(Rc::new(Value::Number(n)));
+        }
     }
+    Err(LispError::invalid_arguments(name, args))
 }
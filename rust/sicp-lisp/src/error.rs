@@ -0,0 +1,160 @@
+use super::sexp::*;
+use super::value::*;
+
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone)]
+pub enum LispError {
+    UnboundVariable(String),
+    IllFormedSpecialForm(Sexp),
+    WrongNumberOfArguments {
+        procedure: String,
+        expected: String,
+        given: usize,
+    },
+    InvalidArguments {
+        procedure: String,
+        arguments: Vec<Rc<Value>>,
+    },
+    InvalidProcedure(Rc<Value>),
+    InvalidExpansion(Rc<Value>),
+    DivisionByZero,
+    FileNotReadable {
+        path: String,
+        message: String,
+    },
+    Parse(String),
+    User {
+        message: Rc<Value>,
+        irritants: Vec<Rc<Value>>,
+    },
+    Raise(Rc<Value>),
+    // A continuation invoked in a nested run of the evaluator, on its way
+    // to the run it belongs to, with the value it was invoked with.
+    Escape(Rc<Value>, Rc<Value>),
+    // amb and the forms that go with it reached in a run of the ordinary
+    // evaluator, such as one started by a primitive, which cannot
+    // backtrack. It stands for a failure, so guards and handlers let it by.
+    NotInAmbEvaluator,
+    // An error with the applications it passed through on its way out,
+    // innermost first, such as "in factorial at fact.scm:3:12".
+    Traced {
+        error: Box<LispError>,
+        backtrace: Vec<String>,
+    },
+}
+
+impl LispError {
+    pub fn invalid_arguments(procedure: &str, arguments: &[Rc<Value>]) -> Self {
+        LispError::InvalidArguments {
+            procedure: procedure.to_string(),
+            arguments: arguments.to_vec(),
+        }
+    }
+
+    // The object handed to exception handlers: the raised value itself for
+    // `raise`, and a condition object for everything else.
+    pub fn payload(&self) -> Rc<Value> {
+        match self {
+            LispError::Raise(value) => value.clone(),
+            LispError::Traced { error, .. } => error.payload(),
+            _ => Rc::new(Value::Condition(self.clone())),
+        }
+    }
+
+    // Whether guards and exception handlers may catch the error.
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            LispError::Escape(..) | LispError::NotInAmbEvaluator => false,
+            LispError::Traced { error, .. } => error.is_catchable(),
+            _ => true,
+        }
+    }
+
+    // Adds an entry to the error's backtrace. Escapes are left alone, since
+    // they are not errors.
+    pub fn traced(self, entry: String) -> Self {
+        match self {
+            LispError::Escape(..) => self,
+            LispError::Traced {
+                error,
+                mut backtrace,
+            } => {
+                backtrace.push(entry);
+                LispError::Traced { error, backtrace }
+            }
+            error => LispError::Traced {
+                error: Box::new(error),
+                backtrace: vec![entry],
+            },
+        }
+    }
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LispError::UnboundVariable(var) => write!(f, "Unbound variable: {}", var),
+            LispError::IllFormedSpecialForm(exp) => write!(f, "Ill-formed special form: {}", exp),
+            LispError::WrongNumberOfArguments {
+                procedure,
+                expected,
+                given,
+            } => write!(
+                f,
+                "Wrong number of arguments for {}: expected {}, given {}",
+                procedure, expected, given
+            ),
+            LispError::InvalidArguments {
+                procedure,
+                arguments,
+            } => write!(f, "Invalid arguments for {}: {:?}", procedure, arguments),
+            LispError::InvalidProcedure(procedure) => {
+                write!(f, "Invalid procedure: {:?}", procedure)
+            }
+            LispError::InvalidExpansion(value) => {
+                write!(f, "Invalid macro expansion: {:?}", value)
+            }
+            LispError::DivisionByZero => write!(f, "Division by zero"),
+            LispError::FileNotReadable { path, message } => {
+                write!(f, "Cannot read {}: {}", path, message)
+            }
+            LispError::Parse(message) => write!(f, "Parse error: {}", message),
+            LispError::User { message, irritants } => {
+                write!(f, "{}", message)?;
+                for irritant in irritants {
+                    write!(f, " {:?}", irritant)?;
+                }
+                Ok(())
+            }
+            LispError::Raise(value) => write!(f, "Uncaught exception: {:?}", value),
+            LispError::Escape(..) => write!(f, "Continuation invoked outside of its extent"),
+            LispError::NotInAmbEvaluator => write!(f, "Not running in the amb evaluator"),
+            // Runs of the same entry, as left by a deep recursion, are
+            // printed once with a count.
+            LispError::Traced { error, backtrace } => {
+                write!(f, "{}", error)?;
+                let mut i = 0;
+                while i < backtrace.len() {
+                    let n = backtrace[i..]
+                        .iter()
+                        .take_while(|entry| **entry == backtrace[i])
+                        .count();
+                    write!(f, "\n  {}", backtrace[i])?;
+                    if n > 1 {
+                        write!(f, " ({} times)", n)?;
+                    }
+                    i += n;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
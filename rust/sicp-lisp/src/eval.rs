@@ -1,109 +1,1074 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io;
 use std::rc::Rc;
 
+use super::analyze::*;
+use super::compiler::*;
+use super::debug::{Stepper, Tracer};
+use super::diagram;
 use super::environment::*;
+use super::error::*;
+use super::gc;
 use super::sexp::*;
 use super::value::*;
 
-use super::sexp::Sexp::*;
+use super::sexp::Sexp::{Pair, Symbol};
 
-pub fn eval(exp: &Sexp, env: &Rc<Environment>) -> Rc<Value> {
-    match exp {
-        Nil => Rc::new(Value::Nil),
-        Integer(n) => Rc::new(Value::Integer(*n)),
-        Symbol(s) => env.lookup(s),
-        Pair(box Symbol(tag), box Pair(box Symbol(var), box Pair(box val, box Nil)))
-            if tag == "set!" =>
-        {
-            env.set(&var, eval(val, env))
+// The evaluator is a machine with an explicit stack of frames, each saying
+// what to do with the value of the expression being evaluated, instead of
+// Rust recursion. call/cc captures a copy of the stack as a continuation, and
+// invoking the continuation reinstates the copy, any number of times. Tail
+// calls push no frame, so they run in constant space.
+//
+// Primitives and other Rust code that call back into Lisp start a new run of
+// the machine with a stack of its own. A continuation invoked inside a nested
+// run that belongs to an enclosing one unwinds the Rust stack back to that
+// run as an `Escape` error.
+
+#[derive(Clone)]
+enum Frame {
+    // The rest of a sequence, from `index` on.
+    Sequence {
+        nodes: Rc<[Rc<Node>]>,
+        index: usize,
+        env: Rc<Environment>,
+    },
+    If {
+        consequent: Rc<Node>,
+        alternative: Rc<Node>,
+        env: Rc<Environment>,
+    },
+    // An assignment or definition node waiting for its value.
+    Assign {
+        node: Rc<Node>,
+        env: Rc<Environment>,
+    },
+    // An application node waiting for its operator.
+    Operator {
+        node: Rc<Node>,
+        env: Rc<Environment>,
+    },
+    // Waiting for operand number `arguments.len()` of an application node.
+    Operands {
+        node: Rc<Node>,
+        procedure: Rc<Value>,
+        arguments: Vec<Rc<Value>>,
+        operands: Rc<[Rc<Node>]>,
+        env: Rc<Environment>,
+    },
+    // The steps of a quasiquote's cons or append.
+    Car {
+        cdr: Rc<Node>,
+        append: bool,
+        env: Rc<Environment>,
+    },
+    Cdr {
+        car: Rc<Value>,
+        append: bool,
+    },
+    Vector,
+    // Catch errors raised while they are on the stack.
+    Guard {
+        node: Rc<Node>,
+        env: Rc<Environment>,
+    },
+    // A with-exception-handler's handler, called where an error is raised.
+    Handler(Rc<Value>),
+    // A handler called for a raise, with the index of its Handler frame. The
+    // frames from there up to this one are skipped when looking for the
+    // current handler, so that the handler runs with the outer one.
+    Raise {
+        handler: usize,
+        continuable: bool,
+        payload: Rc<Value>,
+    },
+    // The thunk of a dynamic-wind is running. `id` tells the frames of
+    // different dynamic-winds apart across copies of the stack.
+    Wind {
+        id: usize,
+        before: Rc<Value>,
+        after: Rc<Value>,
+    },
+    CallWithValues(Rc<Value>),
+    // A promise being forced, to be memoized.
+    Force(Rc<Value>),
+    // A thunk of the lazy evaluator being forced, to be memoized.
+    Thunk(Rc<Value>),
+    // The thunk among the arguments of a primitive at `index` being forced.
+    Arguments {
+        procedure: Rc<Value>,
+        arguments: Vec<Rc<Value>>,
+        index: usize,
+    },
+    // A thunk being forced to be applied to the arguments.
+    Apply(Vec<Rc<Value>>),
+    // A call of a traced procedure, whose value is printed at this depth.
+    Trace(Rc<Tracer>, usize),
+    // An application seen by the stepper, `level` applications deep, and
+    // whether it stopped there.
+    Step {
+        form: Rc<Sexp>,
+        level: usize,
+        shown: bool,
+    },
+}
+
+enum State {
+    Eval(Rc<Node>, Rc<Environment>),
+    Return(Rc<Value>),
+    Apply(Rc<Value>, Vec<Rc<Value>>),
+    Force(Rc<Value>),
+}
+
+// A captured stack, and the run of the machine it was captured in.
+pub struct Continuation {
+    run: usize,
+    frames: Vec<Frame>,
+}
+
+// The procedures that need access to the machine's stack.
+pub enum Control {
+    CallWithCurrentContinuation,
+    DynamicWind,
+    CallWithValues,
+    Force,
+    Apply,
+    RaiseContinuable,
+}
+
+struct Machine {
+    id: usize,
+    stack: Vec<Frame>,
+    stepper: Option<Stepper>,
+    // The number of Step frames on the stack.
+    level: usize,
+}
+
+thread_local! {
+    // The ids of the runs in progress, innermost last.
+    static RUNS: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+fn next_id() -> usize {
+    NEXT_ID.with(|id| {
+        id.set(id.get() + 1);
+        id.get()
+    })
+}
+
+fn is_running(run: usize) -> bool {
+    RUNS.with(|runs| runs.borrow().contains(&run))
+}
+
+pub fn eval(exp: &Sexp, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+    let node = analyze(exp, &env.scope(), env)?;
+    execute(&Rc::new(node), env)
+}
+
+pub fn execute(node: &Rc<Node>, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+    run(State::Eval(node.clone(), env.clone()))
+}
+
+pub fn apply(procedure: Rc<Value>, arguments: Vec<Rc<Value>>) -> Result<Rc<Value>, LispError> {
+    run(State::Apply(procedure, arguments))
+}
+
+// Executes a node with the stepper stopping at its applications. Runs the
+// evaluator starts for primitives on the way, such as the ones of `apply`
+// with a primitive, are not stepped through.
+pub(crate) fn execute_stepping(
+    node: &Rc<Node>,
+    env: &Rc<Environment>,
+    stepper: Stepper,
+) -> Result<Rc<Value>, LispError> {
+    run_with(State::Eval(node.clone(), env.clone()), Some(stepper))
+}
+
+fn run(state: State) -> Result<Rc<Value>, LispError> {
+    run_with(state, None)
+}
+
+fn run_with(state: State, stepper: Option<Stepper>) -> Result<Rc<Value>, LispError> {
+    let mut machine = Machine {
+        id: next_id(),
+        stack: vec![],
+        stepper,
+        level: 0,
+    };
+    RUNS.with(|runs| runs.borrow_mut().push(machine.id));
+    let result = machine.run(state);
+    RUNS.with(|runs| runs.borrow_mut().pop());
+    result
+}
+
+// The value of a variable or constant, which needs no frame.
+fn simple(node: &Node, env: &Environment) -> Option<Result<Rc<Value>, LispError>> {
+    match node {
+        Node::Constant(value) => Some(Ok(value.clone())),
+        Node::LocalVariable {
+            depth, index, name, ..
+        } => Some(env.lookup_address(*depth, *index, name)),
+        Node::GlobalVariable { name, .. } => Some(env.lookup_global(name)),
+        _ => None,
+    }
+}
+
+// Where an application or variable was read and the procedure it is in, as
+// a line of an error's backtrace. Expressions not read from source have no
+// location.
+fn location(node: &Node) -> Option<String> {
+    let (span, scope) = match node {
+        Node::Application { form, scope, .. } => (form.span()?, scope),
+        Node::LocalVariable { span, scope, .. } | Node::GlobalVariable { span, scope, .. } => {
+            (span.as_ref()?, scope)
         }
-        Pair(box Symbol(tag), box Pair(box Symbol(var), box Pair(box val, box Nil)))
-            if tag == "define" =>
-        {
-            env.define(&var, eval(val, env))
+        _ => return None,
+    };
+    Some(match Scope::procedure_name(scope) {
+        Some(name) => format!("in {} at {}", name, span),
+        None => format!("at {}", span),
+    })
+}
+
+fn trace(e: LispError, node: &Node) -> LispError {
+    match location(node) {
+        Some(entry) => e.traced(entry),
+        None => e,
+    }
+}
+
+// A variable operand is usually located at its application, which is then
+// left to add the entry.
+fn trace_operand(e: LispError, operand: &Node, application: &Node) -> LispError {
+    match location(operand) {
+        Some(entry) if Some(&entry) != location(application).as_ref() => e.traced(entry),
+        _ => e,
+    }
+}
+
+// How far an expression gets without pushing a frame: variables, constants
+// and calls of primitives on them, such as (- n 1), are evaluated right away,
+// and the operator of any other application is looked up.
+enum Quick {
+    Done(Rc<Value>),
+    Call(Rc<Value>),
+    Other,
+}
+
+fn quick(node: &Node, env: &Environment) -> Result<Quick, LispError> {
+    let (operator, operands) = match node {
+        Node::Application {
+            operator, operands, ..
+        } => (operator, operands),
+        _ => {
+            return Ok(match simple(node, env) {
+                Some(value) => Quick::Done(value?),
+                None => Quick::Other,
+            })
         }
-        Pair(box Symbol(tag), box Pair(box p, box Pair(box c, box Pair(box a, box Nil))))
-            if tag == "if" =>
-        {
-            eval(if is_true(&eval(p, env)) { c } else { a }, env)
+    };
+    let procedure = match simple(operator, env) {
+        Some(procedure) => procedure?,
+        None => return Ok(Quick::Other),
+    };
+    let f = match &*procedure {
+        Value::PrimitiveProcedure(f) if operands.iter().all(|o| simple(o, env).is_some()) => f,
+        _ => return Ok(Quick::Call(procedure)),
+    };
+    let mut arguments = Vec::with_capacity(operands.len());
+    for operand in operands.iter() {
+        let argument = simple(operand, env).unwrap()?;
+        // Thunks are forced on the stack.
+        if let Value::Thunk(_) = &*argument {
+            return Ok(Quick::Call(procedure));
         }
-        Pair(box Symbol(tag), box actions) if tag == "begin" => eval_sequence(actions, env),
-        Pair(box Symbol(tag), box Pair(box p, box b)) if tag == "lambda" => eval_lambda(p, b, env),
-        Pair(operator, operands) => apply(eval(operator, env), &list_of_values(operands, env)),
+        arguments.push(argument);
     }
+    Ok(Quick::Done(f(&arguments)?))
 }
 
-fn is_true(exp: &Rc<Value>) -> bool {
-    !is_false(exp)
+// The arguments of a continuation, as the one value it returns.
+fn values(mut arguments: Vec<Rc<Value>>) -> Rc<Value> {
+    match arguments.len() {
+        1 => arguments.pop().unwrap(),
+        _ => Rc::new(Value::Values(arguments)),
+    }
 }
 
-fn is_false(exp: &Rc<Value>) -> bool {
-    if let Value::Symbol(s) = &**exp {
-        s == "false"
-    } else {
-        false
+impl Machine {
+    fn run(&mut self, mut state: State) -> Result<Rc<Value>, LispError> {
+        loop {
+            let next = match state {
+                State::Return(value) if self.stack.is_empty() => return Ok(value),
+                State::Eval(node, env) => self.eval(node, env),
+                State::Return(value) => self.ret(value),
+                State::Apply(procedure, arguments) => self.apply(procedure, arguments),
+                State::Force(value) => Ok(self.force(value)),
+            };
+            state = match next {
+                Ok(state) => state,
+                Err(e) => self.unwind(e)?,
+            };
+        }
     }
-}
 
-fn eval_lambda(p: &Sexp, b: &Sexp, env: &Rc<Environment>) -> Rc<Value> {
-    let mut parameters = vec![];
+    fn eval(&mut self, node: Rc<Node>, env: Rc<Environment>) -> Result<State, LispError> {
+        if self.stepper.is_some() {
+            if let Node::Application { form, .. } = &*node {
+                self.step(form.clone(), &env);
+            }
+        }
+        match quick(&node, &env).map_err(|e| trace(e, &node))? {
+            Quick::Done(value) => return Ok(State::Return(value)),
+            Quick::Call(procedure) => return self.call(node, procedure, env),
+            Quick::Other => {}
+        }
+        let value = match &*node {
+            Node::LocalAssignment { value, .. }
+            | Node::GlobalAssignment { value, .. }
+            | Node::LocalDefinition { value, .. }
+            | Node::GlobalDefinition { value, .. } => {
+                let value = value.clone();
+                self.stack.push(Frame::Assign {
+                    node,
+                    env: env.clone(),
+                });
+                return Ok(State::Eval(value, env));
+            }
+            Node::If(p, c, a) => {
+                let quick = self.quick(p, &env).map_err(|e| trace(e, p))?;
+                match &quick {
+                    Quick::Done(p) if !matches!(&**p, Value::Thunk(_)) => {
+                        let node = if p.is_true() { c } else { a };
+                        return Ok(State::Eval(node.clone(), env));
+                    }
+                    _ => {}
+                }
+                self.stack.push(Frame::If {
+                    consequent: c.clone(),
+                    alternative: a.clone(),
+                    env: env.clone(),
+                });
+                return match quick {
+                    Quick::Done(p) => Ok(State::Force(p)),
+                    Quick::Call(procedure) => self.call(p.clone(), procedure, env),
+                    Quick::Other => Ok(State::Eval(p.clone(), env)),
+                };
+            }
+            Node::Sequence(nodes) => return Ok(self.sequence(nodes.clone(), 0, env)),
+            Node::Lambda(lambda) => lambda.make_procedure(&env),
+            Node::Macro(transformer) => {
+                let transformer = execute(transformer, &env)?;
+                match &*transformer {
+                    Value::CompoundProcedure { .. }
+                    | Value::CompiledProcedure { .. }
+                    | Value::PrimitiveProcedure(_) => gc::track(Value::Macro(transformer)),
+                    _ => return Err(LispError::InvalidProcedure(transformer)),
+                }
+            }
+            Node::Cons(car, cdr) | Node::Append(car, cdr) => {
+                self.stack.push(Frame::Car {
+                    cdr: cdr.clone(),
+                    append: matches!(&*node, Node::Append(..)),
+                    env: env.clone(),
+                });
+                return Ok(State::Eval(car.clone(), env));
+            }
+            Node::Vector(list) => {
+                self.stack.push(Frame::Vector);
+                return Ok(State::Eval(list.clone(), env));
+            }
+            Node::WithExceptionHandler(handler, thunk) => {
+                let handler = execute(handler, &env)?;
+                let thunk = execute(thunk, &env)?;
+                self.stack.push(Frame::Handler(handler));
+                return Ok(State::Apply(thunk, vec![]));
+            }
+            Node::Guard { body, .. } => {
+                let body = body.clone();
+                self.stack.push(Frame::Guard {
+                    node,
+                    env: env.clone(),
+                });
+                return Ok(State::Eval(body, env));
+            }
+            Node::MacroExpand { form, once } => {
+                let mut exp = value_to_sexp(execute(form, &env)?)?;
+                while let Some(expansion) = macroexpand_1(&exp, &env)? {
+                    exp = expansion;
+                    if *once {
+                        break;
+                    }
+                }
+                Value::from_sexp(&exp)
+            }
+            Node::Load(file) => {
+                let file = execute(file, &env)?;
+                match &*file {
+                    Value::String(path) => load(path, &env)?,
+                    _ => return Err(LispError::invalid_arguments("load", &[file])),
+                }
+            }
+            Node::Default { index, definition } => {
+                if env.is_bound_address(*index) {
+                    Value::ok()
+                } else {
+                    return Ok(State::Eval(definition.clone(), env));
+                }
+            }
+            Node::Delay(exp) => {
+                let promise = Thunk::Delayed(exp.clone(), env.clone());
+                gc::track(Value::Promise(RefCell::new(promise)))
+            }
+            Node::Debug(exp) => {
+                let stepper = Stepper::new(Box::new(io::stdin().lock()), Box::new(io::stdout()));
+                execute_stepping(exp, &env, stepper)?
+            }
+            Node::ShowEnvironment { print } => {
+                let diagram = diagram::render(&env);
+                if !*print {
+                    return Ok(State::Return(Rc::new(Value::String(diagram))));
+                }
+                print!("{}", diagram);
+                Value::ok()
+            }
+            Node::Amb(_) | Node::PermanentAssignment(_) | Node::IfFail(..) => {
+                return Err(LispError::NotInAmbEvaluator)
+            }
+            Node::Application { operator, .. } => {
+                let operator = operator.clone();
+                self.stack.push(Frame::Operator {
+                    node,
+                    env: env.clone(),
+                });
+                return Ok(State::Eval(operator, env));
+            }
+            Node::Constant(_) | Node::LocalVariable { .. } | Node::GlobalVariable { .. } => {
+                unreachable!("quick node")
+            }
+        };
+        Ok(State::Return(value))
+    }
 
-    let mut next = &*p;
-    while let Pair(car, cdr) = next {
-        if let Symbol(s) = &**car {
-            parameters.push(s.clone());
+    // While stepping, applications are left to `eval`, so that the stepper
+    // sees every one of them.
+    fn quick(&self, node: &Node, env: &Environment) -> Result<Quick, LispError> {
+        if self.stepper.is_some() && matches!(node, Node::Application { .. }) {
+            return Ok(Quick::Other);
         }
-        next = cdr;
+        quick(node, env)
     }
 
-    let mut body = vec![];
-    let mut next = &*b;
-    while let Pair(car, cdr) = next {
-        body.push(*car.clone());
-        next = cdr;
+    // Lets the stepper stop at an application, and pushes the frame that
+    // shows its value and keeps track of the nesting.
+    fn step(&mut self, form: Rc<Sexp>, env: &Environment) {
+        let stepper = self.stepper.as_mut().expect("a stepper");
+        let shown = stepper.stops_at(self.level);
+        if shown {
+            stepper.stop(&form, env, self.level);
+        }
+        self.stack.push(Frame::Step {
+            form,
+            level: self.level,
+            shown,
+        });
+        self.level += 1;
     }
 
-    Rc::new(Value::CompoundProcedure {
-        body,
-        parameters,
-        environment: env.clone(),
-    })
+    fn sequence(&mut self, nodes: Rc<[Rc<Node>]>, index: usize, env: Rc<Environment>) -> State {
+        let node = match nodes.get(index) {
+            Some(node) => node.clone(),
+            None => return State::Return(Rc::new(Value::Nil)),
+        };
+        if index + 1 < nodes.len() {
+            self.stack.push(Frame::Sequence {
+                nodes,
+                index: index + 1,
+                env: env.clone(),
+            });
+        }
+        State::Eval(node, env)
+    }
+
+    // Continues an application once its operator is known.
+    fn call(
+        &mut self,
+        node: Rc<Node>,
+        procedure: Rc<Value>,
+        env: Rc<Environment>,
+    ) -> Result<State, LispError> {
+        if let Value::Thunk(_) = &*procedure {
+            self.stack.push(Frame::Operator { node, env });
+            return Ok(State::Force(procedure));
+        }
+        let (operands, form, scope) = match &*node {
+            Node::Application {
+                operands,
+                form,
+                scope,
+                ..
+            } => (operands, form, scope),
+            _ => unreachable!("not an application"),
+        };
+        let expansion = match &*procedure {
+            Value::Macro(transformer) => match &**form {
+                Pair(_, operands, _) => Some(expand(transformer, operands)?),
+                _ => None,
+            },
+            Value::Syntax(rules) => Some(rules.expand(form, scope)?),
+            _ => None,
+        };
+        if let Some(expansion) = expansion {
+            let node = analyze(&expansion, scope, &env)?;
+            return Ok(State::Eval(Rc::new(node), env));
+        }
+        if matches!(&*procedure, Value::CompoundProcedure { .. }) && env.is_lazy() {
+            let mut arguments = Vec::with_capacity(operands.len());
+            for operand in operands.iter() {
+                let thunk = Thunk::Delayed(operand.clone(), env.clone());
+                arguments.push(gc::track(Value::Thunk(RefCell::new(thunk))));
+            }
+            return self
+                .apply(procedure, arguments)
+                .map_err(|e| trace(e, &node));
+        }
+        let arguments = Vec::with_capacity(operands.len());
+        let operands = operands.clone();
+        self.operands(node, procedure, arguments, operands, env)
+    }
+
+    // Evaluates the operands from `arguments.len()` on, and then applies the
+    // procedure. Errors raised on the way are located at the application.
+    fn operands(
+        &mut self,
+        node: Rc<Node>,
+        procedure: Rc<Value>,
+        mut arguments: Vec<Rc<Value>>,
+        operands: Rc<[Rc<Node>]>,
+        env: Rc<Environment>,
+    ) -> Result<State, LispError> {
+        while let Some(operand) = operands.get(arguments.len()) {
+            let quick = self
+                .quick(operand, &env)
+                .map_err(|e| trace(trace_operand(e, operand, &node), &node))?;
+            if let Quick::Done(value) = quick {
+                arguments.push(value);
+                continue;
+            }
+            let operand = operand.clone();
+            self.stack.push(Frame::Operands {
+                node,
+                procedure,
+                arguments,
+                operands,
+                env: env.clone(),
+            });
+            return match quick {
+                Quick::Call(procedure) => self.call(operand, procedure, env),
+                _ => Ok(State::Eval(operand, env)),
+            };
+        }
+        self.apply(procedure, arguments)
+            .map_err(|e| trace(e, &node))
+    }
+
+    // Hands a value to the frame on top of the stack.
+    fn ret(&mut self, value: Rc<Value>) -> Result<State, LispError> {
+        let frame = self.stack.pop().expect("a frame to return to");
+        Ok(match frame {
+            Frame::Sequence { nodes, index, env } => self.sequence(nodes, index, env),
+            Frame::If {
+                consequent,
+                alternative,
+                env,
+            } => {
+                if let Value::Thunk(_) = &*value {
+                    self.stack.push(Frame::If {
+                        consequent,
+                        alternative,
+                        env,
+                    });
+                    return Ok(State::Force(value));
+                }
+                let node = if value.is_true() {
+                    consequent
+                } else {
+                    alternative
+                };
+                State::Eval(node, env)
+            }
+            Frame::Assign { node, env } => State::Return(match &*node {
+                Node::LocalAssignment {
+                    depth, index, name, ..
+                } => env.set_address(*depth, *index, name, value)?,
+                Node::GlobalAssignment { name, .. } => env.set_global(name, value)?,
+                Node::LocalDefinition { index, .. } => env.define_address(*index, value),
+                Node::GlobalDefinition { name, .. } => env.define_global(name, value),
+                _ => unreachable!("not an assignment"),
+            }),
+            Frame::Operator { node, env } => self.call(node, value, env)?,
+            Frame::Operands {
+                node,
+                procedure,
+                mut arguments,
+                operands,
+                env,
+            } => {
+                arguments.push(value);
+                self.operands(node, procedure, arguments, operands, env)?
+            }
+            Frame::Car { cdr, append, env } => {
+                self.stack.push(Frame::Cdr { car: value, append });
+                State::Eval(cdr, env)
+            }
+            Frame::Cdr { car, append: false } => State::Return(Value::cons(car, value)),
+            Frame::Cdr { car, append: true } => State::Return(
+                append(&car, value)
+                    .ok_or_else(|| LispError::invalid_arguments("unquote-splicing", &[car]))?,
+            ),
+            Frame::Vector => {
+                let elems = value
+                    .list_to_vec()
+                    .ok_or_else(|| LispError::invalid_arguments("quasiquote", &[value]))?;
+                State::Return(gc::track(Value::Vector(RefCell::new(elems))))
+            }
+            Frame::Guard { .. } | Frame::Handler(_) => State::Return(value),
+            Frame::Raise {
+                continuable: true, ..
+            } => State::Return(value),
+            // A handler may not return from raise; the error that says so
+            // is raised in the handler's dynamic environment.
+            frame @ Frame::Raise { .. } => {
+                let payload = match &frame {
+                    Frame::Raise { payload, .. } => payload.clone(),
+                    _ => unreachable!("not a raise"),
+                };
+                self.stack.push(frame);
+                return Err(LispError::User {
+                    message: Rc::new(Value::String(
+                        "Exception handler returned from raise".to_string(),
+                    )),
+                    irritants: vec![payload],
+                });
+            }
+            Frame::Wind { after, .. } => {
+                apply(after, vec![])?;
+                State::Return(value)
+            }
+            Frame::Force(promise) => match &*promise {
+                Value::Promise(thunk) => {
+                    let mut thunk = thunk.borrow_mut();
+                    // The promise may have been forced while it was being
+                    // forced; the first value wins.
+                    if let Thunk::Forced(value) = &*thunk {
+                        return Ok(State::Return(value.clone()));
+                    }
+                    *thunk = Thunk::Forced(value.clone());
+                    State::Return(value)
+                }
+                _ => unreachable!("not a promise"),
+            },
+            // A thunk's value may be another thunk, which is forced in turn.
+            Frame::Thunk(thunk) => {
+                if let Value::Thunk(_) = &*value {
+                    self.stack.push(Frame::Thunk(thunk));
+                    return Ok(State::Force(value));
+                }
+                if let Value::Thunk(thunk) = &*thunk {
+                    *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                }
+                State::Return(value)
+            }
+            Frame::Arguments {
+                procedure,
+                mut arguments,
+                index,
+            } => {
+                arguments[index] = value;
+                self.force_arguments(procedure, arguments, index + 1)
+            }
+            Frame::Apply(arguments) => State::Apply(value, arguments),
+            Frame::Trace(tracer, depth) => {
+                tracer.leave(depth, Some(&value));
+                State::Return(value)
+            }
+            Frame::Step { form, level, shown } => {
+                self.level = level;
+                if let (true, Some(stepper)) = (shown, &mut self.stepper) {
+                    stepper.returned(&form, &value, level);
+                }
+                State::Return(value)
+            }
+            Frame::CallWithValues(consumer) => match &*value {
+                Value::Values(values) => State::Apply(consumer, values.clone()),
+                _ => State::Apply(consumer, vec![value]),
+            },
+        })
+    }
+
+    fn apply(
+        &mut self,
+        procedure: Rc<Value>,
+        arguments: Vec<Rc<Value>>,
+    ) -> Result<State, LispError> {
+        let strict = matches!(
+            &*procedure,
+            Value::PrimitiveProcedure(_) | Value::CompiledProcedure { .. } | Value::Control(_)
+        );
+        if strict && arguments.iter().any(|a| matches!(&**a, Value::Thunk(_))) {
+            return Ok(self.force_arguments(procedure, arguments, 0));
+        }
+        Ok(match &*procedure {
+            Value::PrimitiveProcedure(f) => State::Return(f(&arguments)?),
+            Value::CompiledProcedure { entry, .. } => {
+                State::Return(apply_compiled(procedure.clone(), entry, arguments)?)
+            }
+            Value::Thunk(_) => {
+                self.stack.push(Frame::Apply(arguments));
+                State::Force(procedure)
+            }
+            Value::CompoundProcedure {
+                lambda,
+                environment,
+            } => {
+                if let Some(tracer) = environment.tracing(&procedure) {
+                    let depth = tracer.enter(lambda, &arguments);
+                    self.stack.push(Frame::Trace(tracer, depth));
+                }
+                let env = lambda.bind(arguments, environment)?;
+                gc::collect_if_needed();
+                State::Eval(lambda.body.clone(), env)
+            }
+            Value::Continuation(_) => return self.throw(procedure, values(arguments)),
+            Value::Control(control) => {
+                match (control, &arguments[..]) {
+                    (Control::CallWithCurrentContinuation, [receiver]) => {
+                        let continuation = Rc::new(Value::Continuation(Continuation {
+                            run: self.id,
+                            frames: self.stack.clone(),
+                        }));
+                        State::Apply(receiver.clone(), vec![continuation])
+                    }
+                    (Control::DynamicWind, [before, thunk, after]) => {
+                        apply(before.clone(), vec![])?;
+                        self.stack.push(Frame::Wind {
+                            id: next_id(),
+                            before: before.clone(),
+                            after: after.clone(),
+                        });
+                        State::Apply(thunk.clone(), vec![])
+                    }
+                    (Control::CallWithValues, [producer, consumer]) => {
+                        self.stack.push(Frame::CallWithValues(consumer.clone()));
+                        State::Apply(producer.clone(), vec![])
+                    }
+                    // (apply f a b '(c d)) is (f a b c d).
+                    (Control::Apply, [procedure, rest @ .., list]) => {
+                        let mut spread = rest.to_vec();
+                        match list.list_to_vec() {
+                            Some(list) => spread.extend(list),
+                            None => return Err(LispError::invalid_arguments("apply", &arguments)),
+                        }
+                        State::Apply(procedure.clone(), spread)
+                    }
+                    // Without a handler to return to, it is raise.
+                    (Control::RaiseContinuable, [payload]) => match self.handler() {
+                        Some((index, handler)) => {
+                            self.stack.push(Frame::Raise {
+                                handler: index,
+                                continuable: true,
+                                payload: payload.clone(),
+                            });
+                            State::Apply(handler, vec![payload.clone()])
+                        }
+                        None => return Err(LispError::Raise(payload.clone())),
+                    },
+                    (Control::Force, [promise]) => {
+                        let thunk = match &**promise {
+                            Value::Promise(thunk) => thunk,
+                            _ => return Ok(State::Return(promise.clone())),
+                        };
+                        let (node, env) = match &*thunk.borrow() {
+                            Thunk::Forced(value) => return Ok(State::Return(value.clone())),
+                            Thunk::Delayed(node, env) => (node.clone(), env.clone()),
+                        };
+                        self.stack.push(Frame::Force(promise.clone()));
+                        State::Eval(node, env)
+                    }
+                    _ => return Err(LispError::invalid_arguments(control.name(), &arguments)),
+                }
+            }
+            _ => return Err(LispError::InvalidProcedure(procedure.clone())),
+        })
+    }
+
+    // Forces a thunk of the lazy evaluator on the stack rather than in a
+    // run of its own, so that forcing a long chain of them, such as an
+    // accumulator built up by a loop, doesn't recurse in Rust.
+    fn force(&mut self, value: Rc<Value>) -> State {
+        if let Value::Thunk(thunk) = &*value {
+            match &*thunk.borrow() {
+                Thunk::Forced(value) => return State::Return(value.clone()),
+                Thunk::Delayed(node, env) => {
+                    self.stack.push(Frame::Thunk(value.clone()));
+                    return State::Eval(node.clone(), env.clone());
+                }
+            }
+        }
+        State::Return(value)
+    }
+
+    // Forces the thunks among the arguments of a primitive, from `index` on,
+    // and then applies it.
+    fn force_arguments(
+        &mut self,
+        procedure: Rc<Value>,
+        arguments: Vec<Rc<Value>>,
+        index: usize,
+    ) -> State {
+        let found = arguments[index..]
+            .iter()
+            .position(|a| matches!(&**a, Value::Thunk(_)));
+        match found {
+            Some(i) => {
+                let argument = arguments[index + i].clone();
+                self.stack.push(Frame::Arguments {
+                    procedure,
+                    arguments,
+                    index: index + i,
+                });
+                State::Force(argument)
+            }
+            None => State::Apply(procedure, arguments),
+        }
+    }
+
+    // Passes a value to a continuation. A continuation of a run that has
+    // finished, such as an earlier REPL expression's, takes over this run.
+    fn throw(&mut self, continuation: Rc<Value>, value: Rc<Value>) -> Result<State, LispError> {
+        let frames = match &*continuation {
+            Value::Continuation(k) if k.run == self.id || !is_running(k.run) => &k.frames,
+            _ => return Err(LispError::Escape(continuation, value)),
+        };
+        self.rewind(frames)?;
+        self.stack = frames.clone();
+        Ok(State::Return(value))
+    }
+
+    // Runs the after thunks of the dynamic-winds being left, innermost
+    // first, and then the before thunks of the ones being entered on the way
+    // to `frames`.
+    fn rewind(&mut self, frames: &[Frame]) -> Result<(), LispError> {
+        let winds = |frames: &[Frame]| -> Vec<(usize, usize)> {
+            let winds = frames
+                .iter()
+                .enumerate()
+                .filter_map(|(i, frame)| match frame {
+                    Frame::Wind { id, .. } => Some((i, *id)),
+                    _ => None,
+                });
+            winds.collect()
+        };
+        let (from, to) = (winds(&self.stack), winds(frames));
+        let common = from
+            .iter()
+            .zip(&to)
+            .take_while(|((_, a), (_, b))| a == b)
+            .count();
+        for &(i, _) in from[common..].iter().rev() {
+            if let Some(Frame::Wind { after, .. }) = self.stack.drain(i..).next() {
+                apply(after, vec![])?;
+            }
+        }
+        for &(i, _) in &to[common..] {
+            if let Frame::Wind { before, .. } = &frames[i] {
+                apply(before.clone(), vec![])?;
+            }
+        }
+        Ok(())
+    }
+
+    // The innermost handler or guard in force, as the index of its frame;
+    // the frames of handlers being run for a raise are skipped over.
+    fn catcher(&self) -> Option<usize> {
+        let mut i = self.stack.len();
+        while i > 0 {
+            i -= 1;
+            match &self.stack[i] {
+                Frame::Handler(_) | Frame::Guard { .. } => return Some(i),
+                Frame::Raise { handler, .. } => i = *handler,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The current handler and the index of its frame, unless a guard is
+    // nearer.
+    fn handler(&self) -> Option<(usize, Rc<Value>)> {
+        let i = self.catcher()?;
+        match &self.stack[i] {
+            Frame::Handler(handler) => Some((i, handler.clone())),
+            _ => None,
+        }
+    }
+
+    // Calls the current handler where the error was raised (R7RS 6.11), or
+    // else pops frames until a guard handles it, running the after thunks
+    // of the dynamic-winds it leaves and adding the applications it leaves
+    // to its backtrace. Escapes to another run's continuation, and amb
+    // failures, pass guards and handlers by.
+    fn unwind(&mut self, mut e: LispError) -> Result<State, LispError> {
+        let mut raised = true;
+        loop {
+            if let LispError::Escape(continuation, value) = &e {
+                if matches!(&**continuation, Value::Continuation(k) if k.run == self.id) {
+                    match self.throw(continuation.clone(), value.clone()) {
+                        Ok(state) => return Ok(state),
+                        Err(e2) => (e, raised) = (e2, true),
+                    }
+                    continue;
+                }
+            }
+            let catchable = e.is_catchable();
+            if raised && catchable {
+                if let Some((index, handler)) = self.handler() {
+                    let payload = e.payload();
+                    self.stack.push(Frame::Raise {
+                        handler: index,
+                        continuable: false,
+                        payload: payload.clone(),
+                    });
+                    return Ok(State::Apply(handler, vec![payload]));
+                }
+            }
+            raised = false;
+            match self.stack.pop() {
+                None => return Err(e),
+                Some(Frame::Wind { after, .. }) => {
+                    if let Err(e2) = apply(after, vec![]) {
+                        (e, raised) = (e2, true);
+                    }
+                }
+                Some(Frame::Guard { node, env }) if catchable => match guard(&node, &env, e) {
+                    Ok(state) => return Ok(state),
+                    Err(e2) => (e, raised) = (e2, true),
+                },
+                Some(Frame::Operator { node, .. }) | Some(Frame::Operands { node, .. }) => {
+                    e = trace(e, &node)
+                }
+                Some(Frame::Trace(tracer, depth)) => tracer.leave(depth, None),
+                Some(Frame::Step { level, .. }) => self.level = level,
+                Some(_) => {}
+            }
+        }
+    }
 }
 
-fn eval_sequence(exp: &Sexp, env: &Rc<Environment>) -> Rc<Value> {
-    let mut result = Rc::new(Value::Nil);
-    let mut p = exp;
-    while let Pair(car, cdr) = p {
-        result = eval(car, env);
-        p = cdr;
+// Picks the guard clause for an error; it is re-raised when no clause
+// matches.
+fn guard(node: &Node, env: &Rc<Environment>, e: LispError) -> Result<State, LispError> {
+    let (scope, clauses) = match node {
+        Node::Guard { scope, clauses, .. } => (scope, clauses),
+        _ => unreachable!("not a guard"),
+    };
+    let env = Environment::extend(scope, vec![e.payload()], env);
+    for clause in clauses {
+        match clause {
+            GuardClause::Else(expressions) => return Ok(State::Eval(expressions.clone(), env)),
+            GuardClause::Test(test, expressions) => {
+                let result = execute(test, &env)?;
+                if result.is_true() {
+                    return Ok(match expressions {
+                        None => State::Return(result),
+                        Some(expressions) => State::Eval(expressions.clone(), env),
+                    });
+                }
+            }
+        }
     }
-    result
+    Err(e)
+}
+
+impl Control {
+    fn name(&self) -> &'static str {
+        match self {
+            Control::CallWithCurrentContinuation => "call-with-current-continuation",
+            Control::DynamicWind => "dynamic-wind",
+            Control::CallWithValues => "call-with-values",
+            Control::Force => "force",
+            Control::Apply => "apply",
+            Control::RaiseContinuable => "raise-continuable",
+        }
+    }
+}
+
+// The value of a thunk, or the value itself if it is not one.
+pub fn force(value: Rc<Value>) -> Result<Rc<Value>, LispError> {
+    match &*value {
+        Value::Thunk(_) => run(State::Force(value)),
+        _ => Ok(value),
+    }
+}
+
+// Reads every expression in a file and evaluates them in order.
+pub fn load(path: &str, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+    let source = fs::read_to_string(path).map_err(|e| LispError::FileNotReadable {
+        path: path.to_string(),
+        message: e.to_string(),
+    })?;
+    let exps = parser::read(&source, path).map_err(|e| LispError::Parse(e.to_string()))?;
+    for exp in &exps {
+        eval(exp, env)?;
+    }
+    Ok(Value::ok())
 }
 
-fn list_of_values(exps: &Sexp, env: &Rc<Environment>) -> Vec<Rc<Value>> {
-    let mut values = vec![];
-    let mut p = exps;
-    while let Pair(car, cdr) = p {
-        values.push(eval(car, env));
+// Calls a macro transformer on the unevaluated operands and turns the
+// resulting value back into an expression.
+pub fn expand(transformer: &Rc<Value>, operands: &Sexp) -> Result<Sexp, LispError> {
+    let mut arguments = vec![];
+    let mut p = operands;
+    while let Pair(car, cdr, _) = p {
+        arguments.push(Value::from_sexp(car));
         p = cdr;
     }
-    values
+    value_to_sexp(apply(transformer.clone(), arguments)?)
 }
 
-fn apply(procedure: Rc<Value>, arguments: &[Rc<Value>]) -> Rc<Value> {
-    match &*procedure {
-        Value::PrimitiveProcedure(f) => f(arguments),
-        Value::CompoundProcedure {
-            parameters,
-            body,
-            environment,
-        } => {
-            let env = Environment::extend(&parameters, arguments, &environment);
-            let mut result = Rc::new(Value::Nil);
-            for b in body {
-                result = eval(b, &env);
+// Expands `exp` once if it is a macro call, and returns None otherwise.
+fn macroexpand_1(exp: &Sexp, env: &Rc<Environment>) -> Result<Option<Sexp>, LispError> {
+    if let Pair(operator, operands, _) = exp {
+        let name = match &**operator {
+            Symbol(name) => name,
+            _ => return Ok(None),
+        };
+        if let Ok(value) = env.lookup(name) {
+            match &*value {
+                Value::Macro(transformer) => return Ok(Some(expand(transformer, operands)?)),
+                Value::Syntax(rules) => return Ok(Some(rules.expand(exp, &None)?)),
+                _ => {}
             }
-            result
         }
-        _ => panic!("Invalid procedure: {:?}", procedure),
     }
+    Ok(None)
+}
+
+fn value_to_sexp(value: Rc<Value>) -> Result<Sexp, LispError> {
+    value
+        .to_sexp()
+        .ok_or_else(|| LispError::InvalidExpansion(value.clone()))
+}
+
+// Copies the proper list `list` in front of `tail`.
+fn append(list: &Rc<Value>, tail: Rc<Value>) -> Option<Rc<Value>> {
+    let elems = list.list_to_vec()?;
+    Some(
+        elems
+            .into_iter()
+            .rev()
+            .fold(tail, |cdr, car| Value::cons(car, cdr)),
+    )
 }
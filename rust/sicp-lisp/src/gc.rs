@@ -0,0 +1,231 @@
+// A cycle collector for the Rc graph. Closures point at their environment and
+// environments point back at the closures defined in them, so plain reference
+// counting never frees a recursive procedure. Every environment and every
+// value that can hold references (pairs, vectors, hash tables, procedures,
+// macros and thunks) is registered here, and `collect` finds the ones kept alive only by
+// each other using trial deletion:
+//
+// 1. count, for each registered object, the references coming from other
+//    registered objects;
+// 2. objects with more strong references than that are referenced from
+//    outside (the Rust stack, the REPL, ...) and are roots;
+// 3. everything not reachable from a root is garbage, and clearing its
+//    mutable contents breaks the cycles so that Rc frees it.
+
+use super::environment::*;
+use super::value::*;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+// Collections run automatically once this many objects are registered, or
+// twice as many as survived the previous collection.
+const MIN_THRESHOLD: usize = 10000;
+
+enum Node {
+    Environment(Weak<Environment>),
+    Value(Weak<Value>),
+}
+
+enum Object {
+    Environment(Rc<Environment>),
+    Value(Rc<Value>),
+}
+
+struct Heap {
+    nodes: Vec<Node>,
+    threshold: usize,
+    collections: usize,
+    reclaimed: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            nodes: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            collections: 0,
+            reclaimed: 0,
+        })
+    };
+}
+
+pub struct Stats {
+    pub environments: usize,
+    pub objects: usize,
+    pub collections: usize,
+    pub reclaimed: usize,
+}
+
+pub fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+pub fn track_environment(env: Environment) -> Rc<Environment> {
+    let env = Rc::new(env);
+    HEAP.with(|heap| {
+        let node = Node::Environment(Rc::downgrade(&env));
+        heap.borrow_mut().nodes.push(node);
+    });
+    env
+}
+
+pub fn track(value: Value) -> Rc<Value> {
+    let value = Rc::new(value);
+    match &*value {
+        Value::Pair(..)
+        | Value::Vector(_)
+        | Value::HashTable(_)
+        | Value::CompoundProcedure { .. }
+        | Value::CompiledProcedure { .. }
+        | Value::Thunk(_)
+        | Value::Promise(_)
+        | Value::Macro(_) => HEAP.with(|heap| {
+            let node = Node::Value(Rc::downgrade(&value));
+            heap.borrow_mut().nodes.push(node);
+        }),
+        _ => {}
+    }
+    value
+}
+
+// Called at safe points, where every live object is held by a strong reference.
+pub fn collect_if_needed() {
+    let needed = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.nodes.len() >= heap.threshold
+    });
+    if needed {
+        collect();
+    }
+}
+
+impl Object {
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Environment(env) => Rc::strong_count(env),
+            Object::Value(value) => Rc::strong_count(value),
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Object::Environment(env) => address(env),
+            Object::Value(value) => address(value),
+        }
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Object::Environment(env) => env.trace(visit),
+            Object::Value(value) => value.trace(visit),
+        }
+    }
+
+    fn clear(&self) {
+        match self {
+            Object::Environment(env) => env.clear(),
+            Object::Value(value) => value.clear(),
+        }
+    }
+
+    fn downgrade(&self) -> Node {
+        match self {
+            Object::Environment(env) => Node::Environment(Rc::downgrade(env)),
+            Object::Value(value) => Node::Value(Rc::downgrade(value)),
+        }
+    }
+}
+
+// Frees unreachable cycles and returns the number of objects reclaimed.
+pub fn collect() -> usize {
+    let objects: Vec<Object> = HEAP.with(|heap| {
+        let nodes = std::mem::take(&mut heap.borrow_mut().nodes);
+        nodes
+            .into_iter()
+            .filter_map(|node| match node {
+                Node::Environment(env) => env.upgrade().map(Object::Environment),
+                Node::Value(value) => value.upgrade().map(Object::Value),
+            })
+            .collect()
+    });
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (object.address(), i))
+        .collect();
+
+    let mut internal = vec![0; objects.len()];
+    let mut children = vec![vec![]; objects.len()];
+    let mut roots = vec![];
+    for (i, object) in objects.iter().enumerate() {
+        let traced = object.trace(&mut |address| {
+            if let Some(&j) = index.get(&address) {
+                internal[j] += 1;
+                children[i].push(j);
+            }
+        });
+        // An object whose contents are borrowed right now is in use.
+        if !traced {
+            roots.push(i);
+        }
+    }
+    for (i, object) in objects.iter().enumerate() {
+        // `objects` itself holds one of the strong references.
+        if object.strong_count() - 1 > internal[i] {
+            roots.push(i);
+        }
+    }
+
+    let mut reachable = vec![false; objects.len()];
+    while let Some(i) = roots.pop() {
+        if !reachable[i] {
+            reachable[i] = true;
+            roots.extend(&children[i]);
+        }
+    }
+
+    let mut reclaimed = 0;
+    let mut survivors = vec![];
+    for (object, reachable) in objects.iter().zip(reachable) {
+        if reachable {
+            survivors.push(object.downgrade());
+        } else {
+            object.clear();
+            reclaimed += 1;
+        }
+    }
+    drop(objects);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        // Objects allocated while the garbage was being dropped are kept too.
+        survivors.append(&mut heap.nodes);
+        heap.threshold = MIN_THRESHOLD.max(survivors.len() * 2);
+        heap.nodes = survivors;
+        heap.collections += 1;
+        heap.reclaimed += reclaimed;
+    });
+    reclaimed
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let mut stats = Stats {
+            environments: 0,
+            objects: 0,
+            collections: heap.collections,
+            reclaimed: heap.reclaimed,
+        };
+        for node in &heap.nodes {
+            match node {
+                Node::Environment(env) if env.strong_count() > 0 => stats.environments += 1,
+                Node::Value(value) if value.strong_count() > 0 => stats.objects += 1,
+                _ => {}
+            }
+        }
+        stats
+    })
+}
@@ -0,0 +1,292 @@
+// The API for Rust programs that embed the interpreter: evaluating source
+// text, defining variables and primitive procedures written as ordinary Rust
+// closures, and converting between `Value`s and Rust types.
+
+use super::amb;
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::number::*;
+use super::sexp::*;
+use super::value::*;
+
+use std::rc::Rc;
+
+// An interpreter with a global environment of its own. Definitions made in
+// one interpreter are never visible in another, and each traces its own
+// procedures; they share only the thread's cycle collector.
+pub struct Interpreter {
+    env: Rc<Environment>,
+}
+
+// How an interpreter evaluates: in normal order (SICP 4.2), with compound
+// procedures receiving their arguments as thunks, and with amb (SICP 4.3),
+// where each expression gives its first value. Interpreters in different
+// modes can be used side by side.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub lazy: bool,
+    pub amb: bool,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Self {
+        let env = Environment::new();
+        env.set_lazy(options.lazy);
+        if options.amb {
+            amb::install(&env).expect("require is defined");
+        }
+        Interpreter { env }
+    }
+
+    // The global environment, for the drivers of the REPL.
+    pub fn environment(&self) -> &Rc<Environment> {
+        &self.env
+    }
+
+    // Evaluates the expressions in `source` in order and returns the value
+    // of the last one.
+    pub fn eval_str(&self, source: &str) -> Result<Rc<Value>, LispError> {
+        let exps = parser::expressions(source).map_err(|e| LispError::Parse(e.to_string()))?;
+        let mut value = Rc::new(Value::Nil);
+        for exp in &exps {
+            value = if self.env.is_amb() {
+                first_value(exp, &self.env)?
+            } else {
+                eval(exp, &self.env).and_then(force)?
+            };
+        }
+        Ok(value)
+    }
+
+    pub fn define<T: IntoValue>(&self, name: &str, value: T) {
+        self.env.define(name, value.into_value());
+    }
+
+    // Defines `name` as a primitive procedure calling `f`, a closure taking
+    // up to four arguments of types that convert from `Value`, or the raw
+    // argument slice. Arguments that don't convert are reported as invalid.
+    pub fn define_primitive_procedure<Args, F: IntoPrimitive<Args>>(&self, name: &str, f: F) {
+        let primitive = Value::PrimitiveProcedure(f.into_primitive(name));
+        self.env.define(name, Rc::new(primitive));
+    }
+}
+
+// The first value of `exp` in the amb evaluator.
+fn first_value(exp: &Sexp, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+    let message = match amb::Driver::default().eval(exp, env)? {
+        amb::Outcome::Value(value) => return force(value),
+        amb::Outcome::NoMoreValues(_) => "There are no more values of",
+        amb::Outcome::NoCurrentProblem => "There is no current problem",
+    };
+    Err(LispError::User {
+        message: Rc::new(Value::String(message.to_string())),
+        irritants: vec![Value::from_sexp(exp)],
+    })
+}
+
+pub trait IntoValue {
+    fn into_value(self) -> Rc<Value>;
+}
+
+pub trait FromValue: Sized {
+    fn from_value(value: &Rc<Value>) -> Option<Self>;
+}
+
+impl IntoValue for Rc<Value> {
+    fn into_value(self) -> Rc<Value> {
+        self
+    }
+}
+
+impl FromValue for Rc<Value> {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Rc<Value> {
+        Value::ok()
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Rc<Value> {
+        Value::boolean(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        match **value {
+            Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Rc<Value> {
+        Rc::new(Value::Number(Number::Integer(self)))
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        match **value {
+            Value::Number(Number::Integer(n)) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+// Any real number converts to f64, exact ones approximately.
+impl IntoValue for f64 {
+    fn into_value(self) -> Rc<Value> {
+        Rc::new(Value::Number(Number::Real(self)))
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        match &**value {
+            Value::Number(n) => Some(n.to_f64()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for char {
+    fn into_value(self) -> Rc<Value> {
+        Rc::new(Value::Char(self))
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        match **value {
+            Value::Char(c) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Rc<Value> {
+        Rc::new(Value::String(self.to_string()))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Rc<Value> {
+        Rc::new(Value::String(self))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        match &**value {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Vectors convert to and from proper lists.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Rc<Value> {
+        Value::list(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Rc<Value>) -> Option<Self> {
+        value.list_to_vec()?.iter().map(T::from_value).collect()
+    }
+}
+
+// What a primitive's closure may return: a value, or a result whose error
+// is raised in Lisp.
+pub trait IntoResult {
+    fn into_result(self) -> Result<Rc<Value>, LispError>;
+}
+
+impl<T: IntoValue> IntoResult for T {
+    fn into_result(self) -> Result<Rc<Value>, LispError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoResult for Result<T, LispError> {
+    fn into_result(self) -> Result<Rc<Value>, LispError> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+// `Args` tells apart the closure signatures, so that each arity gets its own
+// implementation.
+pub trait IntoPrimitive<Args> {
+    fn into_primitive(self, name: &str) -> Box<Primitive>;
+}
+
+// The marker for closures taking the argument slice as it is.
+pub struct Variadic;
+
+impl<F, R> IntoPrimitive<Variadic> for F
+where
+    F: Fn(&[Rc<Value>]) -> R + 'static,
+    R: IntoResult,
+{
+    fn into_primitive(self, _name: &str) -> Box<Primitive> {
+        Box::new(move |args| self(args).into_result())
+    }
+}
+
+macro_rules! impl_into_primitive {
+    ($n:expr; $($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoPrimitive<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoResult,
+            $($arg: FromValue,)*
+        {
+            #[allow(non_snake_case)]
+            fn into_primitive(self, name: &str) -> Box<Primitive> {
+                let name = name.to_string();
+                Box::new(move |args| match args {
+                    [$($arg),*] => {
+                        $(
+                            let $arg = match $arg::from_value($arg) {
+                                Some(value) => value,
+                                None => return Err(LispError::invalid_arguments(&name, args)),
+                            };
+                        )*
+                        self($($arg),*).into_result()
+                    }
+                    _ => Err(LispError::WrongNumberOfArguments {
+                        procedure: name.clone(),
+                        expected: $n.to_string(),
+                        given: args.len(),
+                    }),
+                })
+            }
+        }
+    };
+}
+
+impl_into_primitive!(0;);
+impl_into_primitive!(1; A);
+impl_into_primitive!(2; A, B);
+impl_into_primitive!(3; A, B, C);
+impl_into_primitive!(4; A, B, C, D);
@@ -0,0 +1,2872 @@
+// A Scheme for working through SICP. The `lisp` binary is its REPL; Rust
+// programs embed it through `Interpreter`.
+
+pub mod amb;
+mod analyze;
+mod compiler;
+pub mod debug;
+mod derived;
+pub mod diagram;
+pub mod environment;
+pub mod error;
+pub mod eval;
+mod gc;
+mod interpreter;
+mod machine;
+pub mod number;
+pub mod query;
+pub mod sexp;
+mod syntax_rules;
+pub mod value;
+
+pub use interpreter::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use environment::*;
+    use error::*;
+    use eval::*;
+    use sexp::*;
+    use std::fs;
+    use std::rc::Rc;
+    use value::*;
+
+    fn eval_str(s: &str, env: &Rc<Environment>) -> Result<Rc<Value>, LispError> {
+        eval(&parser::expression(s).unwrap(), env)
+    }
+
+    #[test]
+    fn test_square() {
+        let s1 = "(define square (lambda (x) (* x x)))";
+        let s2 = "(square 10)";
+        let e1 = parser::expression(s1).unwrap();
+        let e2 = parser::expression(s2).unwrap();
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval(&e1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e2, &env).unwrap()), "100");
+    }
+
+    #[test]
+    fn test_factorial() {
+        let s1 = "(define factorial (lambda (n) (if (= n 0) 1 (* n (factorial (- n 1))))))";
+        let s2 = "(factorial 5)";
+        let e1 = parser::expression(s1).unwrap();
+        let e2 = parser::expression(s2).unwrap();
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval(&e1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e2, &env).unwrap()), "120");
+    }
+
+    #[test]
+    fn test_tarai() {
+        let s1 = "(define tarai (lambda (x y z) (if (<= x y) y (tarai (tarai (- x 1) y z) (tarai (- y 1) z x) (tarai (- z 1) x y)))))";
+        let s2 = "(tarai 6 3 0)";
+
+        let e1 = parser::expression(s1).unwrap();
+        let e2 = parser::expression(s2).unwrap();
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval(&e1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e2, &env).unwrap()), "6");
+    }
+
+    #[test]
+    fn test_counter() {
+        let s1 = "(define make-counter (lambda () (begin (define count 0) (lambda () (begin (set! count (+ count 1)) count)))))";
+        let s2 = "(define c1 (make-counter))";
+        let s3 = "(define c2 (make-counter))";
+        let s4 = "(c1)";
+        let s5 = "(c1)";
+        let s6 = "(c2)";
+
+        let e1 = parser::expression(s1).unwrap();
+        let e2 = parser::expression(s2).unwrap();
+        let e3 = parser::expression(s3).unwrap();
+        let e4 = parser::expression(s4).unwrap();
+        let e5 = parser::expression(s5).unwrap();
+        let e6 = parser::expression(s6).unwrap();
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval(&e1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e2, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e3, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e4, &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval(&e5, &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval(&e6, &env).unwrap()), "1");
+    }
+
+    #[test]
+    fn test_list() {
+        let s1 = "(define s1 (cons 1 (cons 2 (cons 3 (cons 4 nil)))))";
+        let s2 = "(car s1)";
+        let s3 = "(cdr s1)";
+        let s4 = "(define sum (lambda (s) (if (null? s) 0 (+ (car s) (sum (cdr s))))))";
+        let s5 = "(sum s1)";
+
+        let e1 = parser::expression(s1).unwrap();
+        let e2 = parser::expression(s2).unwrap();
+        let e3 = parser::expression(s3).unwrap();
+        let e4 = parser::expression(s4).unwrap();
+        let e5 = parser::expression(s5).unwrap();
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval(&e1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e2, &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval(&e3, &env).unwrap()), "(2 3 4)");
+        assert_eq!(format!("{:?}", eval(&e4, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval(&e5, &env).unwrap()), "10");
+    }
+
+    #[test]
+    fn test_unbound_variable() {
+        let env = Environment::new();
+        let e = eval_str("(+ x 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Unbound variable: x");
+        let e = eval_str("(set! x 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Unbound variable: x");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let env = Environment::new();
+        let e = eval_str("(car 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid arguments for car: [1]");
+        let e = eval_str("(car)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid arguments for car: []");
+        let e = eval_str("(/ 1 0)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Division by zero");
+        let e = eval_str("((lambda (x) x))", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Wrong number of arguments for anonymous procedure: expected 1, given 0"
+        );
+        let e = eval_str("((lambda (x) x) 1 2)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Wrong number of arguments for anonymous procedure: expected 1, given 2"
+        );
+        let e = eval_str("(define (dup a a) a)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Ill-formed special form: (a a)");
+        let e = eval_str("(lambda (a #!optional (b 1) . a) a)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Ill-formed special form: (a #!optional (b 1) . a)"
+        );
+        let e = eval_str("(1 2)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid procedure: 1");
+    }
+
+    #[test]
+    fn test_error_keeps_environment() {
+        let env = Environment::new();
+        assert_eq!(
+            format!("{:?}", eval_str("(define x 1)", &env).unwrap()),
+            "ok"
+        );
+        assert!(eval_str("(x 1)", &env).is_err());
+        assert_eq!(format!("{:?}", eval_str("x", &env).unwrap()), "1");
+    }
+
+    #[test]
+    fn test_error_and_raise() {
+        let env = Environment::new();
+        let e = eval_str("(error 1 2 3)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "1 2 3");
+        let e = eval_str("(raise 42)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Uncaught exception: 42");
+    }
+
+    #[test]
+    fn test_with_exception_handler() {
+        let s1 =
+            "(with-exception-handler (lambda (e) 42) (lambda () (+ (raise-continuable 'oops) 1)))";
+        let s2 = "(call/cc (lambda (k) (with-exception-handler (lambda (e) (k (error-object? e))) (lambda () x))))";
+        let s3 = "(with-exception-handler (lambda (e) 0) (lambda () 42))";
+        let s4 = "(with-exception-handler (lambda (e) (raise e)) (lambda () (raise 1)))";
+        let s5 = "(with-exception-handler (lambda (e) (+ e 1)) (lambda () (raise 41)))";
+        let s6 = "(with-exception-handler (lambda (e) (* e 10))
+                    (lambda ()
+                      (with-exception-handler (lambda (e) (+ (raise-continuable (+ e 1)) 1))
+                        (lambda () (raise-continuable 1)))))";
+        let s7 = "(begin
+                  (define log '())
+                  (call/cc
+                    (lambda (k)
+                      (with-exception-handler
+                        (lambda (e) (set! log (cons 'handler log)) (k e))
+                        (lambda ()
+                          (dynamic-wind
+                            (lambda () #f)
+                            (lambda () (raise 'oops))
+                            (lambda () (set! log (cons 'after log))))))))
+                  (reverse log))";
+        let s8 = "(guard (e (#t (error-object-irritants e)))
+                    (with-exception-handler (lambda (e) 0) (lambda () (raise 'oops))))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "43");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "#t");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "42");
+        assert!(eval_str(s4, &env).is_err());
+        assert_eq!(
+            eval_str(s5, &env).unwrap_err().to_string(),
+            "Exception handler returned from raise 41"
+        );
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "21");
+        assert_eq!(
+            format!("{:?}", eval_str(s7, &env).unwrap()),
+            "(handler after)"
+        );
+        assert_eq!(format!("{:?}", eval_str(s8, &env).unwrap()), "(oops)");
+    }
+
+    #[test]
+    fn test_guard() {
+        let s1 = "(guard (e ((= e 42) (* e 2))) (raise 42))";
+        let s2 = "(guard (e ((error-object? e) (error-object-irritants e))) (error 0 1 2))";
+        let s3 = "(guard (e ((error-object? e) (error-object-message e))) (error 0 1 2))";
+        let s4 = "(guard (e ((= e 0) 0) (else 1)) (raise 42))";
+        let s5 = "(guard (e ((= e 0) 0)) (raise 42))";
+        let s6 = "(guard (e (else 0)) 1 2 3)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "84");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "(1 2)");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "0");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "1");
+        let e = eval_str(s5, &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Uncaught exception: 42");
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "3");
+    }
+
+    #[test]
+    fn test_call_cc() {
+        let env = Environment::new();
+        let eval_to_string = |s: &str| format!("{:?}", eval_str(s, &env).unwrap());
+
+        assert_eq!(
+            eval_to_string("(+ 1 (call/cc (lambda (k) (+ 10 (k 1)))))"),
+            "2"
+        );
+        assert_eq!(
+            eval_to_string("(call-with-current-continuation (lambda (k) 42))"),
+            "42"
+        );
+        eval_to_string(
+            "(define (find-first pred items)
+               (call/cc
+                 (lambda (return)
+                   (define (loop items)
+                     (if (null? items)
+                         #f
+                         (begin
+                           (if (pred (car items)) (return (car items)) 'no)
+                           (loop (cdr items)))))
+                   (loop items))))",
+        );
+        assert_eq!(
+            eval_to_string("(find-first (lambda (x) (> x 2)) '(1 2 3 4))"),
+            "3"
+        );
+
+        // A continuation can be re-entered, also after the REPL expression
+        // that captured it has finished.
+        eval_to_string("(define r #f)");
+        assert_eq!(
+            eval_to_string("(+ 1 (call/cc (lambda (k) (set! r k) 1)))"),
+            "2"
+        );
+        assert_eq!(eval_to_string("(r 10)"), "11");
+        assert_eq!(eval_to_string("(r 20)"), "21");
+
+        eval_to_string(
+            "(define (count-to limit)
+               (let ((i 0) (k #f) (acc '()))
+                 (call/cc (lambda (c) (set! k c)))
+                 (set! acc (cons i acc))
+                 (set! i (+ i 1))
+                 (if (< i limit) (k 'again) acc)))",
+        );
+        assert_eq!(eval_to_string("(count-to 5)"), "(4 3 2 1 0)");
+
+        // A generator that resumes its walk where the last call left it.
+        eval_to_string(
+            "(define (make-generator items)
+               (define return #f)
+               (define resume #f)
+               (define (walk items)
+                 (if (null? items)
+                     (return 'done)
+                     (begin
+                       (call/cc (lambda (k) (set! resume k) (return (car items))))
+                       (walk (cdr items)))))
+               (lambda ()
+                 (call/cc
+                   (lambda (r)
+                     (set! return r)
+                     (if resume (resume 'go) (walk items))))))",
+        );
+        eval_to_string("(define g (make-generator '(1 2 3)))");
+        assert_eq!(eval_to_string("`(,(g) ,(g) ,(g) ,(g))"), "(1 2 3 done)");
+
+        // Escapes from Rust code that calls back into Lisp.
+        assert_eq!(
+            eval_to_string("(call/cc (lambda (k) (guard (e ((k 'escaped) #f)) (raise 'oops))))"),
+            "escaped"
+        );
+        eval_to_string("(compile-and-go '(define (call-with-42 k) (k 42)))");
+        assert_eq!(eval_to_string("(+ 1 (call/cc call-with-42))"), "43");
+
+        // Deep recursion no longer uses the Rust stack.
+        eval_to_string("(define (sum n) (if (= n 0) 0 (+ n (sum (- n 1)))))");
+        assert_eq!(eval_to_string("(sum 100000)"), "5000050000");
+
+        assert_eq!(
+            eval_str("(call/cc 1 2)", &env).unwrap_err().to_string(),
+            "Invalid arguments for call-with-current-continuation: [1, 2]"
+        );
+    }
+
+    #[test]
+    fn test_dynamic_wind() {
+        let env = Environment::new();
+        let eval_to_string = |s: &str| format!("{:?}", eval_str(s, &env).unwrap());
+
+        eval_to_string("(define trail '())");
+        eval_to_string("(define (note x) (set! trail (cons x trail)))");
+        eval_to_string("(define (in) (note 'in))");
+        eval_to_string("(define (out) (note 'out))");
+        assert_eq!(
+            eval_to_string("(dynamic-wind in (lambda () (note 'body) 'result) out)"),
+            "result"
+        );
+        assert_eq!(eval_to_string("trail"), "(out body in)");
+
+        eval_to_string("(set! trail '())");
+        assert_eq!(
+            eval_to_string(
+                "(call/cc (lambda (k) (dynamic-wind in (lambda () (k 'escaped) (note 'body)) out)))"
+            ),
+            "escaped"
+        );
+        assert_eq!(eval_to_string("trail"), "(out in)");
+
+        // Re-entering runs the before thunk again.
+        eval_to_string("(set! trail '())");
+        eval_to_string("(define k #f)");
+        eval_to_string(
+            "(dynamic-wind in (lambda () (call/cc (lambda (c) (set! k c))) (note 'body)) out)",
+        );
+        eval_to_string("(k 'again)");
+        assert_eq!(eval_to_string("trail"), "(out body in out body in)");
+
+        // So does leaving because of an error.
+        eval_to_string("(set! trail '())");
+        assert_eq!(
+            eval_to_string("(guard (e (#t e)) (dynamic-wind in (lambda () (raise 'oops)) out))"),
+            "oops"
+        );
+        assert_eq!(eval_to_string("trail"), "(out in)");
+    }
+
+    #[test]
+    fn test_values() {
+        let env = Environment::new();
+        let eval_to_string = |s: &str| format!("{:?}", eval_str(s, &env).unwrap());
+
+        assert_eq!(
+            eval_to_string("(call-with-values (lambda () (values 1 2)) +)"),
+            "3"
+        );
+        assert_eq!(
+            eval_to_string("(call-with-values (lambda () 5) (lambda (x) (* x x)))"),
+            "25"
+        );
+        assert_eq!(
+            eval_to_string("(call-with-values (lambda () (values)) (lambda () 'none))"),
+            "none"
+        );
+        assert_eq!(
+            eval_to_string(
+                "(call-with-values (lambda () (call/cc (lambda (k) (k 1 2)))) (lambda (a b) (- a b)))"
+            ),
+            "-1"
+        );
+        assert_eq!(eval_to_string("(values 1 \"two\")"), "1 \"two\"");
+        assert_eq!(eval_to_string("(values 1)"), "1");
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let s1 = "(define loop (lambda (n) (if (= n 0) 0 (loop (- n 1)))))";
+        let s2 = "(loop 1000000)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "0");
+    }
+
+    #[test]
+    fn test_mutual_tail_call() {
+        let s1 = "(define even? (lambda (n) (if (= n 0) 1 (odd? (- n 1)))))";
+        let s2 = "(define odd? (lambda (n) (if (= n 0) 0 (even? (- n 1)))))";
+        let s3 = "(define count (lambda (n acc) (begin (set! acc (+ acc 1)) (if (= n 0) acc (count (- n 1) acc)))))";
+        let s4 = "(even? 100001)";
+        let s5 = "(count 100000 0)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "0");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "100001");
+    }
+
+    #[test]
+    fn test_define_procedure() {
+        let s1 = "(define (square x) (* x x))";
+        let s2 = "(square 10)";
+        let s3 = "(define (f) (define x 1) (+ x 1))";
+        let s4 = "(f)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "100");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "2");
+    }
+
+    #[test]
+    fn test_cond() {
+        let s1 = "(define (sign x) (cond ((< x 0) (- 0 1)) ((= x 0) 0) (else 1)))";
+        let s2 = "(define (first-pair x) (cond ((null? x) 0) (x => car)))";
+        let s3 = "(define (loop n) (cond ((= n 0) 0) (else (loop (- n 1)))))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(sign (- 0 5))", &env).unwrap()),
+            "-1"
+        );
+        assert_eq!(format!("{:?}", eval_str("(sign 0)", &env).unwrap()), "0");
+        assert_eq!(format!("{:?}", eval_str("(sign 5)", &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(first-pair nil)", &env).unwrap()),
+            "0"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(first-pair (cons 3 4))", &env).unwrap()),
+            "3"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(cond (false 1))", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(cond (false) (2))", &env).unwrap()),
+            "2"
+        );
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(loop 100000)", &env).unwrap()),
+            "0"
+        );
+    }
+
+    #[test]
+    fn test_let() {
+        let s1 = "(let ((x 1) (y 2)) (+ x y))";
+        let s2 = "(let ((x 1)) (let ((x 2) (y x)) y))";
+        let s3 = "(let loop ((i 0) (acc 0)) (if (> i 10) acc (loop (+ i 1) (+ acc i))))";
+        let s4 = "(let loop ((n 100000)) (if (= n 0) 0 (loop (- n 1))))";
+        let s5 = "(let () 42)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "55");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "0");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "42");
+    }
+
+    #[test]
+    fn test_let_star() {
+        let s1 = "(let* ((x 1) (y (+ x 1)) (z (* y 2))) (+ x y z))";
+        let s2 = "(let* () 42)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "7");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "42");
+    }
+
+    #[test]
+    fn test_letrec() {
+        let s1 = "(letrec ((even? (lambda (n) (if (= n 0) true (odd? (- n 1))))) (odd? (lambda (n) (if (= n 0) false (even? (- n 1)))))) (even? 100))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "#t");
+        assert!(eval_str("even?", &env).is_err());
+    }
+
+    #[test]
+    fn test_and_or() {
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str("(and)", &env).unwrap()), "#t");
+        assert_eq!(format!("{:?}", eval_str("(and 1 2 3)", &env).unwrap()), "3");
+        assert_eq!(
+            format!("{:?}", eval_str("(and 1 false x)", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(format!("{:?}", eval_str("(or)", &env).unwrap()), "#f");
+        assert_eq!(
+            format!("{:?}", eval_str("(or false 2 x)", &env).unwrap()),
+            "2"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(or false false)", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(let ((value 1)) (or false value))", &env).unwrap()
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_one_armed_if() {
+        let env = Environment::new();
+        eval_str("(define (f x) (if x 1))", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(f #t)", &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval_str("(f #f)", &env).unwrap()), "#f");
+        eval_str("(compile-and-go '(define (g x) (if x 2)))", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(g #t)", &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str("(g #f)", &env).unwrap()), "#f");
+        let e = eval_str("(if 1 2 3 4)", &env).unwrap_err();
+        assert_eq!(e.to_string(), "Ill-formed special form: (if 1 2 3 4)");
+    }
+
+    #[test]
+    fn test_when_unless() {
+        let s1 = "(define x 0)";
+        let s2 = "(when (= x 0) (set! x 1) (+ x 1))";
+        let s3 = "(unless (= x 0) (set! x 2) (+ x 1))";
+        let s4 = "(when (= x 0) (set! x 3))";
+        let s5 = "(unless (= x 2) (set! x 4))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str("x", &env).unwrap()), "2");
+    }
+
+    #[test]
+    fn test_ill_formed_special_form() {
+        let env = Environment::new();
+        let cases = [
+            "(if)",
+            "(define)",
+            "(define (f x))",
+            "(lambda)",
+            "(cond (else 1) (true 2))",
+            "(cond ())",
+            "(cond 1)",
+            "(let ((x)) x)",
+            "(let ((1 2)) 3)",
+            "(let ((x 1)))",
+            "(let* x 1)",
+            "(letrec)",
+            "(when true)",
+            "(unless)",
+        ];
+        for case in cases.iter() {
+            let e = eval_str(case, &env).unwrap_err();
+            assert_eq!(
+                format!("{}", e),
+                format!("Ill-formed special form: {}", case)
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_quote() {
+        let cases = [
+            ("'a", "(quote a)"),
+            ("'(1 2)", "(quote (1 2))"),
+            (
+                "`(a ,b ,@c)",
+                "(quasiquote (a (unquote b) (unquote-splicing c)))",
+            ),
+            ("''a", "(quote (quote a))"),
+            ("'()", "(quote ())"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{}", parser::expression(s).unwrap()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str("'a", &env).unwrap()), "a");
+        assert_eq!(format!("{:?}", eval_str("(quote a)", &env).unwrap()), "a");
+        assert_eq!(
+            format!("{:?}", eval_str("(car '(a b))", &env).unwrap()),
+            "a"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("'(1 2 3)", &env).unwrap()),
+            "(1 2 3)"
+        );
+        assert_eq!(format!("{:?}", eval_str("'()", &env).unwrap()), "()");
+        assert_eq!(format!("{:?}", eval_str("''a", &env).unwrap()), "(quote a)");
+        assert_eq!(
+            format!("{:?}", eval_str("'(lambda (x) x)", &env).unwrap()),
+            "(lambda (x) x)"
+        );
+        let e = eval_str("(quote)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Ill-formed special form: (quote)");
+    }
+
+    #[test]
+    fn test_quote_long_list() {
+        let env = Environment::new();
+        let elems: Vec<String> = (0..100000).map(|i| i.to_string()).collect();
+        let literal = format!("({})", elems.join(" "));
+        let value = eval_str(&format!("'{}", literal), &env).unwrap();
+        assert_eq!(format!("{:?}", value), literal);
+        let exp = parser::expression(&literal).unwrap();
+        assert_eq!(value.to_sexp(), Some(exp));
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str(&format!("(length '{})", literal), &env).unwrap()
+            ),
+            "100000"
+        );
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        let env = Environment::new();
+        assert_eq!(
+            format!("{:?}", eval_str("(define x 1)", &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(define l '(2 3))", &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(format!("{:?}", eval_str("`(x ,x)", &env).unwrap()), "(x 1)");
+        assert_eq!(
+            format!("{:?}", eval_str("`(0 ,@l 4)", &env).unwrap()),
+            "(0 2 3 4)"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("`(0 . ,x)", &env).unwrap()),
+            "(0 . 1)"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("`(,@'() . end)", &env).unwrap()),
+            "end"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("`(a `(b ,(c ,x)))", &env).unwrap()),
+            "(a (quasiquote (b (unquote (c 1)))))"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("`#(0 ,x ,@l (,x))", &env).unwrap()),
+            "#(0 1 2 3 (1))"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("`(a #(b ,x))", &env).unwrap()),
+            "(a #(b 1))"
+        );
+        let e = eval_str("`(0 ,@x)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Invalid arguments for unquote-splicing: [1]"
+        );
+        let e = eval_str("`,@l", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Ill-formed special form: (unquote-splicing l)"
+        );
+    }
+
+    #[test]
+    fn test_define_macro() {
+        let s1 = "(define-macro (my-unless c a b) `(if ,c ,b ,a))";
+        let s2 = "(my-unless (= 1 2) 'yes (car '()))";
+        let s3 = "(define-macro my-when (lambda (c e) (cons 'if (cons c (cons e '(false))))))";
+        let s4 = "(define-macro (inc! var) `(set! ,var (+ ,var 1)))";
+        let s5 = "(define n 0)";
+        let s6 = "(begin (inc! n) (inc! n) n)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "yes");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(my-when true 1)", &env).unwrap()),
+            "1"
+        );
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "2");
+        assert_eq!(
+            format!("{:?}", eval_str("my-unless", &env).unwrap()),
+            "#<macro>"
+        );
+    }
+
+    #[test]
+    fn test_defmacro() {
+        let s1 = "(defmacro my-or2 (a b) `(let ((t ,a)) (if t t ,b)))";
+        let s2 = "(my-or2 false 2)";
+        let s3 = "(let ((t 5)) (my-or2 false t))";
+        let s4 = "(define (loop n) (my-or2 (= n 0) (loop (- n 1))))";
+        let s5 = "(loop 100000)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "2");
+        // Not hygienic: the macro's `t` captures the caller's `t`.
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "#t");
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        let s1 = "(define-macro (swap! a b) `(let ((tmp ,a)) (set! ,a ,b) (set! ,b tmp)))";
+        let s2 = "(define-macro (my-if c a b) `(cond (,c ,a) (else ,b)))";
+        let s3 = "(define-macro (my-if2 c a b) `(my-if ,c ,a ,b))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+
+        let e = eval_str("(macroexpand '(swap! x y))", &env).unwrap();
+        assert_eq!(
+            format!("{}", e.to_sexp().unwrap()),
+            "(let ((tmp x)) (set! x y) (set! y tmp))"
+        );
+        let e = eval_str("(macroexpand-1 '(my-if2 1 2 3))", &env).unwrap();
+        assert_eq!(format!("{}", e.to_sexp().unwrap()), "(my-if 1 2 3)");
+        let e = eval_str("(macroexpand '(my-if2 1 2 3))", &env).unwrap();
+        assert_eq!(format!("{}", e.to_sexp().unwrap()), "(cond (1 2) (else 3))");
+        let e = eval_str("(macroexpand '(+ 1 2))", &env).unwrap();
+        assert_eq!(format!("{}", e.to_sexp().unwrap()), "(+ 1 2)");
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let s1 = "(define-macro (bad) car)";
+        let s2 = "(define-macro (two a b) a)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        let e = eval_str("(bad)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Invalid macro expansion: #<primitive procedure>"
+        );
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "ok");
+        let e = eval_str("(two 1)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Wrong number of arguments for two: expected 2, given 1"
+        );
+        let e = eval_str("(define-macro m 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid procedure: 1");
+        let e = eval_str("(defmacro m)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Ill-formed special form: (defmacro m)");
+    }
+
+    #[test]
+    fn test_read_dotted_list() {
+        let cases = [
+            ("(a . b)", "(a . b)"),
+            ("(a b . c)", "(a b . c)"),
+            ("(a .b)", "(a .b)"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{}", parser::expression(s).unwrap()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_syntax_rules_swap() {
+        let s1 = "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+        let s2 = "(define x 1)";
+        let s3 = "(define y 2)";
+        let s4 = "(begin (swap! x y) (cons x y))";
+        let s5 = "(let ((tmp 3) (other 4)) (swap! tmp other) (cons tmp other))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "(2 . 1)");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "(4 . 3)");
+    }
+
+    #[test]
+    fn test_syntax_rules_my_or() {
+        let s1 = "(define-syntax my-or (syntax-rules () ((_) false) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))";
+        let s2 = "(let ((t 5)) (my-or false t))";
+        let s3 = "(my-or false false 3)";
+        let s4 = "(my-or)";
+        let s5 = "(define (loop n) (my-or (= n 0) (loop (- n 1))))";
+        let s6 = "(loop 100000)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "5");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "#t");
+    }
+
+    #[test]
+    fn test_syntax_rules_free_identifiers() {
+        let s1 = "(define-syntax kons (syntax-rules () ((_ a b) (cons a b))))";
+        let s2 = "(let ((cons list)) (kons 1 2))";
+        let s3 = "(define-syntax kwote (syntax-rules () ((_) 'cons)))";
+        let s4 = "(let ((cons list)) (kwote))";
+        let s5 = "(define (f x)
+                    (define-syntax add-x (syntax-rules () ((_ e) (+ e x))))
+                    (let ((x 100)) (add-x 1)))";
+        let s6 = "(f 10)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "(1 . 2)");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "cons");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "11");
+
+        // Keywords the template uses stay keywords where the call binds
+        // their names, and the call's own uses of them are its bindings
+        // (R7RS 4.3.2).
+        let s7 = "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((temp e)) (if temp temp (my-or r ...))))))";
+        let s8 =
+            "(let ((x #f) (y 7) (temp 8) (let odd?) (if even?)) (my-or x (let temp) (if y) y))";
+        let s9 = "(let ((if list)) (my-or #f 3))";
+        let s10 = "(define-syntax my-if (syntax-rules () ((_ c t e) (cond (c t) (else e)))))";
+        let s11 = "(let ((else #f)) (my-if #f 1 2))";
+        let s12 = "(let ((if list)) (if 1 2))";
+        assert_eq!(format!("{:?}", eval_str(s7, &env).unwrap()), "ok");
+        eval_str("(define (odd? n) (= (remainder n 2) 1))", &env).unwrap();
+        eval_str("(define (even? n) (= (remainder n 2) 0))", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str(s8, &env).unwrap()), "7");
+        assert_eq!(format!("{:?}", eval_str(s9, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s10, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s11, &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str(s12, &env).unwrap()), "(1 2)");
+    }
+
+    #[test]
+    fn test_syntax_rules_literals_and_ellipsis() {
+        let s1 = "(define-syntax my-cond (syntax-rules (else) ((_ (else e)) e) ((_ (c e) clause ...) (if c e (my-cond clause ...)))))";
+        let s2 = "(my-cond (false 1) ((= 1 1) 2) (else 3))";
+        let s3 = "(my-cond (false 1) (else 3))";
+        let s4 = "(define-syntax my-let* (syntax-rules () ((_ () body ...) (let () body ...)) ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))";
+        let s5 = "(my-let* ((a 1) (b (+ a 1))) (* a b))";
+        let s6 = "(define-syntax flatten (syntax-rules () ((_ (a ...) ...) '(a ... ...))))";
+        let s7 = "(flatten (1 2) () (3))";
+        let s8 = "(define-syntax rev-pair (syntax-rules () ((_ a ... z) '(z a ...))))";
+        let s9 = "(rev-pair 1 2 3)";
+        let s10 = "(define-syntax tail (syntax-rules ::: () ((_ a ::: . z) 'z)))";
+        let s11 = "(tail 1 2 . 3)";
+        let s12 = "(define-syntax ellipsis (syntax-rules () ((_ a) '(a (... ...)))))";
+        let s13 = "(ellipsis 1)";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str(s3, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s4, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s5, &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str(s6, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s7, &env).unwrap()), "(1 2 3)");
+        assert_eq!(format!("{:?}", eval_str(s8, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s9, &env).unwrap()), "(3 1 2)");
+        assert_eq!(format!("{:?}", eval_str(s10, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s11, &env).unwrap()), "3");
+        assert_eq!(format!("{:?}", eval_str(s12, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str(s13, &env).unwrap()), "(1 ...)");
+
+        let s14 = "(define-syntax v (syntax-rules () ((_ #(a b ...)) '(a #(b ...)))))";
+        assert_eq!(format!("{:?}", eval_str(s14, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(v #(1 2 3))", &env).unwrap()),
+            "(1 #(2 3))"
+        );
+        assert!(eval_str("(v (1 2 3))", &env).is_err());
+    }
+
+    #[test]
+    fn test_syntax_rules_errors() {
+        let s1 = "(define-syntax two (syntax-rules () ((_ a b) a)))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        let e = eval_str("(two 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Ill-formed special form: (two 1)");
+        let e = eval_str("(define-syntax bad (syntax-rules () (1 2)))", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "Ill-formed special form: (syntax-rules () (1 2))"
+        );
+        let e = eval_str("(define-syntax bad (lambda (x) x))", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Ill-formed special form: (lambda (x) x)");
+        let e = eval_str("(macroexpand '(two 1 2))", &env).unwrap();
+        assert_eq!(format!("{:?}", e), "1");
+    }
+
+    #[test]
+    fn test_booleans() {
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str("#t", &env).unwrap()), "#t");
+        assert_eq!(format!("{:?}", eval_str("#false", &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str("(if #f 1 2)", &env).unwrap()), "2");
+        assert_eq!(
+            format!("{:?}", eval_str("(if 'false 1 2)", &env).unwrap()),
+            "1"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(if '() 1 2)", &env).unwrap()),
+            "1"
+        );
+        assert_eq!(format!("{:?}", eval_str("(if 0 1 2)", &env).unwrap()), "1");
+        assert_eq!(format!("{:?}", eval_str("false", &env).unwrap()), "#f");
+        assert_eq!(format!("{:?}", eval_str("(< 1 2)", &env).unwrap()), "#t");
+        assert_eq!(
+            format!("{:?}", eval_str("'(#t . #f)", &env).unwrap()),
+            "(#t . #f)"
+        );
+    }
+
+    #[test]
+    fn test_boolean_primitives() {
+        let env = Environment::new();
+        assert_eq!(
+            format!("{:?}", eval_str("(boolean? #f)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(boolean? 'false)", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(format!("{:?}", eval_str("(not #f)", &env).unwrap()), "#t");
+        assert_eq!(format!("{:?}", eval_str("(not 0)", &env).unwrap()), "#f");
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? 'a 'a)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? 'a 'b)", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? '() '())", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? #t #t)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? '(1) '(1))", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(let ((x '(1))) (eq? x x))", &env).unwrap()
+            ),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(eq? car car)", &env).unwrap()),
+            "#t"
+        );
+        let e = eval_str("(not)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid arguments for not: []");
+    }
+
+    #[test]
+    fn test_read_numbers() {
+        let cases = [
+            ("42", "42"),
+            ("-7", "-7"),
+            ("+7", "7"),
+            (
+                "123456789012345678901234567890",
+                "123456789012345678901234567890",
+            ),
+            ("1/3", "1/3"),
+            ("-6/4", "-3/2"),
+            ("4/2", "2"),
+            ("1.5", "1.5"),
+            ("-.5", "-0.5"),
+            ("1e3", "1000.0"),
+            ("2.", "2.0"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{}", parser::expression(s).unwrap()), *expected);
+        }
+        let symbols = ["-", "+", "1+", "-x", "...", "1/0", "1/x"];
+        for s in symbols.iter() {
+            assert_eq!(parser::expression(s).unwrap(), Sexp::Symbol(s.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_bignum() {
+        let s1 = "(define (factorial n) (if (= n 0) 1 (* n (factorial (- n 1)))))";
+
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(s1, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("(factorial 30)", &env).unwrap()),
+            "265252859812191058636308480000000"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(/ (factorial 30) (factorial 28))", &env).unwrap()
+            ),
+            "870"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(+ 9223372036854775807 1)", &env).unwrap()),
+            "9223372036854775808"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(- -9223372036854775808 1)", &env).unwrap()
+            ),
+            "-9223372036854775809"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(- (+ 9223372036854775807 1) 1)", &env).unwrap()
+            ),
+            "9223372036854775807"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str(
+                    "(eq? (- (+ 9223372036854775807 1) 1) 9223372036854775807)",
+                    &env
+                )
+                .unwrap()
+            ),
+            "#t"
+        );
+    }
+
+    #[test]
+    fn test_rational() {
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str("(/ 1 3)", &env).unwrap()), "1/3");
+        assert_eq!(format!("{:?}", eval_str("(/ 6 4)", &env).unwrap()), "3/2");
+        assert_eq!(format!("{:?}", eval_str("(/ 6 3)", &env).unwrap()), "2");
+        assert_eq!(format!("{:?}", eval_str("(/ 2)", &env).unwrap()), "1/2");
+        assert_eq!(format!("{:?}", eval_str("(+ 1/3 2/3)", &env).unwrap()), "1");
+        assert_eq!(
+            format!("{:?}", eval_str("(* 1/3 3/4)", &env).unwrap()),
+            "1/4"
+        );
+        assert_eq!(format!("{:?}", eval_str("(- 1/2)", &env).unwrap()), "-1/2");
+        assert_eq!(
+            format!("{:?}", eval_str("(< 1/3 0.34 1/2)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(= 1/2 0.5)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(format!("{:?}", eval_str("(= 1 1 2)", &env).unwrap()), "#f");
+        // Floats are compared with exact numbers exactly.
+        let cases = [
+            ("(= 1/3 0.3333333333333333)", "#f"),
+            ("(< 1/3 0.3333333333333333)", "#f"),
+            ("(> 1/3 0.3333333333333333)", "#t"),
+            ("(= 9007199254740993 9007199254740992.0)", "#f"),
+            ("(< 1/3 (/ 1.0 0))", "#t"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_real() {
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str("(+ 1 2.5)", &env).unwrap()), "3.5");
+        assert_eq!(
+            format!("{:?}", eval_str("(* 1/2 3.0)", &env).unwrap()),
+            "1.5"
+        );
+        assert_eq!(format!("{:?}", eval_str("(- 1.0 1)", &env).unwrap()), "0.0");
+        assert_eq!(format!("{:?}", eval_str("(- 0.0)", &env).unwrap()), "-0.0");
+        let cases = [
+            ("(remainder 7.0 2)", "1.0"),
+            ("(modulo -7 2.0)", "1.0"),
+            ("(quotient 7 2.0)", "3.0"),
+            ("(remainder 7 2)", "1"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str("(remainder 7.5 2)", &env).is_err());
+        assert_eq!(
+            eval_str("(modulo 7 0.0)", &env).unwrap_err().to_string(),
+            "Division by zero"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(/ 1.0 0)", &env).unwrap()),
+            "+inf.0"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(/ -1 0.0)", &env).unwrap()),
+            "-inf.0"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(exact->inexact 1/3)", &env).unwrap()),
+            "0.3333333333333333"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(inexact->exact 0.5)", &env).unwrap()),
+            "1/2"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(exact? 1/2)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(inexact? 1.)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(integer? 2.0)", &env).unwrap()),
+            "#t"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(integer? 1/2)", &env).unwrap()),
+            "#f"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(number? 'a)", &env).unwrap()),
+            "#f"
+        );
+    }
+
+    #[test]
+    fn test_integer_division() {
+        let env = Environment::new();
+        let cases = [
+            ("(quotient 17 5)", "3"),
+            ("(quotient -17 5)", "-3"),
+            ("(remainder 17 -5)", "2"),
+            ("(remainder -17 5)", "-2"),
+            ("(modulo 17 -5)", "-3"),
+            ("(modulo -17 5)", "3"),
+            ("(modulo -9223372036854775808 -1)", "0"),
+            ("(quotient -9223372036854775808 -1)", "9223372036854775808"),
+            ("(quotient 100000000000000000000 3)", "33333333333333333333"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        let e = eval_str("(quotient 1 0)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Division by zero");
+        let e = eval_str("(modulo 1/2 1)", &env).unwrap_err();
+        assert_eq!(format!("{}", e), "Invalid arguments for modulo: [1/2, 1]");
+    }
+
+    #[test]
+    fn test_expt_sqrt() {
+        let env = Environment::new();
+        let cases = [
+            ("(expt 2 100)", "1267650600228229401496703205376"),
+            ("(expt 2 -2)", "1/4"),
+            ("(expt 2/3 2)", "4/9"),
+            ("(expt 2 0.5)", "1.4142135623730951"),
+            ("(expt 2.0 3)", "8.0"),
+            ("(expt 1 10000000000)", "1"),
+            ("(expt -1 10000000001)", "-1"),
+            ("(expt 0 100000000000000000000)", "0"),
+            ("(expt -2/3 -3)", "-27/8"),
+            ("(sqrt 16)", "4"),
+            ("(sqrt 1/4)", "1/2"),
+            ("(sqrt 2)", "1.4142135623730951"),
+            ("(sqrt 6.25)", "2.5"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert_eq!(
+            eval_str("(expt 0 -1)", &env).unwrap_err().to_string(),
+            "Division by zero"
+        );
+        assert!(eval_str("(expt 2 1000000000000)", &env).is_err());
+        assert!(eval_str("(expt 2 -1000000000000)", &env).is_err());
+        assert!(eval_str("(sqrt -4)", &env).is_err());
+    }
+
+    #[test]
+    fn test_number_to_string() {
+        let env = Environment::new();
+        let cases = [
+            ("(number->string 42)", r#""42""#),
+            ("(number->string 255 16)", r#""ff""#),
+            ("(number->string -5 2)", r#""-101""#),
+            ("(number->string 1/3 3)", r#""1/10""#),
+            ("(number->string 1.5)", r#""1.5""#),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str("(number->string 1.5 2)", &env).is_err());
+        assert!(eval_str("(number->string 1 37)", &env).is_err());
+    }
+
+    #[test]
+    fn test_read_strings_and_chars() {
+        let cases = [
+            (r#""hello""#, Sexp::String("hello".to_string())),
+            (r#""a\"b\\c\n\t""#, Sexp::String("a\"b\\c\n\t".to_string())),
+            (r#""\x41;\x3bb;""#, Sexp::String("A\u{3bb}".to_string())),
+            (r#""""#, Sexp::String("".to_string())),
+            (r"#\a", Sexp::Char('a')),
+            (r"#\A", Sexp::Char('A')),
+            (r"#\(", Sexp::Char('(')),
+            (r"#\space", Sexp::Char(' ')),
+            (r"#\newline", Sexp::Char('\n')),
+            (r"#\x", Sexp::Char('x')),
+            (r"#\x41", Sexp::Char('A')),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(parser::expression(s).unwrap(), *expected);
+        }
+        assert_eq!(
+            parser::expression(r#"(#\a "b c" d)"#).unwrap(),
+            Sexp::list(vec![
+                Sexp::Char('a'),
+                Sexp::String("b c".to_string()),
+                Sexp::symbol("d"),
+            ])
+        );
+        assert!(parser::expression(r"#\bogus").is_err());
+        assert!(parser::expression(r#""unterminated"#).is_err());
+        assert!(parser::expression(r#""\q""#).is_err());
+    }
+
+    #[test]
+    fn test_display_and_write() {
+        let env = Environment::new();
+        let v = eval_str(
+            r#"(cons "a\"b" (cons #\c (cons #\space (cons 1/2 (cons 'd (cons 1 2))))))"#,
+            &env,
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", v), r#"("a\"b" #\c #\space 1/2 d 1 . 2)"#);
+        assert_eq!(format!("{}", v), r#"(a"b c   1/2 d 1 . 2)"#);
+
+        let v = eval_str("(cons (cons 1 (cons 2 '())) (cons '() '()))", &env).unwrap();
+        assert_eq!(format!("{:?}", v), "((1 2) ())");
+        assert_eq!(
+            format!("{:?}", eval_str("'(a (b . c) . d)", &env).unwrap()),
+            "(a (b . c) . d)"
+        );
+        assert_eq!(format!("{:?}", eval_str("(newline)", &env).unwrap()), "ok");
+    }
+
+    #[test]
+    fn test_write_circular() {
+        let env = Environment::new();
+        let v = eval_str("(let ((x (list 1 2 3))) (set-cdr! (cddr x) x) x)", &env).unwrap();
+        assert_eq!(format!("{:?}", v), "#0=(1 2 3 . #0#)");
+        let v = eval_str("(let ((x (list 1 2))) (set-car! (cdr x) x) x)", &env).unwrap();
+        assert_eq!(format!("{}", v), "#0=(1 #0#)");
+        let v = eval_str("(let ((v (vector 1 2))) (vector-set! v 0 v) v)", &env).unwrap();
+        assert_eq!(format!("{:?}", v), "#0=#(#0# 2)");
+        let v = eval_str(
+            "(let ((x (list 'a)) (y (list 'b))) (set-cdr! x y) (set-cdr! y y) (list x x))",
+            &env,
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", v), "((a . #0=(b . #0#)) (a . #0#))");
+        // Shared structure without a cycle is printed as it is.
+        let v = eval_str("(let ((x (list 1))) (list x x))", &env).unwrap();
+        assert_eq!(format!("{:?}", v), "((1) (1))");
+    }
+
+    #[test]
+    fn test_deep_car_nesting() {
+        let env = Environment::new();
+        let s = "(define (nest n x) (if (= n 0) x (nest (- n 1) (list x))))";
+        eval_str(s, &env).unwrap();
+        let v = eval_str("(nest 100000 'a)", &env).unwrap();
+        let printed = format!("{:?}", v);
+        assert_eq!(printed.len(), 200001);
+        assert_eq!(
+            printed,
+            format!("{}a{}", "(".repeat(100000), ")".repeat(100000))
+        );
+        let s = "(equal? (nest 100000 'a) (nest 100000 'a))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "#t");
+        let s = "(equal? (nest 100000 'a) (nest 100000 'b))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "#f");
+        let v = eval_str("(vector (nest 100000 (vector 1)))", &env).unwrap();
+        assert!(format!("{}", v).starts_with("#(((("));
+    }
+
+    #[test]
+    fn test_string_primitives() {
+        let env = Environment::new();
+        let cases = [
+            (r#"(string-length "hello")"#, "5"),
+            (r#"(string-length "\x3bb;")"#, "1"),
+            (r#"(string-ref "hello" 1)"#, r"#\e"),
+            (r#"(substring "hello" 1 3)"#, r#""el""#),
+            (r#"(substring "hello" 2)"#, r#""llo""#),
+            (r#"(string-append "foo" "" "bar")"#, r#""foobar""#),
+            ("(string-append)", r#""""#),
+            (r"(string #\a #\b)", r#""ab""#),
+            (r"(make-string 3 #\z)", r#""zzz""#),
+            (r#"(string->list "abc")"#, r"(#\a #\b #\c)"),
+            (r#"(list->string (string->list "abc"))"#, r#""abc""#),
+            (r#"(string->symbol "foo")"#, "foo"),
+            ("(symbol->string 'foo)", r#""foo""#),
+            (r#"(string-upcase "Hello")"#, r#""HELLO""#),
+            (r#"(string-downcase "Hello")"#, r#""hello""#),
+            (r#"(string=? "a" "a" "a")"#, "#t"),
+            (r#"(string<? "abc" "abd" "b")"#, "#t"),
+            (r#"(string>? "abc" "abd")"#, "#f"),
+            (r#"(string? "a")"#, "#t"),
+            (r"(string? #\a)", "#f"),
+            (r"(char? #\a)", "#t"),
+            ("(symbol? 'a)", "#t"),
+            (r#"(eq? "a" "a")"#, "#f"),
+            (r"(eq? #\a #\a)", "#t"),
+            (r#"(string->number "42")"#, "42"),
+            (r#"(string->number "-1/2")"#, "-1/2"),
+            (r#"(string->number "1e2")"#, "100.0"),
+            (r#"(string->number "ff" 16)"#, "255"),
+            (r#"(string->number "-101" 2)"#, "-5"),
+            (r#"(string->number "abc")"#, "#f"),
+            (r#"(string->number "inf")"#, "#f"),
+            (r#"(string->number "12" 2)"#, "#f"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str(r#"(string-ref "abc" 3)"#, &env).is_err());
+        assert!(eval_str(r#"(substring "abc" 2 1)"#, &env).is_err());
+        assert!(eval_str(r#"(string-append "a" 'b)"#, &env).is_err());
+    }
+
+    #[test]
+    fn test_char_primitives() {
+        let env = Environment::new();
+        let cases = [
+            (r"(char->integer #\A)", "65"),
+            ("(integer->char 955)", "#\\\u{3bb}"),
+            ("(integer->char 10)", r"#\newline"),
+            ("(integer->char 0)", r"#\null"),
+            (r"(char-upcase #\a)", r"#\A"),
+            (r"(char-downcase #\A)", r"#\a"),
+            (r"(char-alphabetic? #\a)", "#t"),
+            (r"(char-numeric? #\7)", "#t"),
+            (r"(char-whitespace? #\tab)", "#t"),
+            (r"(char<? #\a #\b #\c)", "#t"),
+            (r"(char=? #\a #\b)", "#f"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str("(integer->char -1)", &env).is_err());
+        assert!(eval_str(r#"(char->integer "a")"#, &env).is_err());
+    }
+
+    #[test]
+    fn test_error_message_string() {
+        let env = Environment::new();
+        let e = eval_str(r#"(error "Something bad:" "x" 42)"#, &env).unwrap_err();
+        assert_eq!(format!("{}", e), r#"Something bad: "x" 42"#);
+    }
+
+    #[test]
+    fn test_read_expressions() {
+        let source = "; factorial\n(define (f n)\n  (if (= n 0) ; base case\n      1\n      (* n (f (- n 1)))))\n\n(f 5) ; => 120\n";
+        let exps = parser::expressions(source).unwrap();
+        assert_eq!(exps.len(), 2);
+        assert_eq!(format!("{}", exps[1]), "(f 5)");
+
+        let env = Environment::new();
+        let values: Vec<String> = exps
+            .iter()
+            .map(|e| format!("{:?}", eval(e, &env).unwrap()))
+            .collect();
+        assert_eq!(values, ["ok", "120"]);
+
+        assert_eq!(parser::expressions("").unwrap(), vec![]);
+        assert_eq!(parser::expressions("  ; only a comment").unwrap(), vec![]);
+        assert_eq!(
+            parser::expressions("'(1 ; one\n 2)").unwrap(),
+            vec![parser::expression("'(1 2)").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("sicp-lisp-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lib = dir.join("lib.scm");
+        let main = dir.join("main.scm");
+        fs::write(&lib, "(define (square x)\n  (* x x))\n").unwrap();
+        fs::write(
+            &main,
+            format!(
+                "(load {:?})\n(define answer (square 12))\n",
+                lib.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let env = Environment::new();
+        assert_eq!(
+            format!("{:?}", load(main.to_str().unwrap(), &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(format!("{:?}", eval_str("answer", &env).unwrap()), "144");
+
+        let s = format!("(load {:?})", lib.to_str().unwrap());
+        let env = Environment::new();
+        assert_eq!(format!("{:?}", eval_str(&s, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str("(square 3)", &env).unwrap()), "9");
+
+        let broken = dir.join("broken.scm");
+        fs::write(&broken, "(define x 1)\n(define y\n").unwrap();
+        let e = load(broken.to_str().unwrap(), &env).unwrap_err();
+        assert!(format!("{}", e).starts_with("Parse error: "));
+
+        let e = eval_str(r#"(load "/nonexistent/file.scm")"#, &env).unwrap_err();
+        assert!(format!("{}", e).starts_with("Cannot read /nonexistent/file.scm: "));
+        assert!(eval_str("(load 'file)", &env).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_car_cdr() {
+        let env = Environment::new();
+        eval_str("(define x (cons 1 (cons 2 '())))", &env).unwrap();
+        eval_str("(define y x)", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(set-car! x 'a)", &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(set-cdr! (cdr x) 3)", &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(format!("{:?}", eval_str("y", &env).unwrap()), "(a 2 . 3)");
+        assert_eq!(format!("{:?}", eval_str("(pair? y)", &env).unwrap()), "#t");
+        assert_eq!(
+            format!("{:?}", eval_str("(pair? '())", &env).unwrap()),
+            "#f"
+        );
+        assert!(eval_str("(set-car! '() 1)", &env).is_err());
+    }
+
+    #[test]
+    fn test_sicp_queue() {
+        let env = Environment::new();
+        let definitions = r#"
+            (define (front-ptr queue) (car queue))
+            (define (rear-ptr queue) (cdr queue))
+            (define (set-front-ptr! queue item) (set-car! queue item))
+            (define (set-rear-ptr! queue item) (set-cdr! queue item))
+            (define (empty-queue? queue) (null? (front-ptr queue)))
+            (define (make-queue) (cons '() '()))
+            (define (front-queue queue)
+              (if (empty-queue? queue)
+                  (error "FRONT called with an empty queue" queue)
+                  (car (front-ptr queue))))
+            (define (insert-queue! queue item)
+              (let ((new-pair (cons item '())))
+                (cond ((empty-queue? queue)
+                       (set-front-ptr! queue new-pair)
+                       (set-rear-ptr! queue new-pair)
+                       queue)
+                      (else
+                       (set-cdr! (rear-ptr queue) new-pair)
+                       (set-rear-ptr! queue new-pair)
+                       queue))))
+            (define (delete-queue! queue)
+              (cond ((empty-queue? queue)
+                     (error "DELETE! called with an empty queue" queue))
+                    (else
+                     (set-front-ptr! queue (cdr (front-ptr queue)))
+                     queue)))
+            (define q1 (make-queue))
+        "#;
+        for exp in parser::expressions(definitions).unwrap() {
+            eval(&exp, &env).unwrap();
+        }
+        let cases = [
+            ("(insert-queue! q1 'a)", "((a) a)"),
+            ("(insert-queue! q1 'b)", "((a b) b)"),
+            ("(front-queue q1)", "a"),
+            ("(delete-queue! q1)", "((b) b)"),
+            ("(delete-queue! q1)", "(() b)"),
+            ("(empty-queue? q1)", "#t"),
+            ("(insert-queue! q1 'c)", "((c) c)"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        eval_str("(delete-queue! q1)", &env).unwrap();
+        let e = eval_str("(delete-queue! q1)", &env).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "DELETE! called with an empty queue (() c)"
+        );
+    }
+
+    #[test]
+    fn test_sicp_table() {
+        let env = Environment::new();
+        let definitions = r#"
+            (define (assoc key records)
+              (cond ((null? records) false)
+                    ((eq? key (car (car records))) (car records))
+                    (else (assoc key (cdr records)))))
+            (define (make-table)
+              (let ((local-table (cons '*table* '())))
+                (define (lookup key-1 key-2)
+                  (let ((subtable (assoc key-1 (cdr local-table))))
+                    (if subtable
+                        (let ((record (assoc key-2 (cdr subtable))))
+                          (if record (cdr record) false))
+                        false)))
+                (define (insert! key-1 key-2 value)
+                  (let ((subtable (assoc key-1 (cdr local-table))))
+                    (if subtable
+                        (let ((record (assoc key-2 (cdr subtable))))
+                          (if record
+                              (set-cdr! record value)
+                              (set-cdr! subtable
+                                        (cons (cons key-2 value) (cdr subtable)))))
+                        (set-cdr! local-table
+                                  (cons (cons key-1 (cons (cons key-2 value) '()))
+                                        (cdr local-table)))))
+                  'ok)
+                (define (dispatch m)
+                  (cond ((eq? m 'lookup-proc) lookup)
+                        ((eq? m 'insert-proc!) insert!)
+                        (else (error "Unknown operation -- TABLE" m))))
+                dispatch))
+            (define operation-table (make-table))
+            (define get (operation-table 'lookup-proc))
+            (define put (operation-table 'insert-proc!))
+        "#;
+        for exp in parser::expressions(definitions).unwrap() {
+            eval(&exp, &env).unwrap();
+        }
+        let cases = [
+            ("(put 'math '+ 43)", "ok"),
+            ("(put 'math '- 45)", "ok"),
+            ("(put 'letters 'a 97)", "ok"),
+            ("(get 'math '+)", "43"),
+            ("(get 'letters 'a)", "97"),
+            ("(get 'letters 'b)", "#f"),
+            ("(put 'math '+ 'plus)", "ok"),
+            ("(get 'math '+)", "plus"),
+            ("(get 'math '-)", "45"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_vector() {
+        let env = Environment::new();
+        let cases = [
+            ("#(1 #(2) \"three\")", r#"#(1 #(2) "three")"#),
+            ("'#(a b)", "#(a b)"),
+            ("(make-vector 3 0)", "#(0 0 0)"),
+            ("(vector 'a 1/2)", "#(a 1/2)"),
+            ("(vector)", "#()"),
+            ("(define v (make-vector 3 'x))", "ok"),
+            ("(vector-set! v 1 'y)", "ok"),
+            ("v", "#(x y x)"),
+            ("(vector-ref v 1)", "y"),
+            ("(vector-length v)", "3"),
+            ("(vector->list v)", "(x y x)"),
+            ("(list->vector '(1 2))", "#(1 2)"),
+            ("(vector-fill! v 0)", "ok"),
+            ("v", "#(0 0 0)"),
+            ("(vector? v)", "#t"),
+            ("(vector? '(1))", "#f"),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str("(vector-ref v 3)", &env).is_err());
+        assert!(eval_str("(vector-set! v -1 0)", &env).is_err());
+        assert!(eval_str("(make-vector 'a)", &env).is_err());
+
+        // Deeply nested vectors are freed without overflowing the stack.
+        let s = "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (vector acc))))";
+        eval_str(s, &env).unwrap();
+        let s = "(vector-length (nest 1000000 '()))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "1");
+    }
+
+    #[test]
+    fn test_hash_table() {
+        let env = Environment::new();
+        let cases = [
+            ("(define h (make-hash-table))", "ok"),
+            ("(hash-table? h)", "#t"),
+            ("(hash-table-set! h 'a 1)", "ok"),
+            ("(hash-table-set! h \"key\" 2)", "ok"),
+            ("(hash-table-set! h 3/4 'x)", "ok"),
+            ("(hash-table-ref h 'a)", "1"),
+            ("(hash-table-ref h (string-append \"k\" \"ey\"))", "2"),
+            ("(hash-table-ref h 3/4)", "x"),
+            ("(hash-table-ref h 'missing 0)", "0"),
+            ("(hash-table-contains? h 'a)", "#t"),
+            ("(hash-table-count h)", "3"),
+            ("(hash-table-set! h 'a 10)", "ok"),
+            ("(hash-table-ref h 'a)", "10"),
+            ("(hash-table-delete! h 'a)", "ok"),
+            ("(hash-table-contains? h 'a)", "#f"),
+            ("(hash-table-count h)", "2"),
+            ("(define k (cons 1 2))", "ok"),
+            ("(hash-table-set! h k 'pair)", "ok"),
+            ("(hash-table-ref h k)", "pair"),
+            ("(hash-table-contains? h (cons 1 2))", "#f"),
+            ("(hash-table-ref h 1 'none)", "none"),
+            (
+                "(hash-table-ref (begin (hash-table-set! h 1.0 'f) h) 1 'none)",
+                "none",
+            ),
+        ];
+        for (s, expected) in cases.iter() {
+            assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), *expected);
+        }
+        assert!(eval_str("(hash-table-ref h 'a)", &env).is_err());
+
+        let env = Environment::new();
+        eval_str("(define h (make-hash-table))", &env).unwrap();
+        eval_str("(hash-table-set! h 'only 1)", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(hash-table->alist h)", &env).unwrap()),
+            "((only . 1))"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(hash-table-keys h)", &env).unwrap()),
+            "(only)"
+        );
+    }
+
+    #[test]
+    fn test_gc() {
+        let env = Environment::new();
+        eval_str("(define (leak) (define (g) g) 'ok)", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(leak)", &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str("(gc)", &env).unwrap()), "2");
+
+        let s = "(let ((x (cons 1 2))) (set-cdr! x x) 'ok)";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "ok");
+        assert_eq!(format!("{:?}", eval_str("(gc)", &env).unwrap()), "1");
+
+        // Live cycles survive a collection.
+        eval_str("(define c (cons 1 2))", &env).unwrap();
+        eval_str("(set-cdr! c c)", &env).unwrap();
+        eval_str(
+            "(define (make-counter) (define n 0) (define (inc) (set! n (+ n 1)) n) inc)",
+            &env,
+        )
+        .unwrap();
+        eval_str("(define counter (make-counter))", &env).unwrap();
+        eval_str("(counter)", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(gc)", &env).unwrap()), "0");
+        assert_eq!(
+            format!("{:?}", eval_str("(car (cdr (cdr c)))", &env).unwrap()),
+            "1"
+        );
+        assert_eq!(format!("{:?}", eval_str("(counter)", &env).unwrap()), "2");
+    }
+
+    #[test]
+    fn test_gc_flat_memory() {
+        let s1 = "(define (make-loop) (define (f n) (if (= n 0) 0 (f (- n 1)))) f)";
+        let s2 = "(define (repeat n) (if (= n 0) 'done (begin ((make-loop) 3) (repeat (- n 1)))))";
+
+        let env = Environment::new();
+        eval_str(s1, &env).unwrap();
+        eval_str(s2, &env).unwrap();
+        eval_str("(repeat 100)", &env).unwrap();
+        eval_str("(gc)", &env).unwrap();
+        let before = gc::stats();
+
+        eval_str("(repeat 50000)", &env).unwrap();
+        let during = gc::stats();
+        assert!(during.collections > before.collections);
+        assert!(during.environments < 50000);
+
+        eval_str("(gc)", &env).unwrap();
+        let after = gc::stats();
+        assert_eq!(after.environments, before.environments);
+        assert_eq!(after.objects, before.objects);
+        assert!(after.reclaimed >= before.reclaimed + 2 * 50000);
+
+        let stats = format!("{:?}", eval_str("(memory-stats)", &env).unwrap());
+        assert!(stats.starts_with(&format!(
+            "((environments . {}) (objects . {}) (collections . ",
+            after.environments, after.objects
+        )));
+    }
+
+    #[test]
+    fn test_register_machine() {
+        let env = Environment::new();
+        let s = "(define gcd-machine
+                   (make-machine
+                     '(a b t)
+                     `((rem ,remainder) (= ,=))
+                     '(test-b (test (op =) (reg b) (const 0))
+                              (branch (label gcd-done))
+                              (assign t (op rem) (reg a) (reg b))
+                              (assign a (reg b))
+                              (assign b (reg t))
+                              (goto (label test-b))
+                       gcd-done)))";
+        eval_str(s, &env).unwrap();
+        eval_str("(set-register-contents! gcd-machine 'a 206)", &env).unwrap();
+        eval_str("(set-register-contents! gcd-machine 'b 40)", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(start gcd-machine)", &env).unwrap()),
+            "ok"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(get-register-contents gcd-machine 'a)", &env).unwrap()
+            ),
+            "2"
+        );
+
+        let s = "(define fact-machine
+                   (make-machine
+                     '(n val continue)
+                     `((= ,=) (- ,-) (* ,*))
+                     '((assign continue (label fact-done))
+                       fact-loop
+                       (test (op =) (reg n) (const 1))
+                       (branch (label base-case))
+                       (save continue)
+                       (save n)
+                       (assign n (op -) (reg n) (const 1))
+                       (assign continue (label after-fact))
+                       (goto (label fact-loop))
+                       after-fact
+                       (restore n)
+                       (restore continue)
+                       (assign val (op *) (reg n) (reg val))
+                       (goto (reg continue))
+                       base-case
+                       (assign val (const 1))
+                       (goto (reg continue))
+                       fact-done)))";
+        eval_str(s, &env).unwrap();
+        eval_str("(set-register-contents! fact-machine 'n 5)", &env).unwrap();
+        eval_str("(start fact-machine)", &env).unwrap();
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(get-register-contents fact-machine 'val)", &env).unwrap()
+            ),
+            "120"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                eval_str("(machine-statistics fact-machine)", &env).unwrap()
+            ),
+            "((total-pushes . 8) (maximum-depth . 8) (instructions . 49))"
+        );
+
+        let s = "(make-machine '(a) '() '((assign b (const 1))))";
+        assert_eq!(
+            eval_str(s, &env).unwrap_err().to_string(),
+            "Unknown register b"
+        );
+        let s = "(make-machine '(a) '() '((goto (label nowhere))))";
+        assert_eq!(
+            eval_str(s, &env).unwrap_err().to_string(),
+            "Undefined label nowhere"
+        );
+        let s = "(start (make-machine '(a) '() '((restore a))))";
+        assert_eq!(eval_str(s, &env).unwrap_err().to_string(), "Empty stack ()");
+    }
+
+    #[test]
+    fn test_compile() {
+        let env = Environment::new();
+        assert_eq!(
+            format!("{:?}", eval_str("(compile '(f x))", &env).unwrap()),
+            "((assign proc (op lookup-variable-value) (const f) (reg env)) \
+             (assign val (op lookup-variable-value) (const x) (reg env)) \
+             (assign argl (op list) (reg val)) \
+             (test (op compiled-procedure?) (reg proc)) \
+             (branch (label compiled-branch1)) \
+             (assign val (op apply-procedure) (reg proc) (reg argl)) \
+             (goto (label after-call2)) \
+             compiled-branch1 \
+             (assign continue (label after-call2)) \
+             (assign val (op compiled-procedure-entry) (reg proc)) \
+             (goto (reg val)) \
+             after-call2)"
+        );
+
+        let s = "(compile-and-go
+                   '(define (factorial n)
+                      (if (= n 1) 1 (* (factorial (- n 1)) n))))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "ok");
+        assert_eq!(
+            format!("{:?}", eval_str("factorial", &env).unwrap()),
+            "#<compiled procedure>"
+        );
+        // Interpreted code calls compiled code...
+        eval_str("(define (twice f x) (f (f x)))", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(factorial 20)", &env).unwrap()),
+            "2432902008176640000"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(twice factorial 3)", &env).unwrap()),
+            "720"
+        );
+
+        // ...and compiled code calls interpreted code.
+        eval_str("(define (square x) (* x x))", &env).unwrap();
+        let s = "(compile-and-go '(let loop ((i 3) (acc '())) (if (= i 0) acc (loop (- i 1) (cons (square i) acc)))))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(1 4 9)");
+
+        // Tail calls in compiled code run in constant stack.
+        let s = "(compile-and-go '(define (count n) (if (= n 0) 'done (count (- n 1)))))";
+        eval_str(s, &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(count 100000)", &env).unwrap()),
+            "done"
+        );
+
+        // Forms the compiler does not know are left to the interpreter.
+        let s = "(compile-and-go '((lambda (x) `(x ,x ,@(cons x '()))) 1))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(x 1 1)");
+        let s = "(compile-and-go '((lambda (x) (guard (e (#t (cons 'caught e))) (raise x))) 2))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(caught . 2)");
+
+        // Arity errors name the procedure; rest parameters are compiled, and
+        // lambdas with #!optional are left to the interpreter.
+        let s = "(compile-and-go '((lambda (x) x)))";
+        assert_eq!(
+            eval_str(s, &env).unwrap_err().to_string(),
+            "Wrong number of arguments for anonymous compiled procedure: expected 1, given 0"
+        );
+        let s = "(compile-and-go '(define (dup a a) a))";
+        assert_eq!(
+            eval_str(s, &env).unwrap_err().to_string(),
+            "Ill-formed special form: (a a)"
+        );
+        assert_eq!(
+            eval_str("(factorial 1 2)", &env).unwrap_err().to_string(),
+            "Wrong number of arguments for factorial: expected 1, given 2"
+        );
+        eval_str("(compile-and-go '(define (g . args) args))", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(g 1 2)", &env).unwrap()), "(1 2)");
+        assert_eq!(format!("{:?}", eval_str("(g)", &env).unwrap()), "()");
+        let s = "(compile-and-go '(define (h a b . rest) (list a b rest)))";
+        eval_str(s, &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(h 1 2 3 4)", &env).unwrap()),
+            "(1 2 (3 4))"
+        );
+        assert_eq!(
+            eval_str("(h 1)", &env).unwrap_err().to_string(),
+            "Wrong number of arguments for h: expected at least 2, given 1"
+        );
+        let s = "(compile-and-go '(define (k a #!optional (b 10)) (+ a b)))";
+        eval_str(s, &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(k 1)", &env).unwrap()), "11");
+        assert_eq!(
+            eval_str("(k)", &env).unwrap_err().to_string(),
+            "Wrong number of arguments for k: expected between 1 and 2, given 0"
+        );
+    }
+
+    #[test]
+    fn test_streams() {
+        let env = Environment::new();
+        let eval_to_string = |s: &str| format!("{:?}", eval_str(s, &env).unwrap());
+
+        // Promises are memoized.
+        eval_to_string("(define count 0)");
+        eval_to_string("(define p (delay (begin (set! count (+ count 1)) count)))");
+        assert_eq!(eval_to_string("p"), "#<promise>");
+        assert_eq!(eval_to_string("(force p)"), "1");
+        assert_eq!(eval_to_string("(force p)"), "1");
+        assert_eq!(eval_to_string("count"), "1");
+        assert_eq!(eval_to_string("(force (make-promise 5))"), "5");
+        assert_eq!(eval_to_string("(promise? (make-promise p))"), "#t");
+        assert_eq!(eval_to_string("(force 7)"), "7");
+
+        assert_eq!(eval_to_string("(stream-null? the-empty-stream)"), "#t");
+        assert_eq!(
+            eval_to_string("(stream-cdr (cons-stream 1 the-empty-stream))"),
+            "()"
+        );
+        // The cdr of a stream is not evaluated until it is asked for.
+        assert_eq!(
+            eval_to_string("(stream-car (cons-stream 1 (car '())))"),
+            "1"
+        );
+
+        for s in &[
+            "(define (integers-starting-from n)
+               (cons-stream n (integers-starting-from (+ n 1))))",
+            "(define (divisible? x y) (= (remainder x y) 0))",
+            "(define (sieve stream)
+               (cons-stream
+                 (stream-car stream)
+                 (sieve (stream-filter
+                          (lambda (x) (not (divisible? x (stream-car stream))))
+                          (stream-cdr stream)))))",
+            "(define primes (sieve (integers-starting-from 2)))",
+            "(define (stream-head s n)
+               (if (= n 0) '() (cons (stream-car s) (stream-head (stream-cdr s) (- n 1)))))",
+        ] {
+            eval_to_string(s);
+        }
+        assert_eq!(
+            eval_to_string("(stream-head primes 10)"),
+            "(2 3 5 7 11 13 17 19 23 29)"
+        );
+        assert_eq!(eval_to_string("(stream-ref primes 50)"), "233");
+        assert_eq!(
+            eval_to_string("(stream-head (stream-map (lambda (x) (* x x)) primes) 5)"),
+            "(4 9 25 49 121)"
+        );
+
+        assert_eq!(
+            eval_str("(delay)", &env).unwrap_err().to_string(),
+            "Ill-formed special form: (delay)"
+        );
+    }
+
+    fn eval_prelude(s: &str) -> String {
+        let env = Environment::new();
+        match eval_str(s, &env) {
+            Ok(value) => format!("{:?}", value),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_list_procedure() {
+        assert_eq!(eval_prelude("(list 1 (+ 1 1) 'three)"), "(1 2 three)");
+        assert_eq!(eval_prelude("(list)"), "()");
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eq!(
+            eval_prelude("(map (lambda (x) (* x x)) '(1 2 3))"),
+            "(1 4 9)"
+        );
+        assert_eq!(eval_prelude("(map car '())"), "()");
+        assert_eq!(eval_prelude("(map + '(1 2) '(3 4))"), "(4 6)");
+        assert_eq!(
+            eval_prelude("(map list '(1 2 3) '(a b) '(x y z))"),
+            "((1 a x) (2 b y))"
+        );
+        assert_eq!(eval_prelude("(map + '() '(1))"), "()");
+        assert_eq!(
+            eval_prelude(
+                "(begin
+                   (define (iota n acc) (if (= n 0) acc (iota (- n 1) (cons n acc))))
+                   (length (map (lambda (x) x) (iota 100000 '()))))"
+            ),
+            "100000"
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(
+            eval_prelude("(filter (lambda (x) (> x 2)) '(1 3 2 4))"),
+            "(3 4)"
+        );
+        assert_eq!(eval_prelude("(filter number? '(a b))"), "()");
+    }
+
+    #[test]
+    fn test_append() {
+        assert_eq!(
+            eval_prelude("(append '(1 2) '(3) '() '(4 5))"),
+            "(1 2 3 4 5)"
+        );
+        assert_eq!(eval_prelude("(append '(1) 2)"), "(1 . 2)");
+        assert_eq!(eval_prelude("(append)"), "()");
+        assert_eq!(
+            eval_prelude("(append 1 '(2))"),
+            "Error: Invalid arguments for append: [1, (2)]"
+        );
+    }
+
+    #[test]
+    fn test_length() {
+        assert_eq!(eval_prelude("(length '(a b c))"), "3");
+        assert_eq!(eval_prelude("(length '())"), "0");
+        assert_eq!(
+            eval_prelude("(length '(1 . 2))"),
+            "Error: Invalid arguments for length: [(1 . 2)]"
+        );
+        assert_eq!(
+            eval_prelude("(length (let ((x (list 1 2))) (set-cdr! (cdr x) x) x))"),
+            "Error: Invalid arguments for length: [#0=(1 2 . #0#)]"
+        );
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(eval_prelude("(reverse '(1 (2 3) 4))"), "(4 (2 3) 1)");
+        assert_eq!(eval_prelude("(reverse '())"), "()");
+    }
+
+    #[test]
+    fn test_assoc() {
+        let records = "'(((a) 1) ((b) 2))";
+        assert_eq!(
+            eval_prelude(&format!("(assoc '(b) {})", records)),
+            "((b) 2)"
+        );
+        assert_eq!(eval_prelude(&format!("(assoc '(c) {})", records)), "#f");
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(eval_prelude("(apply + '(1 2 3))"), "6");
+        assert_eq!(eval_prelude("(apply list 1 2 '(3 4))"), "(1 2 3 4)");
+        assert_eq!(eval_prelude("(apply (lambda (x y) (- x y)) '(10 3))"), "7");
+        // Calls through apply are tail calls.
+        assert_eq!(
+            eval_prelude(
+                "(begin
+                   (define (loop n) (if (= n 0) 'done (apply loop (list (- n 1)))))
+                   (loop 100000))"
+            ),
+            "done"
+        );
+        assert_eq!(
+            eval_prelude("(apply + 1)"),
+            "Error: Invalid arguments for apply: [#<primitive procedure>, 1]"
+        );
+    }
+
+    #[test]
+    fn test_cadr() {
+        assert_eq!(eval_prelude("(cadr '(1 2 3))"), "2");
+        assert_eq!(eval_prelude("(caddr '(1 2 3))"), "3");
+        assert_eq!(eval_prelude("(cddr '(1 2 3))"), "(3)");
+        assert_eq!(eval_prelude("(caar '((1) 2))"), "1");
+        assert_eq!(eval_prelude("(cdar '((1 . 4) 2))"), "4");
+        assert_eq!(eval_prelude("(cadddr '(1 2 3 4))"), "4");
+    }
+
+    #[test]
+    fn test_equal() {
+        assert_eq!(
+            eval_prelude("(equal? '(1 (2 #(3 \"s\"))) '(1 (2 #(3 \"s\"))))"),
+            "#t"
+        );
+        assert_eq!(eval_prelude("(equal? '(1 2) '(1 3))"), "#f");
+        assert_eq!(eval_prelude("(equal? \"abc\" \"abc\")"), "#t");
+        assert_eq!(eval_prelude("(equal? 'a 'a)"), "#t");
+        assert_eq!(eval_prelude("(equal? '(1) 1)"), "#f");
+    }
+
+    #[test]
+    fn test_for_each() {
+        assert_eq!(
+            eval_prelude(
+                "(begin
+                   (define sum 0)
+                   (for-each (lambda (x) (set! sum (+ sum x))) '(1 2 3))
+                   sum)"
+            ),
+            "6"
+        );
+        assert_eq!(eval_prelude("(for-each car '())"), "done");
+        assert_eq!(
+            eval_prelude(
+                "(begin
+                   (define sum 0)
+                   (for-each (lambda (x y) (set! sum (+ sum (* x y)))) '(1 2 3) '(4 5))
+                   sum)"
+            ),
+            "14"
+        );
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let env = Environment::new();
+        let s = "((lambda args args) 1 2 3)";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(1 2 3)");
+        let s = "((lambda args args))";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "()");
+        let s = "((lambda (a . rest) (list a rest)) 1 2 3)";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(1 (2 3))");
+        let s = "((lambda (a #!rest rest) (list a rest)) 1)";
+        assert_eq!(format!("{:?}", eval_str(s, &env).unwrap()), "(1 ())");
+
+        eval_str("(define (f a b . rest) (list a b rest))", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(f 1 2 3 4)", &env).unwrap()),
+            "(1 2 (3 4))"
+        );
+        let e = eval_str("(f 1)", &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Wrong number of arguments for f: expected at least 2, given 1"
+        );
+    }
+
+    #[test]
+    fn test_optional_parameters() {
+        let env = Environment::new();
+        let s = "(define (f a #!optional b (c (+ a 10))) (list a b c))";
+        eval_str(s, &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("(f 1)", &env).unwrap()),
+            "(1 #f 11)"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(f 1 2)", &env).unwrap()),
+            "(1 2 11)"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(f 1 2 3)", &env).unwrap()),
+            "(1 2 3)"
+        );
+        let e = eval_str("(f)", &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Wrong number of arguments for f: expected between 1 and 3, given 0"
+        );
+        let e = eval_str("(f 1 2 3 4)", &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Wrong number of arguments for f: expected between 1 and 3, given 4"
+        );
+
+        // Optional and rest parameters together.
+        let s = "(define (g #!optional (x 'none) . rest) (list x rest))";
+        eval_str(s, &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(g)", &env).unwrap()), "(none ())");
+        assert_eq!(
+            format!("{:?}", eval_str("(g 1 2)", &env).unwrap()),
+            "(1 (2))"
+        );
+
+        let e = eval_str("(lambda (a 1) a)", &env).unwrap_err();
+        assert_eq!(e.to_string(), "Ill-formed special form: (a 1)");
+    }
+
+    #[test]
+    fn test_spans() {
+        let exps = parser::read("(a\n  (b c)) 'd\n  (e)", "x.scm").unwrap();
+        let span = |exp: &Sexp| exp.span().map(|span| span.to_string());
+        assert_eq!(span(&exps[0]).unwrap(), "x.scm:1:1");
+        assert_eq!(span(exps[0].tail(1).elements().0[0]).unwrap(), "x.scm:2:3");
+        assert_eq!(span(&exps[2]).unwrap(), "x.scm:3:3");
+        // Spans don't take part in comparisons, and expressions read without
+        // a source have none.
+        assert_eq!(exps[0], parser::expression("(a (b c))").unwrap());
+        assert_eq!(span(&parser::expression("(a)").unwrap()), None);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let source = "(define (factorial n)
+  (if (= n 0)
+      (error \"bottom\" n)
+      (* n (factorial (- n 1)))))
+(define (run) (display (factorial 3)))";
+        let env = Environment::new();
+        for exp in parser::read(source, "fact.scm").unwrap() {
+            eval(&exp, &env).unwrap();
+        }
+        let e = eval(&parser::read("(run)", "<repl>").unwrap()[0], &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "bottom 0
+  in factorial at fact.scm:3:7
+  in factorial at fact.scm:4:7 (3 times)
+  in run at fact.scm:5:15"
+        );
+
+        // Handlers see the error itself.
+        let s = "(guard (e ((error-object? e) (error-object-message e))) (run))";
+        let exp = parser::read(s, "<repl>").unwrap().remove(0);
+        assert_eq!(format!("{:?}", eval(&exp, &env).unwrap()), "\"bottom\"");
+
+        let e = eval_str("(run)", &env).unwrap_err();
+        assert_eq!(
+            e.to_string().lines().last().unwrap(),
+            "  in run at fact.scm:5:15"
+        );
+
+        // Variables are located at the form they are in, even when nothing
+        // is left on the stack.
+        let source = "(define (f) foo)
+(define (g x)
+  (if x (f) (+ 1 (* bar 2))))";
+        for exp in parser::read(source, "unbound.scm").unwrap() {
+            eval(&exp, &env).unwrap();
+        }
+        let e = eval(&parser::read("(g #t)", "<repl>").unwrap()[0], &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unbound variable: foo\n  in f at unbound.scm:1:1"
+        );
+        let e = eval(&parser::read("(g #f)", "<repl>").unwrap()[0], &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unbound variable: bar\n  in g at unbound.scm:3:18\n  in g at unbound.scm:3:13"
+        );
+    }
+
+    #[test]
+    fn test_procedure_names() {
+        let env = Environment::new();
+        eval_str("(define (f x) x)", &env).unwrap();
+        eval_str("(define g (lambda (x) x))", &env).unwrap();
+        assert_eq!(
+            format!("{:?}", eval_str("f", &env).unwrap()),
+            "#<procedure f>"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("g", &env).unwrap()),
+            "#<procedure g>"
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(lambda (x) x)", &env).unwrap()),
+            "#<procedure>"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let env = Environment::new();
+        eval_str("(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1)))))", &env).unwrap();
+        eval_str("(trace f)", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(f 3)", &env).unwrap()), "3");
+        assert!(eval_str("(f 'a)", &env).is_err());
+        eval_str("(untrace f)", &env).unwrap();
+        assert_eq!(format!("{:?}", eval_str("(f 3)", &env).unwrap()), "3");
+        eval_str("(trace f)", &env).unwrap();
+        eval_str("(untrace)", &env).unwrap();
+
+        // Each interpreter traces its own procedures.
+        let other = Environment::new();
+        eval_str("(define (f n) n)", &other).unwrap();
+        eval_str("(trace f)", &env).unwrap();
+        let (f, g) = (eval_str("f", &env).unwrap(), eval_str("f", &other).unwrap());
+        assert!(env.tracing(&f).is_some());
+        assert!(other.tracing(&g).is_none());
+        eval_str("(trace f)", &other).unwrap();
+        eval_str("(untrace)", &env).unwrap();
+        assert!(env.tracing(&f).is_none());
+        assert!(other.tracing(&g).is_some());
+
+        let e = eval_str("(trace car)", &env).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid arguments for trace: [#<primitive procedure>]"
+        );
+    }
+
+    // What the stepper writes, kept for inspection.
+    #[derive(Clone, Default)]
+    struct Output(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug() {
+        let env = Environment::new();
+        eval_str("(define (square x) (* x x))", &env).unwrap();
+        let step = |commands: &'static str| {
+            let output = Output::default();
+            let exp = parser::expression("(+ (square 2) (square 3))").unwrap();
+            let input = Box::new(std::io::Cursor::new(commands));
+            let value = debug::debug(&exp, &env, input, Box::new(output.clone())).unwrap();
+            assert_eq!(format!("{:?}", value), "13");
+            let output = output.0.borrow();
+            String::from_utf8(output.clone()).unwrap()
+        };
+
+        assert_eq!(
+            step("s\ns\nn\nc\n"),
+            "[0] (+ (square 2) (square 3))
+    <global environment>
+step> [1] (square 2)
+    <global environment>
+step> [2] (* x x)
+    x = 2
+    <global environment>
+step> [2] (* x x) => 4
+[1] (square 2) => 4
+[1] (square 3)
+    <global environment>
+step> [1] (square 3) => 9
+[0] (+ (square 2) (square 3)) => 13
+"
+        );
+        // Stepping out of the body of the first call stops at the second,
+        // and running out of input runs to the end.
+        assert_eq!(
+            step("s\ns\no\n"),
+            "[0] (+ (square 2) (square 3))
+    <global environment>
+step> [1] (square 2)
+    <global environment>
+step> [2] (* x x)
+    x = 2
+    <global environment>
+step> [2] (* x x) => 4
+[1] (square 2) => 4
+[1] (square 3)
+    <global environment>
+step> [1] (square 3) => 9
+[0] (+ (square 2) (square 3)) => 13
+"
+        );
+    }
+
+    #[test]
+    fn test_environment_diagram() {
+        let env = Environment::new();
+        let diagram = diagram::Diagram::new(&env);
+        assert_eq!(
+            diagram.render(&env),
+            "digraph environment {
+  node [fontname=\"monospace\"];
+  e0 [shape=record, label=\"{global env}\"];
+}
+"
+        );
+
+        // SICP 3.2.3: W1 keeps the frame its balance lives in.
+        eval_str(
+            "(define (make-withdraw balance)
+               (lambda (amount) (set! balance (- balance amount)) balance))",
+            &env,
+        )
+        .unwrap();
+        eval_str("(define W1 (make-withdraw 100))", &env).unwrap();
+        eval_str("(W1 50)", &env).unwrap();
+        eval_str("(define s \"a|b\")", &env).unwrap();
+        assert_eq!(
+            diagram.render(&env),
+            r#"digraph environment {
+  node [fontname="monospace"];
+  e0 [shape=record, label="{global env|<f1> W1:|<f2> make-withdraw:|s: \"a\|b\"}"];
+  e0:f1 -> p0;
+  e0:f2 -> p1;
+  p1 [shape=doublecircle, label="", width=0.2];
+  p1_code [shape=note, label="parameters: balance\lbody: (lambda (amount) (set! balance (- balance amount)) balance)\l"];
+  p1 -> p1_code;
+  p1 -> e0;
+  p0 [shape=doublecircle, label="", width=0.2];
+  p0_code [shape=note, label="parameters: amount\lbody: (set! balance (- balance amount)) balance\l"];
+  p0 -> p0_code;
+  p0 -> e1;
+  e1 [shape=record, label="{E1|balance: 50}"];
+  e1 -> e0;
+}
+"#
+        );
+        assert_eq!(
+            format!("{:?}", eval_str("(show-environment)", &env).unwrap()),
+            "ok"
+        );
+
+        // Inside a procedure body it draws the frame of the call, E1.
+        let env = Environment::new();
+        eval_str("(define (f x) (environment-diagram))", &env).unwrap();
+        assert_eq!(
+            format!("{}", eval_str("(f 1)", &env).unwrap()),
+            r#"digraph environment {
+  node [fontname="monospace"];
+  e1 [shape=record, label="{E1|x: 1}"];
+  e1 -> e0;
+  e0 [shape=record, label="{global env|<f1> f:}"];
+  e0:f1 -> p0;
+  p0 [shape=doublecircle, label="", width=0.2];
+  p0_code [shape=note, label="parameters: x\lbody: (environment-diagram)\l"];
+  p0 -> p0_code;
+  p0 -> e0;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_lazy() {
+        let env = Environment::new();
+        env.set_lazy(true);
+        let force_str = |s: &str| format!("{:?}", force(eval_str(s, &env).unwrap()).unwrap());
+
+        eval_str("(define (try a b) (if (= a 0) 1 b))", &env).unwrap();
+        assert_eq!(force_str("(try 0 (/ 1 0))"), "1");
+        assert_eq!(
+            eval_str("(try 1 (/ 1 0))", &env)
+                .and_then(force)
+                .unwrap_err()
+                .to_string(),
+            "Division by zero"
+        );
+
+        // Thunks are memoized.
+        eval_str("(define count 0)", &env).unwrap();
+        eval_str("(define (id x) (set! count (+ count 1)) x)", &env).unwrap();
+        eval_str("(define w (id (id 10)))", &env).unwrap();
+        assert_eq!(force_str("count"), "1");
+        assert_eq!(force_str("w"), "10");
+        assert_eq!(force_str("count"), "2");
+        assert_eq!(force_str("w"), "10");
+        assert_eq!(force_str("count"), "2");
+
+        // A long chain of thunks is forced without overflowing the stack.
+        eval_str(
+            "(define (loop n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1))))",
+            &env,
+        )
+        .unwrap();
+        assert_eq!(force_str("(loop 10000 0)"), "10000");
+        assert_eq!(force_str("(loop 100000 0)"), "100000");
+
+        // Lazy lists out of procedural pairs (SICP 4.2.3).
+        for s in &[
+            "(define (cons x y) (lambda (m) (m x y)))",
+            "(define (car z) (z (lambda (p q) p)))",
+            "(define (cdr z) (z (lambda (p q) q)))",
+            "(define (list-ref items n) (if (= n 0) (car items) (list-ref (cdr items) (- n 1))))",
+            "(define (map proc items) (if (null? items) '() (cons (proc (car items)) (map proc (cdr items)))))",
+            "(define (scale-list items factor) (map (lambda (x) (* x factor)) items))",
+            "(define (add-lists list1 list2)
+               (cond ((null? list1) list2)
+                     ((null? list2) list1)
+                     (else (cons (+ (car list1) (car list2))
+                                 (add-lists (cdr list1) (cdr list2))))))",
+            "(define ones (cons 1 ones))",
+            "(define integers (cons 1 (add-lists ones integers)))",
+        ] {
+            eval_str(s, &env).unwrap();
+        }
+        assert_eq!(force_str("(list-ref integers 17)"), "18");
+        assert_eq!(force_str("(list-ref (scale-list integers 3) 4)"), "15");
+
+        // Solving the differential equation dy/dt = y without delay.
+        for s in &[
+            "(define (integral integrand initial-value dt)
+               (define int (cons initial-value (add-lists (scale-list integrand dt) int)))
+               int)",
+            "(define (solve f y0 dt) (define y (integral dy y0 dt)) (define dy (map f y)) y)",
+        ] {
+            eval_str(s, &env).unwrap();
+        }
+        assert_eq!(
+            force_str("(list-ref (solve (lambda (x) x) 1 0.1) 10)"),
+            "2.5937424601"
+        );
+    }
+
+    #[test]
+    fn test_amb_outside_amb_mode() {
+        let env = Environment::new();
+        assert_eq!(
+            eval_str("(amb 1 2)", &env).unwrap_err().to_string(),
+            "Unbound variable: amb"
+        );
+    }
+
+    const MICROSHAFT: &str = "
+        (address (Bitdiddle Ben) (Slumerville (Ridge Road) 10))
+        (job (Bitdiddle Ben) (computer wizard))
+        (salary (Bitdiddle Ben) 60000)
+        (address (Hacker Alyssa P) (Cambridge (Mass Ave) 78))
+        (job (Hacker Alyssa P) (computer programmer))
+        (salary (Hacker Alyssa P) 40000)
+        (supervisor (Hacker Alyssa P) (Bitdiddle Ben))
+        (address (Fect Cy D) (Cambridge (Ames Street) 3))
+        (job (Fect Cy D) (computer programmer))
+        (salary (Fect Cy D) 35000)
+        (supervisor (Fect Cy D) (Bitdiddle Ben))
+        (address (Tweakit Lem E) (Boston (Bay State Road) 22))
+        (job (Tweakit Lem E) (computer technician))
+        (salary (Tweakit Lem E) 25000)
+        (supervisor (Tweakit Lem E) (Bitdiddle Ben))
+        (address (Reasoner Louis) (Slumerville (Pine Tree Road) 80))
+        (job (Reasoner Louis) (computer programmer trainee))
+        (salary (Reasoner Louis) 30000)
+        (supervisor (Reasoner Louis) (Hacker Alyssa P))
+        (supervisor (Bitdiddle Ben) (Warbucks Oliver))
+        (address (Warbucks Oliver) (Swellesley (Top Heap Road)))
+        (job (Warbucks Oliver) (administration big wheel))
+        (salary (Warbucks Oliver) 150000)
+        (address (Scrooge Eben) (Weston (Shady Lane) 10))
+        (job (Scrooge Eben) (accounting chief accountant))
+        (salary (Scrooge Eben) 75000)
+        (supervisor (Scrooge Eben) (Warbucks Oliver))
+        (address (Cratchet Robert) (Allston (N Harvard Street) 16))
+        (job (Cratchet Robert) (accounting scrivener))
+        (salary (Cratchet Robert) 18000)
+        (supervisor (Cratchet Robert) (Scrooge Eben))
+        (address (Aull DeWitt) (Slumerville (Onion Square) 5))
+        (job (Aull DeWitt) (administration secretary))
+        (salary (Aull DeWitt) 25000)
+        (supervisor (Aull DeWitt) (Warbucks Oliver))
+        (can-do-job (computer wizard) (computer programmer))
+        (can-do-job (computer wizard) (computer technician))
+        (can-do-job (computer programmer) (computer programmer trainee))
+        (can-do-job (administration secretary) (administration big wheel))
+        (rule (lives-near ?person-1 ?person-2)
+              (and (address ?person-1 (?town . ?rest-1))
+                   (address ?person-2 (?town . ?rest-2))
+                   (not (same ?person-1 ?person-2))))
+        (rule (same ?x ?x))
+        (rule (wheel ?person)
+              (and (supervisor ?middle-manager ?person)
+                   (supervisor ?x ?middle-manager)))
+        (rule (outranked-by ?staff-person ?boss)
+              (or (supervisor ?staff-person ?boss)
+                  (and (supervisor ?staff-person ?middle-manager)
+                       (outranked-by ?middle-manager ?boss))))
+        (rule (append-to-form () ?y ?y))
+        (rule (append-to-form (?u . ?v) ?y (?u . ?z))
+              (append-to-form ?v ?y ?z))";
+
+    #[test]
+    fn test_query() {
+        let env = Environment::new();
+        let database = query::Database::new(&env);
+        for exp in parser::expressions(MICROSHAFT).unwrap() {
+            database.assert(&exp).unwrap();
+        }
+        // Answers come from interleaved streams, so compare them sorted.
+        let query = |s: &str| {
+            let mut results: Vec<String> = database
+                .query(&parser::expression(s).unwrap())
+                .map(|result| result.map(|result| result.to_string()))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            results.sort();
+            results
+        };
+
+        assert_eq!(
+            query("(job ?x (computer programmer))"),
+            [
+                "(job (Fect Cy D) (computer programmer))",
+                "(job (Hacker Alyssa P) (computer programmer))",
+            ]
+        );
+        assert_eq!(query("(address ?x ?y)").len(), 9);
+        assert_eq!(query("(supervisor ?x ?x)"), Vec::<String>::new(),);
+        assert_eq!(
+            query("(job ?x (computer . ?type))"),
+            [
+                "(job (Bitdiddle Ben) (computer wizard))",
+                "(job (Fect Cy D) (computer programmer))",
+                "(job (Hacker Alyssa P) (computer programmer))",
+                "(job (Reasoner Louis) (computer programmer trainee))",
+                "(job (Tweakit Lem E) (computer technician))",
+            ]
+        );
+        assert_eq!(
+            query("(and (job ?person (computer programmer)) (address ?person ?where))"),
+            [
+                "(and (job (Fect Cy D) (computer programmer)) (address (Fect Cy D) (Cambridge (Ames Street) 3)))",
+                "(and (job (Hacker Alyssa P) (computer programmer)) (address (Hacker Alyssa P) (Cambridge (Mass Ave) 78)))",
+            ]
+        );
+        assert_eq!(
+            query("(or (supervisor ?x (Bitdiddle Ben)) (supervisor ?x (Hacker Alyssa P)))"),
+            [
+                "(or (supervisor (Fect Cy D) (Bitdiddle Ben)) (supervisor (Fect Cy D) (Hacker Alyssa P)))",
+                "(or (supervisor (Hacker Alyssa P) (Bitdiddle Ben)) (supervisor (Hacker Alyssa P) (Hacker Alyssa P)))",
+                "(or (supervisor (Reasoner Louis) (Bitdiddle Ben)) (supervisor (Reasoner Louis) (Hacker Alyssa P)))",
+                "(or (supervisor (Tweakit Lem E) (Bitdiddle Ben)) (supervisor (Tweakit Lem E) (Hacker Alyssa P)))",
+            ]
+        );
+        assert_eq!(
+            query("(and (supervisor ?x (Bitdiddle Ben)) (not (job ?x (computer programmer))))"),
+            ["(and (supervisor (Tweakit Lem E) (Bitdiddle Ben)) (not (job (Tweakit Lem E) (computer programmer))))"]
+        );
+        assert_eq!(
+            query("(and (salary ?person ?amount) (lisp-value > ?amount 50000))"),
+            [
+                "(and (salary (Bitdiddle Ben) 60000) (lisp-value > 60000 50000))",
+                "(and (salary (Scrooge Eben) 75000) (lisp-value > 75000 50000))",
+                "(and (salary (Warbucks Oliver) 150000) (lisp-value > 150000 50000))",
+            ]
+        );
+
+        // Rules.
+        assert_eq!(
+            query("(lives-near ?x (Bitdiddle Ben))"),
+            [
+                "(lives-near (Aull DeWitt) (Bitdiddle Ben))",
+                "(lives-near (Reasoner Louis) (Bitdiddle Ben))",
+            ]
+        );
+        // Exercise 4.65: Warbucks is found once per middle manager's report.
+        assert_eq!(
+            query("(wheel ?who)"),
+            [
+                "(wheel (Bitdiddle Ben))",
+                "(wheel (Warbucks Oliver))",
+                "(wheel (Warbucks Oliver))",
+                "(wheel (Warbucks Oliver))",
+                "(wheel (Warbucks Oliver))",
+            ]
+        );
+        assert_eq!(
+            query("(outranked-by (Reasoner Louis) ?who)"),
+            [
+                "(outranked-by (Reasoner Louis) (Bitdiddle Ben))",
+                "(outranked-by (Reasoner Louis) (Hacker Alyssa P))",
+                "(outranked-by (Reasoner Louis) (Warbucks Oliver))",
+            ]
+        );
+        assert_eq!(
+            query("(append-to-form (a b) (c d) ?z)"),
+            ["(append-to-form (a b) (c d) (a b c d))"]
+        );
+        assert_eq!(
+            query("(append-to-form ?x ?y (a b c d))"),
+            [
+                "(append-to-form () (a b c d) (a b c d))",
+                "(append-to-form (a b c d) () (a b c d))",
+                "(append-to-form (a b c) (d) (a b c d))",
+                "(append-to-form (a b) (c d) (a b c d))",
+                "(append-to-form (a) (b c d) (a b c d))",
+            ]
+        );
+        // Unbound variables are shown with the id of the rule application.
+        let results = query("(append-to-form (a) ?y ?z)");
+        let y = results[0]
+            .strip_prefix("(append-to-form (a) ?y-")
+            .and_then(|s| s.split(' ').next())
+            .unwrap();
+        assert_eq!(
+            results,
+            [format!("(append-to-form (a) ?y-{0} (a . ?y-{0}))", y)]
+        );
+
+        assert_eq!(
+            database
+                .query(&parser::expression("(lisp-value > ?x 1)").unwrap())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_err()
+                .to_string(),
+            "Unknown pat var -- LISP-VALUE ?x"
+        );
+
+        // Answers are found as they are asked for, even when they never end.
+        for s in ["(nat zero)", "(rule (nat (succ ?n)) (nat ?n))"] {
+            database.assert(&parser::expression(s).unwrap()).unwrap();
+        }
+        let answers: Vec<String> = database
+            .query(&parser::expression("(nat ?x)").unwrap())
+            .take(3)
+            .map(|answer| answer.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                "(nat zero)",
+                "(nat (succ zero))",
+                "(nat (succ (succ zero)))"
+            ]
+        );
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_tarai
+    #[test]
+    #[ignore]
+    fn bench_tarai() {
+        let s1 = "(define tarai (lambda (x y z) (if (<= x y) y (tarai (tarai (- x 1) y z) (tarai (- y 1) z x) (tarai (- z 1) x y)))))";
+        let s2 = "(tarai 10 5 0)";
+
+        let env = Environment::new();
+        eval_str(s1, &env).unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(format!("{:?}", eval_str(s2, &env).unwrap()), "10");
+        println!("(tarai 10 5 0): {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let interpreter = Interpreter::new();
+        let value = interpreter
+            .eval_str("(define (square x) (* x x)) (square 12)")
+            .unwrap();
+        assert_eq!(i64::from_value(&value), Some(144));
+        assert_eq!(format!("{:?}", interpreter.eval_str("").unwrap()), "()");
+        let e = interpreter.eval_str("(square").unwrap_err();
+        assert!(e.to_string().starts_with("Parse error: "));
+
+        interpreter.define("name", "sicp");
+        interpreter.define("sizes", vec![1i64, 2, 3]);
+        let value = interpreter.eval_str("(cons name sizes)").unwrap();
+        assert_eq!(format!("{:?}", value), "(\"sicp\" 1 2 3)");
+        let value = interpreter.eval_str("(map square sizes)").unwrap();
+        assert_eq!(Vec::<i64>::from_value(&value), Some(vec![1, 4, 9]));
+        assert_eq!(String::from_value(&value), None);
+    }
+
+    #[test]
+    fn test_interpreter_primitives() {
+        let interpreter = Interpreter::new();
+        interpreter.define_primitive_procedure("add", |a: i64, b: i64| a + b);
+        interpreter.define_primitive_procedure("half", |x: f64| x / 2.0);
+        interpreter.define_primitive_procedure("greet", |name: String| format!("hello, {}", name));
+        interpreter.define_primitive_procedure("answer", || 42i64);
+        interpreter.define_primitive_procedure("checked-div", |a: i64, b: i64| {
+            if b == 0 {
+                Err(LispError::DivisionByZero)
+            } else {
+                Ok(a / b)
+            }
+        });
+        interpreter.define_primitive_procedure("count", |args: &[Rc<Value>]| args.len() as i64);
+
+        let eval = |s: &str| match interpreter.eval_str(s) {
+            Ok(value) => format!("{:?}", value),
+            Err(e) => format!("Error: {}", e),
+        };
+        assert_eq!(eval("(add 1 2)"), "3");
+        assert_eq!(eval("(half 3)"), "1.5");
+        assert_eq!(eval("(greet \"lisp\")"), "\"hello, lisp\"");
+        assert_eq!(eval("(answer)"), "42");
+        assert_eq!(eval("(checked-div 7 2)"), "3");
+        assert_eq!(eval("(checked-div 7 0)"), "Error: Division by zero");
+        assert_eq!(eval("(count 1 'a \"b\")"), "3");
+        assert_eq!(eval("(apply add '(3 4))"), "7");
+
+        assert_eq!(
+            eval("(add 1)"),
+            "Error: Wrong number of arguments for add: expected 2, given 1"
+        );
+        assert_eq!(
+            eval("(add 1 'x)"),
+            "Error: Invalid arguments for add: [1, x]"
+        );
+        // Errors from primitives can be caught like any other.
+        assert_eq!(eval("(guard (e (#t 'caught)) (add 'x 'y))"), "caught");
+    }
+
+    #[test]
+    fn test_interpreters_are_independent() {
+        let a = Interpreter::new();
+        let b = Interpreter::new();
+        a.eval_str("(define x 1)").unwrap();
+        a.define_primitive_procedure("only-in-a", || true);
+        a.eval_str("(define (car x) 'redefined)").unwrap();
+
+        assert_eq!(
+            b.eval_str("x").unwrap_err().to_string(),
+            "Unbound variable: x"
+        );
+        assert_eq!(
+            b.eval_str("(only-in-a)").unwrap_err().to_string(),
+            "Unbound variable: only-in-a"
+        );
+        assert_eq!(format!("{:?}", b.eval_str("(car '(1 2))").unwrap()), "1");
+        assert_eq!(
+            format!("{:?}", a.eval_str("(car '(1 2))").unwrap()),
+            "redefined"
+        );
+    }
+
+    #[test]
+    fn test_interpreter_modes() {
+        let strict = Interpreter::new();
+        let lazy = Interpreter::with_options(Options {
+            lazy: true,
+            ..Options::default()
+        });
+        let amb = Interpreter::with_options(Options {
+            amb: true,
+            ..Options::default()
+        });
+        let eval = |interpreter: &Interpreter, s: &str| match interpreter.eval_str(s) {
+            Ok(value) => format!("{:?}", value),
+            Err(e) => format!("Error: {}", e),
+        };
+
+        let s = "(define (try a b) (if (= a 0) 1 b)) (try 0 (/ 1 0))";
+        assert_eq!(eval(&lazy, s), "1");
+        assert_eq!(eval(&strict, s), "Error: Division by zero");
+        assert_eq!(eval(&amb, s), "Error: Division by zero");
+
+        let s = "(let ((x (amb 1 2 3))) (require (> x 1)) x)";
+        assert_eq!(eval(&amb, s), "2");
+        assert_eq!(
+            eval(&amb, "(amb)"),
+            "Error: There are no more values of (amb)"
+        );
+        assert_eq!(eval(&strict, s), "Error: Unbound variable: amb");
+        assert_eq!(eval(&lazy, "(define amb 1) amb"), "1");
+    }
+}
@@ -0,0 +1,404 @@
+// A register-machine simulator (SICP 5.2). A controller is assembled into a
+// `Code` whose registers and labels are resolved to indices, and `run` steps
+// through it. Operations are ordinary Lisp procedures, so the same simulator
+// runs both hand-written controllers and the output of the compiler.
+
+use super::error::*;
+use super::eval::*;
+use super::number::*;
+use super::sexp::*;
+use super::value::*;
+
+use super::sexp::Sexp::{Pair, Symbol};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub struct Code {
+    registers: Vec<String>,
+    instructions: Vec<Instruction>,
+}
+
+// A position in some assembled code, as held by `continue` and friends.
+#[derive(Clone)]
+pub struct Label {
+    code: Rc<Code>,
+    index: usize,
+}
+
+enum Instruction {
+    Assign(usize, Source),
+    Test(Operation),
+    Branch(usize),
+    Goto(Target),
+    Save(usize),
+    Restore(usize),
+    Perform(Operation),
+}
+
+enum Source {
+    Operand(Operand),
+    Operation(Operation),
+}
+
+enum Operand {
+    Register(usize),
+    Constant(Rc<Value>),
+    Label(usize),
+}
+
+struct Operation {
+    procedure: Rc<Value>,
+    operands: Vec<Operand>,
+}
+
+enum Target {
+    Label(usize),
+    Register(usize),
+}
+
+#[derive(Default)]
+pub struct Stack {
+    values: Vec<Rc<Value>>,
+    pushes: usize,
+    max_depth: usize,
+    instructions: usize,
+}
+
+pub struct Machine {
+    code: Rc<Code>,
+    registers: Vec<Rc<Value>>,
+    stack: Stack,
+}
+
+fn machine_error(message: &str, irritant: Rc<Value>) -> LispError {
+    LispError::User {
+        message: Rc::new(Value::String(message.to_string())),
+        irritants: vec![irritant],
+    }
+}
+
+fn sexp_error(message: &str, irritant: &Sexp) -> LispError {
+    machine_error(message, Value::from_sexp(irritant))
+}
+
+impl Label {
+    // Jumping here ends the run, like falling off the end of the controller.
+    pub fn halt() -> Label {
+        Label {
+            code: Rc::new(Code {
+                registers: vec![],
+                instructions: vec![],
+            }),
+            index: 0,
+        }
+    }
+}
+
+impl Code {
+    // Assembles a controller: a list of instructions and label symbols.
+    pub fn assemble(
+        registers: Vec<String>,
+        controller: &[&Sexp],
+        operations: &HashMap<String, Rc<Value>>,
+    ) -> Result<Code, LispError> {
+        let mut labels = HashMap::new();
+        let mut index = 0;
+        for statement in controller {
+            match statement {
+                Symbol(name) => {
+                    if labels.insert(name.as_str(), index).is_some() {
+                        return Err(sexp_error("Duplicate label", statement));
+                    }
+                }
+                _ => index += 1,
+            }
+        }
+
+        let assembler = Assembler {
+            registers: &registers,
+            labels: &labels,
+            operations,
+        };
+        let mut instructions = vec![];
+        for statement in controller {
+            if !matches!(statement, Symbol(_)) {
+                instructions.push(assembler.instruction(statement)?);
+            }
+        }
+        Ok(Code {
+            registers,
+            instructions,
+        })
+    }
+
+    pub fn start(self: &Rc<Self>) -> Label {
+        Label {
+            code: self.clone(),
+            index: 0,
+        }
+    }
+}
+
+struct Assembler<'a> {
+    registers: &'a [String],
+    labels: &'a HashMap<&'a str, usize>,
+    operations: &'a HashMap<String, Rc<Value>>,
+}
+
+impl Assembler<'_> {
+    fn instruction(&self, statement: &Sexp) -> Result<Instruction, LispError> {
+        let unknown = || sexp_error("Unknown instruction type", statement);
+        let parts = statement.to_vec().ok_or_else(unknown)?;
+        let instruction = match parts.as_slice() {
+            [Symbol(tag), Symbol(r), source @ ..] if tag == "assign" => {
+                let source = match source {
+                    [Pair(car, _, _), ..] if matches!(&**car, Symbol(tag) if tag == "op") => {
+                        Source::Operation(self.operation(source)?)
+                    }
+                    [operand] => Source::Operand(self.operand(operand)?),
+                    _ => return Err(unknown()),
+                };
+                Instruction::Assign(self.register(r)?, source)
+            }
+            [Symbol(tag), operation @ ..] if tag == "test" => {
+                Instruction::Test(self.operation(operation)?)
+            }
+            [Symbol(tag), destination] if tag == "branch" || tag == "goto" => {
+                match (tag.as_str(), destination.to_vec().as_deref()) {
+                    ("branch", Some([Symbol(kind), Symbol(l)])) if kind == "label" => {
+                        Instruction::Branch(self.label(l)?)
+                    }
+                    ("goto", Some([Symbol(kind), Symbol(l)])) if kind == "label" => {
+                        Instruction::Goto(Target::Label(self.label(l)?))
+                    }
+                    ("goto", Some([Symbol(kind), Symbol(r)])) if kind == "reg" => {
+                        Instruction::Goto(Target::Register(self.register(r)?))
+                    }
+                    _ => return Err(unknown()),
+                }
+            }
+            [Symbol(tag), Symbol(r)] if tag == "save" => Instruction::Save(self.register(r)?),
+            [Symbol(tag), Symbol(r)] if tag == "restore" => Instruction::Restore(self.register(r)?),
+            [Symbol(tag), operation @ ..] if tag == "perform" => {
+                Instruction::Perform(self.operation(operation)?)
+            }
+            _ => return Err(unknown()),
+        };
+        Ok(instruction)
+    }
+
+    // ((op name) operand ...)
+    fn operation(&self, exps: &[&Sexp]) -> Result<Operation, LispError> {
+        let bad = || {
+            sexp_error(
+                "Bad operation",
+                &Sexp::list(exps.iter().map(|&e| e.clone()).collect()),
+            )
+        };
+        let (op, operands) = exps.split_first().ok_or_else(bad)?;
+        match op.to_vec().as_deref() {
+            Some([Symbol(tag), Symbol(name)]) if tag == "op" => {
+                let procedure = self
+                    .operations
+                    .get(name)
+                    .ok_or_else(|| sexp_error("Unknown operation", &Sexp::symbol(name)))?;
+                Ok(Operation {
+                    procedure: procedure.clone(),
+                    operands: operands
+                        .iter()
+                        .map(|operand| self.operand(operand))
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+            _ => Err(bad()),
+        }
+    }
+
+    fn operand(&self, exp: &Sexp) -> Result<Operand, LispError> {
+        match exp.to_vec().as_deref() {
+            Some([Symbol(tag), Symbol(r)]) if tag == "reg" => {
+                Ok(Operand::Register(self.register(r)?))
+            }
+            Some([Symbol(tag), c]) if tag == "const" => Ok(Operand::Constant(Value::from_sexp(c))),
+            Some([Symbol(tag), Symbol(l)]) if tag == "label" => Ok(Operand::Label(self.label(l)?)),
+            _ => Err(sexp_error("Unknown expression type", exp)),
+        }
+    }
+
+    fn register(&self, name: &str) -> Result<usize, LispError> {
+        self.registers
+            .iter()
+            .position(|r| r == name)
+            .ok_or_else(|| sexp_error("Unknown register", &Sexp::symbol(name)))
+    }
+
+    fn label(&self, name: &str) -> Result<usize, LispError> {
+        self.labels
+            .get(name)
+            .copied()
+            .ok_or_else(|| sexp_error("Undefined label", &Sexp::symbol(name)))
+    }
+}
+
+impl Stack {
+    fn push(&mut self, value: Rc<Value>) {
+        self.values.push(value);
+        self.pushes += 1;
+        self.max_depth = self.max_depth.max(self.values.len());
+    }
+
+    fn pop(&mut self) -> Result<Rc<Value>, LispError> {
+        self.values
+            .pop()
+            .ok_or_else(|| machine_error("Empty stack", Rc::new(Value::Nil)))
+    }
+
+    // The performance counters as an alist, like memory-stats.
+    pub fn statistics(&self) -> Rc<Value> {
+        let entry = |name: &str, n: usize| {
+            Value::cons(
+                Rc::new(Value::Symbol(name.to_string())),
+                Rc::new(Value::Number(Number::Integer(n as i64))),
+            )
+        };
+        Value::list(vec![
+            entry("total-pushes", self.pushes),
+            entry("maximum-depth", self.max_depth),
+            entry("instructions", self.instructions),
+        ])
+    }
+}
+
+// Executes from `label` until control runs off the end of the code.
+pub fn run(label: Label, registers: &mut [Rc<Value>], stack: &mut Stack) -> Result<(), LispError> {
+    let Label { mut code, index } = label;
+    let mut pc = index;
+    let mut flag = false;
+    while let Some(instruction) = code.instructions.get(pc) {
+        stack.instructions += 1;
+        pc += 1;
+        let mut jump = None;
+        match instruction {
+            Instruction::Assign(r, Source::Operand(operand)) => {
+                registers[*r] = operand_value(operand, &code, registers);
+            }
+            Instruction::Assign(r, Source::Operation(operation)) => {
+                registers[*r] = perform(operation, &code, registers)?;
+            }
+            Instruction::Test(operation) => flag = perform(operation, &code, registers)?.is_true(),
+            Instruction::Branch(index) => {
+                if flag {
+                    pc = *index;
+                }
+            }
+            Instruction::Goto(Target::Label(index)) => pc = *index,
+            Instruction::Goto(Target::Register(r)) => match &*registers[*r] {
+                Value::Label(label) => jump = Some(label.clone()),
+                _ => return Err(machine_error("Not a label", registers[*r].clone())),
+            },
+            Instruction::Save(r) => stack.push(registers[*r].clone()),
+            Instruction::Restore(r) => registers[*r] = stack.pop()?,
+            Instruction::Perform(operation) => {
+                perform(operation, &code, registers)?;
+            }
+        }
+        if let Some(label) = jump {
+            // A label from a machine with other registers would index them wrongly.
+            if !label.code.instructions.is_empty() && label.code.registers != code.registers {
+                let label = Rc::new(Value::Label(label));
+                return Err(machine_error("Label of another machine", label));
+            }
+            code = label.code;
+            pc = label.index;
+        }
+    }
+    Ok(())
+}
+
+fn operand_value(operand: &Operand, code: &Rc<Code>, registers: &[Rc<Value>]) -> Rc<Value> {
+    match operand {
+        Operand::Register(r) => registers[*r].clone(),
+        Operand::Constant(value) => value.clone(),
+        Operand::Label(index) => Rc::new(Value::Label(Label {
+            code: code.clone(),
+            index: *index,
+        })),
+    }
+}
+
+fn perform(
+    operation: &Operation,
+    code: &Rc<Code>,
+    registers: &[Rc<Value>],
+) -> Result<Rc<Value>, LispError> {
+    let arguments = operation
+        .operands
+        .iter()
+        .map(|operand| operand_value(operand, code, registers))
+        .collect();
+    apply(operation.procedure.clone(), arguments)
+}
+
+impl Machine {
+    // (make-machine register-names operations controller)
+    pub fn new(
+        registers: &Value,
+        operations: &Value,
+        controller: &Value,
+    ) -> Option<Result<Machine, LispError>> {
+        let registers = registers
+            .list_to_vec()?
+            .iter()
+            .map(|r| match &**r {
+                Value::Symbol(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let mut table = HashMap::new();
+        for operation in operations.list_to_vec()? {
+            match operation.list_to_vec()?.as_slice() {
+                [name, procedure] => match &**name {
+                    Value::Symbol(name) => table.insert(name.clone(), procedure.clone()),
+                    _ => return None,
+                },
+                _ => return None,
+            };
+        }
+        let controller = controller.to_sexp()?;
+        let statements = controller.to_vec()?;
+
+        let unassigned = Rc::new(Value::Symbol("*unassigned*".to_string()));
+        Some(
+            Code::assemble(registers, &statements, &table).map(|code| Machine {
+                registers: vec![unassigned; code.registers.len()],
+                code: Rc::new(code),
+                stack: Stack::default(),
+            }),
+        )
+    }
+
+    fn register(&mut self, name: &str) -> Option<&mut Rc<Value>> {
+        let index = self.code.registers.iter().position(|r| r == name)?;
+        Some(&mut self.registers[index])
+    }
+
+    pub fn get_register_contents(&mut self, name: &str) -> Option<Rc<Value>> {
+        self.register(name).map(|r| r.clone())
+    }
+
+    pub fn set_register_contents(&mut self, name: &str, value: Rc<Value>) -> Option<()> {
+        *self.register(name)? = value;
+        Some(())
+    }
+
+    // Starts at the beginning of the controller with a fresh stack.
+    pub fn start(&mut self) -> Result<(), LispError> {
+        self.stack = Stack::default();
+        run(self.code.start(), &mut self.registers, &mut self.stack)
+    }
+
+    pub fn statistics(&self) -> Rc<Value> {
+        self.stack.statistics()
+    }
+}
@@ -1,121 +1,410 @@
-#![feature(box_syntax, box_patterns)]
+use lisp::diagram::Diagram;
+use lisp::environment::*;
+use lisp::eval::*;
+use lisp::sexp::*;
+use lisp::{amb, error, query, Interpreter, Options};
 
-mod environment;
-mod eval;
-mod sexp;
-mod value;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 
-use environment::*;
-use eval::*;
-use sexp::*;
-
-use std::io::prelude::*;
-use std::io::{self, BufReader};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
 
 fn main() {
-    let env = Environment::new();
+    let mut args: Vec<String> = env::args().collect();
+    let mut options = Options::default();
+    if let Some(i) = args.iter().position(|arg| arg == "--lazy") {
+        args.remove(i);
+        options.lazy = true;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--amb") {
+        args.remove(i);
+        options.amb = true;
+    }
+    let interpreter = Interpreter::with_options(options);
+    let env = interpreter.environment();
 
-    let prompt = || {
-        print!("> ");
-        io::stdout().flush().unwrap();
-    };
-    prompt();
+    let mut driver = Driver::Eval;
+    if options.amb {
+        driver = Driver::Amb(amb::Driver::default());
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--query") {
+        args.remove(i);
+        driver = Driver::Query(query::Database::new(env));
+    }
+    // --dump-env writes an environment diagram to stderr after each
+    // top-level expression, leaving out what the interpreter starts with.
+    let mut diagram = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--dump-env") {
+        args.remove(i);
+        diagram = Some(Diagram::new(env));
+    }
+    match &args[1..] {
+        [] => repl(env, driver, diagram.as_ref()),
+        [path] => {
+            // The file is loaded as a whole, unless a diagram is to follow
+            // each of its expressions.
+            let exps = match &diagram {
+                Some(_) => read_file(path),
+                None => Ok(vec![Sexp::list(vec![
+                    Sexp::symbol("load"),
+                    Sexp::String(path.clone()),
+                ])]),
+            };
+            // A script prints only the answers to its queries.
+            let is_query = matches!(driver, Driver::Query(_));
+            let mut print = |output: &str| {
+                if output.starts_with(";;;") {
+                    eprintln!("{}", output);
+                    process::exit(1);
+                }
+                if is_query {
+                    println!("{}", output);
+                }
+            };
+            let result = exps.and_then(|exps| {
+                for exp in &exps {
+                    eval_print(exp, env, &mut driver, &mut print)?;
+                    dump(diagram.as_ref(), env);
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!(
+                "usage: {} [--lazy | --amb | --query] [--dump-env] [file.scm]",
+                args[0]
+            );
+            process::exit(2);
+        }
+    }
+}
 
-    let stdin = io::stdin();
-    let reader = BufReader::new(stdin.lock());
+// The expressions of a source file, read with their locations.
+fn read_file(path: &str) -> Result<Vec<Sexp>, error::LispError> {
+    let source = fs::read_to_string(path).map_err(|e| error::LispError::FileNotReadable {
+        path: path.to_string(),
+        message: e.to_string(),
+    })?;
+    parser::read(&source, path).map_err(|e| error::LispError::Parse(e.to_string()))
+}
 
-    for line in reader.lines() {
-        let e = parser::expression(&line.unwrap()).unwrap();
-        println!("{:?}", eval(&e, &env));
-        prompt();
+fn dump(diagram: Option<&Diagram>, env: &Rc<Environment>) {
+    if let Some(diagram) = diagram {
+        eprint!("{}", diagram.render(env));
     }
 }
 
+// What the top level hands expressions to: the evaluator itself, the amb
+// evaluator's driver loop (SICP 4.3.3), which also answers try-again, or the
+// query system's (SICP 4.4.4.1).
+enum Driver {
+    Eval,
+    Amb(amb::Driver),
+    Query(Rc<query::Database>),
+}
+
+// Evaluates a top-level expression and hands what the REPL prints for it to
+// `print`, a line at a time; the answers to a query are printed as they are
+// found.
+fn eval_print(
+    exp: &Sexp,
+    env: &Rc<Environment>,
+    driver: &mut Driver,
+    print: &mut dyn FnMut(&str),
+) -> Result<(), error::LispError> {
+    match driver {
+        Driver::Eval => print(&format!("{:?}", eval(exp, env).and_then(force)?)),
+        Driver::Amb(driver) => match driver.eval(exp, env)? {
+            amb::Outcome::Value(value) => print(&format!("{:?}", value)),
+            amb::Outcome::NoMoreValues(problem) => {
+                print(&format!(";;; There are no more values of {}", problem))
+            }
+            amb::Outcome::NoCurrentProblem => print(";;; There is no current problem"),
+        },
+        Driver::Query(database) => match database.eval(exp)? {
+            query::Outcome::Asserted => print("Assertion added to data base."),
+            query::Outcome::Results(answers) => {
+                for answer in answers {
+                    print(&answer?.to_string());
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+fn repl(env: &Rc<Environment>, mut driver: Driver, diagram: Option<&Diagram>) {
+    let mut editor = DefaultEditor::new().unwrap();
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".sicp_lisp_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "  " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if !is_complete(&input) {
+                    continue;
+                }
+                let _ = editor.add_history_entry(input.trim_end());
+                match parser::read(&input, "<repl>") {
+                    Ok(exps) => {
+                        for exp in &exps {
+                            match eval_print(exp, env, &mut driver, &mut |output| {
+                                println!("{}", output)
+                            }) {
+                                Ok(()) => dump(diagram, env),
+                                Err(e) => {
+                                    println!("Error: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => println!("Parse error: {}", e),
+                }
+                input.clear();
+            }
+            // Ctrl-C discards a partially typed expression.
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                break;
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+}
+
+// Whether the input has no unclosed parentheses or strings, so that the REPL
+// can stop asking for continuation lines.
+fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ';' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            // A character literal such as #\( is not a parenthesis.
+            '#' if chars.clone().next() == Some('\\') => {
+                chars.nth(1);
+            }
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some(_) => {}
+                    None => return false,
+                }
+            },
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_square() {
-        let s1 = "(define square (lambda (x) (* x x)))";
-        let s2 = "(square 10)";
-        let e1 = parser::expression(s1).unwrap();
-        let e2 = parser::expression(s2).unwrap();
-
-        let env = Environment::new();
-        assert_eq!(format!("{:?}", eval(&e1, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e2, &env)), "100");
+    fn test_is_complete() {
+        assert!(is_complete("(+ 1 2)\n"));
+        assert!(is_complete("42\n"));
+        assert!(is_complete("\n"));
+        assert!(!is_complete("(define (f x)\n"));
+        assert!(is_complete("(define (f x)\n  x)\n"));
+        assert!(!is_complete("(display \"a)\n"));
+        assert!(is_complete("(display \"a)\")\n"));
+        assert!(is_complete("(display \"\\\"(\")\n"));
+        assert!(!is_complete("(f ; )\n"));
+        assert!(is_complete("(char->integer #\\()\n"));
+        assert!(is_complete("(char->integer #\\))\n"));
     }
 
     #[test]
-    fn test_factorial() {
-        let s1 = "(define factorial (lambda (n) (if (= n 0) 1 (* n (factorial (- n 1))))))";
-        let s2 = "(factorial 5)";
-        let e1 = parser::expression(s1).unwrap();
-        let e2 = parser::expression(s2).unwrap();
+    fn test_amb() {
+        let interpreter = Interpreter::with_options(Options {
+            amb: true,
+            ..Options::default()
+        });
+        let env = interpreter.environment();
+        let mut driver = Driver::Amb(amb::Driver::default());
+        let mut eval_amb = |s: &str| {
+            let mut lines = vec![];
+            let exp = parser::expression(s).unwrap();
+            eval_print(&exp, env, &mut driver, &mut |line| {
+                lines.push(line.to_string())
+            })
+            .unwrap();
+            lines.join("\n")
+        };
 
-        let env = Environment::new();
-        assert_eq!(format!("{:?}", eval(&e1, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e2, &env)), "120");
-    }
+        assert_eq!(eval_amb("try-again"), ";;; There is no current problem");
+        assert_eq!(eval_amb("(amb 1 2 3)"), "1");
+        assert_eq!(eval_amb("try-again"), "2");
+        assert_eq!(eval_amb("try-again"), "3");
+        assert_eq!(
+            eval_amb("try-again"),
+            ";;; There are no more values of (amb 1 2 3)"
+        );
+        assert_eq!(eval_amb("(amb)"), ";;; There are no more values of (amb)");
 
-    #[test]
-    fn test_tarai() {
-        let s1 = "(define tarai (lambda (x y z) (if (<= x y) y (tarai (tarai (- x 1) y z) (tarai (- y 1) z x) (tarai (- z 1) x y)))))";
-        let s2 = "(tarai 6 3 0)";
+        eval_amb(
+            "(define (an-element-of items)
+               (require (not (null? items)))
+               (amb (car items) (an-element-of (cdr items))))",
+        );
+        eval_amb("(define (even? n) (= (remainder n 2) 0))");
 
-        let e1 = parser::expression(s1).unwrap();
-        let e2 = parser::expression(s2).unwrap();
+        // set! is undone on backtracking, permanent-set! is not.
+        eval_amb("(define z 10)");
+        assert_eq!(
+            eval_amb("(begin (set! z 20) (amb))"),
+            ";;; There are no more values of (begin (set! z 20) (amb))"
+        );
+        assert_eq!(eval_amb("z"), "10");
+        eval_amb("(define count 0)");
+        let s = "(let ((x (an-element-of '(a b c)))
+                       (y (an-element-of '(a b c))))
+                   (permanent-set! count (+ count 1))
+                   (require (not (eq? x y)))
+                   `(,x ,y ,count))";
+        assert_eq!(eval_amb(s), "(a b 2)");
+        assert_eq!(eval_amb("try-again"), "(a c 3)");
 
-        let env = Environment::new();
-        assert_eq!(format!("{:?}", eval(&e1, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e2, &env)), "6");
-    }
+        let s = "(if-fail (let ((x (an-element-of '(1 3 5)))) (require (even? x)) x) 'all-odd)";
+        assert_eq!(eval_amb(s), "all-odd");
+        let s = "(if-fail (let ((x (an-element-of '(1 3 5 8)))) (require (even? x)) x) 'all-odd)";
+        assert_eq!(eval_amb(s), "8");
 
-    #[test]
-    fn test_counter() {
-        let s1 = "(define make-counter (lambda () (begin (define count 0) (lambda () (begin (set! count (+ count 1)) count)))))";
-        let s2 = "(define c1 (make-counter))";
-        let s3 = "(define c2 (make-counter))";
-        let s4 = "(c1)";
-        let s5 = "(c1)";
-        let s6 = "(c2)";
-
-        let e1 = parser::expression(s1).unwrap();
-        let e2 = parser::expression(s2).unwrap();
-        let e3 = parser::expression(s3).unwrap();
-        let e4 = parser::expression(s4).unwrap();
-        let e5 = parser::expression(s5).unwrap();
-        let e6 = parser::expression(s6).unwrap();
+        // Long searches run in constant stack.
+        eval_amb("(define (an-integer-between a b) (require (<= a b)) (amb a (an-integer-between (+ a 1) b)))");
+        let s = "(let ((n (an-integer-between 1 20000))) (require (= n 20000)) n)";
+        assert_eq!(eval_amb(s), "20000");
 
-        let env = Environment::new();
-        assert_eq!(format!("{:?}", eval(&e1, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e2, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e3, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e4, &env)), "1");
-        assert_eq!(format!("{:?}", eval(&e5, &env)), "2");
-        assert_eq!(format!("{:?}", eval(&e6, &env)), "1");
+        for s in &[
+            "(define (abs x) (if (< x 0) (- x) x))",
+            "(define (member x items)
+               (cond ((null? items) false)
+                     ((eq? x (car items)) items)
+                     (else (member x (cdr items)))))",
+            "(define (distinct? items)
+               (cond ((null? items) true)
+                     ((null? (cdr items)) true)
+                     ((member (car items) (cdr items)) false)
+                     (else (distinct? (cdr items)))))",
+            "(define (multiple-dwelling)
+               (let ((baker (amb 1 2 3 4 5))
+                     (cooper (amb 1 2 3 4 5))
+                     (fletcher (amb 1 2 3 4 5))
+                     (miller (amb 1 2 3 4 5))
+                     (smith (amb 1 2 3 4 5)))
+                 (require (distinct? `(,baker ,cooper ,fletcher ,miller ,smith)))
+                 (require (not (= baker 5)))
+                 (require (not (= cooper 1)))
+                 (require (not (= fletcher 5)))
+                 (require (not (= fletcher 1)))
+                 (require (> miller cooper))
+                 (require (not (= (abs (- smith fletcher)) 1)))
+                 (require (not (= (abs (- fletcher cooper)) 1)))
+                 `((baker ,baker) (cooper ,cooper) (fletcher ,fletcher)
+                   (miller ,miller) (smith ,smith))))",
+        ] {
+            eval_amb(s);
+        }
+        assert_eq!(
+            eval_amb("(multiple-dwelling)"),
+            "((baker 3) (cooper 2) (fletcher 4) (miller 5) (smith 1))"
+        );
+        assert_eq!(
+            eval_amb("try-again"),
+            ";;; There are no more values of (multiple-dwelling)"
+        );
+
+        // apply, and the prelude procedures that use it, make choices too.
+        eval_amb("(define (f x) (require (= x (amb 1 2))) x)");
+        assert_eq!(eval_amb("(apply f '(2))"), "2");
+        assert_eq!(eval_amb("(map + '(1 2) (list (amb 10 20) 30))"), "(11 32)");
+        assert_eq!(eval_amb("try-again"), "(21 32)");
+        assert_eq!(
+            eval_amb(
+                "(begin (for-each (lambda (x y) (require (< x y))) '(1 2) (list 2 (amb 1 3))) 'ok)"
+            ),
+            "ok"
+        );
+
+        // A failure passes guards and handlers by, and backtracking can
+        // return into their bodies.
+        let s = "(let ((x (amb 1 2))) (guard (e (#t 'caught)) (require (= x 2)) x))";
+        assert_eq!(eval_amb(s), "2");
+        let s = "(guard (e ((symbol? e) (list 'caught e))) (let ((x (amb 1 2))) (if (= x 1) (raise 'one) x)))";
+        assert_eq!(eval_amb(s), "(caught one)");
+        assert_eq!(eval_amb("try-again"), "2");
+        let s = "(with-exception-handler (lambda (e) 10) (lambda () (+ (raise-continuable (amb 1 2)) 1)))";
+        assert_eq!(eval_amb(s), "11");
+        let s = "(with-exception-handler (lambda (e) 0) (lambda () (require #f)))";
+        assert_eq!(
+            eval_amb(s),
+            format!(";;; There are no more values of {}", s)
+        );
+        // Except where a nested run of the ordinary evaluator cannot
+        // backtrack.
+        let s = "(guard (e (#t 'caught)) (dynamic-wind (lambda () #f) (lambda () (amb)) (lambda () #f)))";
+        assert_eq!(
+            interpreter.eval_str(s).unwrap_err().to_string(),
+            "Not running in the amb evaluator"
+        );
     }
 
     #[test]
-    fn test_list() {
-        let s1 = "(define s1 (cons 1 (cons 2 (cons 3 (cons 4 nil)))))";
-        let s2 = "(car s1)";
-        let s3 = "(cdr s1)";
-        let s4 = "(define sum (lambda (s) (if (null? s) 0 (+ (car s) (sum (cdr s))))))";
-        let s5 = "(sum s1)";
-
-        let e1 = parser::expression(s1).unwrap();
-        let e2 = parser::expression(s2).unwrap();
-        let e3 = parser::expression(s3).unwrap();
-        let e4 = parser::expression(s4).unwrap();
-        let e5 = parser::expression(s5).unwrap();
-
+    fn test_query_driver() {
         let env = Environment::new();
-        assert_eq!(format!("{:?}", eval(&e1, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e2, &env)), "1");
-        assert_eq!(format!("{:?}", eval(&e3, &env)), "(2 . (3 . (4 . nil)))");
-        assert_eq!(format!("{:?}", eval(&e4, &env)), "ok");
-        assert_eq!(format!("{:?}", eval(&e5, &env)), "10");
+        let mut driver = Driver::Query(query::Database::new(&env));
+        let mut eval_query = |s: &str| {
+            let mut lines = vec![];
+            let exp = parser::expression(s).unwrap();
+            eval_print(&exp, &env, &mut driver, &mut |line| {
+                lines.push(line.to_string())
+            })
+            .unwrap();
+            lines.join("\n")
+        };
+
+        assert_eq!(
+            eval_query("(assert! (meeting accounting (Monday 9am)))"),
+            "Assertion added to data base."
+        );
+        assert_eq!(
+            eval_query("(assert! (rule (busy ?day) (meeting ?division (?day ?time))))"),
+            "Assertion added to data base."
+        );
+        assert_eq!(
+            eval_query("(meeting ?division (Monday ?time))"),
+            "(meeting accounting (Monday 9am))"
+        );
+        assert_eq!(eval_query("(busy Monday)"), "(busy Monday)");
+        assert_eq!(eval_query("(busy Friday)"), "");
     }
 }
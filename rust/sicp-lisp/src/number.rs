@@ -0,0 +1,391 @@
+// The numeric tower: fixnums that overflow into bignums, exact rationals and
+// IEEE floats. Exact results are always normalised, so a bignum that fits in
+// an i64 becomes a fixnum and a rational with denominator 1 an integer.
+
+use num_bigint::{BigInt, Sign};
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Pow, Signed, ToPrimitive, Zero};
+
+use super::sexp::{parser, Sexp};
+
+use std::cmp::Ordering;
+use std::fmt;
+
+// The largest exact power expt computes, in bits: (expt 2 1000000000000)
+// would not fit in memory.
+const MAX_EXPT_BITS: u64 = 1 << 31;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Number {
+    Integer(i64),
+    BigInteger(BigInt),
+    Rational(BigRational),
+    Real(f64),
+}
+
+// Both operands of a binary operation, converted to the wider of their types.
+enum Coerced {
+    Integer(i64, i64),
+    BigInteger(BigInt, BigInt),
+    Rational(BigRational, BigRational),
+    Real(f64, f64),
+}
+
+impl Number {
+    pub fn from_bigint(n: BigInt) -> Number {
+        match n.to_i64() {
+            Some(n) => Number::Integer(n),
+            None => Number::BigInteger(n),
+        }
+    }
+
+    pub fn from_rational(r: BigRational) -> Number {
+        if r.denom().is_one() {
+            Number::from_bigint(r.numer().clone())
+        } else {
+            Number::Rational(r)
+        }
+    }
+
+    // Parses a literal such as 42, -7, 123456789012345678901234567890, 1/3 or 1.5e3.
+    pub fn parse(s: &str) -> Option<Number> {
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Number::Integer(n));
+        }
+        if let Ok(n) = s.parse::<BigInt>() {
+            return Some(Number::from_bigint(n));
+        }
+        if let Some(i) = s.find('/') {
+            let numer = s[..i].parse::<BigInt>().ok()?;
+            let denom = s[i + 1..].parse::<BigInt>().ok()?;
+            if denom.is_zero() {
+                return None;
+            }
+            return Some(Number::from_rational(BigRational::new(numer, denom)));
+        }
+        s.parse::<f64>().ok().map(Number::Real)
+    }
+
+    // Parses the argument of string->number: any literal in radix 10, and
+    // integers or rationals in other radixes.
+    pub fn parse_radix(s: &str, radix: u32) -> Option<Number> {
+        if radix == 10 {
+            return match &parser::expression(s) {
+                Ok(Sexp::Number(n)) if !s.starts_with(char::is_whitespace) => Some(n.clone()),
+                _ => None,
+            };
+        }
+        let (sign, digits) = match s.strip_prefix('-') {
+            Some(digits) => (-1, digits),
+            None => (1, s.strip_prefix('+').unwrap_or(s)),
+        };
+        if digits.is_empty() || digits.starts_with(['+', '-']) {
+            return None;
+        }
+        let n = match digits.find('/') {
+            Some(i) => {
+                let numer = BigInt::parse_bytes(&digits.as_bytes()[..i], radix)?;
+                let denom = BigInt::parse_bytes(&digits.as_bytes()[i + 1..], radix)?;
+                if denom.is_zero() || digits[i + 1..].starts_with(['+', '-']) {
+                    return None;
+                }
+                Number::from_rational(BigRational::new(numer, denom))
+            }
+            None => Number::from_bigint(BigInt::parse_bytes(digits.as_bytes(), radix)?),
+        };
+        Some(n.mul(&Number::Integer(sign)))
+    }
+
+    fn level(&self) -> u8 {
+        match self {
+            Number::Integer(_) => 0,
+            Number::BigInteger(_) => 1,
+            Number::Rational(_) => 2,
+            Number::Real(_) => 3,
+        }
+    }
+
+    fn to_bigint(&self) -> BigInt {
+        match self {
+            Number::Integer(n) => BigInt::from(*n),
+            Number::BigInteger(n) => n.clone(),
+            Number::Rational(r) => r.to_integer(),
+            Number::Real(x) => BigInt::from(*x as i64),
+        }
+    }
+
+    fn to_rational(&self) -> BigRational {
+        match self {
+            Number::Rational(r) => r.clone(),
+            Number::Real(x) => BigRational::from_float(*x).unwrap_or_else(BigRational::zero),
+            _ => BigRational::from_integer(self.to_bigint()),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(n) => *n as f64,
+            Number::BigInteger(n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Real(x) => *x,
+        }
+    }
+
+    fn coerce(&self, other: &Number) -> Coerced {
+        match self.level().max(other.level()) {
+            0 => match (self, other) {
+                (Number::Integer(a), Number::Integer(b)) => Coerced::Integer(*a, *b),
+                _ => unreachable!(),
+            },
+            1 => Coerced::BigInteger(self.to_bigint(), other.to_bigint()),
+            2 => Coerced::Rational(self.to_rational(), other.to_rational()),
+            _ => Coerced::Real(self.to_f64(), other.to_f64()),
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Real(_))
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Number::Integer(_) | Number::BigInteger(_) => true,
+            Number::Rational(_) => false,
+            Number::Real(x) => x.fract() == 0.0,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(n) => *n == 0,
+            Number::BigInteger(n) => n.is_zero(),
+            Number::Rational(r) => r.is_zero(),
+            Number::Real(x) => *x == 0.0,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Number::Integer(n) => *n < 0,
+            Number::BigInteger(n) => n.is_negative(),
+            Number::Rational(r) => r.is_negative(),
+            Number::Real(x) => *x < 0.0,
+        }
+    }
+
+    pub fn add(&self, other: &Number) -> Number {
+        match self.coerce(other) {
+            Coerced::Integer(a, b) => match a.checked_add(b) {
+                Some(n) => Number::Integer(n),
+                None => Number::from_bigint(BigInt::from(a) + b),
+            },
+            Coerced::BigInteger(a, b) => Number::from_bigint(a + b),
+            Coerced::Rational(a, b) => Number::from_rational(a + b),
+            Coerced::Real(a, b) => Number::Real(a + b),
+        }
+    }
+
+    pub fn sub(&self, other: &Number) -> Number {
+        match self.coerce(other) {
+            Coerced::Integer(a, b) => match a.checked_sub(b) {
+                Some(n) => Number::Integer(n),
+                None => Number::from_bigint(BigInt::from(a) - b),
+            },
+            Coerced::BigInteger(a, b) => Number::from_bigint(a - b),
+            Coerced::Rational(a, b) => Number::from_rational(a - b),
+            Coerced::Real(a, b) => Number::Real(a - b),
+        }
+    }
+
+    pub fn mul(&self, other: &Number) -> Number {
+        match self.coerce(other) {
+            Coerced::Integer(a, b) => match a.checked_mul(b) {
+                Some(n) => Number::Integer(n),
+                None => Number::from_bigint(BigInt::from(a) * b),
+            },
+            Coerced::BigInteger(a, b) => Number::from_bigint(a * b),
+            Coerced::Rational(a, b) => Number::from_rational(a * b),
+            Coerced::Real(a, b) => Number::Real(a * b),
+        }
+    }
+
+    // Exact division by zero has no result; inexact division gives an infinity.
+    pub fn div(&self, other: &Number) -> Option<Number> {
+        match self.coerce(other) {
+            Coerced::Real(a, b) => Some(Number::Real(a / b)),
+            _ if other.is_zero() => None,
+            Coerced::Integer(a, b) => {
+                Some(Number::from_rational(BigRational::new(a.into(), b.into())))
+            }
+            Coerced::BigInteger(a, b) => Some(Number::from_rational(BigRational::new(a, b))),
+            Coerced::Rational(a, b) => Some(Number::from_rational(a / b)),
+        }
+    }
+
+    // Negating a float flips its sign, so that (- 0.0) is -0.0.
+    pub fn neg(&self) -> Number {
+        match self {
+            Number::Real(x) => Number::Real(-x),
+            _ => Number::Integer(0).sub(self),
+        }
+    }
+
+    // An exact number and a finite float are compared exactly, since the
+    // float may not be the nearest one to the exact number.
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        let finite = |n: &Number| !matches!(n, Number::Real(x) if !x.is_finite());
+        if self.is_exact() != other.is_exact() && finite(self) && finite(other) {
+            return Some(self.to_rational().cmp(&other.to_rational()));
+        }
+        match self.coerce(other) {
+            Coerced::Integer(a, b) => Some(a.cmp(&b)),
+            Coerced::BigInteger(a, b) => Some(a.cmp(&b)),
+            Coerced::Rational(a, b) => Some(a.cmp(&b)),
+            Coerced::Real(a, b) => a.partial_cmp(&b),
+        }
+    }
+
+    // quotient, remainder and modulo are only defined on integers; with an
+    // inexact operand the result is inexact.
+    fn integer_division(
+        &self,
+        other: &Number,
+        fixnum: fn(i64, i64) -> Option<i64>,
+        bignum: fn(&BigInt, &BigInt) -> BigInt,
+    ) -> Option<Number> {
+        if !self.is_integer() || !other.is_integer() || other.is_zero() {
+            return None;
+        }
+        if !self.is_exact() || !other.is_exact() {
+            let (a, b) = (self.to_exact()?, other.to_exact()?);
+            return a
+                .integer_division(&b, fixnum, bignum)
+                .map(|n| n.to_inexact());
+        }
+        if let (Number::Integer(a), Number::Integer(b)) = (self, other) {
+            if let Some(n) = fixnum(*a, *b) {
+                return Some(Number::Integer(n));
+            }
+        }
+        Some(Number::from_bigint(bignum(
+            &self.to_bigint(),
+            &other.to_bigint(),
+        )))
+    }
+
+    pub fn quotient(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, i64::checked_div, |a, b| a / b)
+    }
+
+    pub fn remainder(&self, other: &Number) -> Option<Number> {
+        self.integer_division(other, i64::checked_rem, |a, b| a % b)
+    }
+
+    pub fn modulo(&self, other: &Number) -> Option<Number> {
+        self.integer_division(
+            other,
+            |a, b| a.checked_rem(b).map(|_| a.mod_floor(&b)),
+            |a, b| a.mod_floor(b),
+        )
+    }
+
+    pub fn to_inexact(&self) -> Number {
+        Number::Real(self.to_f64())
+    }
+
+    pub fn to_exact(&self) -> Option<Number> {
+        match self {
+            Number::Real(x) => BigRational::from_float(*x).map(Number::from_rational),
+            _ => Some(self.clone()),
+        }
+    }
+
+    // Exact for an exact base and an integer exponent, however large, as
+    // long as the result fits in MAX_EXPT_BITS bits; None beyond that.
+    pub fn expt(&self, exponent: &Number) -> Option<Number> {
+        if self.is_exact() {
+            match exponent {
+                Number::Integer(e) => return self.expt_exact(&BigInt::from(*e)),
+                Number::BigInteger(e) => return self.expt_exact(e),
+                _ => {}
+            }
+        }
+        Some(Number::Real(self.to_f64().powf(exponent.to_f64())))
+    }
+
+    fn expt_exact(&self, exponent: &BigInt) -> Option<Number> {
+        let base = self.to_rational();
+        if base.is_zero() {
+            return match exponent.sign() {
+                Sign::Minus => None,
+                Sign::NoSign => Some(Number::Integer(1)),
+                Sign::Plus => Some(Number::Integer(0)),
+            };
+        }
+        // 1 and -1 stay small whatever the exponent.
+        if base.abs().is_one() {
+            let odd = base.is_negative() && exponent.is_odd();
+            return Some(Number::Integer(if odd { -1 } else { 1 }));
+        }
+        let e = exponent.magnitude().to_u64()?;
+        let bits = base.numer().bits().max(base.denom().bits());
+        if bits.checked_mul(e)? > MAX_EXPT_BITS {
+            return None;
+        }
+        let (mut numer, mut denom) = (Pow::pow(base.numer(), e), Pow::pow(base.denom(), e));
+        if exponent.is_negative() {
+            std::mem::swap(&mut numer, &mut denom);
+            if denom.is_negative() {
+                numer = -numer;
+                denom = -denom;
+            }
+        }
+        // The powers of a reduced fraction are reduced too.
+        Some(Number::from_rational(BigRational::new_raw(numer, denom)))
+    }
+
+    // Exact for exact perfect squares; negative numbers have no real root.
+    pub fn sqrt(&self) -> Option<Number> {
+        if self.is_negative() {
+            return None;
+        }
+        if self.is_exact() {
+            let r = self.to_rational();
+            let (numer, denom) = (r.numer().sqrt(), r.denom().sqrt());
+            if &(&numer * &numer) == r.numer() && &(&denom * &denom) == r.denom() {
+                return Some(Number::from_rational(BigRational::new(numer, denom)));
+            }
+        }
+        Some(Number::Real(self.to_f64().sqrt()))
+    }
+
+    pub fn to_string_radix(&self, radix: u32) -> Option<String> {
+        match self {
+            Number::Integer(n) => Some(BigInt::from(*n).to_str_radix(radix)),
+            Number::BigInteger(n) => Some(n.to_str_radix(radix)),
+            Number::Rational(r) => Some(format!(
+                "{}/{}",
+                r.numer().to_str_radix(radix),
+                r.denom().to_str_radix(radix)
+            )),
+            Number::Real(_) if radix == 10 => Some(self.to_string()),
+            Number::Real(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Integer(n) => write!(f, "{}", n),
+            Number::BigInteger(n) => write!(f, "{}", n),
+            Number::Rational(r) => write!(f, "{}", r),
+            Number::Real(x) if x.is_nan() => write!(f, "+nan.0"),
+            Number::Real(x) if x.is_infinite() => {
+                write!(f, "{}inf.0", if *x > 0.0 { "+" } else { "-" })
+            }
+            Number::Real(x) => write!(f, "{:?}", x),
+        }
+    }
+}
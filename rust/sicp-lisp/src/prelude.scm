@@ -0,0 +1,77 @@
+;; Procedures written in Lisp itself, evaluated into every global
+;; environment by Environment::new.
+
+;; Lists. list, length, append, reverse, equal? and apply are primitives.
+
+(define (caar x) (car (car x)))
+(define (cadr x) (car (cdr x)))
+(define (cdar x) (cdr (car x)))
+(define (cddr x) (cdr (cdr x)))
+(define (caddr x) (car (cdr (cdr x))))
+(define (cdddr x) (cdr (cdr (cdr x))))
+(define (cadddr x) (car (cdr (cdr (cdr x)))))
+
+;; map and for-each take one or more lists, and stop at the end of the
+;; shortest one.
+
+(define (map proc items . more)
+  (define (map-1 f items)
+    (if (null? items)
+        '()
+        (cons (f (car items))
+              (map-1 f (cdr items)))))
+  (define (map-n lists)
+    (if (null? (filter null? lists))
+        (cons (apply proc (map-1 car lists))
+              (map-n (map-1 cdr lists)))
+        '()))
+  (if (null? more)
+      (map-1 proc items)
+      (map-n (cons items more))))
+
+(define (filter pred items)
+  (cond ((null? items) '())
+        ((pred (car items))
+         (cons (car items) (filter pred (cdr items))))
+        (else (filter pred (cdr items)))))
+
+(define (for-each proc items . more)
+  (define (for-each-n lists)
+    (if (null? (filter null? lists))
+        (begin (apply proc (map car lists))
+               (for-each-n (map cdr lists)))
+        'done))
+  (for-each-n (cons items more)))
+
+(define (assoc key records)
+  (cond ((null? records) false)
+        ((equal? key (caar records)) (car records))
+        (else (assoc key (cdr records)))))
+
+;; Streams (SICP 3.5). cons-stream and delay are special forms.
+
+(define the-empty-stream '())
+
+(define (stream-null? s) (null? s))
+
+(define (stream-car s) (car s))
+
+(define (stream-cdr s) (force (cdr s)))
+
+(define (stream-ref s n)
+  (if (= n 0)
+      (stream-car s)
+      (stream-ref (stream-cdr s) (- n 1))))
+
+(define (stream-map proc s)
+  (if (stream-null? s)
+      the-empty-stream
+      (cons-stream (proc (stream-car s))
+                   (stream-map proc (stream-cdr s)))))
+
+(define (stream-filter pred s)
+  (cond ((stream-null? s) the-empty-stream)
+        ((pred (stream-car s))
+         (cons-stream (stream-car s)
+                      (stream-filter pred (stream-cdr s))))
+        (else (stream-filter pred (stream-cdr s)))))
@@ -0,0 +1,524 @@
+// The logic programming query system of SICP 4.4. Queries are evaluated
+// against streams of frames, each frame binding pattern variables (symbols
+// starting with ?) to values. Rust iterators play the part of the book's
+// delayed streams, so a query that has infinitely many answers is fine as
+// long as only some of them are asked for.
+
+use super::environment::*;
+use super::error::*;
+use super::eval::*;
+use super::sexp::*;
+use super::value::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::iter;
+use std::rc::Rc;
+
+// A pattern variable. Variables in a rule get a fresh id each time the rule
+// is applied, so that different applications don't clash; 0 is for the
+// variables of the query itself.
+#[derive(PartialEq, Clone)]
+struct Variable {
+    name: Rc<str>,
+    id: usize,
+}
+
+enum Term {
+    Constant(Sexp),
+    Variable(Variable),
+    Pair(Rc<Term>, Rc<Term>),
+}
+
+struct Rule {
+    conclusion: Rc<Term>,
+    body: Rc<Term>,
+}
+
+#[derive(Clone, Default)]
+struct Frame(Option<Rc<Binding>>);
+
+struct Binding {
+    variable: Variable,
+    value: Rc<Term>,
+    next: Frame,
+}
+
+type Frames = Box<dyn Iterator<Item = Frame>>;
+
+// Assertions and rules are indexed by the symbol in the car of their
+// pattern, as in SICP 4.4.4.5.
+struct Index<T> {
+    all: Vec<Rc<T>>,
+    by_key: HashMap<String, Vec<Rc<T>>>,
+}
+
+pub struct Database {
+    assertions: RefCell<Index<Term>>,
+    rules: RefCell<Index<Rule>>,
+    // Rules whose conclusion starts with a variable can match any query.
+    unindexed_rules: RefCell<Vec<Rc<Rule>>>,
+    rule_counter: Cell<usize>,
+    // Where lisp-value looks up its predicates.
+    env: Rc<Environment>,
+    // The first error raised by lisp-value, which ends the query.
+    error: RefCell<Option<LispError>>,
+}
+
+// The answers to a query, found one at a time as they are asked for, so
+// that a query with endless answers can print them as they come.
+pub type Answers = Box<dyn Iterator<Item = Result<Sexp, LispError>>>;
+
+pub enum Outcome {
+    Asserted,
+    Results(Answers),
+}
+
+fn query_error(message: &str, irritant: &Sexp) -> LispError {
+    LispError::User {
+        message: Rc::new(Value::String(message.to_string())),
+        irritants: vec![Value::from_sexp(irritant)],
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            0 => write!(f, "?{}", self.name),
+            id => write!(f, "?{}-{}", self.name, id),
+        }
+    }
+}
+
+impl Term {
+    fn new(exp: &Sexp) -> Rc<Term> {
+        Rc::new(match exp {
+            Sexp::Symbol(s) if s.starts_with('?') => Term::Variable(Variable {
+                name: Rc::from(&s[1..]),
+                id: 0,
+            }),
+            Sexp::Pair(car, cdr, _) => Term::Pair(Term::new(car), Term::new(cdr)),
+            _ => Term::Constant(exp.clone()),
+        })
+    }
+
+    // The symbol that assertions and rules are indexed by.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Term::Pair(car, _) => match &**car {
+                Term::Constant(Sexp::Symbol(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn type_and_contents(&self) -> Option<(&str, Vec<Rc<Term>>)> {
+        let (car, mut p) = match self {
+            Term::Pair(car, cdr) => (car, cdr),
+            _ => return None,
+        };
+        let query_type = match &**car {
+            Term::Constant(Sexp::Symbol(s)) => s,
+            _ => return None,
+        };
+        let mut contents = vec![];
+        while let Term::Pair(car, cdr) = &**p {
+            contents.push(car.clone());
+            p = cdr;
+        }
+        match &**p {
+            Term::Constant(Sexp::Nil) => Some((query_type, contents)),
+            _ => None,
+        }
+    }
+
+    fn rename(self: &Rc<Self>, id: usize) -> Rc<Term> {
+        match &**self {
+            Term::Constant(_) => self.clone(),
+            Term::Variable(v) => Rc::new(Term::Variable(Variable {
+                name: v.name.clone(),
+                id,
+            })),
+            Term::Pair(car, cdr) => Rc::new(Term::Pair(car.rename(id), cdr.rename(id))),
+        }
+    }
+
+    // Replaces the variables bound in the frame by their values, and the
+    // unbound ones by whatever `unbound` says.
+    fn instantiate(
+        &self,
+        frame: &Frame,
+        unbound: &dyn Fn(&Variable) -> Result<Sexp, LispError>,
+    ) -> Result<Sexp, LispError> {
+        match self {
+            Term::Constant(exp) => Ok(exp.clone()),
+            Term::Variable(v) => match frame.lookup(v) {
+                Some(value) => value.instantiate(frame, unbound),
+                None => unbound(v),
+            },
+            Term::Pair(car, cdr) => Ok(Sexp::cons(
+                car.instantiate(frame, unbound)?,
+                cdr.instantiate(frame, unbound)?,
+            )),
+        }
+    }
+}
+
+impl Rule {
+    fn new(exp: &Sexp) -> Result<Rule, LispError> {
+        match exp.to_vec().as_deref() {
+            Some([_, conclusion]) => Ok(Rule {
+                conclusion: Term::new(conclusion),
+                body: Term::new(&Sexp::list(vec![Sexp::symbol("always-true")])),
+            }),
+            Some([_, conclusion, body]) => Ok(Rule {
+                conclusion: Term::new(conclusion),
+                body: Term::new(body),
+            }),
+            _ => Err(LispError::IllFormedSpecialForm(exp.clone())),
+        }
+    }
+}
+
+impl Frame {
+    fn lookup(&self, variable: &Variable) -> Option<&Rc<Term>> {
+        let mut p = &self.0;
+        while let Some(binding) = p {
+            if binding.variable == *variable {
+                return Some(&binding.value);
+            }
+            p = &binding.next.0;
+        }
+        None
+    }
+
+    fn extend(self, variable: &Variable, value: Rc<Term>) -> Frame {
+        Frame(Some(Rc::new(Binding {
+            variable: variable.clone(),
+            value,
+            next: self,
+        })))
+    }
+}
+
+impl<T> Default for Index<T> {
+    fn default() -> Self {
+        Index {
+            all: vec![],
+            by_key: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Index<T> {
+    fn add(&mut self, key: Option<&str>, item: Rc<T>) {
+        if let Some(key) = key {
+            self.by_key
+                .entry(key.to_string())
+                .or_default()
+                .push(item.clone());
+        }
+        self.all.push(item);
+    }
+
+    fn fetch(&self, key: Option<&str>) -> Vec<Rc<T>> {
+        match key {
+            Some(key) => self.by_key.get(key).cloned().unwrap_or_default(),
+            None => self.all.clone(),
+        }
+    }
+}
+
+// Matches a pattern against an assertion, which has no variables.
+fn pattern_match(pattern: &Rc<Term>, datum: &Rc<Term>, frame: Frame) -> Option<Frame> {
+    match (&**pattern, &**datum) {
+        (Term::Variable(v), _) => match frame.lookup(v) {
+            Some(value) => pattern_match(&value.clone(), datum, frame),
+            None => Some(frame.extend(v, datum.clone())),
+        },
+        (Term::Pair(p1, p2), Term::Pair(d1, d2)) => {
+            let frame = pattern_match(p1, d1, frame)?;
+            pattern_match(p2, d2, frame)
+        }
+        (Term::Constant(a), Term::Constant(b)) if a == b => Some(frame),
+        _ => None,
+    }
+}
+
+// Unifies two patterns, either of which may contain variables.
+fn unify_match(p1: &Rc<Term>, p2: &Rc<Term>, frame: Frame) -> Option<Frame> {
+    match (&**p1, &**p2) {
+        (Term::Variable(a), Term::Variable(b)) if a == b => Some(frame),
+        (Term::Variable(v), _) => extend_if_possible(p1, v, p2, frame),
+        (_, Term::Variable(v)) => extend_if_possible(p2, v, p1, frame),
+        (Term::Pair(a1, a2), Term::Pair(b1, b2)) => {
+            let frame = unify_match(a1, b1, frame)?;
+            unify_match(a2, b2, frame)
+        }
+        (Term::Constant(a), Term::Constant(b)) if a == b => Some(frame),
+        _ => None,
+    }
+}
+
+fn extend_if_possible(
+    term: &Rc<Term>,
+    variable: &Variable,
+    value: &Rc<Term>,
+    frame: Frame,
+) -> Option<Frame> {
+    if let Some(binding) = frame.lookup(variable) {
+        return unify_match(&binding.clone(), value, frame);
+    }
+    if let Term::Variable(v) = &**value {
+        if let Some(binding) = frame.lookup(v) {
+            return unify_match(term, &binding.clone(), frame);
+        }
+    }
+    if depends_on(value, variable, &frame) {
+        return None;
+    }
+    Some(frame.extend(variable, value.clone()))
+}
+
+// Whether binding the variable to the term would make it refer to itself.
+fn depends_on(term: &Term, variable: &Variable, frame: &Frame) -> bool {
+    match term {
+        Term::Constant(_) => false,
+        Term::Variable(v) if v == variable => true,
+        Term::Variable(v) => match frame.lookup(v) {
+            Some(value) => depends_on(value, variable, frame),
+            None => false,
+        },
+        Term::Pair(car, cdr) => {
+            depends_on(car, variable, frame) || depends_on(cdr, variable, frame)
+        }
+    }
+}
+
+// Merges a stream of frame streams, taking from each in turn like the book's
+// interleave, so that an infinite stream can't hide the others' frames. New
+// streams are started as fast as frames are taken.
+struct Interleave {
+    streams: iter::Fuse<Box<dyn Iterator<Item = Frames>>>,
+    started: VecDeque<Frames>,
+}
+
+fn interleave(streams: impl Iterator<Item = Frames> + 'static) -> Frames {
+    let streams: Box<dyn Iterator<Item = Frames>> = Box::new(streams);
+    Box::new(Interleave {
+        streams: streams.fuse(),
+        started: VecDeque::new(),
+    })
+}
+
+impl Iterator for Interleave {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        loop {
+            if let Some(stream) = self.streams.next() {
+                self.started.push_front(stream);
+            }
+            let mut stream = self.started.pop_front()?;
+            if let Some(frame) = stream.next() {
+                self.started.push_back(stream);
+                return Some(frame);
+            }
+        }
+    }
+}
+
+impl Database {
+    pub fn new(env: &Rc<Environment>) -> Rc<Database> {
+        Rc::new(Database {
+            assertions: RefCell::new(Index::default()),
+            rules: RefCell::new(Index::default()),
+            unindexed_rules: RefCell::new(vec![]),
+            rule_counter: Cell::new(0),
+            env: env.clone(),
+            error: RefCell::new(None),
+        })
+    }
+
+    // The query driver loop (SICP 4.4.4.1): `(assert! ...)` adds an
+    // assertion or rule, `(load "file")` runs every form in a file, and
+    // anything else is a query.
+    pub fn eval(self: &Rc<Self>, exp: &Sexp) -> Result<Outcome, LispError> {
+        match exp.to_vec().as_deref() {
+            Some([Sexp::Symbol(s), assertion]) if s == "assert!" => {
+                self.assert(assertion)?;
+                Ok(Outcome::Asserted)
+            }
+            Some([Sexp::Symbol(s), Sexp::String(path)]) if s == "load" => {
+                let input = fs::read_to_string(path).map_err(|e| LispError::FileNotReadable {
+                    path: path.clone(),
+                    message: e.to_string(),
+                })?;
+                let exps =
+                    parser::expressions(&input).map_err(|e| LispError::Parse(e.to_string()))?;
+                // Each form runs when the answers before it have been taken,
+                // and the first error ends the file.
+                let db = self.clone();
+                let answers = exps.into_iter().flat_map(move |exp| -> Answers {
+                    match db.eval(&exp) {
+                        Ok(Outcome::Results(answers)) => answers,
+                        Ok(Outcome::Asserted) => Box::new(iter::empty()),
+                        Err(e) => Box::new(iter::once(Err(e))),
+                    }
+                });
+                Ok(Outcome::Results(Box::new(answers.scan(
+                    false,
+                    |failed, answer| {
+                        if *failed {
+                            return None;
+                        }
+                        *failed = answer.is_err();
+                        Some(answer)
+                    },
+                ))))
+            }
+            _ => Ok(Outcome::Results(self.query(exp))),
+        }
+    }
+
+    pub fn assert(&self, exp: &Sexp) -> Result<(), LispError> {
+        match exp {
+            Sexp::Pair(car, _, _) if matches!(&**car, Sexp::Symbol(s) if s == "rule") => {
+                let rule = Rc::new(Rule::new(exp)?);
+                match &*rule.conclusion {
+                    Term::Pair(car, _) if matches!(**car, Term::Variable(_)) => {
+                        self.unindexed_rules.borrow_mut().push(rule.clone());
+                    }
+                    _ => {}
+                }
+                let key = rule.conclusion.key().map(str::to_string);
+                self.rules.borrow_mut().add(key.as_deref(), rule);
+            }
+            _ => {
+                let assertion = Term::new(exp);
+                let key = assertion.key().map(str::to_string);
+                self.assertions.borrow_mut().add(key.as_deref(), assertion);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the query instantiated with each frame that satisfies it, in
+    // turn; an error ends the answers.
+    pub fn query(self: &Rc<Self>, exp: &Sexp) -> Answers {
+        let query = Term::new(exp);
+        self.error.borrow_mut().take();
+        let mut frames = self.qeval(&query, Frame::default());
+        let db = self.clone();
+        let mut done = false;
+        Box::new(iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let frame = frames.next();
+            if let Some(e) = db.error.borrow_mut().take() {
+                done = true;
+                return Some(Err(e));
+            }
+            let answer = query.instantiate(&frame?, &|v| Ok(Sexp::Symbol(v.to_string())));
+            done = answer.is_err();
+            Some(answer)
+        }))
+    }
+
+    fn qeval(self: &Rc<Self>, query: &Rc<Term>, frame: Frame) -> Frames {
+        let (query_type, contents) = match query.type_and_contents() {
+            Some(special) => special,
+            None => return self.simple_query(query, frame),
+        };
+        match query_type {
+            "and" => self.conjoin(Rc::from(contents), 0, frame),
+            "or" => {
+                let db = self.clone();
+                interleave(
+                    contents
+                        .into_iter()
+                        .map(move |disjunct| db.qeval(&disjunct, frame.clone())),
+                )
+            }
+            "not" if contents.len() == 1 => {
+                if self.qeval(&contents[0], frame.clone()).next().is_none() {
+                    Box::new(iter::once(frame))
+                } else {
+                    Box::new(iter::empty())
+                }
+            }
+            "lisp-value" if !contents.is_empty() => match self.lisp_value(&contents, &frame) {
+                Ok(true) => Box::new(iter::once(frame)),
+                Ok(false) => Box::new(iter::empty()),
+                Err(e) => {
+                    self.error.borrow_mut().get_or_insert(e);
+                    Box::new(iter::empty())
+                }
+            },
+            "always-true" => Box::new(iter::once(frame)),
+            _ => self.simple_query(query, frame),
+        }
+    }
+
+    fn conjoin(self: &Rc<Self>, conjuncts: Rc<[Rc<Term>]>, i: usize, frame: Frame) -> Frames {
+        if i == conjuncts.len() {
+            return Box::new(iter::once(frame));
+        }
+        let db = self.clone();
+        let frames = self.qeval(&conjuncts[i], frame);
+        interleave(frames.map(move |frame| db.conjoin(conjuncts.clone(), i + 1, frame)))
+    }
+
+    fn lisp_value(&self, call: &[Rc<Term>], frame: &Frame) -> Result<bool, LispError> {
+        let call = call
+            .iter()
+            .map(|term| {
+                term.instantiate(frame, &|v| {
+                    Err(query_error(
+                        "Unknown pat var -- LISP-VALUE",
+                        &Sexp::Symbol(v.to_string()),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let predicate = eval(&call[0], &self.env)?;
+        let arguments = call[1..].iter().map(Value::from_sexp).collect();
+        Ok(apply(predicate, arguments)?.is_true())
+    }
+
+    fn simple_query(self: &Rc<Self>, pattern: &Rc<Term>, frame: Frame) -> Frames {
+        let assertions = self.assertions.borrow().fetch(pattern.key());
+        let matches = {
+            let pattern = pattern.clone();
+            let frame = frame.clone();
+            assertions
+                .into_iter()
+                .filter_map(move |assertion| pattern_match(&pattern, &assertion, frame.clone()))
+        };
+        let db = self.clone();
+        let pattern = pattern.clone();
+        let rules = iter::once(()).flat_map(move |_| db.apply_rules(&pattern, frame.clone()));
+        Box::new(matches.chain(rules))
+    }
+
+    fn apply_rules(self: &Rc<Self>, pattern: &Rc<Term>, frame: Frame) -> Frames {
+        let mut rules = self.rules.borrow().fetch(pattern.key());
+        if pattern.key().is_some() {
+            rules.extend(self.unindexed_rules.borrow().iter().cloned());
+        }
+        let db = self.clone();
+        let pattern = pattern.clone();
+        interleave(rules.into_iter().map(move |rule| {
+            let id = db.rule_counter.get() + 1;
+            db.rule_counter.set(id);
+            match unify_match(&pattern, &rule.conclusion.rename(id), frame.clone()) {
+                Some(frame) => db.qeval(&rule.body.rename(id), frame),
+                None => Box::new(iter::empty()),
+            }
+        }))
+    }
+}
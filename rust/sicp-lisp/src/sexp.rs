@@ -1,11 +1,320 @@
-#[derive(Debug, PartialEq, Eq, Clone)]
+use super::number::*;
+
+use std::fmt;
+use std::rc::Rc;
+
+// Where a list was read: the name of its source and the line and column,
+// both counted from 1, of its opening parenthesis.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Span {
+    pub source: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
+
+// Source text being read, with the offsets at which its lines start, for
+// turning the reader's byte offsets into spans.
+pub struct Source<'a> {
+    name: Rc<str>,
+    text: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(name: &str, text: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Source {
+            name: Rc::from(name),
+            text,
+            line_starts,
+        }
+    }
+
+    fn span(&self, offset: usize) -> Rc<Span> {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let start = self.line_starts[line];
+        Rc::new(Span {
+            source: self.name.clone(),
+            line: line + 1,
+            column: self.text[start..offset].chars().count() + 1,
+        })
+    }
+}
+
+// Gives the list read at `offset` its span, when reading from a source.
+fn located(mut exp: Sexp, source: Option<&Source>, offset: usize) -> Sexp {
+    if let (Sexp::Pair(_, _, span), Some(source)) = (&mut exp, source) {
+        *span = Some(source.span(offset));
+    }
+    exp
+}
+
+// A pair read from source carries the span of the list it starts; spans are
+// ignored when comparing expressions. Cloning, comparing and dropping walk
+// along the cdrs in a loop, so that long lists do not overflow the stack.
+#[derive(Debug)]
 pub enum Sexp {
     Nil,
-    Integer(i64),
+    Boolean(bool),
+    Number(Number),
+    String(String),
+    Char(char),
     Symbol(String),
-    Pair(Box<Sexp>, Box<Sexp>),
+    Pair(Box<Sexp>, Box<Sexp>, Option<Rc<Span>>),
+    Vector(Vec<Sexp>),
 }
 
-pub mod parser {
+impl PartialEq for Sexp {
+    fn eq(&self, other: &Sexp) -> bool {
+        let (mut a, mut b) = (self, other);
+        while let (Sexp::Pair(car_a, cdr_a, _), Sexp::Pair(car_b, cdr_b, _)) = (a, b) {
+            if car_a != car_b {
+                return false;
+            }
+            (a, b) = (cdr_a, cdr_b);
+        }
+        match (a, b) {
+            (Sexp::Nil, Sexp::Nil) => true,
+            (Sexp::Boolean(a), Sexp::Boolean(b)) => a == b,
+            (Sexp::Number(a), Sexp::Number(b)) => a == b,
+            (Sexp::String(a), Sexp::String(b)) => a == b,
+            (Sexp::Char(a), Sexp::Char(b)) => a == b,
+            (Sexp::Symbol(a), Sexp::Symbol(b)) => a == b,
+            (Sexp::Vector(a), Sexp::Vector(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Clone for Sexp {
+    fn clone(&self) -> Sexp {
+        let mut pairs = vec![];
+        let mut tail = self;
+        while let Sexp::Pair(car, cdr, span) = tail {
+            pairs.push((car, span));
+            tail = cdr;
+        }
+        let tail = match tail {
+            Sexp::Nil => Sexp::Nil,
+            Sexp::Boolean(b) => Sexp::Boolean(*b),
+            Sexp::Number(n) => Sexp::Number(n.clone()),
+            Sexp::String(s) => Sexp::String(s.clone()),
+            Sexp::Char(c) => Sexp::Char(*c),
+            Sexp::Symbol(s) => Sexp::Symbol(s.clone()),
+            Sexp::Vector(elems) => Sexp::Vector(elems.clone()),
+            Sexp::Pair(..) => unreachable!("the cdrs are followed above"),
+        };
+        pairs.into_iter().rev().fold(tail, |cdr, (car, span)| {
+            Sexp::Pair(car.clone(), Box::new(cdr), span.clone())
+        })
+    }
+}
+
+impl Drop for Sexp {
+    fn drop(&mut self) {
+        let mut next = match self {
+            Sexp::Pair(_, cdr, _) => std::mem::replace(&mut **cdr, Sexp::Nil),
+            _ => return,
+        };
+        while let Sexp::Pair(_, cdr, _) = &mut next {
+            next = std::mem::replace(&mut **cdr, Sexp::Nil);
+        }
+    }
+}
+
+impl Sexp {
+    pub fn symbol(s: &str) -> Sexp {
+        Sexp::Symbol(s.to_string())
+    }
+
+    pub fn cons(car: Sexp, cdr: Sexp) -> Sexp {
+        Sexp::Pair(Box::new(car), Box::new(cdr), None)
+    }
+
+    pub fn list(elems: Vec<Sexp>) -> Sexp {
+        Sexp::list_with_tail(elems, Sexp::Nil)
+    }
+
+    pub fn list_with_tail(elems: Vec<Sexp>, tail: Sexp) -> Sexp {
+        elems
+            .into_iter()
+            .rev()
+            .fold(tail, |cdr, car| Sexp::cons(car, cdr))
+    }
+
+    // The elements of a list followed by whatever ends it, so that the shape
+    // of a form can be matched with slice patterns: (a b . c) is ([a, b], c).
+    pub fn elements(&self) -> (Vec<&Sexp>, &Sexp) {
+        let mut elems = vec![];
+        let mut p = self;
+        while let Sexp::Pair(car, cdr, _) = p {
+            elems.push(&**car);
+            p = cdr;
+        }
+        (elems, p)
+    }
+
+    // The span of the list this expression starts, if it was read from
+    // source.
+    pub fn span(&self) -> Option<&Rc<Span>> {
+        match self {
+            Sexp::Pair(_, _, span) => span.as_ref(),
+            _ => None,
+        }
+    }
+
+    // The list after its first `n` elements.
+    pub fn tail(&self, n: usize) -> &Sexp {
+        let mut p = self;
+        for _ in 0..n {
+            match p {
+                Sexp::Pair(_, cdr, _) => p = cdr,
+                _ => break,
+            }
+        }
+        p
+    }
+
+    // Returns the elements of a proper list, or None for anything else.
+    pub fn to_vec(&self) -> Option<Vec<&Sexp>> {
+        let mut elems = vec![];
+        let mut p = self;
+        while let Sexp::Pair(car, cdr, _) = p {
+            elems.push(&**car);
+            p = cdr;
+        }
+        match p {
+            Sexp::Nil => Some(elems),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Nil => write!(f, "()"),
+            Sexp::Boolean(true) => write!(f, "#t"),
+            Sexp::Boolean(false) => write!(f, "#f"),
+            Sexp::Number(n) => write!(f, "{}", n),
+            Sexp::String(s) => write_string(f, s),
+            Sexp::Char(c) => write_char(f, *c),
+            Sexp::Symbol(s) => write!(f, "{}", s),
+            Sexp::Vector(elems) => {
+                write!(f, "#(")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, ")")
+            }
+            Sexp::Pair(car, cdr, _) => {
+                write!(f, "({}", car)?;
+                let mut p = &**cdr;
+                while let Sexp::Pair(car, cdr, _) = p {
+                    write!(f, " {}", car)?;
+                    p = cdr;
+                }
+                match p {
+                    Sexp::Nil => write!(f, ")"),
+                    _ => write!(f, " . {})", p),
+                }
+            }
+        }
+    }
+}
+
+const CHAR_NAMES: [(&str, char); 9] = [
+    ("alarm", '\x07'),
+    ("backspace", '\x08'),
+    ("delete", '\x7f'),
+    ("escape", '\x1b'),
+    ("newline", '\n'),
+    ("null", '\0'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("tab", '\t'),
+];
+
+// The character named by a #\ literal: a single character, a name such as
+// `space`, or a hex scalar value such as `x41`.
+pub fn char_from_name(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return Some(c),
+        (Some('x'), Some(_)) => {
+            if let Ok(code) = u32::from_str_radix(&name[1..], 16) {
+                return std::char::from_u32(code);
+            }
+        }
+        _ => {}
+    }
+    CHAR_NAMES.iter().find(|(n, _)| *n == name).map(|(_, c)| *c)
+}
+
+// Writes a string in the reader's syntax, quoted and escaped.
+pub fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\x07' => write!(f, "\\a")?,
+            c if c.is_control() => write!(f, "\\x{:x};", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Writes a character in the reader's #\ syntax.
+pub fn write_char(f: &mut fmt::Formatter<'_>, c: char) -> fmt::Result {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => write!(f, "#\\{}", name),
+        None if c.is_control() => write!(f, "#\\x{:x}", c as u32),
+        None => write!(f, "#\\{}", c),
+    }
+}
+
+#[allow(clippy::all, ellipsis_inclusive_range_patterns)]
+mod grammar {
+    use super::super::number::Number;
+    use super::{char_from_name, located, Source};
     include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
 }
+
+pub mod parser {
+    use super::{grammar, Sexp, Source};
+
+    pub use super::grammar::ParseError;
+
+    pub fn expression(input: &str) -> Result<Sexp, ParseError> {
+        grammar::expression(input, None)
+    }
+
+    pub fn expressions(input: &str) -> Result<Vec<Sexp>, ParseError> {
+        grammar::expressions(input, None)
+    }
+
+    // Reads the expressions in `input`, giving each list the span at which
+    // it starts in the source called `name`.
+    pub fn read(input: &str, name: &str) -> Result<Vec<Sexp>, ParseError> {
+        grammar::expressions(input, Some(&Source::new(name, input)))
+    }
+}
@@ -0,0 +1,471 @@
+// R7RS syntax-rules. A rule's pattern is matched against the macro call by
+// destructuring the same Pair/Symbol structure that `eval` works on, and its
+// template is instantiated with the matched pieces.
+//
+// Hygiene is provided by renaming: every identifier that the template itself
+// binds (lambda parameters, let/let*/letrec/do variables, a named let's name,
+// a guard variable) is replaced by a fresh symbol on each expansion, so the
+// bindings a macro introduces can never capture identifiers from the call.
+// The other way round, a free identifier of the template that a binding
+// around the call would capture is replaced by an alias, which the scope of
+// the call resolves in the scope the macro was defined in; an alias for a
+// keyword such as `if` still stands for the keyword. Auxiliary keywords such
+// as `else`, which the forms around them look for by name, are never
+// aliased. Quoted data in the template is left as written.
+
+use super::environment::Scope;
+use super::error::*;
+use super::sexp::*;
+
+use super::sexp::Sexp::{Nil, Pair, Symbol};
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Numbers the fresh names of expansions. It is shared by every interpreter,
+// which only makes the names of one interpreter's expansions skip numbers.
+static RENAME_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const AUXILIARY_KEYWORDS: &[&str] = &["else", "=>", "unquote", "unquote-splicing"];
+
+pub struct SyntaxRules {
+    ellipsis: String,
+    literals: Vec<String>,
+    rules: Vec<Rule>,
+    // The scope the macro was defined in.
+    scope: Option<Rc<Scope>>,
+}
+
+struct Rule {
+    pattern: Sexp,
+    template: Sexp,
+    // Template identifiers in binding positions, renamed on each expansion.
+    renamed: HashSet<String>,
+    // The other identifiers of the template outside quoted data.
+    free: HashSet<String>,
+}
+
+enum Binding {
+    One(Sexp),
+    Many(Vec<Bindings>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+fn ill_formed(exp: &Sexp) -> LispError {
+    LispError::IllFormedSpecialForm(exp.clone())
+}
+
+impl SyntaxRules {
+    // (syntax-rules (literal ...) (pattern template) ...)
+    // (syntax-rules ellipsis (literal ...) (pattern template) ...)
+    pub fn new(spec: &Sexp, scope: &Option<Rc<Scope>>) -> Result<Self, LispError> {
+        let operands = match spec {
+            Pair(car, operands, _) if matches!(&**car, Symbol(tag) if tag == "syntax-rules") => {
+                operands
+            }
+            _ => return Err(ill_formed(spec)),
+        };
+        let operands = operands.to_vec().ok_or_else(|| ill_formed(spec))?;
+        let (ellipsis, operands) = match operands.as_slice() {
+            [Symbol(ellipsis), rest @ ..] => (ellipsis.clone(), rest),
+            rest => ("...".to_string(), rest),
+        };
+        let (literals, rules) = match operands.split_first() {
+            Some(split) => split,
+            None => return Err(ill_formed(spec)),
+        };
+
+        let mut syntax_rules = SyntaxRules {
+            ellipsis,
+            literals: vec![],
+            rules: vec![],
+            scope: scope.clone(),
+        };
+        for literal in literals.to_vec().ok_or_else(|| ill_formed(spec))? {
+            match literal {
+                Symbol(s) => syntax_rules.literals.push(s.clone()),
+                _ => return Err(ill_formed(spec)),
+            }
+        }
+        for rule in rules {
+            match rule.to_vec().as_deref() {
+                Some([pattern @ Pair(..), template]) => {
+                    let mut variables = HashSet::new();
+                    syntax_rules.pattern_variables(pattern, &mut variables);
+                    let mut renamed = HashSet::new();
+                    binding_identifiers(template, &mut renamed);
+                    let renamed: HashSet<String> = renamed
+                        .into_iter()
+                        .filter(|s| !variables.contains(s) && *s != syntax_rules.ellipsis)
+                        .collect();
+                    let mut free = HashSet::new();
+                    free_identifiers(template, &mut free);
+                    let free = free
+                        .into_iter()
+                        .filter(|s| {
+                            !variables.contains(s)
+                                && !renamed.contains(s)
+                                && *s != syntax_rules.ellipsis
+                                && !AUXILIARY_KEYWORDS.contains(&s.as_str())
+                        })
+                        .collect();
+                    syntax_rules.rules.push(Rule {
+                        pattern: (*pattern).clone(),
+                        template: (*template).clone(),
+                        renamed,
+                        free,
+                    });
+                }
+                _ => return Err(ill_formed(spec)),
+            }
+        }
+        Ok(syntax_rules)
+    }
+
+    // Expands `form`, a call of the macro in `scope`.
+    pub fn expand(&self, form: &Sexp, scope: &Option<Rc<Scope>>) -> Result<Sexp, LispError> {
+        for rule in &self.rules {
+            let mut bindings = HashMap::new();
+            // The keyword position of the pattern is ignored.
+            if let (Pair(_, pattern, _), Pair(_, operands, _)) = (&rule.pattern, form) {
+                if self.match_pattern(pattern, operands, &mut bindings) {
+                    let id = RENAME_COUNTER.fetch_add(1, Ordering::Relaxed);
+                    let mut renames: HashMap<String, String> = rule
+                        .renamed
+                        .iter()
+                        .map(|s| (s.clone(), format!("{}#{}", s, id)))
+                        .collect();
+                    for s in &rule.free {
+                        let fresh = || {
+                            let id = RENAME_COUNTER.fetch_add(1, Ordering::Relaxed);
+                            format!("{}#{}", s, id)
+                        };
+                        if let Some(alias) = Scope::alias(scope, &self.scope, s, fresh) {
+                            renames.insert(s.clone(), alias);
+                        }
+                    }
+                    return self.instantiate(&rule.template, &bindings, &renames, form);
+                }
+            }
+        }
+        Err(ill_formed(form))
+    }
+
+    fn is_ellipsis(&self, exp: &Sexp) -> bool {
+        match exp {
+            Symbol(s) => *s == self.ellipsis,
+            _ => false,
+        }
+    }
+
+    fn pattern_variables(&self, pattern: &Sexp, variables: &mut HashSet<String>) {
+        match pattern {
+            Symbol(s) if *s != self.ellipsis && *s != "_" && !self.literals.contains(s) => {
+                variables.insert(s.clone());
+            }
+            Pair(car, cdr, _) => {
+                self.pattern_variables(car, variables);
+                self.pattern_variables(cdr, variables);
+            }
+            Sexp::Vector(elems) => {
+                for elem in elems {
+                    self.pattern_variables(elem, variables);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn match_pattern(&self, pattern: &Sexp, form: &Sexp, bindings: &mut Bindings) -> bool {
+        match pattern {
+            Symbol(s) if *s == "_" => true,
+            Symbol(s) if self.literals.contains(s) => form == pattern,
+            Symbol(s) => {
+                bindings.insert(s.clone(), Binding::One(form.clone()));
+                true
+            }
+            Pair(p, cdr, _) if matches!(&**cdr, Pair(e, _, _) if self.is_ellipsis(e)) => {
+                // The elements after the ellipsis must match the end of the form.
+                let rest = cdr.tail(1);
+                let mut rest_len = 0;
+                let mut q = rest;
+                while let Pair(_, cdr, _) = q {
+                    rest_len += 1;
+                    q = cdr;
+                }
+                let mut items = vec![];
+                let mut f = form;
+                while let Pair(car, cdr, _) = f {
+                    items.push(&**car);
+                    f = cdr;
+                }
+                if items.len() < rest_len {
+                    return false;
+                }
+                let repeated = items.len() - rest_len;
+
+                let mut matches = vec![];
+                for item in &items[..repeated] {
+                    let mut b = HashMap::new();
+                    if !self.match_pattern(p, item, &mut b) {
+                        return false;
+                    }
+                    matches.push(b);
+                }
+                let mut variables = HashSet::new();
+                self.pattern_variables(p, &mut variables);
+                for var in variables {
+                    let seq = matches
+                        .iter_mut()
+                        .map(|b| {
+                            let mut m = HashMap::new();
+                            if let Some(binding) = b.remove(&var) {
+                                m.insert(var.clone(), binding);
+                            }
+                            m
+                        })
+                        .collect();
+                    bindings.insert(var, Binding::Many(seq));
+                }
+
+                let mut f = form;
+                for _ in 0..repeated {
+                    if let Pair(_, cdr, _) = f {
+                        f = cdr;
+                    }
+                }
+                self.match_pattern(rest, f, bindings)
+            }
+            Pair(p_car, p_cdr, _) => match form {
+                Pair(f_car, f_cdr, _) => {
+                    self.match_pattern(p_car, f_car, bindings)
+                        && self.match_pattern(p_cdr, f_cdr, bindings)
+                }
+                _ => false,
+            },
+            // A vector pattern matches the elements of a vector as a list.
+            Sexp::Vector(patterns) => match form {
+                Sexp::Vector(elems) => self.match_pattern(
+                    &Sexp::list(patterns.clone()),
+                    &Sexp::list(elems.clone()),
+                    bindings,
+                ),
+                _ => false,
+            },
+            _ => form == pattern,
+        }
+    }
+
+    fn instantiate(
+        &self,
+        template: &Sexp,
+        bindings: &Bindings,
+        renames: &HashMap<String, String>,
+        form: &Sexp,
+    ) -> Result<Sexp, LispError> {
+        match template {
+            Symbol(s) => match bindings.get(s) {
+                Some(Binding::One(exp)) => Ok(exp.clone()),
+                Some(Binding::Many(_)) => Err(ill_formed(form)),
+                None => Ok(Symbol(renames.get(s).unwrap_or(s).clone())),
+            },
+            Pair(sub, rest, _) => {
+                // (... template) escapes the ellipsis.
+                if self.is_ellipsis(sub) {
+                    if let Pair(escaped, end, _) = &**rest {
+                        if **end == Nil {
+                            return Ok((**escaped).clone());
+                        }
+                    }
+                }
+                // Quoted data gets the matched pieces but no renaming.
+                if matches!(&**sub, Symbol(s) if s == "quote") {
+                    let rest = self.instantiate(rest, bindings, &HashMap::new(), form)?;
+                    return Ok(Sexp::cons((**sub).clone(), rest));
+                }
+                let mut depth = 0;
+                let mut rest: &Sexp = rest;
+                while let Pair(e, cdr, _) = rest {
+                    if !self.is_ellipsis(e) {
+                        break;
+                    }
+                    depth += 1;
+                    rest = cdr;
+                }
+                let rest = self.instantiate(rest, bindings, renames, form)?;
+                if depth == 0 {
+                    let car = self.instantiate(sub, bindings, renames, form)?;
+                    return Ok(Sexp::cons(car, rest));
+                }
+                let mut items = vec![];
+                self.instantiate_ellipsis(sub, bindings, renames, form, depth, &mut items)?;
+                Ok(Sexp::list_with_tail(items, rest))
+            }
+            // Vectors are data, like quoted ones.
+            Sexp::Vector(elems) => {
+                let list = Sexp::list(elems.clone());
+                let list = self.instantiate(&list, bindings, &HashMap::new(), form)?;
+                match list.to_vec() {
+                    Some(elems) => Ok(Sexp::Vector(elems.into_iter().cloned().collect())),
+                    None => Err(ill_formed(form)),
+                }
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    fn instantiate_ellipsis(
+        &self,
+        template: &Sexp,
+        bindings: &Bindings,
+        renames: &HashMap<String, String>,
+        form: &Sexp,
+        depth: usize,
+        items: &mut Vec<Sexp>,
+    ) -> Result<(), LispError> {
+        if depth == 0 {
+            items.push(self.instantiate(template, bindings, renames, form)?);
+            return Ok(());
+        }
+
+        let mut variables = HashSet::new();
+        self.pattern_variables(template, &mut variables);
+        let sequences: Vec<(&String, &Vec<Bindings>)> = variables
+            .iter()
+            .filter_map(|var| match bindings.get(var) {
+                Some(Binding::Many(seq)) => Some((var, seq)),
+                _ => None,
+            })
+            .collect();
+        let len = match sequences.first() {
+            Some((_, seq)) => seq.len(),
+            None => return Err(ill_formed(form)),
+        };
+        if sequences.iter().any(|(_, seq)| seq.len() != len) {
+            return Err(ill_formed(form));
+        }
+
+        for i in 0..len {
+            let mut inner: Bindings = HashMap::new();
+            for (var, binding) in bindings {
+                if let Some((_, seq)) = sequences.iter().find(|(v, _)| *v == var) {
+                    if let Some(b) = seq[i].get(var) {
+                        inner.insert(var.clone(), clone_binding(b));
+                    }
+                } else {
+                    inner.insert(var.clone(), clone_binding(binding));
+                }
+            }
+            self.instantiate_ellipsis(template, &inner, renames, form, depth - 1, items)?;
+        }
+        Ok(())
+    }
+}
+
+fn clone_binding(binding: &Binding) -> Binding {
+    match binding {
+        Binding::One(exp) => Binding::One(exp.clone()),
+        Binding::Many(seq) => Binding::Many(
+            seq.iter()
+                .map(|b| {
+                    b.iter()
+                        .map(|(k, v)| (k.clone(), clone_binding(v)))
+                        .collect()
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn add_parameters(parameters: &Sexp, identifiers: &mut HashSet<String>) {
+    let mut p = parameters;
+    while let Pair(car, cdr, _) = p {
+        match &**car {
+            // The #!optional and #!rest markers are not parameters.
+            Symbol(s) if s.starts_with("#!") => {}
+            Symbol(s) => {
+                identifiers.insert(s.clone());
+            }
+            Pair(name, _, _) => {
+                if let Symbol(s) = &**name {
+                    identifiers.insert(s.clone());
+                }
+            }
+            _ => {}
+        }
+        p = cdr;
+    }
+    if let Symbol(s) = p {
+        identifiers.insert(s.clone());
+    }
+}
+
+fn add_bindings(bindings: &Sexp, identifiers: &mut HashSet<String>) {
+    let mut p = bindings;
+    while let Pair(binding, cdr, _) = p {
+        if let Pair(name, _, _) = &**binding {
+            if let Symbol(s) = &**name {
+                identifiers.insert(s.clone());
+            }
+        }
+        p = cdr;
+    }
+}
+
+// Collects the identifiers of a template, leaving out quoted data.
+fn free_identifiers(template: &Sexp, identifiers: &mut HashSet<String>) {
+    match template {
+        Symbol(s) => {
+            identifiers.insert(s.clone());
+        }
+        Pair(car, _, _) if matches!(&**car, Symbol(s) if s == "quote") => {}
+        Pair(..) => {
+            let mut p = template;
+            while let Pair(car, cdr, _) = p {
+                free_identifiers(car, identifiers);
+                p = cdr;
+            }
+            free_identifiers(p, identifiers);
+        }
+        Sexp::Vector(elems) => {
+            for elem in elems {
+                free_identifiers(elem, identifiers);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Collects the identifiers that binding forms inside a template bind.
+fn binding_identifiers(template: &Sexp, identifiers: &mut HashSet<String>) {
+    match &template.elements().0[..] {
+        [Symbol(tag), parameters, ..] if tag == "lambda" => {
+            add_parameters(parameters, identifiers);
+        }
+        [Symbol(tag), Pair(_, parameters, _), ..] if tag == "define" => {
+            add_parameters(parameters, identifiers);
+        }
+        [Symbol(tag), Symbol(name), bindings, ..] if tag == "let" => {
+            identifiers.insert(name.clone());
+            add_bindings(bindings, identifiers);
+        }
+        [Symbol(tag), bindings, ..]
+            if tag == "let" || tag == "let*" || tag == "letrec" || tag == "do" =>
+        {
+            add_bindings(bindings, identifiers);
+        }
+        [Symbol(tag), Pair(var, _, _), ..] if tag == "guard" => {
+            if let Symbol(var) = &**var {
+                identifiers.insert(var.clone());
+            }
+        }
+        _ => {}
+    }
+    let mut p = template;
+    while let Pair(car, cdr, _) = p {
+        binding_identifiers(car, identifiers);
+        p = cdr;
+    }
+}
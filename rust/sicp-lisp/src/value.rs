@@ -1,44 +1,596 @@
+use super::analyze::{Lambda, Node};
 use super::environment::*;
+use super::error::*;
+use super::eval::{Continuation, Control};
+use super::gc;
+use super::machine::*;
+use super::number::*;
 use super::sexp::*;
+use super::syntax_rules::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::rc::Rc;
 
+pub type Primitive = dyn Fn(&[Rc<Value>]) -> Result<Rc<Value>, LispError>;
+
+// Each entry keeps its original key next to the value.
+pub type HashTable = HashMap<HashKey, (Rc<Value>, Rc<Value>)>;
+
 pub enum Value {
     Nil,
+    Boolean(bool),
     Symbol(String),
-    Integer(i64),
-    Pair(Rc<Value>, Rc<Value>),
-    PrimitiveProcedure(Box<dyn Fn(&[Rc<Value>]) -> Rc<Value>>),
+    Number(Number),
+    String(String),
+    Char(char),
+    Pair(RefCell<Rc<Value>>, RefCell<Rc<Value>>),
+    Vector(RefCell<Vec<Rc<Value>>>),
+    HashTable(RefCell<HashTable>),
+    PrimitiveProcedure(Box<Primitive>),
     CompoundProcedure {
-        body: Vec<Sexp>,
-        parameters: Vec<String>,
+        lambda: Rc<Lambda>,
+        environment: Rc<Environment>,
+    },
+    Macro(Rc<Value>),
+    Syntax(SyntaxRules),
+    Condition(LispError),
+    // A delayed argument of lazy mode.
+    Thunk(RefCell<Thunk>),
+    // What delay returns; unlike a thunk it is only forced by force.
+    Promise(RefCell<Thunk>),
+    // Register-machine values (SICP chapter 5).
+    Machine(RefCell<Machine>),
+    Label(Label),
+    Environment(Rc<Environment>),
+    CompiledProcedure {
+        entry: Label,
         environment: Rc<Environment>,
     },
+    Continuation(Continuation),
+    Control(Control),
+    // What (values ...) returns for anything but exactly one value.
+    Values(Vec<Rc<Value>>),
+}
+
+// Forcing a thunk replaces the expression and its environment with the value
+// (SICP 4.2.2), so it is evaluated at most once.
+pub enum Thunk {
+    Delayed(Rc<Node>, Rc<Environment>),
+    Forced(Rc<Value>),
+}
+
+// Hash table keys: atoms and strings hash by content, everything else by
+// identity. The table holds on to the key itself, so the address of an
+// object key stays valid as long as its entry does.
+#[derive(PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Nil,
+    Boolean(bool),
+    Number(String),
+    String(String),
+    Char(char),
+    Symbol(String),
+    Object(usize),
+}
+
+impl HashKey {
+    pub fn new(value: &Rc<Value>) -> HashKey {
+        match &**value {
+            Value::Nil => HashKey::Nil,
+            Value::Boolean(b) => HashKey::Boolean(*b),
+            Value::Number(n) => HashKey::Number(n.to_string()),
+            Value::String(s) => HashKey::String(s.clone()),
+            Value::Char(c) => HashKey::Char(*c),
+            Value::Symbol(s) => HashKey::Symbol(s.clone()),
+            _ => HashKey::Object(Rc::as_ptr(value) as usize),
+        }
+    }
 }
 
+thread_local! {
+    static NIL: Rc<Value> = Rc::new(Value::Nil);
+}
+
+// Takes the parts of a pair or vector being freed that nothing else holds,
+// so that they can be freed next.
+fn take_parts(value: &mut Value, pending: &mut Vec<Rc<Value>>) {
+    let nil = || {
+        NIL.try_with(Rc::clone)
+            .unwrap_or_else(|_| Rc::new(Value::Nil))
+    };
+    match value {
+        Value::Pair(car, cdr) => {
+            for part in [car.get_mut(), cdr.get_mut()] {
+                if Rc::strong_count(part) == 1
+                    && matches!(**part, Value::Pair(..) | Value::Vector(_))
+                {
+                    pending.push(std::mem::replace(part, nil()));
+                }
+            }
+        }
+        Value::Vector(elems) => pending.append(elems.get_mut()),
+        _ => {}
+    }
+}
+
+// Lists and vectors are freed a part at a time. Left to the default drop,
+// each pair would free its cdr recursively, and a long list or deeply nested
+// vectors would overflow the Rust stack.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut pending = vec![];
+        take_parts(self, &mut pending);
+        while let Some(part) = pending.pop() {
+            if let Ok(mut value) = Rc::try_unwrap(part) {
+                take_parts(&mut value, &mut pending);
+            }
+        }
+    }
+}
+
+// `write` renders data the way the reader reads it back; `display` prints
+// strings and characters as their raw contents.
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.print(f, true)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.print(f, false)
+    }
+}
+
+// Datum labels for printing circular structure, as in #0=(1 2 . #0#): the
+// pairs and vectors that a value reaches again from inside themselves, and
+// the numbers given to those printed so far.
+#[derive(Default)]
+struct Labels {
+    cyclic: HashSet<usize>,
+    numbers: HashMap<usize, usize>,
+}
+
+impl Labels {
+    fn new(value: &Value) -> Self {
+        let mut labels = Labels::default();
+        labels.find_cycles(value);
+        labels
+    }
+
+    // A depth-first walk on a stack of its own, so that structure nested
+    // deeply in the cars or the cdrs does not run out of Rust stack. The
+    // pairs and vectors being walked are in `path`, and those walked already
+    // in `done`.
+    fn find_cycles(&mut self, value: &Value) {
+        let (mut path, mut done) = (HashSet::new(), HashSet::new());
+        let mut pending = vec![];
+        self.enter(value, &mut path, &done, &mut pending);
+        while let Some(walk) = pending.pop() {
+            match walk {
+                Walk::Enter(value) => self.enter(&value, &mut path, &done, &mut pending),
+                Walk::Leave(address) => {
+                    path.remove(&address);
+                    done.insert(address);
+                }
+            }
+        }
+    }
+
+    fn enter(
+        &mut self,
+        value: &Value,
+        path: &mut HashSet<usize>,
+        done: &HashSet<usize>,
+        pending: &mut Vec<Walk>,
+    ) {
+        let address = address_of(value);
+        if !matches!(value, Value::Pair(..) | Value::Vector(_)) || done.contains(&address) {
+            return;
+        }
+        if !path.insert(address) {
+            self.cyclic.insert(address);
+            return;
+        }
+        pending.push(Walk::Leave(address));
+        match value {
+            Value::Pair(car, cdr) => {
+                pending.push(Walk::Enter(cdr.borrow().clone()));
+                pending.push(Walk::Enter(car.borrow().clone()));
+            }
+            Value::Vector(elems) => {
+                pending.extend(elems.borrow().iter().rev().cloned().map(Walk::Enter));
+            }
+            _ => unreachable!("only pairs and vectors are walked"),
+        }
+    }
+
+    // Writes the label of a value in a cycle the first time it is printed;
+    // after that writes a reference to it and returns true.
+    fn write(&mut self, f: &mut fmt::Formatter<'_>, value: &Value) -> Result<bool, fmt::Error> {
+        let address = address_of(value);
+        if !self.cyclic.contains(&address) {
+            return Ok(false);
+        }
+        if let Some(n) = self.numbers.get(&address) {
+            write!(f, "#{}#", n)?;
+            return Ok(true);
+        }
+        let n = self.numbers.len();
+        self.numbers.insert(address, n);
+        write!(f, "#{}=", n)?;
+        Ok(false)
+    }
+
+    fn is_cyclic(&self, value: &Value) -> bool {
+        self.cyclic.contains(&address_of(value))
+    }
+}
+
+enum Walk {
+    Enter(Rc<Value>),
+    Leave(usize),
+}
+
+// What is left to print of a value: another value, the rest of a list
+// after its car, or closing text.
+enum Print {
+    Value(Rc<Value>),
+    Rest(Rc<Value>),
+    Text(&'static str),
+}
+
+// The address of a value, the same as gc::address gives for its Rc.
+fn address_of(value: &Value) -> usize {
+    value as *const Value as *const () as usize
+}
+
+impl Value {
+    fn print(&self, f: &mut fmt::Formatter<'_>, write: bool) -> fmt::Result {
+        self.print_labelled(f, write, &mut Labels::new(self))
+    }
+
+    // Prints on a stack of its own, so that deeply nested lists and vectors
+    // do not run out of Rust stack.
+    fn print_labelled(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        write: bool,
+        labels: &mut Labels,
+    ) -> fmt::Result {
+        let mut pending = vec![];
+        self.print_one(f, write, labels, &mut pending)?;
+        while let Some(print) = pending.pop() {
+            match print {
+                Print::Value(value) => value.print_one(f, write, labels, &mut pending)?,
+                // A labelled pair in the cdrs is printed as a dotted tail.
+                Print::Rest(p) => match &*p {
+                    Value::Pair(car, cdr) if !labels.is_cyclic(&p) => {
+                        write!(f, " ")?;
+                        pending.push(Print::Rest(cdr.borrow().clone()));
+                        pending.push(Print::Value(car.borrow().clone()));
+                    }
+                    Value::Nil => write!(f, ")")?,
+                    _ => {
+                        write!(f, " . ")?;
+                        pending.push(Print::Text(")"));
+                        pending.push(Print::Value(p.clone()));
+                    }
+                },
+                Print::Text(text) => write!(f, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Prints an atom, or the start of a list or vector, leaving the rest
+    // to `pending`.
+    fn print_one(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        write: bool,
+        labels: &mut Labels,
+        pending: &mut Vec<Print>,
+    ) -> fmt::Result {
         match self {
-            Value::Nil => write!(f, "nil"),
+            Value::Nil => write!(f, "()"),
+            Value::Boolean(true) => write!(f, "#t"),
+            Value::Boolean(false) => write!(f, "#f"),
             Value::Symbol(s) => write!(f, "{}", s),
-            Value::Integer(n) => write!(f, "{}", n),
-            Value::Pair(car, cdr) => write!(f, "({:?} . {:?})", car, cdr),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) if write => write_string(f, s),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Char(c) if write => write_char(f, *c),
+            Value::Char(c) => write!(f, "{}", c),
+            Value::Pair(car, cdr) => {
+                if labels.write(f, self)? {
+                    return Ok(());
+                }
+                write!(f, "(")?;
+                pending.push(Print::Rest(cdr.borrow().clone()));
+                pending.push(Print::Value(car.borrow().clone()));
+                Ok(())
+            }
+            Value::Vector(elems) => {
+                if labels.write(f, self)? {
+                    return Ok(());
+                }
+                write!(f, "#(")?;
+                pending.push(Print::Text(")"));
+                for (i, elem) in elems.borrow().iter().enumerate().rev() {
+                    pending.push(Print::Value(elem.clone()));
+                    if i > 0 {
+                        pending.push(Print::Text(" "));
+                    }
+                }
+                Ok(())
+            }
+            Value::HashTable(_) => write!(f, "#<hash-table>"),
             Value::PrimitiveProcedure(_) => write!(f, "#<primitive procedure>"),
-            Value::CompoundProcedure { .. } => write!(f, "#<procedure>"),
+            Value::CompoundProcedure { lambda, .. } => match &lambda.name {
+                Some(name) => write!(f, "#<procedure {}>", name),
+                None => write!(f, "#<procedure>"),
+            },
+            Value::Macro(_) => write!(f, "#<macro>"),
+            Value::Syntax(_) => write!(f, "#<syntax-rules>"),
+            Value::Condition(e) => write!(f, "#<condition: {}>", e),
+            Value::Thunk(_) => write!(f, "#<thunk>"),
+            Value::Promise(_) => write!(f, "#<promise>"),
+            Value::Machine(_) => write!(f, "#<machine>"),
+            Value::Label(_) => write!(f, "#<label>"),
+            Value::Environment(_) => write!(f, "#<environment>"),
+            Value::CompiledProcedure { .. } => write!(f, "#<compiled procedure>"),
+            Value::Continuation(_) => write!(f, "#<continuation>"),
+            Value::Control(_) => write!(f, "#<primitive procedure>"),
+            Value::Values(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    value.print(f, write)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Quoted data: symbols stay symbols instead of being looked up.
+    // Both conversions loop along the cdrs, so that a long quoted list does
+    // not run out of stack.
+    pub fn from_sexp(exp: &Sexp) -> Rc<Self> {
+        let mut cars = vec![];
+        let mut tail = exp;
+        while let Sexp::Pair(car, cdr, _) = tail {
+            cars.push(car);
+            tail = cdr;
+        }
+        let tail = gc::track(match tail {
+            Sexp::Nil => Value::Nil,
+            Sexp::Boolean(b) => Value::Boolean(*b),
+            Sexp::Number(n) => Value::Number(n.clone()),
+            Sexp::String(s) => Value::String(s.clone()),
+            Sexp::Char(c) => Value::Char(*c),
+            Sexp::Symbol(s) => Value::Symbol(s.clone()),
+            Sexp::Vector(elems) => {
+                Value::Vector(RefCell::new(elems.iter().map(Value::from_sexp).collect()))
+            }
+            Sexp::Pair(..) => unreachable!("the cdrs are followed above"),
+        });
+        cars.into_iter()
+            .rev()
+            .fold(tail, |cdr, car| Value::cons(Value::from_sexp(car), cdr))
+    }
+
+    // The inverse of from_sexp, for turning a macro expansion back into code.
+    pub fn to_sexp(&self) -> Option<Sexp> {
+        let mut cars = vec![];
+        let mut rest: Option<Rc<Value>> = None;
+        while let Value::Pair(car, cdr) = rest.as_deref().unwrap_or(self) {
+            cars.push(car.borrow().to_sexp()?);
+            let cdr = cdr.borrow().clone();
+            rest = Some(cdr);
+        }
+        let tail = match rest.as_deref().unwrap_or(self) {
+            Value::Nil => Sexp::Nil,
+            Value::Boolean(b) => Sexp::Boolean(*b),
+            Value::Number(n) => Sexp::Number(n.clone()),
+            Value::String(s) => Sexp::String(s.clone()),
+            Value::Char(c) => Sexp::Char(*c),
+            Value::Symbol(s) => Sexp::Symbol(s.clone()),
+            Value::Vector(elems) => Sexp::Vector(
+                elems
+                    .borrow()
+                    .iter()
+                    .map(|e| e.to_sexp())
+                    .collect::<Option<_>>()?,
+            ),
+            _ => return None,
+        };
+        Some(Sexp::list_with_tail(cars, tail))
+    }
+
+    pub fn cons(car: Rc<Value>, cdr: Rc<Value>) -> Rc<Self> {
+        gc::track(Value::Pair(RefCell::new(car), RefCell::new(cdr)))
+    }
+
+    pub fn car(&self) -> Option<Rc<Value>> {
+        match self {
+            Value::Pair(car, _) => Some(car.borrow().clone()),
+            _ => None,
+        }
+    }
+
+    pub fn cdr(&self) -> Option<Rc<Value>> {
+        match self {
+            Value::Pair(_, cdr) => Some(cdr.borrow().clone()),
+            _ => None,
+        }
+    }
+
+    pub fn list(elems: Vec<Rc<Value>>) -> Rc<Self> {
+        elems
+            .into_iter()
+            .rev()
+            .fold(Rc::new(Value::Nil), |cdr, car| Value::cons(car, cdr))
+    }
+
+    // Returns the elements of a proper list, or None for anything else,
+    // including a circular list: `slow` follows the cdrs at half the speed,
+    // and meeting it again means going round a cycle.
+    pub fn list_to_vec(&self) -> Option<Vec<Rc<Value>>> {
+        let mut elems = vec![];
+        let mut fast: Option<Rc<Value>> = None;
+        let mut slow: Option<Rc<Value>> = None;
+        loop {
+            let next = match fast.as_deref().unwrap_or(self) {
+                Value::Nil => return Some(elems),
+                Value::Pair(car, cdr) => {
+                    elems.push(car.borrow().clone());
+                    cdr.borrow().clone()
+                }
+                _ => return None,
+            };
+            if elems.len() % 2 == 0 {
+                let cdr = slow.as_deref().unwrap_or(self).cdr()?;
+                slow = Some(cdr);
+            }
+            if slow.as_ref().is_some_and(|slow| Rc::ptr_eq(slow, &next)) {
+                return None;
+            }
+            fast = Some(next);
+        }
+    }
+
+    // Reports the addresses this value refers to, or returns false if its
+    // contents are borrowed and cannot be inspected.
+    pub fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Value::Pair(car, cdr) => match (car.try_borrow(), cdr.try_borrow()) {
+                (Ok(car), Ok(cdr)) => {
+                    visit(gc::address(&car));
+                    visit(gc::address(&cdr));
+                }
+                _ => return false,
+            },
+            Value::Vector(elems) => match elems.try_borrow() {
+                Ok(elems) => elems.iter().for_each(|e| visit(gc::address(e))),
+                Err(_) => return false,
+            },
+            Value::HashTable(entries) => match entries.try_borrow() {
+                Ok(entries) => {
+                    for (key, value) in entries.values() {
+                        visit(gc::address(key));
+                        visit(gc::address(value));
+                    }
+                }
+                Err(_) => return false,
+            },
+            Value::CompoundProcedure { environment, .. }
+            | Value::CompiledProcedure { environment, .. } => visit(gc::address(environment)),
+            Value::Macro(transformer) => visit(gc::address(transformer)),
+            Value::Thunk(thunk) | Value::Promise(thunk) => match thunk.try_borrow() {
+                Ok(thunk) => match &*thunk {
+                    Thunk::Delayed(_, env) => visit(gc::address(env)),
+                    Thunk::Forced(value) => visit(gc::address(value)),
+                },
+                Err(_) => return false,
+            },
+            _ => {}
+        }
+        true
+    }
+
+    // Drops the references held by a mutable value; used by the collector to
+    // break garbage cycles.
+    pub fn clear(&self) {
+        match self {
+            Value::Pair(car, cdr) => {
+                if let (Ok(mut car), Ok(mut cdr)) = (car.try_borrow_mut(), cdr.try_borrow_mut()) {
+                    *car = Rc::new(Value::Nil);
+                    *cdr = Rc::new(Value::Nil);
+                }
+            }
+            Value::Vector(elems) => {
+                if let Ok(mut elems) = elems.try_borrow_mut() {
+                    elems.clear();
+                }
+            }
+            Value::HashTable(entries) => {
+                if let Ok(mut entries) = entries.try_borrow_mut() {
+                    entries.clear();
+                }
+            }
+            Value::Thunk(thunk) | Value::Promise(thunk) => {
+                if let Ok(mut thunk) = thunk.try_borrow_mut() {
+                    *thunk = Thunk::Forced(Rc::new(Value::Nil));
+                }
+            }
+            _ => {}
         }
     }
-}
 
-impl Value {
     pub fn ok() -> Rc<Self> {
         Rc::new(Value::Symbol("ok".to_string()))
     }
 
     pub fn t() -> Rc<Self> {
-        Rc::new(Value::Symbol("true".to_string()))
+        Rc::new(Value::Boolean(true))
     }
 
     pub fn f() -> Rc<Self> {
-        Rc::new(Value::Symbol("false".to_string()))
+        Rc::new(Value::Boolean(false))
+    }
+
+    pub fn boolean(b: bool) -> Rc<Self> {
+        Rc::new(Value::Boolean(b))
+    }
+
+    pub fn is_false(&self) -> bool {
+        matches!(self, Value::Boolean(false))
+    }
+
+    pub fn is_true(&self) -> bool {
+        !self.is_false()
+    }
+
+    // Pairs, vectors and strings compare by contents, everything else as
+    // with eq?. The parts still to compare are kept on a stack, so that
+    // nesting in the cars as well as the cdrs does not run out of Rust stack.
+    pub fn is_equal(lhs: &Rc<Value>, rhs: &Rc<Value>) -> bool {
+        let mut pending = vec![(lhs.clone(), rhs.clone())];
+        while let Some((lhs, rhs)) = pending.pop() {
+            if Value::is_eq(&lhs, &rhs) {
+                continue;
+            }
+            match (&*lhs, &*rhs) {
+                (Value::Pair(a, b), Value::Pair(c, d)) => {
+                    pending.push((b.borrow().clone(), d.borrow().clone()));
+                    pending.push((a.borrow().clone(), c.borrow().clone()));
+                }
+                (Value::String(a), Value::String(b)) if a == b => {}
+                (Value::Vector(a), Value::Vector(b)) => {
+                    let (a, b) = (a.borrow(), b.borrow());
+                    if a.len() != b.len() {
+                        return false;
+                    }
+                    pending.extend(a.iter().cloned().zip(b.iter().cloned()).rev());
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // Atoms compare by value, everything else by identity.
+    pub fn is_eq(lhs: &Rc<Value>, rhs: &Rc<Value>) -> bool {
+        match (&**lhs, &**rhs) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            _ => Rc::ptr_eq(lhs, rhs),
+        }
     }
 }